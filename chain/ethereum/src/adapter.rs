@@ -894,12 +894,52 @@ pub trait EthereumAdapter: Send + Sync + 'static {
         block_hash: H256,
     ) -> Box<dyn Future<Item = Option<LightEthereumBlock>, Error = Error> + Send>;
 
+    /// Find a block by its hash with a single request, without retrying, for callers that retry
+    /// on their own. Adapters whose `block_by_hash` retries have to override this.
+    async fn block_by_hash_once(
+        &self,
+        logger: &Logger,
+        block_hash: H256,
+    ) -> Result<Option<LightEthereumBlock>, Error> {
+        self.block_by_hash(logger, block_hash).compat().await
+    }
+
+    /// Whether `blocks_by_hashes_batched` can be used with this adapter.
+    fn supports_batch_requests(&self) -> bool {
+        false
+    }
+
+    /// Find blocks by their hashes with a single JSON-RPC batch request, without retrying. There
+    /// is an answer for every hash, `None` if the node doesn't know the block, but not every node
+    /// answers a batch in order: callers have to match the blocks with `block_hashes` by their
+    /// hash.
+    async fn blocks_by_hashes_batched(
+        &self,
+        _logger: &Logger,
+        _block_hashes: &[H256],
+    ) -> Result<Vec<Option<LightEthereumBlock>>, Error> {
+        Err(anyhow!(
+            "provider `{}` does not support batch requests",
+            self.provider()
+        ))
+    }
+
     fn block_by_number(
         &self,
         logger: &Logger,
         block_number: BlockNumber,
     ) -> Box<dyn Future<Item = Option<LightEthereumBlock>, Error = Error> + Send>;
 
+    /// Find a block by its number with a single request, without retrying, like
+    /// `block_by_hash_once`.
+    async fn block_by_number_once(
+        &self,
+        logger: &Logger,
+        block_number: BlockNumber,
+    ) -> Result<Option<LightEthereumBlock>, Error> {
+        self.block_by_number(logger, block_number).compat().await
+    }
+
     /// Load full information for the specified `block` (in particular, transaction receipts).
     fn load_full_block(
         &self,
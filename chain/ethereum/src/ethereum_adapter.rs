@@ -1099,6 +1099,43 @@ impl EthereumAdapterTrait for EthereumAdapter {
         )
     }
 
+    async fn block_by_hash_once(
+        &self,
+        _logger: &Logger,
+        block_hash: H256,
+    ) -> Result<Option<LightEthereumBlock>, Error> {
+        self.web3
+            .eth()
+            .block_with_txs(BlockId::Hash(block_hash))
+            .await
+            .map_err(Error::from)
+    }
+
+    fn supports_batch_requests(&self) -> bool {
+        true
+    }
+
+    async fn blocks_by_hashes_batched(
+        &self,
+        _logger: &Logger,
+        block_hashes: &[H256],
+    ) -> Result<Vec<Option<LightEthereumBlock>>, Error> {
+        let batching_web3 = Web3::new(Batch::new(self.web3.transport().clone()));
+        let eth = batching_web3.eth();
+        let block_futures = block_hashes
+            .iter()
+            .map(|block_hash| eth.block_with_txs(BlockId::Hash(*block_hash)))
+            .collect::<Vec<_>>();
+
+        batching_web3.transport().submit_batch().await?;
+
+        let mut blocks = Vec::with_capacity(block_futures.len());
+        for block in block_futures {
+            blocks.push(block.await?);
+        }
+        Ok(blocks)
+    }
+
     fn block_by_number(
         &self,
         logger: &Logger,
@@ -1136,6 +1173,18 @@ impl EthereumAdapterTrait for EthereumAdapter {
         )
     }
 
+    async fn block_by_number_once(
+        &self,
+        _logger: &Logger,
+        block_number: BlockNumber,
+    ) -> Result<Option<LightEthereumBlock>, Error> {
+        self.web3
+            .eth()
+            .block_with_txs(BlockId::Number(block_number.into()))
+            .await
+            .map_err(Error::from)
+    }
+
     fn load_full_block(
         &self,
         logger: &Logger,
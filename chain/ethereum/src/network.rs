@@ -131,6 +131,13 @@ impl EthereumNetworkAdapters {
             .map(|ethereum_network_adapter| ethereum_network_adapter.adapter.clone())
     }
 
+    /// All adapters, in the order in which `cheapest` considers them.
+    pub fn all(&self) -> impl Iterator<Item = Arc<EthereumAdapter>> + '_ {
+        self.adapters
+            .iter()
+            .map(|ethereum_network_adapter| ethereum_network_adapter.adapter.clone())
+    }
+
     pub fn remove(&mut self, provider: &str) {
         self.adapters
             .retain(|adapter| adapter.adapter.provider() != provider);
@@ -13,7 +13,7 @@ use graph::{
     },
     url::Url,
 };
-use graph_chain_ethereum::{EthereumAdapter, EthereumNetworks};
+use graph_chain_ethereum::{EthereumAdapter, EthereumAdapterTrait, EthereumNetworks};
 use graph_graphql::prelude::GraphQlRunner;
 use graph_node::config::{self, Config as Cfg};
 use graph_node::manager::color::Terminal;
@@ -32,6 +32,7 @@ use graph_store_postgres::{
 };
 use lazy_static::lazy_static;
 use std::collections::BTreeMap;
+use std::path::PathBuf;
 use std::{collections::HashMap, env, num::ParseIntError, sync::Arc, time::Duration};
 const VERSION_LABEL_KEY: &str = "version";
 
@@ -514,8 +515,231 @@ pub enum ChainCommand {
         #[clap(subcommand)] // Note that we mark a field as a subcommand
         method: CheckBlockMethod,
         /// Chain name (must be an existing chain, see 'chain list')
-        #[clap(empty_values = false)]
-        chain_name: String,
+        #[clap(empty_values = false, required_unless_present = "all-chains")]
+        chain_name: Option<String>,
+        /// Check the blocks on every configured chain instead of a single one. Only works with
+        /// `by-range`
+        #[clap(long, conflicts_with = "chain-name")]
+        all_chains: bool,
+        /// Compare with the provider that has this label in the configuration instead of the
+        /// chain's cheapest provider
+        #[clap(
+            long,
+            global = true,
+            value_name = "LABEL",
+            conflicts_with = "all-chains"
+        )]
+        provider: Option<String>,
+        /// With `--all-chains`, how many chains to check at the same time. Each chain being
+        /// checked holds its own database connections, so keep this well below the size of the
+        /// connection pool
+        #[clap(long, requires = "all-chains", default_value = "1")]
+        parallel_chains: usize,
+        /// How many blocks to request from the JSON-RPC provider concurrently
+        #[clap(long, global = true, default_value_t = commands::check_blocks::DEFAULT_CONCURRENCY)]
+        concurrency: usize,
+        /// Request blocks in JSON-RPC batches of this many, which saves round trips on large
+        /// ranges. `--concurrency` then counts batches. Ignored for providers that can't batch
+        #[clap(long, global = true, value_name = "BLOCKS")]
+        rpc_batch_size: Option<usize>,
+        /// Send at most this many requests per second to the providers, however high the
+        /// concurrency. The rate is lowered for a while when a provider rejects requests as too
+        /// many
+        #[clap(long, global = true, value_name = "REQUESTS")]
+        rps: Option<f64>,
+        /// How many database connections to load cached blocks with at the same time. At most
+        /// all but one of the connections in the pool are used
+        #[clap(long, global = true, default_value = "1")]
+        db_concurrency: usize,
+        /// How many blocks to load, compare and delete at a time
+        #[clap(long, global = true, default_value_t = commands::check_blocks::DEFAULT_CHUNK_SIZE)]
+        chunk_size: usize,
+        /// Only report the blocks that would be deleted, without touching the block cache
+        #[clap(long, global = true)]
+        dry_run: bool,
+        /// Check without touching the block cache, like `--dry-run`, and exit with an error if any
+        /// block diverges from the provider, e.g. to use the check in CI
+        #[clap(long, global = true)]
+        check: bool,
+        /// With `--dry-run`, print the SQL statement and bind parameters that would delete each
+        /// block, without running it
+        #[clap(long, global = true, requires = "dry-run")]
+        explain: bool,
+        /// Delete diverged blocks without asking for confirmation, even if there are many
+        #[clap(long = "yes", short = 'y', global = true)]
+        skip_confirmation: bool,
+        /// Overwrite diverged blocks with the provider's version instead of deleting them
+        #[clap(long, global = true)]
+        replace: bool,
+        /// Abort the check instead of deleting more than this many blocks
+        #[clap(long, global = true)]
+        max_deletions: Option<usize>,
+        /// Only delete a diverged block if it differs from the provider's in at least this many
+        /// fields; blocks with fewer differences are reported and left alone
+        #[clap(long, global = true, value_name = "COUNT")]
+        min_diff_fields: Option<usize>,
+        /// Print the results as `human` readable text or as `json`
+        #[clap(
+            long,
+            global = true,
+            default_value = "human",
+            possible_values = &["human", "json"]
+        )]
+        output: commands::check_blocks::OutputFormat,
+        /// Show the number of fetched and diverging blocks on stderr while checking
+        #[clap(long, global = true)]
+        progress: bool,
+        /// Which part of the blocks to compare: the `header` without the transactions, only the
+        /// `transactions`, or the `full` block
+        #[clap(
+            long,
+            global = true,
+            default_value = "header",
+            possible_values = &["header", "transactions", "full"]
+        )]
+        scope: commands::check_blocks::CompareScope,
+        /// How to print the diff of a diverging block: the `full` structure of the differing
+        /// fields, or a `compact` list with one line per differing field
+        #[clap(
+            long,
+            global = true,
+            default_value = "full",
+            possible_values = &["full", "compact"]
+        )]
+        diff_style: commands::check_blocks::DiffStyle,
+        /// Print diffs as flat `path: cached -> provider` lines, the same as `--diff-style compact`
+        #[clap(long, global = true, conflicts_with = "diff-style")]
+        summary_diff: bool,
+        /// Cut the diff of every diverging block off after this many lines
+        #[clap(long, global = true, value_name = "LINES")]
+        diff_max_lines: Option<usize>,
+        /// Write the diffs of diverging blocks to this file instead of stderr, uncolored and
+        /// never cut off, so that only the summary and progress stay on the terminal
+        #[clap(long, global = true, value_name = "PATH")]
+        diff_file: Option<PathBuf>,
+        /// Compare blocks byte for byte, without lowercasing hex strings and stripping leading
+        /// zeros from quantities first
+        #[clap(long, global = true)]
+        exact: bool,
+        /// Compare withdrawals, blob gas and the beacon block root even if the cached block
+        /// predates them and lacks these fields
+        #[clap(long, global = true)]
+        strict_fields: bool,
+        /// Don't count fields that only the provider's block has as a divergence, e.g. because
+        /// the cached block was stored before graph-node kept them. Changed values still count
+        #[clap(long, global = true, conflicts_with = "strict-fields")]
+        superset_ok: bool,
+        /// Sort the transactions of both blocks by their index before comparing them, so that
+        /// blocks whose transactions are merely in a different order don't diverge
+        #[clap(long, global = true)]
+        ignore_tx_order: bool,
+        /// A block field to leave out when comparing blocks, as a JSON pointer like
+        /// `/totalDifficulty`. Can be repeated; passing it replaces the default list
+        #[clap(
+            long = "ignore-field",
+            value_name = "POINTER",
+            global = true,
+            default_values = commands::check_blocks::DEFAULT_IGNORED_FIELDS
+        )]
+        ignored_fields: Vec<String>,
+        /// Only compare the block fields matching this glob over dotted paths, like
+        /// `transactions.*.from`. `*` matches within a path segment, `**` any number of segments.
+        /// Can be repeated
+        #[clap(long, value_name = "GLOB", global = true)]
+        diff_include: Vec<String>,
+        /// Don't compare the block fields matching this glob over dotted paths, like `extraData`.
+        /// Can be repeated
+        #[clap(long, value_name = "GLOB", global = true)]
+        diff_exclude: Vec<String>,
+        /// How many times to request a block from the provider before giving up
+        #[clap(long, global = true, default_value_t = commands::check_blocks::DEFAULT_MAX_ATTEMPTS)]
+        max_attempts: u32,
+        /// How long to wait in milliseconds before retrying a failed provider request. The delay
+        /// doubles with every further attempt
+        #[clap(
+            long,
+            global = true,
+            value_name = "MILLIS",
+            default_value_t = commands::check_blocks::DEFAULT_RETRY_DELAY_MILLIS
+        )]
+        retry_delay: u64,
+        /// How long in seconds a single provider request may take before it is retried, or
+        /// counted as failed once `--max-attempts` is used up
+        #[clap(
+            long,
+            global = true,
+            value_name = "SECS",
+            default_value_t = commands::check_blocks::DEFAULT_RPC_TIMEOUT_SECS
+        )]
+        rpc_timeout: u64,
+        /// Skip the blocks at most this many blocks below the chain head that the provider
+        /// doesn't have yet, instead of failing. Deeper blocks the provider lacks still fail.
+        /// `backfill` leaves the missing blocks this close to the chain head alone
+        #[clap(long, global = true, value_name = "BLOCKS")]
+        skip_tip_gap: Option<u32>,
+        /// With `by-range`, also ask the provider for the blocks that are missing from the cache
+        #[clap(long, global = true)]
+        find_gaps: bool,
+        /// Add the blocks that are missing from the cache. Implies `--find-gaps`
+        #[clap(long, global = true)]
+        fill_gaps: bool,
+        /// Append a JSON line for every deleted or replaced block to this file
+        #[clap(long, global = true, value_name = "PATH")]
+        audit_log: Option<PathBuf>,
+        /// Don't compare the blocks that this audit log of an earlier check records as deleted
+        /// or replaced, e.g. when re-running a repair after the provider was fixed
+        #[clap(long, global = true, value_name = "PATH")]
+        skip_audited: Option<PathBuf>,
+        /// Compare the blocks in the `--skip-audited` log after all
+        #[clap(long, global = true, requires = "skip-audited")]
+        recheck: bool,
+        /// Write the cached and the provider's version of every diverged block to files in this
+        /// directory, named by the block hash. Works with `--dry-run`, too
+        #[clap(long, global = true, value_name = "PATH")]
+        export_dir: Option<PathBuf>,
+        /// Gzip the audit log and the exported blocks. An audit log whose name ends in `.gz` is
+        /// always gzipped, and with this flag, its name must end in `.gz`
+        #[clap(long, global = true)]
+        compress: bool,
+        /// Compare the cached blocks with the blocks in this directory, stored as `<hash>.json`,
+        /// instead of asking the provider. An `--export-dir` can be used, too. Implies `--dry-run`
+        #[clap(long, global = true, value_name = "DIR")]
+        provider_fixture: Option<PathBuf>,
+        /// Delete cached blocks that are missing a hash, number or parent hash
+        #[clap(long, global = true)]
+        delete_corrupt: bool,
+        /// After deleting a block, check that the cache doesn't hold it anymore, and report
+        /// deletions that removed nothing
+        #[clap(long, global = true)]
+        verify_deletes: bool,
+        /// Print a `graphman` command that re-checks exactly the diverged blocks
+        #[clap(long, global = true)]
+        emit_command: bool,
+        /// Only treat a block as diverged if this many of the chain's providers return the same
+        /// block that differs from the cached one
+        #[clap(long, global = true, default_value = "1")]
+        quorum: usize,
+        /// Print how much time went into provider requests and database lookups
+        #[clap(long, global = true)]
+        timing: bool,
+        /// Stop after the chunk that is being checked once the check has run this long, like
+        /// `90s`, `30m` or `2h`. A number without a unit is in seconds
+        #[clap(
+            long,
+            global = true,
+            value_name = "DURATION",
+            parse(try_from_str = parse_duration_with_unit)
+        )]
+        max_runtime: Option<Duration>,
+        /// Look up a chain head again if it was looked up more than this many seconds ago.
+        /// Without it, every chain head is looked up once per run
+        #[clap(
+            long,
+            global = true,
+            value_name = "SECS",
+            parse(try_from_str = parse_duration_in_secs)
+        )]
+        refresh_head_interval: Option<Duration>,
     },
     /// Truncates the whole block cache for the given chain.
     Truncate {
@@ -736,10 +960,17 @@ pub enum DatabaseCommand {
 }
 #[derive(Clone, Debug, Subcommand)]
 pub enum CheckBlockMethod {
-    /// The hash of the target block
+    /// The hashes of the target blocks
     ByHash {
-        /// The block hash to verify
-        hash: String,
+        /// The block hashes to verify, which are checked together
+        #[clap(required = true, value_name = "HASH")]
+        hashes: Vec<String>,
+    },
+
+    /// Block hashes read from a file, one per line
+    ByHashList {
+        /// The file to read the block hashes from, or `-` to read them from stdin
+        source: String,
     },
 
     /// The number of the target block
@@ -749,9 +980,17 @@ pub enum CheckBlockMethod {
         /// Delete duplicated blocks (by number) if found
         #[clap(long, short, action)]
         delete_duplicates: bool,
+        /// Also ask the provider for the hash of its canonical block with this number, and
+        /// delete the cached block if it is not that block, e.g. because of a reorg
+        #[clap(long = "by-number-reorg")]
+        check_canonical: bool,
     },
 
     /// A block number range, inclusive on both ends.
+    ///
+    /// Instead of `--from` and `--to`, the blocks can be given as a range expression made of
+    /// comma-separated segments, e.g. `100..200,5000..=5005,999999`. Bounds can also be relative
+    /// to the chain head, like `head-1000..=head`, or the hashes of cached blocks
     ByRange {
         /// The first block number to verify
         #[clap(long, short)]
@@ -759,9 +998,139 @@ pub enum CheckBlockMethod {
         /// The last block number to verify
         #[clap(long, short)]
         to: Option<i32>,
+        /// The blocks to verify as a range expression
+        #[clap(conflicts_with_all = &["from", "to"])]
+        range: Option<commands::check_blocks::RangeSet>,
         /// Delete duplicated blocks (by number) if found
         #[clap(long, short, action)]
         delete_duplicates: bool,
+        /// Delete all cached blocks in the range without comparing them with the provider,
+        /// with one query per range segment. Asks for confirmation unless `--yes` is given
+        #[clap(long)]
+        purge: bool,
+        /// Record the highest checked block number in this file after every chunk whose blocks
+        /// were fixed
+        #[clap(long, value_name = "PATH", conflicts_with = "purge")]
+        checkpoint: Option<PathBuf>,
+        /// Skip the blocks up to the one recorded in the `--checkpoint` file
+        #[clap(long, requires = "checkpoint")]
+        resume: bool,
+    },
+
+    /// List the blocks in a range that diverge from the provider, without changing the cache.
+    ///
+    /// Only the hashes of the diverged blocks are printed, one per line, so they can be passed
+    /// to `by-hash-list` later
+    Scan {
+        /// The first block number to scan
+        #[clap(long, short)]
+        from: Option<i32>,
+        /// The last block number to scan
+        #[clap(long, short)]
+        to: Option<i32>,
+        /// The blocks to scan as a range expression
+        #[clap(conflicts_with_all = &["from", "to"])]
+        range: Option<commands::check_blocks::RangeSet>,
+        /// Record the highest scanned block number in this file after every chunk without
+        /// diverged blocks
+        #[clap(long, value_name = "PATH")]
+        checkpoint: Option<PathBuf>,
+        /// Skip the blocks up to the one recorded in the `--checkpoint` file
+        #[clap(long, requires = "checkpoint")]
+        resume: bool,
+    },
+
+    /// Compare the transaction receipts cached with the blocks in a range with the provider's.
+    ///
+    /// Receipts are cached with their block, so a block whose receipts diverge is deleted, or
+    /// replaced with `--replace`. Blocks cached without receipts are skipped
+    Receipts {
+        /// The blocks whose receipts to check as a range expression, e.g. `100..200,5000..=5005`
+        #[clap(env = "GRAPHMAN_FIX_RANGE")]
+        range: commands::check_blocks::RangeSet,
+    },
+
+    /// Quickly check whether a full check of a range is likely to find anything.
+    ///
+    /// Compares the number of cached blocks in the range with the range's length and with the
+    /// blocks the provider has, and compares a sample of the blocks with the provider's. Exits
+    /// with an error if any of that looks wrong. This does not change the cache
+    Quickcheck {
+        /// The blocks to check as a range expression, e.g. `100..200,5000..=5005`
+        #[clap(env = "GRAPHMAN_FIX_RANGE")]
+        range: commands::check_blocks::RangeSet,
+        /// How many blocks, spread evenly over the range, to compare with the provider
+        #[clap(long, default_value = "20", value_name = "BLOCKS")]
+        sample_size: usize,
+    },
+
+    /// Add the provider's canonical block for every number in a range that has no cached block.
+    ///
+    /// Useful after part of the cache was lost. Numbers that already have a block are left
+    /// alone, and numbers the provider has no block for either are skipped
+    Backfill {
+        /// The blocks to backfill as a range expression, e.g. `100..200,5000..=5005`
+        #[clap(env = "GRAPHMAN_FIX_RANGE")]
+        range: commands::check_blocks::RangeSet,
+    },
+
+    /// Check that the cached blocks in a range link up through their parent hashes.
+    ///
+    /// This only looks at the block cache and does not contact the provider
+    CheckLinkage {
+        /// The blocks to check as a range expression, e.g. `100..200,5000..=5005`
+        #[clap(env = "GRAPHMAN_FIX_RANGE")]
+        range: commands::check_blocks::RangeSet,
+        /// Delete blocks whose parent hash doesn't match and their descendants in the range
+        #[clap(long)]
+        delete: bool,
+    },
+
+    /// Recompute the hash of each cached block in a range from its header and compare it with
+    /// the hash the block is cached under.
+    ///
+    /// This only looks at the block cache and does not contact the provider. Blocks that don't
+    /// match are corrupt cache entries; they are only deleted with `--delete-corrupt`
+    VerifyChecksums {
+        /// The blocks to verify as a range expression, e.g. `100..200,5000..=5005`
+        #[clap(env = "GRAPHMAN_FIX_RANGE")]
+        range: commands::check_blocks::RangeSet,
+    },
+
+    /// List the block numbers in a range for which the cache holds more than one block.
+    ///
+    /// This only looks at the block cache and does not change it
+    ListForks {
+        /// The blocks to look at as a range expression, e.g. `100..200,5000..=5005`
+        #[clap(env = "GRAPHMAN_FIX_RANGE")]
+        range: commands::check_blocks::RangeSet,
+    },
+
+    /// List the cached blocks in a range whose contents are identical to those of another
+    /// cached block, e.g. because a block was stored under the wrong hash.
+    ///
+    /// This only looks at the block cache and does not change it
+    FindDupes {
+        /// The blocks to look at as a range expression, e.g. `100..200,5000..=5005`
+        #[clap(env = "GRAPHMAN_FIX_RANGE")]
+        range: commands::check_blocks::RangeSet,
+    },
+
+    /// Compare the cached blocks in a range with those of another block cache, e.g. that of a
+    /// redundant node, and list where they disagree.
+    ///
+    /// This does not contact the provider and changes neither cache. The other cache is that
+    /// of the chain in the databases of `--other-config`, or that of `--other-chain`
+    DiffCaches {
+        /// The blocks to compare as a range expression, e.g. `100..200,5000..=5005`
+        #[clap(env = "GRAPHMAN_FIX_RANGE")]
+        range: commands::check_blocks::RangeSet,
+        /// The configuration file of the node whose cache to compare with
+        #[clap(long, value_name = "FILE")]
+        other_config: Option<String>,
+        /// The name of the chain in the other cache, if it is not the same
+        #[clap(long, value_name = "CHAIN")]
+        other_chain: Option<String>,
     },
 }
 
@@ -826,6 +1195,26 @@ impl Context {
         }
     }
 
+    /// A context for the graph-node installation whose configuration is in the file `config`,
+    /// e.g. to look at the block cache of a redundant node
+    fn for_config(&self, config: &str) -> anyhow::Result<Self> {
+        let mut config_opt = config::Opt::default();
+        config_opt.config = Some(config.to_string());
+        config_opt.store_connection_pool_size = 5;
+        config_opt.node_id = self.node_id.to_string();
+        let config = Cfg::load(&self.logger, &config_opt)
+            .with_context(|| format!("Configuration error in `{config}`"))?;
+        Ok(Context::new(
+            self.logger.clone(),
+            self.node_id.clone(),
+            config,
+            self.ipfs_url.clone(),
+            self.arweave_url.clone(),
+            self.fork_base.clone(),
+            None,
+        ))
+    }
+
     fn metrics_registry(&self) -> Arc<MetricsRegistry> {
         self.registry.clone()
     }
@@ -970,21 +1359,94 @@ impl Context {
             .ok_or_else(|| anyhow::anyhow!("Could not find a network named '{}'", chain_name))
     }
 
+    /// The chain stores for `chain_name` and `other_chain`, which share the databases and
+    /// connection pools of this context
+    fn chain_store_pair(
+        self,
+        chain_name: &str,
+        other_chain: &str,
+    ) -> anyhow::Result<(Arc<ChainStore>, Arc<ChainStore>)> {
+        use graph::components::store::BlockStore;
+        let block_store = self.store().block_store();
+        let chain_store = |chain_name: &str| {
+            block_store
+                .chain_store(chain_name)
+                .ok_or_else(|| anyhow::anyhow!("Could not find a network named '{}'", chain_name))
+        };
+        Ok((chain_store(chain_name)?, chain_store(other_chain)?))
+    }
+
+    /// The chain store for `chain_name`, and its cheapest adapter, or the one with the label
+    /// `provider` if that is given, together with all other adapters for the chain
     async fn chain_store_and_adapter(
         self,
         chain_name: &str,
-    ) -> anyhow::Result<(Arc<ChainStore>, Arc<EthereumAdapter>)> {
+        provider: Option<&str>,
+    ) -> anyhow::Result<(
+        Arc<ChainStore>,
+        Arc<EthereumAdapter>,
+        Vec<Arc<EthereumAdapter>>,
+    )> {
         let ethereum_networks = self.ethereum_networks().await?;
         let chain_store = self.chain_store(chain_name)?;
-        let ethereum_adapter = ethereum_networks
+        let mut adapters = ethereum_networks
             .networks
             .get(chain_name)
-            .and_then(|adapters| adapters.cheapest())
-            .ok_or(anyhow::anyhow!(
+            .map(|adapters| adapters.all().collect::<Vec<_>>())
+            .unwrap_or_default();
+        if adapters.is_empty() {
+            bail!(
                 "Failed to obtain an Ethereum adapter for chain '{}'",
                 chain_name
-            ))?;
-        Ok((chain_store, ethereum_adapter))
+            );
+        }
+        let index = match provider {
+            Some(provider) => adapters
+                .iter()
+                .position(|adapter| adapter.provider() == provider)
+                .ok_or_else(|| {
+                    let labels: Vec<_> =
+                        adapters.iter().map(|adapter| adapter.provider()).collect();
+                    anyhow::anyhow!(
+                        "Chain '{}' has no provider labelled '{}'; its providers are {}",
+                        chain_name,
+                        provider,
+                        labels.join(", ")
+                    )
+                })?,
+            None => 0,
+        };
+        let ethereum_adapter = adapters.remove(index);
+        Ok((chain_store, ethereum_adapter, adapters))
+    }
+
+    /// The chain stores of all configured chains, together with the cheapest adapter for each
+    /// chain that has one
+    async fn chain_stores_and_adapters(
+        self,
+    ) -> anyhow::Result<(
+        Vec<Arc<ChainStore>>,
+        HashMap<String, Arc<dyn EthereumAdapterTrait>>,
+    )> {
+        use graph::components::store::BlockStore;
+
+        let ethereum_networks = self.ethereum_networks().await?;
+        let ethereum_adapters = ethereum_networks
+            .networks
+            .iter()
+            .filter_map(|(name, adapters)| {
+                let adapter: Arc<dyn EthereumAdapterTrait> = adapters.cheapest()?;
+                Some((name.clone(), adapter))
+            })
+            .collect();
+        let block_store = self.store().block_store();
+        let mut chain_names: Vec<_> = ethereum_networks.networks.keys().collect();
+        chain_names.sort();
+        let chain_stores = chain_names
+            .into_iter()
+            .filter_map(|name| block_store.chain_store(name))
+            .collect();
+        Ok((chain_stores, ethereum_adapters))
     }
 }
 
@@ -1269,45 +1731,418 @@ async fn main() -> anyhow::Result<()> {
                     let (block_store, primary) = ctx.block_store_and_primary_pool();
                     commands::chain::remove(primary, block_store, name)
                 }
-                CheckBlocks { method, chain_name } => {
-                    use commands::check_blocks::{by_hash, by_number, by_range};
+                CheckBlocks {
+                    method,
+                    chain_name,
+                    all_chains,
+                    provider,
+                    parallel_chains,
+                    concurrency,
+                    rpc_batch_size,
+                    rps,
+                    db_concurrency,
+                    chunk_size,
+                    dry_run,
+                    check,
+                    explain,
+                    skip_confirmation,
+                    replace,
+                    max_deletions,
+                    min_diff_fields,
+                    output,
+                    progress,
+                    scope,
+                    diff_style,
+                    summary_diff,
+                    diff_max_lines,
+                    diff_file,
+                    exact,
+                    strict_fields,
+                    superset_ok,
+                    ignore_tx_order,
+                    ignored_fields,
+                    diff_include,
+                    diff_exclude,
+                    max_attempts,
+                    retry_delay,
+                    rpc_timeout,
+                    skip_tip_gap,
+                    timing,
+                    find_gaps,
+                    fill_gaps,
+                    audit_log,
+                    skip_audited,
+                    recheck,
+                    export_dir,
+                    compress,
+                    provider_fixture,
+                    delete_corrupt,
+                    verify_deletes,
+                    emit_command,
+                    quorum,
+                    refresh_head_interval,
+                    max_runtime,
+                } => {
+                    use commands::check_blocks::{
+                        backfill, by_hash, by_hash_list, by_number, by_range, by_range_all_chains,
+                        check_audit_log, check_linkage, check_receipts, diff_caches, find_dupes,
+                        list_forks, purge_range, quickcheck, read_audited_hashes, verify_checksums,
+                        ChainHeads, CheckBlocksMetrics, CheckOptions, DiffFile, DiffRenderOptions,
+                        DiffStyle, Interrupt, RangeSet, RateLimiter, Witnesses,
+                    };
                     use CheckBlockMethod::*;
+                    if let Some(rps) = rps {
+                        if rps <= 0.0 || !rps.is_finite() {
+                            bail!("`--rps` must be a positive number of requests per second");
+                        }
+                    }
+                    if let Some(audit_log) = &audit_log {
+                        check_audit_log(audit_log, compress)?;
+                    }
                     let logger = ctx.logger.clone();
-                    let (chain_store, ethereum_adapter) =
-                        ctx.chain_store_and_adapter(&chain_name).await?;
-                    match method {
-                        ByHash { hash } => {
-                            by_hash(&hash, chain_store, &ethereum_adapter, &logger).await
+                    let list_only = matches!(method, Scan { .. });
+                    // Only ranges are checked in order, so that a checkpoint means something
+                    let (checkpoint, resume) = match &method {
+                        ByRange {
+                            checkpoint, resume, ..
+                        }
+                        | Scan {
+                            checkpoint, resume, ..
+                        } => (checkpoint.clone(), *resume),
+                        _ => (None, false),
+                    };
+                    let mut options = CheckOptions {
+                        concurrency,
+                        rpc_batch_size,
+                        db_concurrency,
+                        // A scan never changes the cache, and neither does a replay from a fixture
+                        dry_run: dry_run || check || list_only || provider_fixture.is_some(),
+                        explain,
+                        skip_confirmation,
+                        replace,
+                        chunk_size,
+                        max_deletions,
+                        min_diff_fields,
+                        output,
+                        progress,
+                        scope,
+                        diff_render: DiffRenderOptions {
+                            style: if summary_diff {
+                                DiffStyle::Compact
+                            } else {
+                                diff_style
+                            },
+                            color: Terminal::stderr_colors(),
+                            max_lines: diff_max_lines,
+                        },
+                        diff_file: diff_file
+                            .map(|path| DiffFile::create(path).map(Arc::new))
+                            .transpose()?,
+                        exact,
+                        strict_fields,
+                        superset_ok,
+                        ignore_tx_order,
+                        ignored_fields,
+                        diff_include,
+                        diff_exclude,
+                        max_attempts,
+                        retry_delay: Duration::from_millis(retry_delay),
+                        rpc_timeout: Duration::from_secs(rpc_timeout),
+                        skip_tip_gap,
+                        timing,
+                        find_gaps: find_gaps || fill_gaps,
+                        fill_gaps,
+                        audit_log,
+                        skip_audited: match skip_audited {
+                            Some(path) if !recheck => Some(Arc::new(read_audited_hashes(&path)?)),
+                            _ => None,
+                        },
+                        export_dir,
+                        compress,
+                        provider_fixture,
+                        delete_corrupt,
+                        verify_deletes,
+                        emit_command,
+                        quorum,
+                        witnesses: Witnesses::default(),
+                        checkpoint,
+                        resume,
+                        list_only,
+                        status: None,
+                        metrics: Some(Arc::new(CheckBlocksMetrics::new(ctx.metrics_registry()))),
+                        rate_limiter: rps.map(|rps| Arc::new(RateLimiter::new(rps))),
+                        interrupt: match max_runtime {
+                            Some(max_runtime) => {
+                                Interrupt::on_ctrl_c().with_max_runtime(max_runtime)
+                            }
+                            None => Interrupt::on_ctrl_c(),
+                        },
+                        chain_heads: Arc::new(ChainHeads::new(refresh_head_interval)),
+                        deletions: Arc::default(),
+                        audit_stream: Arc::default(),
+                    };
+                    if all_chains {
+                        if options.checkpoint.is_some() {
+                            bail!("`--checkpoint` can't be used with `--all-chains`");
+                        }
+                        if options.quorum > 1 {
+                            bail!("`--quorum` can't be used with `--all-chains`");
+                        }
+                        if parallel_chains == 0 {
+                            bail!("`--parallel-chains` must be at least 1");
+                        }
+                        let (range, delete_duplicates) = match method {
+                            ByRange { purge: true, .. } => {
+                                bail!("`--purge` can't be used with `--all-chains`")
+                            }
+                            ByRange {
+                                from,
+                                to,
+                                range,
+                                delete_duplicates,
+                                purge: false,
+                                ..
+                            } => (RangeSet::from_args(range, from, to)?, delete_duplicates),
+                            _ => bail!("`--all-chains` can only be used with `by-range`"),
+                        };
+                        let (chain_stores, ethereum_adapters) =
+                            ctx.chain_stores_and_adapters().await?;
+                        let report = by_range_all_chains(
+                            chain_stores,
+                            &ethereum_adapters,
+                            &range,
+                            &logger,
+                            delete_duplicates,
+                            parallel_chains,
+                            &options,
+                        )
+                        .await;
+                        report.render(&options)?;
+                        report.emit_repair_commands(&options);
+                        let failed = report.failed_chains();
+                        if failed > 0 {
+                            bail!("Checking blocks failed for {failed} chains");
+                        }
+                        let diverged = report.diverged_blocks();
+                        if check && diverged > 0 {
+                            bail!("{diverged} blocks diverged from the provider");
+                        }
+                        let fetch_failed = report.fetch_failed_blocks();
+                        if fetch_failed > 0 {
+                            bail!("{fetch_failed} blocks could not be fetched from the provider");
+                        }
+                        return Ok(());
+                    }
+                    // Unwrap: clap requires a chain name unless `--all-chains` is given
+                    let chain_name = chain_name.unwrap();
+                    if let CheckLinkage { range, delete } = method {
+                        // Linkage is checked within the cache and doesn't need a provider
+                        let chain_store = ctx.chain_store(&chain_name)?;
+                        return check_linkage(chain_store, &range, delete, &options)
+                            .await?
+                            .render(&options);
+                    }
+                    if let VerifyChecksums { range } = method {
+                        let chain_store = ctx.chain_store(&chain_name)?;
+                        return verify_checksums(chain_store, &range, &options)
+                            .await?
+                            .render(&options);
+                    }
+                    if let ByRange {
+                        from,
+                        to,
+                        range,
+                        purge: true,
+                        ..
+                    } = method
+                    {
+                        // Purged blocks aren't compared, so no provider is needed
+                        let range = RangeSet::from_args(range, from, to)?;
+                        let chain_store = ctx.chain_store(&chain_name)?;
+                        return purge_range(chain_store, &range, &options)
+                            .await?
+                            .render(&options);
+                    }
+                    if let ListForks { range } = method {
+                        let chain_store = ctx.chain_store(&chain_name)?;
+                        return list_forks(chain_store, &range, &options)
+                            .await?
+                            .render(&options);
+                    }
+                    if let FindDupes { range } = method {
+                        let chain_store = ctx.chain_store(&chain_name)?;
+                        return find_dupes(chain_store, &range, &options)
+                            .await?
+                            .render(&options);
+                    }
+                    if let DiffCaches {
+                        range,
+                        other_config,
+                        other_chain,
+                    } = method
+                    {
+                        if other_config.is_none() && other_chain.is_none() {
+                            bail!("`diff-caches` needs `--other-config` or `--other-chain`");
+                        }
+                        let other_chain = other_chain.unwrap_or_else(|| chain_name.clone());
+                        let (chain_store, other_store) = match other_config {
+                            Some(other_config) => {
+                                let other_store =
+                                    ctx.for_config(&other_config)?.chain_store(&other_chain)?;
+                                (ctx.chain_store(&chain_name)?, other_store)
+                            }
+                            None => ctx.chain_store_pair(&chain_name, &other_chain)?,
+                        };
+                        return diff_caches(chain_store, other_store, &range, &options)
+                            .await?
+                            .render(&options);
+                    }
+                    let (chain_store, ethereum_adapter, other_adapters) = ctx
+                        .chain_store_and_adapter(&chain_name, provider.as_deref())
+                        .await?;
+                    if options.quorum > 1 {
+                        options.witnesses = Witnesses(
+                            other_adapters
+                                .into_iter()
+                                .map(|adapter| adapter as Arc<dyn EthereumAdapterTrait>)
+                                .collect(),
+                        );
+                    }
+                    let report = match method {
+                        ByHash { hashes } => {
+                            by_hash(&hashes, chain_store, &*ethereum_adapter, &logger, &options)
+                                .await
+                        }
+                        ByHashList { source } => {
+                            by_hash_list(
+                                &source,
+                                chain_store,
+                                &*ethereum_adapter,
+                                &logger,
+                                &options,
+                            )
+                            .await
                         }
                         ByNumber {
                             number,
                             delete_duplicates,
+                            check_canonical,
                         } => {
                             by_number(
                                 number,
                                 chain_store,
-                                &ethereum_adapter,
+                                &*ethereum_adapter,
                                 &logger,
                                 delete_duplicates,
+                                check_canonical,
+                                &options,
                             )
                             .await
                         }
                         ByRange {
                             from,
                             to,
+                            range,
                             delete_duplicates,
+                            ..
                         } => {
+                            let range = RangeSet::from_args(range, from, to)?;
                             by_range(
                                 chain_store,
-                                &ethereum_adapter,
-                                from,
-                                to,
+                                &*ethereum_adapter,
+                                &range,
                                 &logger,
                                 delete_duplicates,
+                                &options,
                             )
                             .await
                         }
+                        Scan {
+                            from, to, range, ..
+                        } => {
+                            let range = RangeSet::from_args(range, from, to)?;
+                            by_range(
+                                chain_store,
+                                &*ethereum_adapter,
+                                &range,
+                                &logger,
+                                false,
+                                &options,
+                            )
+                            .await
+                        }
+                        Receipts { range } => {
+                            let report = check_receipts(
+                                chain_store,
+                                &*ethereum_adapter,
+                                &range,
+                                &logger,
+                                &options,
+                            )
+                            .await?;
+                            report.render(&options)?;
+                            if check && !report.diverged.is_empty() {
+                                bail!(
+                                    "{} blocks have receipts that diverged from the provider",
+                                    report.diverged.len()
+                                );
+                            }
+                            return Ok(());
+                        }
+                        Quickcheck { range, sample_size } => {
+                            let report = quickcheck(
+                                chain_store,
+                                &*ethereum_adapter,
+                                &range,
+                                &logger,
+                                sample_size,
+                                &options,
+                            )
+                            .await?;
+                            report.render(&options)?;
+                            if !report.passed() {
+                                bail!("The quick check found signs of problems in the range");
+                            }
+                            return Ok(());
+                        }
+                        Backfill { range } => {
+                            let report = backfill(
+                                chain_store,
+                                &*ethereum_adapter,
+                                &range,
+                                &logger,
+                                &options,
+                            )
+                            .await?;
+                            report.render(&options)?;
+                            if !report.failed.is_empty() {
+                                bail!("{} blocks could not be backfilled", report.failed.len());
+                            }
+                            return Ok(());
+                        }
+                        CheckLinkage { .. }
+                        | VerifyChecksums { .. }
+                        | ListForks { .. }
+                        | FindDupes { .. }
+                        | DiffCaches { .. } => {
+                            unreachable!("cache-only methods are handled above")
+                        }
+                    }?;
+                    report.render(&options)?;
+                    report.emit_repair_command(&chain_name, &options);
+                    if check && !report.diverged.is_empty() {
+                        bail!(
+                            "{} blocks diverged from the provider",
+                            report.diverged.len()
+                        );
                     }
+                    if !report.fetch_failed.is_empty() {
+                        bail!(
+                            "{} blocks could not be fetched from the provider",
+                            report.fetch_failed.len()
+                        );
+                    }
+                    Ok(())
                 }
                 Truncate { chain_name, force } => {
                     use commands::check_blocks::truncate;
@@ -1513,3 +2348,24 @@ async fn main() -> anyhow::Result<()> {
 fn parse_duration_in_secs(s: &str) -> Result<Duration, ParseIntError> {
     Ok(Duration::from_secs(s.parse()?))
 }
+
+/// Parses a duration like `90s`, `30m` or `2h`; a number without a unit is in seconds
+fn parse_duration_with_unit(s: &str) -> Result<Duration, String> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => s.split_at(index),
+        None => (s, "s"),
+    };
+    let secs_per_unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        _ => return Err(format!("unknown unit `{unit}` in `{s}`, use s, m or h")),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|e| format!("invalid duration `{s}`: {e}"))?;
+    let secs = number
+        .checked_mul(secs_per_unit)
+        .ok_or_else(|| format!("the duration `{s}` is too long"))?;
+    Ok(Duration::from_secs(secs))
+}
@@ -35,6 +35,7 @@ use graph_node::chain::{
     create_firehose_networks, create_ipfs_clients, create_substreams_networks,
 };
 use graph_node::config::Config;
+use graph_node::manager::commands::check_blocks::{api::BlockCacheService, CheckBlocksMetrics};
 use graph_node::opt;
 use graph_node::store_builder::StoreBuilder;
 use graph_server_http::GraphQLServer as GraphQLQueryServer;
@@ -553,11 +554,18 @@ async fn main() {
         );
 
         // Start admin JSON-RPC server.
+        let block_cache_service = BlockCacheService::new(
+            network_store.block_store(),
+            eth_networks.clone(),
+            Arc::new(CheckBlocksMetrics::new(metrics_registry.clone())),
+            logger.clone(),
+        );
         let json_rpc_server = JsonRpcServer::serve(
             json_rpc_port,
             http_port,
             ws_port,
             subgraph_registrar.clone(),
+            Arc::new(block_cache_service),
             node_id.clone(),
             logger.clone(),
         )
@@ -7,6 +7,7 @@ use super::CmdResult;
 
 lazy_static! {
     static ref COLOR_MODE: Mutex<ColorChoice> = Mutex::new(ColorChoice::Auto);
+    static ref STDERR_COLOR: Mutex<bool> = Mutex::new(false);
 }
 
 /// A helper to generate colored terminal output
@@ -30,6 +31,17 @@ impl Terminal {
             _ => ColorChoice::Never,
         };
         *COLOR_MODE.lock().unwrap() = choice;
+        *STDERR_COLOR.lock().unwrap() = match pref {
+            "always" | "ansi" => true,
+            "auto" => isatty::stderr_isatty(),
+            _ => false,
+        };
+    }
+
+    /// Whether text written to stderr should be colorized according to the preference; with
+    /// `auto`, that depends on stderr being a terminal
+    pub fn stderr_colors() -> bool {
+        *STDERR_COLOR.lock().unwrap()
     }
 
     fn color_preference() -> ColorChoice {
@@ -1,307 +1,1579 @@
 use crate::manager::prompt::prompt_for_confirmation;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::stream::{self, Stream, StreamExt};
+use futures::{Future, FutureExt};
+use git_testament::{git_testament, render_testament};
 use graph::{
     anyhow::{bail, ensure},
+    blockchain::{BlockHash, ChainIdentifier, IngestorError},
     components::store::ChainStore as ChainStoreTrait,
     prelude::{
         anyhow::{self, anyhow, Context},
+        serde_json::{self, Value},
+        thiserror, tokio,
+        transaction_receipt::LightTransactionReceipt,
         web3::types::H256,
+        EthereumBlock, MetricsRegistry,
     },
     slog::Logger,
 };
-use graph_chain_ethereum::{EthereumAdapter, EthereumAdapterTrait};
+use graph_chain_ethereum::EthereumAdapterTrait;
 use graph_store_postgres::ChainStore;
-use std::sync::Arc;
-
-pub async fn by_hash(
-    hash: &str,
-    chain_store: Arc<ChainStore>,
-    ethereum_adapter: &EthereumAdapter,
-    logger: &Logger,
-) -> anyhow::Result<()> {
-    let block_hash = helpers::parse_block_hash(hash)?;
-    run(&block_hash, &chain_store, ethereum_adapter, logger).await
-}
-
-pub async fn by_number(
-    number: i32,
-    chain_store: Arc<ChainStore>,
-    ethereum_adapter: &EthereumAdapter,
-    logger: &Logger,
-    delete_duplicates: bool,
-) -> anyhow::Result<()> {
-    let block_hashes = steps::resolve_block_hash_from_block_number(number, &chain_store)?;
-
-    match &block_hashes.as_slice() {
-        [] => bail!("Could not find a block with number {} in store", number),
-        [block_hash] => run(block_hash, &chain_store, ethereum_adapter, logger).await,
-        &block_hashes => {
-            handle_multiple_block_hashes(number, block_hashes, &chain_store, delete_duplicates)
-                .await
-        }
-    }
-}
-
-pub async fn by_range(
-    chain_store: Arc<ChainStore>,
-    ethereum_adapter: &EthereumAdapter,
-    range_from: Option<i32>,
-    range_to: Option<i32>,
-    logger: &Logger,
-    delete_duplicates: bool,
-) -> anyhow::Result<()> {
-    // Resolve a range of block numbers into a collection of blocks hashes
-    let range = ranges::Range::new(range_from, range_to)?;
-    let max = match range.upper_bound {
-        // When we have an open upper bound, we use the chain head's block number
-        None => steps::find_chain_head(&chain_store)?,
-        Some(x) => x,
-    };
-    // FIXME: This performs poorly.
-    // TODO: This could be turned into async code
-    for block_number in range.lower_bound..=max {
-        println!("Checking block [{block_number}/{max}]");
-        let block_hashes = steps::resolve_block_hash_from_block_number(block_number, &chain_store)?;
-        match &block_hashes.as_slice() {
-            [] => eprintln!("Found no block hash with number {block_number}"),
-            [block_hash] => run(block_hash, &chain_store, ethereum_adapter, logger).await?,
-            &block_hashes => {
-                handle_multiple_block_hashes(
-                    block_number,
-                    block_hashes,
-                    &chain_store,
-                    delete_duplicates,
-                )
-                .await?
+use progress::Progress;
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, LineWriter, Read, Write};
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+git_testament!(TESTAMENT);
+
+pub mod api;
+mod backfill;
+mod by_hash;
+mod by_number;
+mod by_range;
+mod checksums;
+mod compare;
+mod diff_caches;
+mod dupes;
+mod forks;
+mod header;
+mod helpers;
+mod linkage;
+mod profiles;
+mod progress;
+mod quickcheck;
+mod ranges;
+mod receipts;
+mod steps;
+#[cfg(test)]
+mod tests;
+
+pub use backfill::{backfill, BackfillFailure, BackfillReport};
+pub use by_hash::{by_hash, by_hash_list};
+pub use by_number::by_number;
+pub use by_range::{by_range, by_range_all_chains, purge_range, MultiChainReport};
+pub use by_range::{PurgeReport, PurgedSegment};
+pub use checksums::{verify_checksums, ChecksumMismatch, ChecksumReport};
+pub use compare::{compare_range, ComparedBlock};
+pub use diff_caches::{diff_caches, CacheDiffReport, CacheDivergence};
+pub use dupes::{find_dupes, DupesReport, DuplicateBlocks};
+pub use forks::{list_forks, Fork, ForksReport};
+pub use linkage::{check_linkage, LinkageBreak, LinkageReport};
+pub use profiles::NetworkProfile;
+pub use quickcheck::{quickcheck, QuickcheckReport};
+pub use ranges::RangeSet;
+pub use receipts::{check_receipts, ReceiptDivergence, ReceiptsReport};
+
+use compare::{check_canonical_block, check_gaps, comparable_pair, compare_loaded_blocks};
+use compare::{delete_duplicate_blocks, handle_forked_block_number};
+use compare::{handle_multiple_block_hashes, run};
+
+/// How many of the fields that diverged blocks differ in most often the summary of a check lists.
+const TOP_DIVERGING_FIELDS: usize = 10;
+
+/// Deleting more diverged blocks than this at once needs to be confirmed by the user.
+const DELETE_CONFIRMATION_THRESHOLD: usize = 100;
+
+/// A check gives up once the provider failed on this many blocks in a row, since it is then
+/// most likely down, rather than spending every retry on the rest of the range.
+const MAX_CONSECUTIVE_FETCH_FAILURES: usize = 20;
+
+/// How many blocks the chain head in the store may be behind the provider's latest block before
+/// checks up to the chain head warn that they don't reach the tip of the chain.
+const HEAD_LAG_WARNING_THRESHOLD: i32 = 50;
+
+/// The fields of blocks, transactions and receipts that JSON RPC encodes as quantities, whose
+/// leading zeros carry no meaning. The block `nonce` is left out since it is 8 bytes of data.
+const QUANTITY_FIELDS: &[&str] = &[
+    "baseFeePerGas",
+    "blobGasUsed",
+    "blockNumber",
+    "chainId",
+    "cumulativeGasUsed",
+    "difficulty",
+    "effectiveGasPrice",
+    "excessBlobGas",
+    "gas",
+    "gasLimit",
+    "gasPrice",
+    "gasUsed",
+    "logIndex",
+    "maxFeePerGas",
+    "maxPriorityFeePerGas",
+    "number",
+    "r",
+    "s",
+    "size",
+    "status",
+    "timestamp",
+    "totalDifficulty",
+    "transactionIndex",
+    "type",
+    "v",
+    "value",
+];
+
+/// Block fields that later forks added: withdrawals with Shanghai, blob gas and the beacon block
+/// root with Dencun. Blocks that were cached before graph-node stored them lack them, although
+/// the provider now returns them, so unless `strict_fields` is set, they are only compared when
+/// the cached block has them.
+const FORK_BLOCK_FIELDS: &[&str] = &[
+    "blobGasUsed",
+    "excessBlobGas",
+    "parentBeaconBlockRoot",
+    "withdrawals",
+    "withdrawalsRoot",
+];
+
+/// Errors that `check-blocks` treats differently from others, or that callers might want to tell
+/// apart. They are passed on inside an [`anyhow::Error`], usually with some context added; use
+/// [`CheckBlocksError::find`] to get at them.
+#[derive(thiserror::Error, Debug)]
+pub enum CheckBlocksError {
+    /// The provider answered with a block other than the one that was asked for. That says
+    /// nothing about the cached block, so it is neither compared nor deleted.
+    #[error("Provider responded with block {returned_hash:?} when asked for block {block_hash:?}")]
+    ProviderMisbehavior {
+        block_hash: H256,
+        returned_hash: Option<H256>,
+    },
+    /// A block that was asked for by its hash is not in the block cache.
+    #[error("Failed to locate block with hash {hash:?} in store")]
+    BlockNotCached { hash: H256 },
+    /// The block cache holds more than one block with the same hash. `found` describes the first
+    /// few of them.
+    #[error("Found {count} blocks with hash {hash:?} in store: {found}")]
+    AmbiguousBlock {
+        hash: H256,
+        count: usize,
+        found: String,
+    },
+    /// The provider doesn't know a block that is in the cache.
+    #[error("JRPC provider found no block with hash {hash:?}")]
+    ProviderMissingBlock { hash: H256 },
+    /// A block in the cache, or its hash, is malformed, so it can't be checked. The `hash` is
+    /// only a [`BlockHash`] since a malformed one may not even have 32 bytes.
+    #[error("The cached block {hash} is corrupt: {problem}")]
+    CorruptCacheEntry { hash: BlockHash, problem: String },
+    /// The provider could not be reached, or kept timing out, until all attempts were used up.
+    #[error("failed to fetch {what} after {}", attempts_text(.attempts))]
+    ProviderUnreachable {
+        what: String,
+        attempts: u32,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+}
+
+impl CheckBlocksError {
+    /// The `CheckBlocksError` in the chain of causes of `e`, if there is one.
+    pub fn find(e: &anyhow::Error) -> Option<&Self> {
+        e.chain().find_map(|cause| cause.downcast_ref())
+    }
+}
+
+fn attempts_text(attempts: &u32) -> String {
+    match attempts {
+        1 => "1 attempt".to_string(),
+        _ => format!("{attempts} attempts"),
+    }
+}
+
+/// The version of the JSON object that a check prints with `--output json`. Bump it whenever
+/// the shape of that object or of its results changes, since dashboards are built on it.
+pub const JSON_SCHEMA_VERSION: u32 = 5;
+
+/// How the results of a check are written to stdout.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Colorized diffs and progress messages meant to be read by people.
+    Human,
+    /// A JSON object with the [`JSON_SCHEMA_VERSION`], the manifest of the check and one result
+    /// per checked block. Progress messages go to stderr.
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(OutputFormat::Human),
+            "json" => Ok(OutputFormat::Json),
+            _ => bail!("unknown output format `{s}`, expected `human` or `json`"),
+        }
+    }
+}
+
+/// Which part of a block is compared with the provider.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompareScope {
+    /// Everything but the list of transactions. Depending on how a block got into the cache, it
+    /// might only hold transaction hashes while the provider returns full transactions.
+    Header,
+    /// Only the list of transactions.
+    Transactions,
+    /// The whole block.
+    Full,
+}
+
+impl CompareScope {
+    /// Leaves only the part of `block` that this scope covers.
+    fn narrow(self, mut block: Value) -> Value {
+        match self {
+            CompareScope::Full => block,
+            CompareScope::Header => {
+                if let Value::Object(fields) = &mut block {
+                    fields.remove("transactions");
+                }
+                block
+            }
+            CompareScope::Transactions => {
+                let transactions = block
+                    .get_mut("transactions")
+                    .map(Value::take)
+                    .unwrap_or_default();
+                serde_json::json!({ "transactions": transactions })
             }
         }
     }
-    Ok(())
+
+    /// Like [`CompareScope::narrow`], but leaves `block` alone and only copies what the scope
+    /// keeps of it.
+    fn narrowed(self, block: &Value) -> Value {
+        match (self, block) {
+            (CompareScope::Header, Value::Object(fields)) => Value::Object(
+                fields
+                    .iter()
+                    .filter(|(name, _)| name.as_str() != "transactions")
+                    .map(|(name, value)| (name.clone(), value.clone()))
+                    .collect(),
+            ),
+            (CompareScope::Transactions, block) => {
+                let transactions = block.get("transactions").cloned().unwrap_or_default();
+                serde_json::json!({ "transactions": transactions })
+            }
+            (_, block) => block.clone(),
+        }
+    }
 }
 
-pub fn truncate(chain_store: Arc<ChainStore>, skip_confirmation: bool) -> anyhow::Result<()> {
-    let prompt = format!(
-        "This will delete all cached blocks for {}.\nProceed?",
-        chain_store.chain
-    );
-    if !skip_confirmation && !prompt_for_confirmation(&prompt)? {
-        println!("Aborting.");
-        return Ok(());
+impl FromStr for CompareScope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "header" => Ok(CompareScope::Header),
+            "transactions" => Ok(CompareScope::Transactions),
+            "full" => Ok(CompareScope::Full),
+            _ => bail!("unknown scope `{s}`, expected `header`, `transactions` or `full`"),
+        }
     }
+}
 
-    chain_store
-        .truncate_block_cache()
-        .with_context(|| format!("Failed to truncate block cache for {}", chain_store.chain))
-}
-
-async fn run(
-    block_hash: &H256,
-    chain_store: &ChainStore,
-    ethereum_adapter: &EthereumAdapter,
-    logger: &Logger,
-) -> anyhow::Result<()> {
-    let cached_block = steps::fetch_single_cached_block(*block_hash, chain_store)?;
-    let provider_block =
-        steps::fetch_single_provider_block(block_hash, ethereum_adapter, logger).await?;
-    let diff = steps::diff_block_pair(&cached_block, &provider_block);
-    steps::report_difference(diff.as_deref(), block_hash);
-    if diff.is_some() {
-        steps::delete_block(block_hash, chain_store)?;
+/// How the diff of a diverging block is printed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiffStyle {
+    /// The structure of the block, reduced to the fields that differ, with old and new values.
+    Full,
+    /// One line per differing field, with its JSON pointer and both values.
+    Compact,
+}
+
+impl FromStr for DiffStyle {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(DiffStyle::Full),
+            "compact" => Ok(DiffStyle::Compact),
+            _ => bail!("unknown diff style `{s}`, expected `full` or `compact`"),
+        }
     }
-    Ok(())
 }
 
-async fn handle_multiple_block_hashes(
-    block_number: i32,
-    block_hashes: &[H256],
-    chain_store: &ChainStore,
-    delete_duplicates: bool,
-) -> anyhow::Result<()> {
-    println!(
-        "graphman found {} different block hashes for block number {} in the store \
-         and is unable to tell which one to check:",
-        block_hashes.len(),
-        block_number
-    );
-    for (num, hash) in block_hashes.iter().enumerate() {
-        println!("{:>4}:  {hash:?}", num + 1);
+/// How diffs of diverging blocks are rendered for people. This is decided once per command, so
+/// that all blocks are shown the same way.
+#[derive(Clone, Copy, Debug)]
+pub struct DiffRenderOptions {
+    pub style: DiffStyle,
+    /// Colorize the diff. Compact diffs are never colorized.
+    pub color: bool,
+    /// Cut every diff off after this many lines.
+    pub max_lines: Option<usize>,
+}
+
+impl Default for DiffRenderOptions {
+    fn default() -> Self {
+        DiffRenderOptions {
+            style: DiffStyle::Full,
+            color: false,
+            max_lines: None,
+        }
     }
-    if delete_duplicates {
-        println!("Deleting duplicated blocks...");
-        for hash in block_hashes {
-            steps::delete_block(hash, chain_store)?;
+}
+
+/// Settings shared by the `by_hash`, `by_number` and `by_range` commands.
+#[derive(Clone, Debug)]
+pub struct CheckOptions {
+    /// How many blocks to request from the JSON RPC provider concurrently.
+    pub concurrency: usize,
+    /// Request blocks from the provider in JSON-RPC batches of this many, if the adapter
+    /// supports batch requests. `concurrency` then counts batches rather than blocks.
+    pub rpc_batch_size: Option<usize>,
+    /// How many database connections to load cached blocks with at the same time. Never more
+    /// than all but one of the connections in the store's pool are used.
+    pub db_concurrency: usize,
+    /// Report the blocks that would be deleted, but leave the block cache untouched.
+    pub dry_run: bool,
+    /// In a dry run, print the SQL statement that would delete each block.
+    pub explain: bool,
+    /// Delete diverged blocks without asking, no matter how many there are.
+    pub skip_confirmation: bool,
+    /// Replace diverged blocks in the cache with the provider's version instead of deleting them.
+    pub replace: bool,
+    /// How many blocks to load, compare and delete at a time.
+    pub chunk_size: usize,
+    /// Abort instead of deleting or replacing more than this many blocks of a chain.
+    pub max_deletions: Option<usize>,
+    /// Only count a block as diverged if it differs from the provider's in at least this many
+    /// fields, i.e. leaves of the diff. Blocks with fewer differences are reported and left alone.
+    pub min_diff_fields: Option<usize>,
+    /// How to print the results.
+    pub output: OutputFormat,
+    /// Show a progress line on stderr while blocks are being checked. Ignored for JSON output.
+    pub progress: bool,
+    /// Which part of the blocks to compare.
+    pub scope: CompareScope,
+    /// How diffs are printed in human output.
+    pub diff_render: DiffRenderOptions,
+    /// Where the diffs of diverging blocks go in human output instead of stderr, if set. They
+    /// are never colorized or cut off there.
+    pub diff_file: Option<Arc<DiffFile>>,
+    /// Compare blocks byte for byte. Otherwise, hex strings are lowercased and quantities
+    /// stripped of leading zeros first, since providers differ in how they encode them.
+    pub exact: bool,
+    /// Compare the fields that later forks added to blocks, like `withdrawals`, even if the
+    /// cached block lacks them.
+    pub strict_fields: bool,
+    /// Ignore every field of the provider's block that the cached block lacks, at any depth, so
+    /// that a cached block that only misses fields doesn't diverge.
+    pub superset_ok: bool,
+    /// Sort the transactions of both blocks by their index before comparing them, since some
+    /// providers return them in a different order than the cache has them.
+    pub ignore_tx_order: bool,
+    /// JSON pointers (RFC 6901) to block fields that are removed from both the cached and the
+    /// provider block before comparing them, like `/totalDifficulty`.
+    pub ignored_fields: Vec<String>,
+    /// Globs over dotted field paths like `transactions.*.from`. If there are any, only the
+    /// fields they match are compared.
+    pub diff_include: Vec<String>,
+    /// Globs over dotted field paths like `extraData`; the fields they match are not compared.
+    pub diff_exclude: Vec<String>,
+    /// How many times a block is requested from the provider before giving up on it.
+    pub max_attempts: u32,
+    /// How long to wait before the first retry of a failed provider request. Every further retry
+    /// waits twice as long as the previous one.
+    pub retry_delay: Duration,
+    /// How long a single provider request may take before it is abandoned and, attempts
+    /// permitting, retried.
+    pub rpc_timeout: Duration,
+    /// Skip the blocks at most this many blocks below the chain head that the provider doesn't
+    /// have, since it may just not have caught up with them yet, instead of failing the check.
+    pub skip_tip_gap: Option<u32>,
+    /// In `by_range`, ask the provider for the block numbers that have no cached block, to find
+    /// gaps in the cache.
+    pub find_gaps: bool,
+    /// Add the blocks that `find_gaps` finds to the cache.
+    pub fill_gaps: bool,
+    /// A file to which a JSON line is appended for every block that is deleted or replaced.
+    pub audit_log: Option<PathBuf>,
+    /// The blocks that an earlier audit log records, as read by [`read_audited_hashes`]. They
+    /// are not compared again.
+    pub skip_audited: Option<Arc<HashSet<H256>>>,
+    /// A directory into which the cached and the provider's version of every diverged block are
+    /// written, also in a dry run.
+    pub export_dir: Option<PathBuf>,
+    /// Gzip the audit log and exported blocks. The audit log is also gzipped if its name ends in
+    /// `.gz`.
+    pub compress: bool,
+    /// A directory with the provider's blocks as JSON files named by their hash, which are
+    /// compared with the cached blocks instead of asking the provider. An `export_dir` can be
+    /// used as one, which replays the comparisons of an earlier check.
+    pub provider_fixture: Option<PathBuf>,
+    /// Delete cached blocks that aren't well-formed, instead of only reporting them. They are
+    /// never compared with the provider.
+    pub delete_corrupt: bool,
+    /// Look the block up again after deleting it, and report the deletion as unverified if it
+    /// didn't remove anything or the block is still there.
+    pub verify_deletes: bool,
+    /// How many providers, the main one and the `witnesses`, have to return the same block that
+    /// differs from the cached one for the cached block to count as diverged.
+    pub quorum: usize,
+    /// The providers besides the main one that diverged blocks are checked with when `quorum` is
+    /// more than 1.
+    pub witnesses: Witnesses,
+    /// Print a `graphman` command line that re-checks just the diverged blocks, so that a scan
+    /// on one machine can be handed over as a repair on another.
+    pub emit_command: bool,
+    /// In `by_range`, write the number of the highest block that has been fully processed to this
+    /// file after every chunk.
+    pub checkpoint: Option<PathBuf>,
+    /// In `by_range`, skip the blocks up to the number in `checkpoint`.
+    pub resume: bool,
+    /// Only list which blocks diverge, for `scan`: diffs are neither computed nor shown, and
+    /// diverged blocks are left in the cache. Progress messages go to stderr.
+    pub list_only: bool,
+    /// Print where the time was spent: in provider requests or in database lookups.
+    pub timing: bool,
+    /// Where to keep count of the fetched and diverged blocks while checking, for callers that
+    /// can't watch the progress line, like the admin API.
+    pub status: Option<Arc<CheckStatus>>,
+    /// Where to count diverged and deleted blocks and failed provider requests.
+    pub metrics: Option<Arc<CheckBlocksMetrics>>,
+    /// Keeps provider requests under a rate, if set.
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    /// Once this is set, no further chunks are checked.
+    pub interrupt: Interrupt,
+    /// The chain heads that open ranges were resolved with, shared by all checks with these
+    /// options.
+    pub chain_heads: Arc<ChainHeads>,
+    /// How many blocks of each chain the checks with these options deleted or replaced so far,
+    /// which `max_deletions` caps.
+    pub deletions: Arc<DeletionCounts>,
+    /// The gzip stream that the lines of a gzipped audit log are written to, shared by all checks
+    /// with these options.
+    pub audit_stream: Arc<AuditStream>,
+}
+
+/// Remembers the chain head of every chain once it has been looked up, so that checking a
+/// chain again, or several of them, doesn't query the store for it every time. With a refresh
+/// interval, a chain head that was looked up longer ago than that is looked up again.
+#[derive(Debug, Default)]
+pub struct ChainHeads {
+    refresh_interval: Option<Duration>,
+    heads: Mutex<HashMap<String, (i32, Instant)>>,
+}
+
+impl ChainHeads {
+    pub fn new(refresh_interval: Option<Duration>) -> Self {
+        ChainHeads {
+            refresh_interval,
+            heads: Mutex::default(),
         }
-    } else {
-        eprintln!(
-            "Operation aborted for block number {block_number}.\n\
-             To delete the duplicated blocks and continue this operation, rerun this command with \
-             the `--delete-duplicates` option."
-        )
     }
-    Ok(())
+
+    /// The chain head of `chain`, which `fetch` looks up if it isn't known yet or has to be
+    /// refreshed.
+    fn get(&self, chain: &str, fetch: impl FnOnce() -> anyhow::Result<i32>) -> anyhow::Result<i32> {
+        let mut heads = self.heads.lock().unwrap();
+        if let Some((head, fetched)) = heads.get(chain) {
+            match self.refresh_interval {
+                Some(interval) if fetched.elapsed() >= interval => {}
+                _ => return Ok(*head),
+            }
+        }
+        let head = fetch()?;
+        heads.insert(chain.to_string(), (head, Instant::now()));
+        Ok(head)
+    }
 }
 
-mod steps {
-    use super::*;
+/// Counts the blocks of every chain that are deleted or replaced, so that `max_deletions` caps
+/// them however they come to be deleted: as diverged, corrupt, stale or orphaned blocks.
+#[derive(Debug, Default)]
+pub struct DeletionCounts {
+    counts: Mutex<HashMap<String, usize>>,
+}
 
-    use futures::compat::Future01CompatExt;
-    use graph::{
-        anyhow::bail,
-        prelude::serde_json::{self, Value},
-    };
-    use json_structural_diff::{colorize as diff_to_string, JsonDiff};
+impl DeletionCounts {
+    /// Counts one more deletion for `chain`, unless that would be more than `max_deletions`. In
+    /// that case, nothing is counted and the number of blocks deleted so far is returned.
+    fn add(&self, chain: &str, max_deletions: Option<usize>) -> Result<(), usize> {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(chain.to_string()).or_default();
+        match max_deletions {
+            Some(max_deletions) if *count >= max_deletions => Err(*count),
+            _ => {
+                *count += 1;
+                Ok(())
+            }
+        }
+    }
+}
 
-    /// Queries the [`ChainStore`] about the block hash for the given block number.
-    ///
-    /// Multiple block hashes can be returned as the store does not enforce uniqueness based on
-    /// block numbers.
-    /// Returns an empty vector if no block hash is found.
-    pub(super) fn resolve_block_hash_from_block_number(
-        number: i32,
-        chain_store: &ChainStore,
-    ) -> anyhow::Result<Vec<H256>> {
-        let block_hashes = chain_store.block_hashes_by_block_number(number)?;
-        Ok(block_hashes
-            .into_iter()
-            .map(|x| H256::from_slice(&x.as_slice()[..32]))
-            .collect())
+/// The gzip stream of a gzipped audit log. It is opened with the first line and finished when
+/// the options that share it are dropped, so that a run appends one gzip member to the log rather
+/// than one per line. Every line is flushed into the file as soon as it is written, so a run that
+/// is killed leaves a member without its end, but with all its lines readable.
+#[derive(Default)]
+pub struct AuditStream {
+    encoder: Mutex<Option<GzEncoder<File>>>,
+}
+
+impl AuditStream {
+    /// Appends `line` to the gzip stream of the audit log at `path`, opening it first if needed.
+    fn append(&self, path: &Path, line: &[u8]) -> anyhow::Result<()> {
+        let mut encoder = self.encoder.lock().unwrap();
+        if encoder.is_none() {
+            *encoder = Some(GzEncoder::new(
+                open_audit_log(path)?,
+                Compression::default(),
+            ));
+        }
+        let encoder = encoder.as_mut().expect("the stream was just opened");
+        encoder
+            .write_all(line)
+            .and_then(|()| encoder.flush())
+            .with_context(|| format!("failed to write to audit log `{}`", path.display()))
     }
+}
 
-    /// Queries the [`ChainStore`] for a cached block given a block hash.
-    ///
-    /// Errors on a non-unary result.
-    pub(super) fn fetch_single_cached_block(
-        block_hash: H256,
-        chain_store: &ChainStore,
-    ) -> anyhow::Result<Value> {
-        let blocks = chain_store.blocks(&[block_hash.into()])?;
-        match blocks.len() {
-            0 => bail!("Failed to locate block with hash {} in store", block_hash),
-            1 => {}
-            _ => bail!("Found multiple blocks with hash {} in store", block_hash),
-        };
-        // Unwrap: We just checked that the vector has a single element
-        Ok(blocks.into_iter().next().unwrap())
+impl Drop for AuditStream {
+    fn drop(&mut self) {
+        if let Ok(Some(encoder)) = self.encoder.get_mut().map(Option::take) {
+            if let Err(e) = encoder.finish() {
+                eprintln!("Failed to finish the gzipped audit log: {e}");
+            }
+        }
     }
+}
 
-    /// Fetches a block from a JRPC endpoint.
-    ///
-    /// Errors on provider failure or if the returned block has a different hash than the one
-    /// requested.
-    pub(super) async fn fetch_single_provider_block(
-        block_hash: &H256,
-        ethereum_adapter: &EthereumAdapter,
-        logger: &Logger,
-    ) -> anyhow::Result<Value> {
-        let provider_block = ethereum_adapter
-            .block_by_hash(logger, *block_hash)
-            .compat()
-            .await
-            .with_context(|| format!("failed to fetch block {block_hash}"))?
-            .ok_or_else(|| anyhow!("JRPC provider found no block with hash {block_hash:?}"))?;
-        ensure!(
-            provider_block.hash == Some(*block_hash),
-            "Provider responded with a different block hash"
-        );
-        serde_json::to_value(provider_block)
-            .context("failed to parse provider block as a JSON value")
+impl fmt::Debug for AuditStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let open = self
+            .encoder
+            .lock()
+            .map_or(false, |encoder| encoder.is_some());
+        f.debug_struct("AuditStream").field("open", &open).finish()
     }
+}
+
+/// Opens the audit log at `path` for appending, creating it if needed.
+fn open_audit_log(path: &Path) -> anyhow::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("failed to open audit log `{}`", path.display()))
+}
+
+/// The file that the diffs of diverging blocks are written to, so that the terminal only shows
+/// the summary and progress of a big check. Every line is written out as soon as it is complete.
+#[derive(Debug)]
+pub struct DiffFile {
+    path: PathBuf,
+    writer: Mutex<LineWriter<File>>,
+}
+
+impl DiffFile {
+    /// Creates the file at `path`, replacing any file that is already there.
+    pub fn create(path: PathBuf) -> anyhow::Result<Self> {
+        let file = File::create(&path)
+            .with_context(|| format!("failed to create diff file `{}`", path.display()))?;
+        Ok(DiffFile {
+            path,
+            writer: Mutex::new(LineWriter::new(file)),
+        })
+    }
+
+    /// Writes the `diff` of the block with `hash` in the same form it would have on stderr.
+    fn write_diff(&self, hash: &H256, number: Option<i32>, diff: &str) -> anyhow::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        writeln!(
+            writer,
+            "{} diverges from cache:\n{diff}",
+            steps::describe_block(hash, number)
+        )
+        .with_context(|| format!("failed to write to diff file `{}`", self.path.display()))
+    }
+
+    /// Writes out whatever hasn't been yet, e.g. before a check stops because it is interrupted.
+    fn flush(&self) -> anyhow::Result<()> {
+        self.writer
+            .lock()
+            .unwrap()
+            .flush()
+            .with_context(|| format!("failed to write to diff file `{}`", self.path.display()))
+    }
+}
 
-    /// Compares two [`serde_json::Value`] values.
+/// Tells a check to stop after the chunk it is working on, so that it never stops halfway
+/// through deleting blocks. That happens on Ctrl-C, or once the check has used up its runtime.
+#[derive(Clone, Debug, Default)]
+pub struct Interrupt {
+    flag: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl Interrupt {
+    /// An interrupt that is set by the first Ctrl-C. A second Ctrl-C exits right away.
     ///
-    /// If they are different, returns a user-friendly string ready to be displayed.
-    pub(super) fn diff_block_pair(a: &Value, b: &Value) -> Option<String> {
-        if a == b {
-            None
-        } else {
-            match JsonDiff::diff(a, b, false).diff {
-                // The diff could potentially be a `Value::Null`, which is equivalent to not being
-                // different at all.
-                None | Some(Value::Null) => None,
-                Some(diff) => {
-                    // Convert the JSON diff to a pretty-formatted text that will be displayed to
-                    // the user
-                    Some(diff_to_string(&diff, false))
+    /// This replaces the default handling of Ctrl-C for the rest of the process, so it is only
+    /// meant for `graphman`.
+    pub fn on_ctrl_c() -> Self {
+        let interrupt = Interrupt::default();
+        let flag = interrupt.flag.clone();
+        graph::spawn(async move {
+            while graph::prelude::tokio::signal::ctrl_c().await.is_ok() {
+                if flag.swap(true, Ordering::SeqCst) {
+                    eprintln!("Interrupted again, exiting without finishing the current chunk");
+                    std::process::exit(130);
                 }
+                eprintln!(
+                    "Interrupted, finishing the current chunk (press Ctrl-C again to exit now)"
+                );
             }
+        });
+        interrupt
+    }
+
+    /// Also sets the interrupt once `max_runtime` has passed from now.
+    pub fn with_max_runtime(self, max_runtime: Duration) -> Self {
+        Interrupt {
+            deadline: Some(Instant::now() + max_runtime),
+            ..self
         }
     }
 
-    /// Prints the difference between two [`serde_json::Value`] values to the user.
-    pub(super) fn report_difference(difference: Option<&str>, hash: &H256) {
-        if let Some(diff) = difference {
-            eprintln!("block {hash} diverges from cache:");
-            eprintln!("{diff}");
-        } else {
-            println!("Cached block is equal to the same block from provider.")
+    pub fn is_set(&self) -> bool {
+        self.flag.load(Ordering::SeqCst) || self.out_of_time()
+    }
+
+    /// Whether the check has used up the runtime it was given with [`Self::with_max_runtime`].
+    pub fn out_of_time(&self) -> bool {
+        self.deadline
+            .map_or(false, |deadline| Instant::now() >= deadline)
+    }
+}
+
+/// Prometheus counters for what checks find and do, labelled with the chain. Routine checks
+/// that suddenly delete many blocks usually point to a provider problem rather than a reorg.
+///
+/// The counters are only registered once they are first incremented, and a counter that can't
+/// be registered is no reason to fail a check.
+pub struct CheckBlocksMetrics {
+    registry: Arc<MetricsRegistry>,
+}
+
+impl fmt::Debug for CheckBlocksMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CheckBlocksMetrics").finish_non_exhaustive()
+    }
+}
+
+impl CheckBlocksMetrics {
+    pub fn new(registry: Arc<MetricsRegistry>) -> Self {
+        CheckBlocksMetrics { registry }
+    }
+
+    fn block_diverged(&self, chain: &str) {
+        self.inc(
+            "fix_block_diverged_total",
+            "Number of cached blocks found to diverge from the provider",
+            chain,
+        );
+    }
+
+    fn block_deleted(&self, chain: &str) {
+        self.blocks_deleted(chain, 1);
+    }
+
+    fn blocks_deleted(&self, chain: &str, count: usize) {
+        self.inc_by(
+            "fix_block_deleted_total",
+            "Number of diverged, corrupt or purged blocks deleted from the block cache",
+            chain,
+            count as f64,
+        );
+    }
+
+    fn rpc_error(&self, chain: &str) {
+        self.inc(
+            "fix_block_rpc_errors_total",
+            "Number of failed provider requests while checking cached blocks",
+            chain,
+        );
+    }
+
+    fn inc(&self, name: &str, help: &str, chain: &str) {
+        self.inc_by(name, help, chain, 1.0);
+    }
+
+    fn inc_by(&self, name: &str, help: &str, chain: &str, count: f64) {
+        if let Ok(counter) = self.registry.global_counter_vec(name, help, &["chain"]) {
+            counter.with_label_values(&[chain]).inc_by(count);
         }
     }
+}
 
-    /// Attempts to delete a block from the block cache.
-    pub(super) fn delete_block(hash: &H256, chain_store: &ChainStore) -> anyhow::Result<()> {
-        println!("Deleting block {hash} from cache.");
-        chain_store.delete_blocks(&[hash])?;
-        println!("Done.");
-        Ok(())
+/// A token bucket that keeps provider requests under a rate. It is shared by all requests of a
+/// command, however many are in flight.
+///
+/// When the provider says that requests are coming in too fast, the rate is halved, down to a
+/// sixteenth of the configured one; every request that succeeds afterwards raises it again by a
+/// twentieth of the configured rate, until it is back to that.
+pub struct RateLimiter {
+    max_rate: f64,
+    bucket: std::sync::Mutex<Bucket>,
+}
+
+struct Bucket {
+    /// Requests per second that are currently allowed.
+    rate: f64,
+    tokens: f64,
+    refilled: tokio::time::Instant,
+}
+
+impl fmt::Debug for RateLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimiter")
+            .field("max_rate", &self.max_rate)
+            .field("rate", &self.rate())
+            .finish()
+    }
+}
+
+impl RateLimiter {
+    /// A limiter for `requests_per_second`, which has to be positive.
+    pub fn new(requests_per_second: f64) -> Self {
+        RateLimiter {
+            max_rate: requests_per_second,
+            bucket: std::sync::Mutex::new(Bucket {
+                rate: requests_per_second,
+                tokens: 1.0,
+                refilled: tokio::time::Instant::now(),
+            }),
+        }
+    }
+
+    /// The requests per second that are currently allowed.
+    pub fn rate(&self) -> f64 {
+        self.bucket.lock().unwrap().rate
+    }
+
+    /// Waits until the next request may be sent, and charges it as `requests` requests, like a
+    /// JSON-RPC batch of that many blocks. At most a second's worth of requests can be sent in a
+    /// burst. A batch that costs more than the bucket holds goes out once there is a token, and
+    /// the requests after it wait until the bucket has refilled for all of the batch.
+    async fn acquire(&self, requests: usize) {
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap();
+                let now = tokio::time::Instant::now();
+                let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+                bucket.tokens = (bucket.tokens + elapsed * bucket.rate).min(bucket.rate.max(1.0));
+                bucket.refilled = now;
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= requests as f64;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - bucket.tokens) / bucket.rate)
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Slows down after the provider rejected a request for exceeding its quota.
+    fn throttled(&self) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.rate = (bucket.rate / 2.0).max(self.max_rate / 16.0);
+        bucket.tokens = bucket.tokens.min(0.0);
+    }
+
+    /// Speeds up again after a request went through.
+    fn succeeded(&self) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.rate = (bucket.rate + self.max_rate / 20.0).min(self.max_rate);
+    }
+}
+
+/// How far a check has come, updated while it runs.
+#[derive(Debug, Default)]
+pub struct CheckStatus {
+    total: AtomicUsize,
+    fetched: AtomicUsize,
+    diverged: AtomicUsize,
+}
+
+impl CheckStatus {
+    /// The counts as a JSON object with the `total` number of blocks to check and how many of
+    /// them were `fetched` and `diverged` so far.
+    pub fn to_json(&self) -> Value {
+        serde_json::json!({
+            "total": self.total.load(Ordering::Relaxed),
+            "fetched": self.fetched.load(Ordering::Relaxed),
+            "diverged": self.diverged.load(Ordering::Relaxed),
+        })
+    }
+}
+
+/// Further providers to ask for a block that diverged from the main provider's.
+#[derive(Clone, Default)]
+pub struct Witnesses(pub Vec<Arc<dyn EthereumAdapterTrait>>);
+
+impl fmt::Debug for Witnesses {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.0.iter().map(|adapter| adapter.provider()))
+            .finish()
+    }
+}
+
+/// The default of [`CheckOptions::concurrency`], and of `--concurrency`.
+pub const DEFAULT_CONCURRENCY: usize = 16;
+/// The default of [`CheckOptions::chunk_size`], and of `--chunk-size`.
+pub const DEFAULT_CHUNK_SIZE: usize = 1000;
+/// The default of [`CheckOptions::max_attempts`], and of `--max-attempts`.
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+/// The default of [`CheckOptions::retry_delay`] in milliseconds, and of `--retry-delay`.
+pub const DEFAULT_RETRY_DELAY_MILLIS: u64 = 500;
+/// The default of [`CheckOptions::rpc_timeout`] in seconds, and of `--rpc-timeout`.
+pub const DEFAULT_RPC_TIMEOUT_SECS: u64 = 30;
+/// The default of [`CheckOptions::ignored_fields`], and of `--ignore-field`: fields that some
+/// providers leave out or compute differently, which says nothing about the block.
+pub const DEFAULT_IGNORED_FIELDS: &[&str] = &["/totalDifficulty", "/size"];
+
+/// The options of `graphman chain check-blocks` without any flags, which checks started over
+/// the API use, too.
+impl Default for CheckOptions {
+    fn default() -> Self {
+        CheckOptions {
+            concurrency: DEFAULT_CONCURRENCY,
+            rpc_batch_size: None,
+            db_concurrency: 1,
+            dry_run: false,
+            explain: false,
+            skip_confirmation: false,
+            replace: false,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            max_deletions: None,
+            min_diff_fields: None,
+            output: OutputFormat::Human,
+            progress: false,
+            scope: CompareScope::Header,
+            diff_render: DiffRenderOptions::default(),
+            diff_file: None,
+            exact: false,
+            strict_fields: false,
+            superset_ok: false,
+            ignore_tx_order: false,
+            ignored_fields: DEFAULT_IGNORED_FIELDS
+                .iter()
+                .map(|field| field.to_string())
+                .collect(),
+            diff_include: vec![],
+            diff_exclude: vec![],
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            retry_delay: Duration::from_millis(DEFAULT_RETRY_DELAY_MILLIS),
+            rpc_timeout: Duration::from_secs(DEFAULT_RPC_TIMEOUT_SECS),
+            skip_tip_gap: None,
+            find_gaps: false,
+            fill_gaps: false,
+            audit_log: None,
+            skip_audited: None,
+            export_dir: None,
+            compress: false,
+            provider_fixture: None,
+            delete_corrupt: false,
+            verify_deletes: false,
+            quorum: 1,
+            witnesses: Witnesses::default(),
+            emit_command: false,
+            checkpoint: None,
+            resume: false,
+            list_only: false,
+            timing: false,
+            status: None,
+            metrics: None,
+            rate_limiter: None,
+            interrupt: Interrupt::default(),
+            chain_heads: Arc::default(),
+            deletions: Arc::default(),
+            audit_stream: Arc::default(),
+        }
+    }
+}
+
+impl CheckOptions {
+    /// What happens to diverged blocks, for messages to the user.
+    fn fix_action(&self) -> &'static str {
+        if self.replace {
+            "replaced"
+        } else {
+            "deleted"
+        }
     }
 
-    /// Queries the [`ChainStore`] about the chain head.
-    pub(super) fn find_chain_head(chain_store: &ChainStore) -> anyhow::Result<i32> {
-        let chain_head: Option<i32> = chain_store.chain_head_block(&chain_store.chain)?;
-        chain_head.ok_or_else(|| anyhow!("Could not find the chain head for {}", chain_store.chain))
+    /// Prints a progress message. Stdout is reserved for the results in JSON mode, so the
+    /// message goes to stderr then.
+    fn info(&self, msg: impl fmt::Display) {
+        match self.output {
+            OutputFormat::Human if !self.list_only => println!("{msg}"),
+            _ => eprintln!("{msg}"),
+        }
     }
 }
 
-mod helpers {
-    use super::*;
-    use graph::prelude::hex;
+/// Reads the hashes of the blocks that the audit log at `path`, written by earlier checks, records
+/// as deleted or replaced, for [`CheckOptions::skip_audited`]. Gzipped audit logs are read, too.
+pub fn read_audited_hashes(path: &Path) -> anyhow::Result<HashSet<H256>> {
+    let data = helpers::read_maybe_gzipped(path)?;
+    let text =
+        String::from_utf8(data).with_context(|| format!("`{}` is not text", path.display()))?;
+    helpers::audited_hashes(&text).with_context(|| format!("failed to read `{}`", path.display()))
+}
 
-    /// Tries to parse a [`H256`] from a hex string.
-    pub(super) fn parse_block_hash(hash: &str) -> anyhow::Result<H256> {
-        let hash = hash.trim_start_matches("0x");
-        let hash = hex::decode(hash)?;
-        Ok(H256::from_slice(&hash))
+/// Makes sure that the lines for the audit log at `path` can be appended to it: with
+/// `compress`, its name must end in `.gz`, and a log that is already there must be gzipped if and
+/// only if its name ends in `.gz`, so that gzipped and plain lines never end up in one file. A
+/// gzipped log must also end with a complete gzip member.
+pub fn check_audit_log(path: &Path, compress: bool) -> anyhow::Result<()> {
+    let gzip_name = path
+        .extension()
+        .map_or(false, |extension| extension == "gz");
+    ensure!(
+        gzip_name || !compress,
+        "`--compress` gzips the audit log, so its name must end in `.gz`, which `{}` doesn't",
+        path.display()
+    );
+    let mut start = Vec::new();
+    match File::open(path) {
+        Ok(file) => file
+            .take(helpers::GZIP_MAGIC.len() as u64)
+            .read_to_end(&mut start)
+            .with_context(|| format!("failed to read audit log `{}`", path.display()))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => {
+            return Err(e).with_context(|| format!("failed to open audit log `{}`", path.display()))
+        }
+    };
+    if start.is_empty() {
+        return Ok(());
+    }
+    let gzipped = start.starts_with(&helpers::GZIP_MAGIC);
+    ensure!(
+        gzipped || !gzip_name,
+        "the audit log `{}` holds plain text, which gzipped lines can't be appended to; use \
+         another file",
+        path.display()
+    );
+    ensure!(
+        gzip_name || !gzipped,
+        "the audit log `{}` is gzipped, so its name must end in `.gz`",
+        path.display()
+    );
+    if gzipped {
+        let file = File::open(path)
+            .with_context(|| format!("failed to open audit log `{}`", path.display()))?;
+        let decoded = io::copy(
+            &mut flate2::read::MultiGzDecoder::new(BufReader::new(file)),
+            &mut io::sink(),
+        );
+        // Whatever is appended after a member without an end can't be read anymore
+        match decoded {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => bail!(
+                "the audit log `{}` ends in the middle of a gzip member, as a killed run leaves \
+                 it; use another file",
+                path.display()
+            ),
+            decoded => decoded
+                .with_context(|| format!("failed to decompress audit log `{}`", path.display()))?,
+        };
     }
+    Ok(())
 }
 
-/// Custom range type
-mod ranges {
-    use graph::prelude::anyhow::{self, bail};
+pub fn truncate(chain_store: Arc<ChainStore>, skip_confirmation: bool) -> anyhow::Result<()> {
+    if !skip_confirmation {
+        // The count is only there to inform the decision; not knowing it is no reason to fail
+        let prompt = match chain_store.cached_block_count() {
+            Ok(count) => format!(
+                "This will delete all {count} cached blocks for {}.\nProceed?",
+                chain_store.chain
+            ),
+            Err(_) => format!(
+                "This will delete all cached blocks for {}.\nProceed?",
+                chain_store.chain
+            ),
+        };
+        if !prompt_for_confirmation(&prompt)? {
+            println!("Aborting.");
+            return Ok(());
+        }
+    }
 
-    pub(super) struct Range {
-        pub(super) lower_bound: i32,
-        pub(super) upper_bound: Option<i32>,
+    let deleted = chain_store
+        .truncate_block_cache()
+        .with_context(|| format!("Failed to truncate block cache for {}", chain_store.chain))?;
+    println!(
+        "Deleted {deleted} cached blocks for chain {}",
+        chain_store.chain
+    );
+    Ok(())
+}
+
+/// The outcome of checking a single block, as it appears in JSON output.
+#[derive(Debug, Default, Serialize)]
+struct BlockResult {
+    hash: H256,
+    number: Option<i32>,
+    diverged: bool,
+    deleted: bool,
+    diff: Option<Value>,
+    /// The `diff` rendered like it is printed, but never colorized or cut off.
+    diff_text: Option<String>,
+    /// The cached block was overwritten with the provider's version.
+    replaced: bool,
+    /// The provider returned a different block than requested, so nothing was compared.
+    provider_misbehavior: bool,
+    /// The cache had no block with this number, but the provider did.
+    missing: bool,
+    /// The provider's block was added to the cache because it was missing.
+    inserted: bool,
+    /// The cached block is malformed, so it wasn't compared with the provider.
+    corrupt: bool,
+    /// With a quorum, how many providers, the main one included, returned the same block that
+    /// differs from the cached one.
+    agreeing_providers: Option<usize>,
+    /// With `--by-number-reorg`, the hash of the provider's canonical block at this number, if
+    /// that isn't the cached block.
+    canonical_hash: Option<H256>,
+    /// The cached block changed after it was compared, so it was not deleted.
+    raced: bool,
+    /// The provider doesn't have the block yet, and it is close enough to the chain head to be
+    /// skipped.
+    tip_skipped: bool,
+    /// Why the provider's block could not be fetched, in which case nothing was compared.
+    fetch_error: Option<String>,
+    /// The digest of the cached block as it was compared, for a diverged block that may be
+    /// deleted.
+    #[serde(skip)]
+    cached_digest: Option<H256>,
+}
+
+/// What a check found and did.
+#[derive(Debug, Default)]
+pub struct CheckBlocksReport {
+    /// The manifest the check printed when it started, along with the time it started, as it is
+    /// recorded in the audit log.
+    pub manifest: Option<Value>,
+    /// How many blocks were compared with the provider.
+    pub checked: usize,
+    /// The hash and number of every block that differs from the provider's.
+    pub diverged: Vec<(H256, i32)>,
+    /// The blocks that were removed from the cache, or that would have been in a dry run.
+    pub deleted: Vec<H256>,
+    /// The blocks that were overwritten with the provider's version in `--replace` mode, or that
+    /// would have been in a dry run.
+    pub replaced: Vec<H256>,
+    /// The blocks for which the provider returned a different block than requested. These were
+    /// not compared and are left alone.
+    pub provider_misbehavior: Vec<H256>,
+    /// The block numbers in a range for which nothing is cached, sorted.
+    pub missing: Vec<i32>,
+    /// The block numbers for which nothing is cached but the provider has a block, with the hash
+    /// of that block. Only looked for with `find_gaps`.
+    pub gaps: Vec<(i32, H256)>,
+    /// The blocks from `gaps` that were added to the cache, or that would have been in a dry run.
+    pub filled_gaps: Vec<H256>,
+    /// The blocks that diverged from the main provider, but not from enough of the other providers
+    /// to reach `quorum`. They were left alone.
+    pub unconfirmed: Vec<H256>,
+    /// The blocks that differ from the provider's in fewer fields than `min_diff_fields`, with
+    /// how many. They were left alone.
+    pub minor: Vec<(H256, usize)>,
+    /// The diverged blocks that changed in the cache between comparing and deleting them, e.g.
+    /// because graph-node replaced them meanwhile. They were not deleted.
+    pub raced: Vec<H256>,
+    /// The blocks that `verify_deletes` found were not actually removed by deleting them, with
+    /// what went wrong.
+    pub unverified_deletes: Vec<(H256, String)>,
+    /// The cached blocks that lack one of the fields every block has, with what is wrong with
+    /// them. They are deleted with `delete_corrupt`.
+    pub corrupt: Vec<(H256, String)>,
+    /// The (1-based) numbers of the input lines that `by_hash_list` couldn't parse.
+    pub skipped_lines: Vec<usize>,
+    /// The arguments of `by_hash` that are not block hashes, with what is wrong with them.
+    pub invalid_hashes: Vec<(String, String)>,
+    /// The blocks that were not compared because the audit log in `skip_audited` records them.
+    pub audited: Vec<H256>,
+    /// How many cached blocks `by_range` counted in its range before checking them.
+    pub cached_blocks: Option<usize>,
+    /// The blocks near the chain head that the provider doesn't have yet, which were skipped
+    /// because of `skip_tip_gap`.
+    pub tip_skipped: Vec<H256>,
+    /// The blocks that could not be fetched from the provider, with the error, even after
+    /// retrying. They were not compared, but the check went on with the other blocks.
+    pub fetch_failed: Vec<(H256, String)>,
+    /// If the check was interrupted, how many blocks were left unchecked.
+    pub interrupted: Option<usize>,
+    /// Whether the check was interrupted because it used up its runtime rather than by Ctrl-C.
+    pub out_of_time: bool,
+    /// How many diverged blocks differ in each field, by the JSON pointer of the field with the
+    /// array indexes replaced by `*`, see [`CheckBlocksReport::top_diverging_fields`].
+    pub diverging_fields: HashMap<String, usize>,
+    pub timings: Timings,
+    results: Vec<BlockResult>,
+}
+
+impl CheckBlocksReport {
+    /// The `n` fields that the most diverged blocks differ in, with how many blocks differ in
+    /// them, most common first. A field that all diverged blocks differ in usually points to a
+    /// quirk of the provider rather than to reorgs.
+    pub fn top_diverging_fields(&self, n: usize) -> Vec<(&str, usize)> {
+        let mut fields: Vec<(&str, usize)> = self
+            .diverging_fields
+            .iter()
+            .map(|(path, count)| (path.as_str(), *count))
+            .collect();
+        fields.sort_unstable_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+        fields.truncate(n);
+        fields
+    }
+
+    /// Adds `hash` to `deleted`, `raced` or `unverified_deletes`, depending on what deleting it
+    /// came to.
+    fn record_delete_outcome(&mut self, hash: H256, outcome: steps::DeleteOutcome) {
+        match outcome {
+            steps::DeleteOutcome::Deleted => self.deleted.push(hash),
+            steps::DeleteOutcome::Raced => self.raced.push(hash),
+            steps::DeleteOutcome::Unverified(problem) => {
+                self.unverified_deletes.push((hash, problem))
+            }
+        }
     }
 
-    impl Range {
-        pub fn new(lower_bound: Option<i32>, upper_bound: Option<i32>) -> anyhow::Result<Self> {
-            let (lower_bound, upper_bound) = match (lower_bound, upper_bound) {
-                // Invalid cases:
-                (None, None) => {
-                    bail!(
-                        "This would wipe the whole cache. \
-                         Use `graphman chain truncate` instead"
-                    )
+    /// Prints the report in the format selected by `options.output`.
+    pub fn render(&self, options: &CheckOptions) -> anyhow::Result<()> {
+        if let Some(unchecked) = self.interrupted {
+            let resume = match &options.checkpoint {
+                Some(path) => format!(
+                    ", pass `--resume --checkpoint {}` to check them",
+                    path.display()
+                ),
+                None => String::new(),
+            };
+            if self.out_of_time {
+                let reached = match self.results.iter().filter_map(|result| result.number).max() {
+                    Some(number) => format!(" after checking blocks up to #{number}"),
+                    None => String::new(),
+                };
+                eprintln!(
+                    "The check stopped at its maximum runtime{reached}, and {unchecked} blocks \
+                     were not checked{resume}"
+                );
+            } else {
+                eprintln!(
+                    "The check was interrupted and {unchecked} blocks were not checked{resume}"
+                );
+            }
+        }
+        if options.list_only {
+            return self.render_list(options);
+        }
+        match options.output {
+            OutputFormat::Human => {
+                let (fix_count, fix_action) = if options.replace {
+                    (self.replaced.len(), "replaced with the provider's version")
+                } else {
+                    (self.deleted.len(), "deleted")
+                };
+                let fix_action = if options.dry_run {
+                    format!("would have been {fix_action}")
+                } else {
+                    fix_action.to_string()
+                };
+                println!(
+                    "Checked {} blocks: {} diverged from the provider, {fix_count} {fix_action}.",
+                    self.checked,
+                    self.diverged.len(),
+                );
+                let top_fields = self.top_diverging_fields(TOP_DIVERGING_FIELDS);
+                if !top_fields.is_empty() {
+                    println!("The fields that diverged most often, by number of blocks:");
+                    for (path, count) in top_fields {
+                        println!("  {count:>8}  {path}");
+                    }
                 }
-                (Some(0), _) => bail!("Genesis block can't be removed"),
-                (Some(x), _) | (_, Some(x)) if x < 0 => {
-                    bail!("Negative block number used as range bound: {}", x)
+                if !self.provider_misbehavior.is_empty() {
+                    println!(
+                        "The provider returned the wrong block for {} blocks, \
+                         which were not checked:",
+                        self.provider_misbehavior.len()
+                    );
+                    for hash in &self.provider_misbehavior {
+                        println!("  {hash:?}");
+                    }
                 }
-                (Some(lower), Some(upper)) if upper < lower => bail!(
-                    "Upper bound ({}) can't be smaller than lower bound ({})",
-                    upper,
-                    lower
-                ),
+                if !self.tip_skipped.is_empty() {
+                    println!(
+                        "{} blocks near the chain head are not available from the provider yet \
+                         and were skipped:",
+                        self.tip_skipped.len()
+                    );
+                    for hash in &self.tip_skipped {
+                        println!("  {hash:?}");
+                    }
+                }
+                if !self.fetch_failed.is_empty() {
+                    println!(
+                        "{} blocks could not be fetched from the provider and were not checked:",
+                        self.fetch_failed.len()
+                    );
+                    for (hash, error) in &self.fetch_failed {
+                        println!("  {hash:?}: {error}");
+                    }
+                }
+                if !self.unconfirmed.is_empty() {
+                    println!(
+                        "{} blocks diverged from the main provider, but fewer than {} providers \
+                         agreed, so they were left alone:",
+                        self.unconfirmed.len(),
+                        options.quorum
+                    );
+                    for hash in &self.unconfirmed {
+                        println!("  {hash:?}");
+                    }
+                }
+                if let (false, Some(min_diff_fields)) =
+                    (self.minor.is_empty(), options.min_diff_fields)
+                {
+                    println!(
+                        "{} blocks differ from the provider in fewer than {min_diff_fields} \
+                         fields, so they were left alone:",
+                        self.minor.len(),
+                    );
+                    for (hash, fields) in &self.minor {
+                        println!("  {hash:?}: {fields} fields");
+                    }
+                }
+                if !self.raced.is_empty() {
+                    println!(
+                        "{} diverged blocks changed in the cache after they were compared, so \
+                         they were not deleted:",
+                        self.raced.len()
+                    );
+                    for hash in &self.raced {
+                        println!("  {hash:?}");
+                    }
+                }
+                print_unverified_deletes(&self.unverified_deletes);
+                if !self.corrupt.is_empty() {
+                    let action = match (options.delete_corrupt, options.dry_run) {
+                        (false, _) => "left in the cache, use `--delete-corrupt` to delete them",
+                        (true, true) => "would have been deleted",
+                        (true, false) => "deleted",
+                    };
+                    println!(
+                        "{} cached blocks are corrupt and were not checked ({action}):",
+                        self.corrupt.len()
+                    );
+                    for (hash, problem) in &self.corrupt {
+                        println!("  {hash:?}: {problem}");
+                    }
+                }
+                if !self.missing.is_empty() {
+                    println!(
+                        "{} block numbers have no cached block and were skipped: {}",
+                        self.missing.len(),
+                        helpers::format_block_numbers(&self.missing)
+                    );
+                }
+                if options.find_gaps {
+                    let numbers: Vec<i32> = self.gaps.iter().map(|(number, _)| *number).collect();
+                    println!(
+                        "{} blocks are missing from the cache, but not from the provider: {}",
+                        numbers.len(),
+                        helpers::format_block_numbers(&numbers)
+                    );
+                    if options.fill_gaps {
+                        let added = if options.dry_run {
+                            "would have been added"
+                        } else {
+                            "were added"
+                        };
+                        println!("{} of them {added} to the cache.", self.filled_gaps.len());
+                    }
+                }
+                if !self.audited.is_empty() {
+                    println!(
+                        "Skipped {} blocks that the audit log already records, pass `--recheck` \
+                         to check them again.",
+                        self.audited.len()
+                    );
+                }
+                self.timings.render(self.checked, options);
+                if !self.skipped_lines.is_empty() {
+                    let lines = self
+                        .skipped_lines
+                        .iter()
+                        .map(|line| line.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    println!(
+                        "Skipped {} input lines that are not block hashes: {lines}",
+                        self.skipped_lines.len()
+                    );
+                }
+                if !self.invalid_hashes.is_empty() {
+                    println!(
+                        "Skipped {} arguments that are not block hashes:",
+                        self.invalid_hashes.len()
+                    );
+                    for (hash, problem) in &self.invalid_hashes {
+                        println!("  {hash}: {problem}");
+                    }
+                }
+            }
+            OutputFormat::Json => {
+                let json = serde_json::to_string_pretty(&self.json_envelope())
+                    .context("failed to serialize the results as JSON")?;
+                println!("{json}");
+                if !self.missing.is_empty() {
+                    eprintln!(
+                        "{} block numbers have no cached block and were skipped: {}",
+                        self.missing.len(),
+                        helpers::format_block_numbers(&self.missing)
+                    );
+                }
+                self.timings.render(self.checked, options);
+            }
+        }
+        Ok(())
+    }
 
-                // Valid cases:
-                // Open lower bounds are set to the lowest possible block number
-                (None, upper @ Some(_)) => (1, upper),
-                (Some(lower), upper) => (lower, upper),
-            };
+    /// The JSON object that `--output json` prints: the `results` of the checked blocks,
+    /// together with the [`JSON_SCHEMA_VERSION`] and the manifest of the check, the
+    /// `invalid_hashes` arguments that were skipped, and the `top_diverging_fields` that the
+    /// human summary lists.
+    fn json_envelope(&self) -> Value {
+        let results =
+            serde_json::to_value(&self.results).expect("check results can always be serialized");
+        let mut json = helpers::json_envelope(self.manifest.as_ref(), results);
+        json["invalid_hashes"] = helpers::invalid_hashes_value(&self.invalid_hashes);
+        json["top_diverging_fields"] = self
+            .top_diverging_fields(TOP_DIVERGING_FIELDS)
+            .into_iter()
+            .map(|(field, blocks)| serde_json::json!({ "field": field, "blocks": blocks }))
+            .collect();
+        json
+    }
 
-            Ok(Self {
-                lower_bound,
-                upper_bound,
-            })
+    /// The report as a JSON object, for callers that don't print it: the envelope that JSON
+    /// output prints, with the hashes of the blocks that were `deleted` or `replaced` and the
+    /// other details of the check alongside the `results`.
+    pub fn to_json(&self) -> Value {
+        let mut json = self.json_envelope();
+        let details = serde_json::json!({
+            "checked": self.checked,
+            "deleted": self.deleted,
+            "replaced": self.replaced,
+            "unconfirmed": self.unconfirmed,
+            "minor": self.minor,
+            "raced": self.raced,
+            "unverified_deletes": self.unverified_deletes,
+            "corrupt": self.corrupt,
+            "missing": self.missing,
+            "audited": self.audited,
+            "tip_skipped": self.tip_skipped,
+            "fetch_failed": self.fetch_failed,
+            "diverging_fields": self.diverging_fields,
+        });
+        if let (Value::Object(json), Value::Object(details)) = (&mut json, details) {
+            json.extend(details);
         }
+        json
     }
+
+    /// Prints just the diverged blocks, one hash per line so the output can be fed to
+    /// `by-hash-list`, or as a JSON array of their numbers and hashes. Everything else goes to
+    /// stderr.
+    fn render_list(&self, options: &CheckOptions) -> anyhow::Result<()> {
+        match options.output {
+            // The command from `emit_repair_command` takes the place of the list
+            OutputFormat::Human if options.emit_command => {}
+            OutputFormat::Human => {
+                for (hash, _) in &self.diverged {
+                    println!("{hash:?}");
+                }
+            }
+            OutputFormat::Json => {
+                let diverged: Vec<_> = self
+                    .diverged
+                    .iter()
+                    .map(|(hash, number)| serde_json::json!({ "number": number, "hash": hash }))
+                    .collect();
+                helpers::print_json_envelope(self.manifest.as_ref(), diverged)?;
+            }
+        }
+        eprintln!(
+            "Checked {} blocks: {} diverged from the provider.",
+            self.checked,
+            self.diverged.len()
+        );
+        if !self.provider_misbehavior.is_empty() {
+            eprintln!(
+                "The provider returned the wrong block for {} blocks, which were not checked.",
+                self.provider_misbehavior.len()
+            );
+        }
+        if !self.corrupt.is_empty() {
+            eprintln!(
+                "{} cached blocks are corrupt and were not checked.",
+                self.corrupt.len()
+            );
+        }
+        if !self.tip_skipped.is_empty() {
+            eprintln!(
+                "{} blocks near the chain head are not available from the provider yet and were \
+                 skipped.",
+                self.tip_skipped.len()
+            );
+        }
+        if !self.fetch_failed.is_empty() {
+            eprintln!(
+                "{} blocks could not be fetched from the provider and were not checked.",
+                self.fetch_failed.len()
+            );
+        }
+        self.timings.render(self.checked, options);
+        Ok(())
+    }
+
+    /// A `graphman` command line that checks the diverged blocks of `chain` again, or `None` if
+    /// no block diverged. It relies on `GRAPH_NODE_CONFIG` for the configuration, since the
+    /// configuration file may well be somewhere else on the machine where it is run.
+    pub fn repair_command(&self, chain: &str) -> Option<String> {
+        match self.diverged.as_slice() {
+            [] => None,
+            [(hash, _)] => Some(format!(
+                "graphman chain check-blocks {chain} by-hash {hash:?}"
+            )),
+            diverged => {
+                let hashes = diverged
+                    .iter()
+                    .map(|(hash, _)| format!("{hash:?}"))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                Some(format!(
+                    "printf '%s\\n' {hashes} | graphman chain check-blocks {chain} by-hash-list -"
+                ))
+            }
+        }
+    }
+
+    /// Prints [`Self::repair_command`] if `options.emit_command` is set. It goes to stdout,
+    /// unless that is taken by JSON output.
+    pub fn emit_repair_command(&self, chain: &str, options: &CheckOptions) {
+        if !options.emit_command {
+            return;
+        }
+        match (self.repair_command(chain), options.output) {
+            (Some(command), OutputFormat::Human) => println!("{command}"),
+            (Some(command), OutputFormat::Json) => eprintln!("{command}"),
+            (None, _) => eprintln!("No block diverged, there's nothing to re-check."),
+        }
+    }
+}
+
+/// Prints the blocks that deleting didn't remove from the cache, with what went wrong.
+fn print_unverified_deletes(unverified_deletes: &[(H256, String)]) {
+    if unverified_deletes.is_empty() {
+        return;
+    }
+    println!(
+        "Deleting {} blocks didn't remove them from the cache:",
+        unverified_deletes.len()
+    );
+    for (hash, problem) in unverified_deletes {
+        println!("  {hash:?}: {problem}");
+    }
+}
+
+/// Where the time of a check went.
+///
+/// This is cheap enough to always collect; only printing the breakdown is left to `--timing`.
+#[derive(Debug, Default)]
+pub struct Timings {
+    /// How long the whole check took.
+    pub total: Duration,
+    /// The time spent looking up blocks and block hashes in the database.
+    pub db: Duration,
+    /// How long each block took to arrive from the provider, retries included.
+    pub rpc_latencies: Vec<Duration>,
+}
+
+impl Timings {
+    /// The sum of all provider request latencies. Requests run concurrently, so this can be
+    /// larger than `total`.
+    pub fn rpc(&self) -> Duration {
+        self.rpc_latencies.iter().sum()
+    }
+
+    pub fn mean_rpc_latency(&self) -> Option<Duration> {
+        let count = u32::try_from(self.rpc_latencies.len()).ok()?;
+        (count > 0).then(|| self.rpc() / count)
+    }
+
+    pub fn p95_rpc_latency(&self) -> Option<Duration> {
+        let mut latencies = self.rpc_latencies.clone();
+        latencies.sort_unstable();
+        let index = ((latencies.len() * 95 + 99) / 100).checked_sub(1)?;
+        latencies.get(index).copied()
+    }
+
+    /// Prints the throughput, and with `--timing`, where the time was spent.
+    fn render(&self, checked: usize, options: &CheckOptions) {
+        let seconds = self.total.as_secs_f64();
+        let throughput = if seconds > 0.0 {
+            checked as f64 / seconds
+        } else {
+            0.0
+        };
+        options.info(format_args!(
+            "Took {:.1?} ({throughput:.1} blocks/s).",
+            self.total
+        ));
+        if options.timing {
+            let format_latency = |latency: Option<Duration>| match latency {
+                Some(latency) => format!("{latency:.1?}"),
+                None => "-".to_string(),
+            };
+            options.info(format_args!(
+                "  RPC: {:.1?} in total, {} mean and {} p95 per block",
+                self.rpc(),
+                format_latency(self.mean_rpc_latency()),
+                format_latency(self.p95_rpc_latency())
+            ));
+            options.info(format_args!("  DB:  {:.1?} in total", self.db));
+        }
+    }
+}
+
+/// A cached block, identified by its number and hash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct CachedBlockRef {
+    pub number: i32,
+    pub hash: H256,
+}
+
+/// What the cache holds at a block number of a range.
+#[derive(Debug, PartialEq, Eq)]
+enum Height {
+    /// No block, so there is nothing to compare.
+    Missing,
+    /// Exactly one block, which is compared with the provider.
+    Present(H256),
+    /// Several blocks, of which at most one can be canonical.
+    Ambiguous(Vec<H256>),
 }
@@ -0,0 +1,227 @@
+//! Runs block cache checks on behalf of the admin JSON-RPC server, so that operators can start
+//! them remotely with `chain_checkBlocks` and follow them with `chain_checkBlocksStatus`.
+
+use super::helpers::panic_error;
+use super::{by_hash, by_number, by_range, ChainHeads, CheckBlocksMetrics, CheckOptions};
+use super::{CheckStatus, OutputFormat, RangeSet};
+use futures::FutureExt;
+use graph::components::store::BlockStore as _;
+use graph::prelude::{anyhow, serde_json, Logger};
+use graph_chain_ethereum::{EthereumAdapterTrait, EthereumNetworks};
+use graph_server_json_rpc::{BlockCacheChecker, CheckBlocksParams, CheckBlocksTarget};
+use graph_store_postgres::BlockStore;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How long the service trusts a chain head it looked up for an earlier check. It runs for as
+/// long as graph-node does, so the chain heads have to be refreshed now and then.
+const CHAIN_HEAD_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How many finished checks the service remembers. Once there are more, the oldest ones are
+/// forgotten, and polling their status fails as it does for an unknown id.
+const MAX_FINISHED_CHECKS: usize = 100;
+
+/// A check that was started over the API, and its outcome once it is done.
+struct Check {
+    status: Arc<CheckStatus>,
+    outcome: Mutex<Option<Result<Value, String>>>,
+}
+
+/// The [`BlockCacheChecker`] that graph-node hands to the admin server. Checks run in the
+/// background, and they are kept around so that their status can be polled: all the running ones,
+/// and the last [`MAX_FINISHED_CHECKS`] finished ones.
+pub struct BlockCacheService {
+    block_store: Arc<BlockStore>,
+    ethereum_networks: EthereumNetworks,
+    metrics: Arc<CheckBlocksMetrics>,
+    logger: Logger,
+    checks: Mutex<Checks>,
+    chain_heads: Arc<ChainHeads>,
+}
+
+/// The checks the service remembers, by id. Ids are never reused, so an id that was forgotten
+/// can't turn into another check's.
+#[derive(Default)]
+struct Checks {
+    next_id: u64,
+    by_id: BTreeMap<u64, Arc<Check>>,
+}
+
+impl Checks {
+    fn insert(&mut self, check: Arc<Check>) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.by_id.insert(id, check);
+        self.forget_old_finished_checks();
+        id
+    }
+
+    /// Forget the oldest finished checks beyond [`MAX_FINISHED_CHECKS`]
+    fn forget_old_finished_checks(&mut self) {
+        let finished: Vec<u64> = self
+            .by_id
+            .iter()
+            .filter(|(_, check)| check.outcome.lock().unwrap().is_some())
+            .map(|(id, _)| *id)
+            .collect();
+        let excess = finished.len().saturating_sub(MAX_FINISHED_CHECKS);
+        for id in &finished[..excess] {
+            self.by_id.remove(id);
+        }
+    }
+}
+
+impl BlockCacheService {
+    pub fn new(
+        block_store: Arc<BlockStore>,
+        ethereum_networks: EthereumNetworks,
+        metrics: Arc<CheckBlocksMetrics>,
+        logger: Logger,
+    ) -> Self {
+        BlockCacheService {
+            block_store,
+            ethereum_networks,
+            metrics,
+            logger,
+            checks: Mutex::new(Checks::default()),
+            chain_heads: Arc::new(ChainHeads::new(Some(CHAIN_HEAD_REFRESH_INTERVAL))),
+        }
+    }
+}
+
+/// The options of a check started over the API: those of `graphman chain check-blocks` with its
+/// default flags. Nobody can answer a confirmation prompt, so blocks are only deleted if the
+/// request asks for that with `confirm`, and the check is a dry run otherwise.
+fn options(
+    params: &CheckBlocksParams,
+    status: Arc<CheckStatus>,
+    metrics: Arc<CheckBlocksMetrics>,
+    chain_heads: Arc<ChainHeads>,
+) -> CheckOptions {
+    CheckOptions {
+        dry_run: !params.confirm,
+        skip_confirmation: params.confirm,
+        replace: params.replace,
+        max_deletions: params.max_deletions,
+        // Progress messages go to stderr in JSON mode, which keeps them off graph-node's stdout
+        output: OutputFormat::Json,
+        status: Some(status),
+        metrics: Some(metrics),
+        chain_heads,
+        ..CheckOptions::default()
+    }
+}
+
+impl BlockCacheChecker for BlockCacheService {
+    fn start_check(&self, params: CheckBlocksParams) -> anyhow::Result<u64> {
+        // Without a prompt, the limit is the only thing standing between a typo in the range and
+        // an emptied block cache
+        anyhow::ensure!(
+            !params.confirm || params.max_deletions.is_some(),
+            "`confirm` deletes blocks without asking, so it needs `max_deletions` as well"
+        );
+        let chain_store = self
+            .block_store
+            .chain_store(&params.chain)
+            .ok_or_else(|| anyhow::anyhow!("Could not find a network named '{}'", params.chain))?;
+        let ethereum_adapter: Arc<dyn EthereumAdapterTrait> = self
+            .ethereum_networks
+            .networks
+            .get(&params.chain)
+            .and_then(|adapters| adapters.cheapest())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Failed to obtain an Ethereum adapter for chain '{}'",
+                    params.chain
+                )
+            })?;
+        // Fail right away on a malformed range rather than in the background
+        let range = match &params.target {
+            CheckBlocksTarget::Range(range) => Some(range.parse::<RangeSet>()?),
+            CheckBlocksTarget::Hash(_) | CheckBlocksTarget::Number(_) => None,
+        };
+
+        let check = Arc::new(Check {
+            status: Arc::new(CheckStatus::default()),
+            outcome: Mutex::new(None),
+        });
+        let id = self.checks.lock().unwrap().insert(check.clone());
+
+        let logger = self.logger.clone();
+        let options = options(
+            &params,
+            check.status.clone(),
+            self.metrics.clone(),
+            self.chain_heads.clone(),
+        );
+        // The checks make synchronous database calls, so they get a thread of their own. A check
+        // that panics is reported as failed, or it would never be done
+        graph::spawn_blocking(async move {
+            let adapter = &*ethereum_adapter;
+            let report = async move {
+                match (params.target, range) {
+                    (CheckBlocksTarget::Hash(hash), _) => {
+                        by_hash(
+                            std::slice::from_ref(&hash),
+                            chain_store,
+                            adapter,
+                            &logger,
+                            &options,
+                        )
+                        .await
+                    }
+                    (CheckBlocksTarget::Number(number), _) => {
+                        by_number(
+                            number,
+                            chain_store,
+                            adapter,
+                            &logger,
+                            false,
+                            false,
+                            &options,
+                        )
+                        .await
+                    }
+                    (CheckBlocksTarget::Range(_), Some(range)) => {
+                        by_range(chain_store, adapter, &range, &logger, false, &options).await
+                    }
+                    (CheckBlocksTarget::Range(_), None) => {
+                        unreachable!("the range was parsed above")
+                    }
+                }
+            };
+            let report = AssertUnwindSafe(report)
+                .catch_unwind()
+                .await
+                .unwrap_or_else(|panic| Err(panic_error(panic)));
+            let outcome = report
+                .map(|report| report.to_json())
+                .map_err(|e| format!("{e:#}"));
+            *check.outcome.lock().unwrap() = Some(outcome);
+        });
+        Ok(id)
+    }
+
+    fn check_status(&self, id: u64) -> Option<Value> {
+        let check = self.checks.lock().unwrap().by_id.get(&id)?.clone();
+        let mut status = serde_json::json!({
+            "done": false,
+            "progress": check.status.to_json(),
+        });
+        match &*check.outcome.lock().unwrap() {
+            None => {}
+            Some(Ok(report)) => {
+                status["done"] = true.into();
+                status["report"] = report.clone();
+            }
+            Some(Err(e)) => {
+                status["done"] = true.into();
+                status["error"] = e.clone().into();
+            }
+        }
+        Some(status)
+    }
+}
@@ -0,0 +1,161 @@
+//! `backfill`, which adds the canonical blocks that are missing from the cache in a range.
+
+use super::*;
+
+/// Adds the provider's canonical block to the cache for every number in `range` that has no
+/// cached block, e.g. after part of the cache was lost. Numbers that already have a block,
+/// including forked ones, are left alone.
+///
+/// Blocks are fetched with up to `options.concurrency` requests in flight, and a block that
+/// can't be fetched or stored is reported rather than ending the backfill. Once more than
+/// [`DELETE_CONFIRMATION_THRESHOLD`] blocks are to be added, the user is asked first.
+pub async fn backfill(
+    chain_store: Arc<ChainStore>,
+    ethereum_adapter: &dyn EthereumAdapterTrait,
+    range: &RangeSet,
+    logger: &Logger,
+    options: &CheckOptions,
+) -> anyhow::Result<BackfillReport> {
+    ensure!(options.concurrency > 0, "Concurrency must be at least 1");
+    ensure!(options.max_attempts > 0, "Max attempts must be at least 1");
+    steps::ensure_same_chain(&chain_store, ethereum_adapter, options).await?;
+    let range = steps::resolve_range_hashes(range, &chain_store).await?;
+    let block_numbers = range.block_numbers(
+        || steps::find_chain_head(&chain_store, options),
+        || steps::find_earliest_cached_block(&chain_store),
+    )?;
+    let block_hashes =
+        steps::resolve_block_hashes_from_block_numbers(&block_numbers, &chain_store)?;
+    let mut missing = helpers::missing_block_numbers(&block_numbers, block_hashes);
+    let target = format!(
+        "the missing blocks of {}",
+        helpers::format_block_numbers(&block_numbers)
+    );
+    let manifest = steps::print_manifest(
+        &chain_store,
+        ethereum_adapter,
+        &target,
+        Some(&helpers::block_runs(&block_numbers)),
+        options,
+    )?;
+    options.info(format_args!(
+        "{} of {} block numbers have no cached block",
+        missing.len(),
+        block_numbers.len()
+    ));
+
+    let mut report = BackfillReport {
+        manifest: Some(manifest),
+        ..Default::default()
+    };
+    // The canonical block close to the chain head may still be reorged away, and the block
+    // ingestor will add it soon enough anyway
+    if let Some(gap) = options.skip_tip_gap {
+        let chain_head = steps::find_chain_head(&chain_store, options)?;
+        (missing, report.tip_skipped) = helpers::split_off_tip(missing, chain_head, gap);
+        if !report.tip_skipped.is_empty() {
+            options.info(format_args!(
+                "Leaving {} block numbers within {gap} blocks of the chain head alone",
+                report.tip_skipped.len()
+            ));
+        }
+    }
+    if missing.is_empty() || !steps::confirm_backfill(&missing, &chain_store, options)? {
+        return Ok(report);
+    }
+    let provider_blocks =
+        steps::fetch_provider_blocks_by_number(&missing, ethereum_adapter, logger, options);
+    futures::pin_mut!(provider_blocks);
+    let mut done = 0;
+    while let Some((number, provider_block)) = provider_blocks.next().await {
+        if options.interrupt.is_set() {
+            report.interrupted = Some(missing.len() - done);
+            break;
+        }
+        done += 1;
+        let inserted = match provider_block {
+            Ok(Some(block)) => match block.hash {
+                Some(hash) => steps::insert_block(
+                    &hash,
+                    block,
+                    &chain_store,
+                    ethereum_adapter,
+                    logger,
+                    options,
+                )
+                .await
+                .map(|()| Some(hash)),
+                None => Err(anyhow!(
+                    "The provider returned block {number} without a hash"
+                )),
+            },
+            Ok(None) => Ok(None),
+            Err(e) => Err(e),
+        };
+        match inserted {
+            Ok(Some(hash)) => report.inserted.push(CachedBlockRef { number, hash }),
+            Ok(None) => report.skipped.push(number),
+            Err(e) => {
+                let error = format!("{e:#}");
+                eprintln!("Failed to backfill block number {number}: {error}");
+                report.failed.push(BackfillFailure { number, error });
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// A block number that [`backfill`] couldn't add a block for.
+#[derive(Debug, Serialize)]
+pub struct BackfillFailure {
+    pub number: i32,
+    pub error: String,
+}
+
+/// The outcome of [`backfill`].
+#[derive(Debug, Default, Serialize)]
+pub struct BackfillReport {
+    /// The blocks that were added to the cache, or that would have been in a dry run.
+    pub inserted: Vec<CachedBlockRef>,
+    /// The block numbers that the provider has no block for either.
+    pub skipped: Vec<i32>,
+    /// The block numbers within `options.skip_tip_gap` of the chain head, which were left alone.
+    pub tip_skipped: Vec<i32>,
+    pub failed: Vec<BackfillFailure>,
+    /// If the backfill was interrupted, how many block numbers were left.
+    pub interrupted: Option<usize>,
+    /// The manifest entry of the check, for the JSON output.
+    #[serde(skip)]
+    pub manifest: Option<Value>,
+}
+
+impl BackfillReport {
+    /// Prints the report in the format selected by `options.output`.
+    pub fn render(&self, options: &CheckOptions) -> anyhow::Result<()> {
+        if let Some(left) = self.interrupted {
+            eprintln!("The backfill was interrupted and {left} block numbers were left");
+        }
+        match options.output {
+            OutputFormat::Human => {
+                for BackfillFailure { number, error } in &self.failed {
+                    println!("block number {number} failed: {error}");
+                }
+                let added = if options.dry_run {
+                    "would have been added"
+                } else {
+                    "added"
+                };
+                println!(
+                    "{} missing blocks {added} to the cache, {} skipped since the provider \
+                     doesn't have them either, {} left alone near the chain head, {} failed.",
+                    self.inserted.len(),
+                    self.skipped.len(),
+                    self.tip_skipped.len(),
+                    self.failed.len()
+                );
+            }
+            OutputFormat::Json => helpers::print_json_envelope(self.manifest.as_ref(), self)?,
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,99 @@
+//! The `by-hash` and `by-hash-list` checks, of the cached blocks with the given hashes.
+
+use super::*;
+
+/// Checks the blocks with the given `hashes` together, as one check with one report.
+///
+/// Hashes that aren't valid are reported and skipped, unless no hash is valid at all.
+pub async fn by_hash(
+    hashes: &[String],
+    chain_store: Arc<ChainStore>,
+    ethereum_adapter: &dyn EthereumAdapterTrait,
+    logger: &Logger,
+    options: &CheckOptions,
+) -> anyhow::Result<CheckBlocksReport> {
+    let (block_hashes, invalid_hashes) = helpers::parse_block_hashes(hashes)?;
+    steps::ensure_same_chain(&chain_store, ethereum_adapter, options).await?;
+    let target = match block_hashes.as_slice() {
+        [block_hash] => format!("block {block_hash:?}"),
+        block_hashes => format!("{} block hashes", block_hashes.len()),
+    };
+    let manifest = steps::print_manifest(&chain_store, ethereum_adapter, &target, None, options)?;
+    let mut report = run(
+        &block_hashes,
+        &chain_store,
+        ethereum_adapter,
+        logger,
+        options,
+        None,
+        None,
+    )
+    .await?;
+    report.invalid_hashes = invalid_hashes;
+    report.manifest = Some(manifest);
+    Ok(report)
+}
+
+/// Checks all block hashes listed in `source`, one per line. `source` is a file path, or `-` to
+/// read from stdin.
+///
+/// Lines that aren't a valid block hash are reported and skipped; empty lines are ignored.
+pub async fn by_hash_list(
+    source: &str,
+    chain_store: Arc<ChainStore>,
+    ethereum_adapter: &dyn EthereumAdapterTrait,
+    logger: &Logger,
+    options: &CheckOptions,
+) -> anyhow::Result<CheckBlocksReport> {
+    let lines: Vec<String> = if source == "-" {
+        io::stdin()
+            .lock()
+            .lines()
+            .collect::<Result<_, _>>()
+            .context("failed to read block hashes from stdin")?
+    } else {
+        let file = File::open(source).with_context(|| format!("failed to open `{source}`"))?;
+        BufReader::new(file)
+            .lines()
+            .collect::<Result<_, _>>()
+            .with_context(|| format!("failed to read block hashes from `{source}`"))?
+    };
+
+    let mut block_hashes = Vec::new();
+    let mut skipped_lines = Vec::new();
+    for (index, line) in lines.iter().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        match helpers::parse_block_hash(line) {
+            Ok(block_hash) => block_hashes.push(block_hash),
+            Err(e) => {
+                eprintln!("Skipping line {}: {e:#}", index + 1);
+                skipped_lines.push(index + 1);
+            }
+        }
+    }
+    options.info(format_args!(
+        "Read {} block hashes, skipped {} invalid lines",
+        block_hashes.len(),
+        skipped_lines.len()
+    ));
+
+    steps::ensure_same_chain(&chain_store, ethereum_adapter, options).await?;
+    let target = format!("{} block hashes from `{source}`", block_hashes.len());
+    let manifest = steps::print_manifest(&chain_store, ethereum_adapter, &target, None, options)?;
+    let mut report = run(
+        &block_hashes,
+        &chain_store,
+        ethereum_adapter,
+        logger,
+        options,
+        None,
+        None,
+    )
+    .await?;
+    report.skipped_lines = skipped_lines;
+    report.manifest = Some(manifest);
+    Ok(report)
+}
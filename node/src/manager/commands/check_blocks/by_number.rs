@@ -0,0 +1,152 @@
+//! The `by-number` check, of the cached blocks at one block number.
+
+use super::*;
+
+/// Checks the cached block with `number`. With `check_canonical`, the provider is also asked for
+/// the hash of its canonical block at that number: a provider may well still return a block that
+/// a reorg replaced when it is asked for it by hash, so comparing blocks by hash alone can hide
+/// the reorg. If the canonical hash is not the cached one, the cached block is stale and fixed
+/// like a diverged block without comparing it.
+pub async fn by_number(
+    number: i32,
+    chain_store: Arc<ChainStore>,
+    ethereum_adapter: &dyn EthereumAdapterTrait,
+    logger: &Logger,
+    delete_duplicates: bool,
+    check_canonical: bool,
+    options: &CheckOptions,
+) -> anyhow::Result<CheckBlocksReport> {
+    steps::ensure_same_chain(&chain_store, ethereum_adapter, options).await?;
+    let manifest = steps::print_manifest(
+        &chain_store,
+        ethereum_adapter,
+        &format!("block #{number}"),
+        Some(&[(number, number)]),
+        options,
+    )?;
+    let started = Instant::now();
+    let block_hashes = steps::resolve_block_hash_from_block_number(number, &chain_store)?;
+    let db_time = started.elapsed();
+
+    let mut report = match &block_hashes.as_slice() {
+        [] => bail!("Could not find a block with number {} in store", number),
+        [block_hash] if check_canonical => {
+            let mut report = check_canonical_block(
+                number,
+                *block_hash,
+                &chain_store,
+                ethereum_adapter,
+                logger,
+                options,
+            )
+            .await?;
+            report.timings.db += db_time;
+            report.timings.total = started.elapsed();
+            report
+        }
+        // Of several cached blocks, the one on the cache's canonical chain is compared with the
+        // provider's canonical block; without one, there is no telling which block to check
+        &block_hashes if check_canonical => {
+            let started_canonical = Instant::now();
+            let store_canonical = chain_store
+                .canonical_block_hash(number)?
+                .filter(|hash| block_hashes.contains(hash));
+            let db_time = db_time + started_canonical.elapsed();
+            match store_canonical {
+                Some(block_hash) => {
+                    options.info(format_args!(
+                        "{} blocks are cached for block number {number}, {block_hash:?} is the one \
+                         on the cached chain",
+                        block_hashes.len()
+                    ));
+                    let mut report = check_canonical_block(
+                        number,
+                        block_hash,
+                        &chain_store,
+                        ethereum_adapter,
+                        logger,
+                        options,
+                    )
+                    .await?;
+                    // The other blocks at this number are off the cached chain, and stale
+                    let others: Vec<H256> = block_hashes
+                        .iter()
+                        .copied()
+                        .filter(|hash| *hash != block_hash)
+                        .collect();
+                    options.info(format_args!(
+                        "The other blocks cached for block number {number} are not on the cached \
+                         chain:"
+                    ));
+                    for (num, hash) in others.iter().enumerate() {
+                        options.info(format_args!("{:>4}:  {hash:?}", num + 1));
+                    }
+                    if delete_duplicates {
+                        delete_duplicate_blocks(
+                            number,
+                            &others,
+                            &chain_store,
+                            options,
+                            &mut report,
+                        )?;
+                    } else {
+                        eprintln!(
+                            "{} other blocks for block number {number} were left in the cache. \
+                             To delete them, rerun this command with the `--delete-duplicates` \
+                             option.",
+                            others.len()
+                        );
+                    }
+                    report.timings.db += db_time;
+                    report.timings.total = started.elapsed();
+                    report
+                }
+                None => {
+                    let mut report = CheckBlocksReport::default();
+                    handle_multiple_block_hashes(
+                        number,
+                        block_hashes,
+                        &chain_store,
+                        delete_duplicates,
+                        options,
+                        &mut report,
+                    )
+                    .await?;
+                    report.timings.db = db_time;
+                    report.timings.total = started.elapsed();
+                    report
+                }
+            }
+        }
+        [block_hash] => {
+            let mut report = run(
+                &[*block_hash],
+                &chain_store,
+                ethereum_adapter,
+                logger,
+                options,
+                None,
+                None,
+            )
+            .await?;
+            report.timings.db += db_time;
+            report.timings.total = started.elapsed();
+            report
+        }
+        &block_hashes => {
+            let mut report = CheckBlocksReport::default();
+            handle_multiple_block_hashes(
+                number,
+                block_hashes,
+                &chain_store,
+                delete_duplicates,
+                options,
+                &mut report,
+            )
+            .await?;
+            report
+        }
+    };
+    report.manifest = Some(manifest);
+    Ok(report)
+}
@@ -0,0 +1,429 @@
+//! The `by-range` and `scan` checks, of all cached blocks in a range, and `by-range --purge`.
+
+use super::*;
+
+pub async fn by_range(
+    chain_store: Arc<ChainStore>,
+    ethereum_adapter: &dyn EthereumAdapterTrait,
+    range: &RangeSet,
+    logger: &Logger,
+    delete_duplicates: bool,
+    options: &CheckOptions,
+) -> anyhow::Result<CheckBlocksReport> {
+    ensure!(options.concurrency > 0, "Concurrency must be at least 1");
+    ensure!(options.max_attempts > 0, "Max attempts must be at least 1");
+    steps::ensure_same_chain(&chain_store, ethereum_adapter, options).await?;
+    let started = Instant::now();
+
+    // Block hashes used as bounds stand for the numbers of these blocks
+    let range = steps::resolve_range_hashes(range, &chain_store).await?;
+    // Resolve a range of block numbers into a collection of blocks hashes. When we have an open
+    // upper bound, we use the chain head's block number
+    let mut chain_head = None;
+    let bounds = range.bounds(
+        || {
+            let head = steps::find_chain_head(&chain_store, options)?;
+            chain_head = Some(head);
+            Ok(head)
+        },
+        || steps::find_earliest_cached_block(&chain_store),
+    )?;
+    if let Some(chain_head) = chain_head {
+        if let Some(lag) =
+            steps::chain_head_lag(chain_head, ethereum_adapter, logger, options).await
+        {
+            eprintln!(
+                "Warning: the chain head in the store, block {chain_head}, is {lag} blocks behind \
+                 the provider's latest block. Blocks above it are not checked."
+            );
+        }
+    }
+    let mut block_numbers: Vec<i32> = bounds
+        .iter()
+        .flat_map(|(lower, upper)| *lower..=*upper)
+        .collect();
+    // The blocks up to the checkpoint were checked before, and are not counted again
+    let mut resumed_after = None;
+    if let (Some(path), true) = (&options.checkpoint, options.resume) {
+        match steps::load_checkpoint(path, &chain_store.chain)? {
+            Some(done) => {
+                options.info(format_args!(
+                    "Resuming from checkpoint `{}`: blocks up to {done} were already checked",
+                    path.display()
+                ));
+                block_numbers.retain(|number| *number > done);
+                resumed_after = Some(done);
+            }
+            None => options.info(format_args!(
+                "No checkpoint at `{}` yet, starting from the beginning",
+                path.display()
+            )),
+        }
+    }
+    let range_description = helpers::format_block_numbers(&block_numbers);
+    let target = format!("blocks {range_description}");
+    let manifest = steps::print_manifest(
+        &chain_store,
+        ethereum_adapter,
+        &target,
+        Some(&helpers::block_runs(&block_numbers)),
+        options,
+    )?;
+    let lookup_started = Instant::now();
+    let mut cached_blocks = 0;
+    for (lower, upper) in &bounds {
+        let lower = resumed_after.map_or(*lower, |done| (*lower).max(done.saturating_add(1)));
+        if lower <= *upper {
+            cached_blocks += chain_store.count_blocks_in_range(lower, *upper)?;
+        }
+    }
+    options.info(format_args!(
+        "About to check {cached_blocks} cached blocks in {range_description}"
+    ));
+    let block_hashes_by_number =
+        steps::resolve_block_hashes_from_block_numbers(&block_numbers, &chain_store)?;
+    let db_time = lookup_started.elapsed();
+    let mut block_hashes_to_check = Vec::new();
+    let mut missing_block_numbers = Vec::new();
+    // What became of the blocks deleted at forked heights, before anything is compared
+    let mut deleted_before_check = CheckBlocksReport::default();
+    // Only heights with cached blocks are compared; the others are reported at the end
+    for (block_number, height) in helpers::classify_heights(&block_numbers, block_hashes_by_number)
+    {
+        match height {
+            Height::Missing => missing_block_numbers.push(block_number),
+            Height::Present(block_hash) => block_hashes_to_check.push(block_hash),
+            Height::Ambiguous(block_hashes) => {
+                let canonical = handle_forked_block_number(
+                    block_number,
+                    &block_hashes,
+                    &chain_store,
+                    ethereum_adapter,
+                    logger,
+                    delete_duplicates,
+                    options,
+                    &mut deleted_before_check,
+                )
+                .await?;
+                block_hashes_to_check.extend(canonical);
+            }
+        }
+    }
+    let mut report = run(
+        &block_hashes_to_check,
+        &chain_store,
+        ethereum_adapter,
+        logger,
+        options,
+        options.checkpoint.as_deref(),
+        Some(cached_blocks),
+    )
+    .await?;
+    report.deleted.extend(deleted_before_check.deleted);
+    report.raced.extend(deleted_before_check.raced);
+    report
+        .unverified_deletes
+        .extend(deleted_before_check.unverified_deletes);
+    report.missing = missing_block_numbers.clone();
+    if options.find_gaps && !missing_block_numbers.is_empty() && report.interrupted.is_none() {
+        check_gaps(
+            &missing_block_numbers,
+            &chain_store,
+            ethereum_adapter,
+            logger,
+            options,
+            &mut report,
+        )
+        .await?;
+    }
+    report.timings.db += db_time;
+    report.timings.total = started.elapsed();
+    report.manifest = Some(manifest);
+    Ok(report)
+}
+
+/// Runs [`by_range`] for each chain in `chain_stores`, with the chain's adapter from
+/// `ethereum_adapters`. Up to `parallel_chains` chains are checked at the same time; each of them
+/// needs its own database connections. Every chain is checked on a thread of its own, since the
+/// store is queried synchronously and would otherwise hold up the other chains.
+///
+/// This has to run on a multi-threaded runtime.
+///
+/// A chain that fails or panics doesn't stop the sweep; its error takes the place of its report.
+/// The reports are in the order of `chain_stores`, no matter which chain finished first.
+pub async fn by_range_all_chains(
+    chain_stores: Vec<Arc<ChainStore>>,
+    ethereum_adapters: &HashMap<String, Arc<dyn EthereumAdapterTrait>>,
+    range: &RangeSet,
+    logger: &Logger,
+    delete_duplicates: bool,
+    parallel_chains: usize,
+    options: &CheckOptions,
+) -> MultiChainReport {
+    let runtime = tokio::runtime::Handle::current();
+    let check_chain = |chain_store: Arc<ChainStore>| {
+        runtime.block_on(async move {
+            let chain = chain_store.chain.clone();
+            if options.interrupt.is_set() {
+                if options.interrupt.out_of_time() {
+                    eprintln!("Not checking chain {chain} since the check used up its runtime");
+                } else {
+                    eprintln!("Not checking chain {chain} since the check was interrupted");
+                }
+                return None;
+            }
+            options.info(format_args!("Checking blocks for chain {chain}"));
+            let chain_report = match ethereum_adapters.get(&chain) {
+                Some(ethereum_adapter) => {
+                    let check = by_range(
+                        chain_store,
+                        ethereum_adapter,
+                        range,
+                        logger,
+                        delete_duplicates,
+                        options,
+                    );
+                    AssertUnwindSafe(check)
+                        .catch_unwind()
+                        .await
+                        .unwrap_or_else(|panic| Err(helpers::panic_error(panic)))
+                }
+                None => Err(anyhow!(
+                    "Failed to obtain an Ethereum adapter for chain '{chain}'"
+                )),
+            };
+            match &chain_report {
+                Ok(_) => options.info(format_args!("Finished checking chain {chain}")),
+                Err(e) => eprintln!("Checking blocks for chain {chain} failed: {e:#}"),
+            }
+            Some((chain, chain_report))
+        })
+    };
+    let chains = tokio::task::block_in_place(|| {
+        helpers::run_in_parallel(chain_stores, parallel_chains, check_chain)
+    });
+    MultiChainReport {
+        chains: chains.into_iter().flatten().collect(),
+    }
+}
+
+/// Deletes all cached blocks in `range` without comparing them with the provider, for ranges
+/// that are known to be bad as a whole. Every segment of the range is deleted with a single
+/// statement, so that it is either gone entirely or left as it was. The genesis block is kept.
+///
+/// Unless this is a dry run or `options.skip_confirmation` is set, the user is asked first, no
+/// matter how few blocks there are.
+pub async fn purge_range(
+    chain_store: Arc<ChainStore>,
+    range: &RangeSet,
+    options: &CheckOptions,
+) -> anyhow::Result<PurgeReport> {
+    let range = steps::resolve_range_hashes(range, &chain_store).await?;
+    let bounds = range.bounds(
+        || steps::find_chain_head(&chain_store, options),
+        || steps::find_earliest_cached_block(&chain_store),
+    )?;
+    let mut report = PurgeReport {
+        manifest: Some(steps::local_manifest(&chain_store, &bounds)),
+        ..Default::default()
+    };
+    for (lower, upper) in bounds {
+        let lower = lower.max(1);
+        if lower > upper {
+            continue;
+        }
+        let blocks = chain_store.count_blocks_in_range(lower, upper)?;
+        report.segments.push(PurgedSegment {
+            lower,
+            upper,
+            blocks,
+        });
+    }
+    let total = report.blocks();
+    if total == 0 {
+        return Ok(report);
+    }
+    let description = helpers::format_runs(report.segments.iter().map(|s| (s.lower, s.upper)));
+    if let Some(max_deletions) = options.max_deletions {
+        if !options.dry_run && total > max_deletions {
+            bail!(
+                "Aborting: blocks {description} hold {total} cached blocks, more than the \
+                 {max_deletions} that `--max-deletions` allows to be deleted"
+            );
+        }
+    }
+    if options.dry_run {
+        options.info(format_args!(
+            "Dry run: would delete {total} cached blocks in {description} from cache."
+        ));
+        return Ok(report);
+    }
+    if !options.skip_confirmation {
+        let prompt = format!(
+            "This will delete all {total} cached blocks for {} in {description}.\nProceed?",
+            chain_store.chain
+        );
+        if !prompt_for_confirmation(&prompt)? {
+            println!("Aborting.");
+            return Ok(report);
+        }
+    }
+
+    for segment in &mut report.segments {
+        let PurgedSegment { lower, upper, .. } = *segment;
+        options.info(format_args!(
+            "Deleting blocks {lower} to {upper} from cache."
+        ));
+        segment.blocks = chain_store
+            .delete_blocks_in_range(lower, upper)
+            .with_context(|| format!("Failed to delete blocks {lower} to {upper}"))?;
+        if let Some(metrics) = &options.metrics {
+            metrics.blocks_deleted(&chain_store.chain, segment.blocks);
+        }
+        steps::record_purge(segment, &chain_store, options)?;
+    }
+    report.purged = true;
+    options.info("Done.");
+    Ok(report)
+}
+
+/// What a check of the same blocks on several chains found, chain by chain.
+#[derive(Debug, Default)]
+pub struct MultiChainReport {
+    pub chains: Vec<(String, anyhow::Result<CheckBlocksReport>)>,
+}
+
+impl MultiChainReport {
+    /// How many chains could not be checked.
+    pub fn failed_chains(&self) -> usize {
+        self.chains
+            .iter()
+            .filter(|(_, report)| report.is_err())
+            .count()
+    }
+
+    /// How many blocks diverged on all chains that could be checked.
+    pub fn diverged_blocks(&self) -> usize {
+        self.chains
+            .iter()
+            .filter_map(|(_, report)| report.as_ref().ok())
+            .map(|report| report.diverged.len())
+            .sum()
+    }
+
+    /// How many blocks could not be fetched from the provider on all chains that could be
+    /// checked.
+    pub fn fetch_failed_blocks(&self) -> usize {
+        self.chains
+            .iter()
+            .filter_map(|(_, report)| report.as_ref().ok())
+            .map(|report| report.fetch_failed.len())
+            .sum()
+    }
+
+    /// Prints the repair command of every chain that could be checked, see
+    /// [`CheckBlocksReport::emit_repair_command`].
+    pub fn emit_repair_commands(&self, options: &CheckOptions) {
+        for (chain, report) in &self.chains {
+            if let Ok(report) = report {
+                if !report.diverged.is_empty() {
+                    report.emit_repair_command(chain, options);
+                }
+            }
+        }
+    }
+
+    /// Prints a table of per-chain counts, or, in JSON mode, an object with the results or the
+    /// error for every chain.
+    pub fn render(&self, options: &CheckOptions) -> anyhow::Result<()> {
+        match options.output {
+            OutputFormat::Human => {
+                println!(
+                    "{:<20} {:>10} {:>10} {:>10}",
+                    "chain", "checked", "diverged", "deleted"
+                );
+                for (chain, report) in &self.chains {
+                    match report {
+                        Ok(report) => println!(
+                            "{:<20} {:>10} {:>10} {:>10}",
+                            chain,
+                            report.checked,
+                            report.diverged.len(),
+                            report.deleted.len()
+                        ),
+                        Err(e) => println!("{:<20} failed: {e:#}", chain),
+                    }
+                }
+            }
+            OutputFormat::Json => {
+                let chains: serde_json::Map<String, Value> = self
+                    .chains
+                    .iter()
+                    .map(|(chain, report)| {
+                        let value = match report {
+                            Ok(report) => report.json_envelope(),
+                            Err(e) => serde_json::json!({ "error": format!("{e:#}") }),
+                        };
+                        (chain.clone(), value)
+                    })
+                    .collect();
+                let json = serde_json::json!({
+                    "schema_version": JSON_SCHEMA_VERSION,
+                    "chains": chains,
+                });
+                let json = serde_json::to_string_pretty(&json)
+                    .context("failed to serialize the results as JSON")?;
+                println!("{json}");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A segment of a range purged by [`purge_range`].
+#[derive(Debug, Serialize)]
+pub struct PurgedSegment {
+    pub lower: i32,
+    pub upper: i32,
+    /// How many cached blocks the segment held, or how many were deleted once it was purged.
+    pub blocks: usize,
+}
+
+/// What purging a range found and did.
+#[derive(Debug, Default, Serialize)]
+pub struct PurgeReport {
+    pub segments: Vec<PurgedSegment>,
+    /// Whether the blocks were deleted, which they aren't in a dry run or when the user declines.
+    pub purged: bool,
+    /// The manifest entry of the check, for the JSON output.
+    #[serde(skip)]
+    pub manifest: Option<Value>,
+}
+
+impl PurgeReport {
+    /// How many cached blocks the range held, or how many were deleted.
+    pub fn blocks(&self) -> usize {
+        self.segments.iter().map(|segment| segment.blocks).sum()
+    }
+
+    /// Prints the report in the format selected by `options.output`.
+    pub fn render(&self, options: &CheckOptions) -> anyhow::Result<()> {
+        match options.output {
+            OutputFormat::Human => {
+                let blocks = self.blocks();
+                if self.purged {
+                    println!(
+                        "Deleted {blocks} cached blocks in {} range segments.",
+                        self.segments.len()
+                    );
+                } else if options.dry_run {
+                    println!("Would have deleted {blocks} cached blocks.");
+                } else {
+                    println!("No blocks were deleted.");
+                }
+            }
+            OutputFormat::Json => helpers::print_json_envelope(self.manifest.as_ref(), self)?,
+        }
+        Ok(())
+    }
+}
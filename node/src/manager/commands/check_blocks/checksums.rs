@@ -0,0 +1,187 @@
+//! `verify-checksums`, which recomputes the hashes of cached blocks from their headers.
+
+use super::*;
+
+/// Recomputes the hash of every cached block in `range` from its header fields and compares it
+/// with the hash the block is cached under. This doesn't need a provider: a mismatch means the
+/// cache entry is corrupt, no matter what the chain looks like.
+///
+/// With `options.delete_corrupt`, the blocks whose hash doesn't match are removed from the cache,
+/// after the same confirmation as diverged blocks. Blocks that lack header fields can't be
+/// verified and are only reported; so are blocks without all the fields that later forks added,
+/// since the cache may have dropped the ones their header has.
+pub async fn verify_checksums(
+    chain_store: Arc<ChainStore>,
+    range: &RangeSet,
+    options: &CheckOptions,
+) -> anyhow::Result<ChecksumReport> {
+    ensure!(options.chunk_size > 0, "Chunk size must be at least 1");
+    let range = steps::resolve_range_hashes(range, &chain_store).await?;
+    let block_numbers = range.block_numbers(
+        || steps::find_chain_head(&chain_store, options),
+        || steps::find_earliest_cached_block(&chain_store),
+    )?;
+    let block_hashes =
+        steps::resolve_block_hashes_from_block_numbers(&block_numbers, &chain_store)?;
+
+    let blocks: Vec<(i32, H256)> = block_numbers
+        .iter()
+        .flat_map(|number| {
+            let hashes = block_hashes.get(number).into_iter().flatten();
+            hashes.map(move |hash| (*number, *hash))
+        })
+        .collect();
+
+    let mut report = ChecksumReport {
+        manifest: Some(steps::local_manifest(
+            &chain_store,
+            &helpers::block_runs(&block_numbers),
+        )),
+        ..Default::default()
+    };
+    for chunk in blocks.chunks(options.chunk_size) {
+        if options.interrupt.is_set() {
+            report.interrupted = Some(blocks.len() - report.checked);
+            break;
+        }
+        let hashes: Vec<H256> = chunk.iter().map(|(_, hash)| *hash).collect();
+        let cached_blocks = steps::fetch_cached_blocks(&hashes, &chain_store, options)?;
+        for ((number, hash), block) in chunk.iter().zip(cached_blocks) {
+            report.checked += 1;
+            match header::header_hash(&block) {
+                Ok(computed_hash) if computed_hash == *hash => {}
+                // The header may well have the fork field, in which case its hash is bound to
+                // differ; that's no sign of corruption
+                Ok(computed_hash) => match header::missing_fork_field(&block) {
+                    Some(field) => report.unverifiable.push((
+                        *hash,
+                        format!(
+                            "its header hashes to {computed_hash:?}, but it lacks `{field}`, which \
+                             the cache may have dropped"
+                        ),
+                    )),
+                    None => report.mismatches.push(ChecksumMismatch {
+                        number: *number,
+                        hash: *hash,
+                        computed_hash,
+                    }),
+                },
+                Err(problem) => report.unverifiable.push((*hash, problem)),
+            }
+        }
+    }
+
+    let numbers: Vec<i32> = report
+        .mismatches
+        .iter()
+        .map(|mismatch| mismatch.number)
+        .collect();
+    if options.delete_corrupt
+        && !report.mismatches.is_empty()
+        && steps::confirm_deletion(
+            report.mismatches.len(),
+            &numbers,
+            report.checked,
+            None,
+            &chain_store,
+            options,
+            &mut None,
+        )?
+    {
+        steps::enforce_max_deletions(0, report.mismatches.len(), options)?;
+        for mismatch in &report.mismatches {
+            // The mismatches that are left are still listed, just not deleted
+            if options.interrupt.is_set() {
+                break;
+            }
+            let deletion = steps::Deletion {
+                number: Some(mismatch.number),
+                reason: "header hash mismatch",
+                diff: None,
+                compared: None,
+            };
+            match steps::delete_block(&mismatch.hash, &chain_store, options, deletion)? {
+                steps::DeleteOutcome::Deleted => report.deleted.push(mismatch.hash),
+                // Only blocks that are deleted with the digest they were compared with can race
+                steps::DeleteOutcome::Raced => {}
+                steps::DeleteOutcome::Unverified(problem) => {
+                    report.unverified_deletes.push((mismatch.hash, problem))
+                }
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// A cached block whose header doesn't hash to the hash it is cached under.
+#[derive(Debug, Serialize)]
+pub struct ChecksumMismatch {
+    pub number: i32,
+    pub hash: H256,
+    /// The hash of the cached header.
+    pub computed_hash: H256,
+}
+
+/// What a checksum verification found and did.
+#[derive(Debug, Default, Serialize)]
+pub struct ChecksumReport {
+    /// How many cached blocks were looked at.
+    #[serde(skip)]
+    pub checked: usize,
+    pub mismatches: Vec<ChecksumMismatch>,
+    /// The blocks whose header couldn't be hashed, with the reason.
+    pub unverifiable: Vec<(H256, String)>,
+    /// The blocks that were removed from the cache, or that would have been in a dry run.
+    #[serde(skip)]
+    pub deleted: Vec<H256>,
+    /// The blocks that `verify_deletes` found were not actually removed by deleting them, with
+    /// what went wrong.
+    pub unverified_deletes: Vec<(H256, String)>,
+    /// If the check was interrupted, how many blocks were left unverified.
+    pub interrupted: Option<usize>,
+    /// The manifest entry of the check, for the JSON output.
+    #[serde(skip)]
+    pub manifest: Option<Value>,
+}
+
+impl ChecksumReport {
+    /// Prints the report in the format selected by `options.output`.
+    pub fn render(&self, options: &CheckOptions) -> anyhow::Result<()> {
+        if let Some(left) = self.interrupted {
+            eprintln!("The check was interrupted and {left} blocks were not verified");
+        }
+        match options.output {
+            OutputFormat::Human => {
+                for ChecksumMismatch {
+                    number,
+                    hash,
+                    computed_hash,
+                } in &self.mismatches
+                {
+                    println!(
+                        "block {number} {hash:?} has a header that hashes to {computed_hash:?}"
+                    );
+                }
+                for (hash, problem) in &self.unverifiable {
+                    println!("block {hash:?} can't be verified: {problem}");
+                }
+                let deleted = if options.dry_run {
+                    "would have been deleted"
+                } else {
+                    "deleted"
+                };
+                println!(
+                    "Checked {} blocks: {} don't match their hash, {} can't be verified, \
+                     {} {deleted}.",
+                    self.checked,
+                    self.mismatches.len(),
+                    self.unverifiable.len(),
+                    self.deleted.len()
+                );
+                print_unverified_deletes(&self.unverified_deletes);
+            }
+            OutputFormat::Json => helpers::print_json_envelope(self.manifest.as_ref(), self)?,
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,1188 @@
+//! Compares cached blocks with the provider's and fixes the ones that diverge, for the checks by
+//! hash, number and range.
+
+use super::*;
+
+/// A cached block as compared by [`compare_range`], which leaves it to the caller what to do
+/// about it.
+#[derive(Debug)]
+pub struct ComparedBlock {
+    pub hash: H256,
+    pub number: Option<i32>,
+    /// The block as it is cached.
+    pub cached: Value,
+    /// The block as the provider returned it.
+    pub provider: Value,
+    /// How the blocks differ once they are normalized like for any other check, or `None` if
+    /// they don't.
+    pub diff: Option<Value>,
+}
+
+impl ComparedBlock {
+    pub fn diverged(&self) -> bool {
+        self.diff.is_some()
+    }
+}
+
+/// Compares the cached blocks in `range` with the provider's, like `by_range` does, but
+/// neither reports nor fixes anything: the comparisons are yielded one by one, so that other
+/// commands can apply their own policy to them. All blocks cached for a forked number are
+/// compared.
+///
+/// Like every check, this fails right away if the provider is not for the chain of `chain_store`.
+/// The range is resolved right away, too. The blocks are then loaded and fetched one chunk of
+/// `options.chunk_size` blocks at a time as the stream is polled, so memory use doesn't grow with
+/// the size of the range. A block that can't be fetched, or that is corrupt in the cache, yields an
+/// error, and the stream goes on with the next one.
+pub async fn compare_range<'a>(
+    chain_store: &'a ChainStore,
+    ethereum_adapter: &'a dyn EthereumAdapterTrait,
+    range: &RangeSet,
+    logger: &'a Logger,
+    options: &'a CheckOptions,
+) -> anyhow::Result<impl Stream<Item = anyhow::Result<ComparedBlock>> + 'a> {
+    ensure!(options.chunk_size > 0, "Chunk size must be at least 1");
+    ensure!(options.concurrency > 0, "Concurrency must be at least 1");
+    steps::ensure_same_chain(chain_store, ethereum_adapter, options).await?;
+    let range = steps::resolve_range_hashes(range, chain_store).await?;
+    let block_numbers = range.block_numbers(
+        || steps::find_chain_head(chain_store, options),
+        || steps::find_earliest_cached_block(chain_store),
+    )?;
+    let mut block_hashes_by_number =
+        steps::resolve_block_hashes_from_block_numbers(&block_numbers, chain_store)?;
+    let block_hashes: Vec<H256> = block_numbers
+        .iter()
+        .flat_map(|number| block_hashes_by_number.remove(number).unwrap_or_default())
+        .collect();
+    let chunks: Vec<Vec<H256>> = block_hashes
+        .chunks(options.chunk_size)
+        .map(<[H256]>::to_vec)
+        .collect();
+    let profile = NetworkProfile::for_network(&chain_store.chain);
+    Ok(stream::iter(chunks)
+        .then(move |chunk| {
+            compare_range_chunk(
+                chunk,
+                chain_store,
+                ethereum_adapter,
+                profile,
+                logger,
+                options,
+            )
+        })
+        .flat_map(|comparisons| stream::iter(comparisons.unwrap_or_else(|e| vec![Err(e)]))))
+}
+
+/// Compares one chunk of the blocks for [`compare_range`]. Fails as a whole if the cached
+/// blocks can't be loaded.
+async fn compare_range_chunk(
+    block_hashes: Vec<H256>,
+    chain_store: &ChainStore,
+    ethereum_adapter: &dyn EthereumAdapterTrait,
+    profile: &NetworkProfile,
+    logger: &Logger,
+    options: &CheckOptions,
+) -> anyhow::Result<Vec<anyhow::Result<ComparedBlock>>> {
+    let cached_blocks = steps::fetch_cached_blocks(&block_hashes, chain_store, options)?;
+    Ok(compare_loaded_blocks(
+        block_hashes,
+        cached_blocks,
+        ethereum_adapter,
+        profile,
+        logger,
+        options,
+    )
+    .await)
+}
+
+/// Compares `cached_blocks`, which line up with `block_hashes`, with the provider's version of
+/// them for [`compare_range`].
+pub(super) async fn compare_loaded_blocks(
+    block_hashes: Vec<H256>,
+    cached_blocks: Vec<Value>,
+    ethereum_adapter: &dyn EthereumAdapterTrait,
+    profile: &NetworkProfile,
+    logger: &Logger,
+    options: &CheckOptions,
+) -> Vec<anyhow::Result<ComparedBlock>> {
+    let progress = Progress::hidden();
+    let provider_blocks: Vec<_> =
+        steps::fetch_provider_blocks(&block_hashes, ethereum_adapter, logger, options, &progress)
+            .collect()
+            .await;
+    block_hashes
+        .into_iter()
+        .zip(cached_blocks)
+        .zip(provider_blocks)
+        .map(|((hash, cached), provider_block)| {
+            let (provider, _latency) =
+                match comparable_provider_block(&cached, provider_block, None, options) {
+                    Ok(provider_block) => provider_block,
+                    Err(outcome) => match *outcome {
+                        Comparison::Corrupt(problem) => {
+                            return Err(CheckBlocksError::CorruptCacheEntry {
+                                hash: hash.into(),
+                                problem,
+                            }
+                            .into())
+                        }
+                        Comparison::ProviderMisbehavior(e) | Comparison::FetchFailed(e) => {
+                            return Err(e)
+                        }
+                        // Without a chain head, no block counts as near the tip
+                        Comparison::NotYetAvailable | Comparison::Compared { .. } => {
+                            unreachable!("the provider block is only missing or malformed")
+                        }
+                    },
+                };
+            let number =
+                helpers::block_number(&cached).or_else(|| helpers::block_number(&provider));
+            let (a, b) = comparable_pair(cached.clone(), provider.clone(), profile, options);
+            let diff = steps::diff_block_pair(&a, &b, &options.ignored_fields);
+            Ok(ComparedBlock {
+                hash,
+                number,
+                cached,
+                provider,
+                diff,
+            })
+        })
+        .collect()
+}
+
+/// Compares the cached version of each block in `block_hashes` with the one from the provider,
+/// deleting the cached blocks that differ.
+///
+/// The blocks are processed in chunks of `options.chunk_size`: each chunk is loaded, compared and
+/// its diverged blocks deleted before the next one is started, so that memory use doesn't grow
+/// with the number of blocks. Once more than [`DELETE_CONFIRMATION_THRESHOLD`] blocks are to be
+/// deleted, the user is asked for confirmation.
+///
+/// Up to `options.concurrency` provider requests are kept in flight at once, and failed requests
+/// are retried according to `options.max_attempts` and `options.retry_delay`. Hashes that appear
+/// more than once in `block_hashes` are only checked once.
+///
+/// With a `checkpoint`, the highest block number of each chunk is recorded in it once the
+/// chunk's fixes were applied. A chunk that is left unfixed, in a dry run or because the user
+/// declined, keeps the checkpoint before it for the rest of the run, so that `--resume` checks
+/// it again.
+pub(super) async fn run(
+    block_hashes: &[H256],
+    chain_store: &ChainStore,
+    ethereum_adapter: &dyn EthereumAdapterTrait,
+    logger: &Logger,
+    options: &CheckOptions,
+    checkpoint: Option<&Path>,
+    cached_blocks: Option<usize>,
+) -> anyhow::Result<CheckBlocksReport> {
+    ensure!(options.chunk_size > 0, "Chunk size must be at least 1");
+    ensure!(
+        !options.rpc_timeout.is_zero(),
+        "RPC timeout must be longer than 0s"
+    );
+    ensure!(options.quorum > 0, "Quorum must be at least 1");
+    let providers = options.witnesses.0.len() + 1;
+    ensure!(
+        options.quorum <= providers,
+        "A quorum of {} needs at least that many providers, but {} only has {providers}",
+        options.quorum,
+        chain_store.chain
+    );
+    for field in &options.ignored_fields {
+        ensure!(
+            field.starts_with('/'),
+            "Ignored field `{field}` is not a JSON pointer, it must start with a `/`"
+        );
+    }
+    for glob in options.diff_include.iter().chain(&options.diff_exclude) {
+        ensure!(
+            !glob.split('.').any(str::is_empty),
+            "Field glob `{glob}` has an empty path segment"
+        );
+    }
+    let started = Instant::now();
+    // Every block is fetched, compared and fixed once, no matter how often it was asked for
+    let block_hashes = helpers::unique_block_hashes(block_hashes);
+    // Blocks that an earlier check already dealt with aren't fetched again
+    let (audited, block_hashes): (Vec<H256>, Vec<H256>) = match &options.skip_audited {
+        Some(audited) => block_hashes
+            .into_iter()
+            .partition(|hash| audited.contains(hash)),
+        None => (Vec::new(), block_hashes),
+    };
+    // The cached blocks that were counted up front but aren't compared here, like those that
+    // lost at a forked height or were audited, count as done from the start
+    let total = cached_blocks.map_or(block_hashes.len(), |cached| cached.max(block_hashes.len()));
+    let progress = Progress::new(total, &chain_store.chain, options);
+    progress.blocks_done(total - block_hashes.len());
+    let mut report = CheckBlocksReport {
+        audited,
+        cached_blocks,
+        ..Default::default()
+    };
+    // Whether the user allowed deleting blocks, once we had to ask
+    let mut deletion_confirmed = None;
+    // Whether every chunk so far was fixed, so that the checkpoint can move past the current one
+    let mut checkpointing = checkpoint.is_some();
+    for (index, chunk) in block_hashes.chunks(options.chunk_size).enumerate() {
+        if options.interrupt.is_set() {
+            report.interrupted = Some(block_hashes.len() - index * options.chunk_size);
+            report.out_of_time = options.interrupt.out_of_time();
+            break;
+        }
+        let first_result = report.results.len();
+        let first_corrupt = report.corrupt.len();
+        let diverged_blocks = check_chunk(
+            chunk,
+            chain_store,
+            ethereum_adapter,
+            logger,
+            options,
+            &progress,
+            &mut report,
+        )
+        .await?;
+        let corrupt_to_delete = options.delete_corrupt && report.corrupt.len() > first_corrupt;
+        // Whether the chunk's fixes were applied; listing blocks fixes none of them
+        let mut fixed = !options.list_only || (!corrupt_to_delete && diverged_blocks.is_empty());
+        if corrupt_to_delete && !options.list_only {
+            fixed &= delete_corrupt_blocks(
+                first_corrupt,
+                first_result,
+                chain_store,
+                options,
+                &progress,
+                &mut report,
+                &mut deletion_confirmed,
+            )?;
+        }
+        if !diverged_blocks.is_empty() && !options.list_only {
+            fixed &= fix_diverged_blocks(
+                diverged_blocks,
+                first_result,
+                chain_store,
+                ethereum_adapter,
+                logger,
+                options,
+                &progress,
+                &mut report,
+                &mut deletion_confirmed,
+            )
+            .await?;
+        }
+        if checkpointing && !fixed {
+            checkpointing = false;
+            progress.suspend(|| {
+                options.info(
+                    "Not moving the checkpoint any further, since blocks that need fixing were \
+                     left alone",
+                )
+            });
+        }
+        if let (Some(path), true) = (checkpoint, checkpointing) {
+            // Chunks are checked in order, so everything up to this chunk's last block is done,
+            // except for blocks that couldn't be fetched: the checkpoint stays below the first
+            // of them for the rest of the run, so that `--resume` checks them again
+            let results = &report.results[first_result..];
+            let first_failed = results
+                .iter()
+                .filter(|result| result.fetch_error.is_some())
+                .filter_map(|result| result.number)
+                .min();
+            if let Some(number) = results
+                .iter()
+                .filter_map(|result| result.number)
+                .filter(|number| first_failed.map_or(true, |failed| *number < failed))
+                .max()
+            {
+                steps::save_checkpoint(path, &chain_store.chain, number)?;
+            }
+            checkpointing = first_failed.is_none();
+        }
+    }
+    progress.finish();
+    // Also after an interrupt, so that the diffs of all checked blocks are in the file
+    if let Some(diff_file) = &options.diff_file {
+        diff_file.flush()?;
+    }
+    report.timings.total = started.elapsed();
+    Ok(report)
+}
+
+/// Deletes the corrupt blocks of the chunk, starting at `first_corrupt` in `report.corrupt`,
+/// whose results start at `first_result`. That takes the same confirmation as fixing diverged
+/// blocks, and counts towards the same `--max-deletions`. Returns whether the blocks were
+/// deleted, which they aren't in a dry run or when the user declined.
+fn delete_corrupt_blocks(
+    first_corrupt: usize,
+    first_result: usize,
+    chain_store: &ChainStore,
+    options: &CheckOptions,
+    progress: &Progress,
+    report: &mut CheckBlocksReport,
+    deletion_confirmed: &mut Option<bool>,
+) -> anyhow::Result<bool> {
+    let corrupt: Vec<(H256, Option<i32>)> = report.corrupt[first_corrupt..]
+        .iter()
+        .map(|(block_hash, _)| {
+            let number = report.results[first_result..]
+                .iter()
+                .find(|result| result.hash == *block_hash)
+                .and_then(|result| result.number);
+            (*block_hash, number)
+        })
+        .collect();
+    let numbers: Vec<i32> = corrupt.iter().filter_map(|(_, number)| *number).collect();
+    let fixed = report.deleted.len() + report.replaced.len();
+    let confirmed = progress.suspend(|| {
+        steps::confirm_deletion(
+            fixed + corrupt.len(),
+            &numbers,
+            report.checked,
+            report.cached_blocks,
+            chain_store,
+            options,
+            deletion_confirmed,
+        )
+    })?;
+    if !confirmed {
+        return Ok(false);
+    }
+    steps::enforce_max_deletions(fixed, corrupt.len(), options)?;
+    for (block_hash, number) in corrupt {
+        let deletion = steps::Deletion {
+            number,
+            reason: "corrupt cache entry",
+            diff: None,
+            compared: None,
+        };
+        let outcome = progress
+            .suspend(|| steps::delete_block(&block_hash, chain_store, options, deletion))?;
+        if matches!(outcome, steps::DeleteOutcome::Deleted) && !options.dry_run {
+            if let Some(result) = report.results[first_result..]
+                .iter_mut()
+                .find(|result| result.hash == block_hash)
+            {
+                result.deleted = true;
+            }
+        }
+        report.record_delete_outcome(block_hash, outcome);
+    }
+    Ok(!options.dry_run)
+}
+
+/// Deletes or replaces the `diverged_blocks` of the chunk whose results start at
+/// `first_result`, once the user agreed to fixing that many blocks. Returns whether the blocks
+/// were fixed, which they aren't in a dry run or when the user declined.
+#[allow(clippy::too_many_arguments)]
+async fn fix_diverged_blocks(
+    diverged_blocks: Vec<(H256, Option<Value>)>,
+    first_result: usize,
+    chain_store: &ChainStore,
+    ethereum_adapter: &dyn EthereumAdapterTrait,
+    logger: &Logger,
+    options: &CheckOptions,
+    progress: &Progress,
+    report: &mut CheckBlocksReport,
+    deletion_confirmed: &mut Option<bool>,
+) -> anyhow::Result<bool> {
+    let fixed = report.deleted.len() + report.replaced.len();
+    let numbers: Vec<i32> = report.diverged.iter().map(|(_, number)| *number).collect();
+    let confirmed = progress.suspend(|| {
+        steps::confirm_deletion(
+            fixed + diverged_blocks.len(),
+            &numbers,
+            report.checked,
+            report.cached_blocks,
+            chain_store,
+            options,
+            deletion_confirmed,
+        )
+    })?;
+    if !confirmed {
+        return Ok(false);
+    }
+    for (block_hash, provider_block) in diverged_blocks {
+        let result = report.results[first_result..]
+            .iter()
+            .find(|result| result.hash == block_hash);
+        let stale = result.map_or(false, |result| result.canonical_hash.is_some());
+        let deletion = steps::Deletion {
+            number: result.and_then(|result| result.number),
+            reason: if stale {
+                "no longer canonical"
+            } else {
+                "diverged from the provider"
+            },
+            diff: result.and_then(|result| result.diff.as_ref()),
+            compared: result.and_then(|result| result.cached_digest),
+        };
+        match provider_block {
+            // The provider's canonical block has another hash than the stale one, so storing it
+            // wouldn't overwrite the stale block: that is deleted, and the canonical one added.
+            // If the stale block may still be cached, adding the canonical one would leave both
+            Some(provider_block) if stale => {
+                let outcome = progress
+                    .suspend(|| steps::delete_block(&block_hash, chain_store, options, deletion))?;
+                match outcome {
+                    steps::DeleteOutcome::Deleted => {
+                        let block: LightEthereumBlock = serde_json::from_value(provider_block)
+                            .context("failed to parse provider block")?;
+                        let canonical_hash = block
+                            .hash
+                            .ok_or_else(|| anyhow!("The provider's canonical block has no hash"))?;
+                        steps::insert_block(
+                            &canonical_hash,
+                            block,
+                            chain_store,
+                            ethereum_adapter,
+                            logger,
+                            options,
+                        )
+                        .await?;
+                        report.replaced.push(block_hash);
+                    }
+                    outcome => report.record_delete_outcome(block_hash, outcome),
+                }
+            }
+            Some(provider_block) => {
+                let outcome = steps::replace_block(
+                    &block_hash,
+                    deletion.number,
+                    deletion.compared,
+                    provider_block,
+                    chain_store,
+                    ethereum_adapter,
+                    logger,
+                    options,
+                    progress,
+                )
+                .await?;
+                match outcome {
+                    steps::DeleteOutcome::Deleted => {
+                        steps::record_deletion(&block_hash, chain_store, options, &deletion)?;
+                        report.replaced.push(block_hash);
+                    }
+                    outcome => report.record_delete_outcome(block_hash, outcome),
+                }
+            }
+            None => {
+                let outcome = progress
+                    .suspend(|| steps::delete_block(&block_hash, chain_store, options, deletion))?;
+                report.record_delete_outcome(block_hash, outcome);
+            }
+        }
+    }
+    if !options.dry_run {
+        let raced: HashSet<H256> = report.raced.iter().copied().collect();
+        let unverified: HashSet<H256> = report
+            .unverified_deletes
+            .iter()
+            .map(|(hash, _)| *hash)
+            .collect();
+        for result in &mut report.results[first_result..] {
+            result.raced = raced.contains(&result.hash);
+            if options.replace {
+                result.replaced = result.diverged && !result.raced;
+            } else {
+                // Corrupt blocks may already have been deleted
+                result.deleted |=
+                    result.diverged && !result.raced && !unverified.contains(&result.hash);
+            }
+        }
+    }
+    Ok(!options.dry_run)
+}
+
+/// Compares the blocks in `block_hashes`, adding the outcome to `report`.
+///
+/// Returns the hashes of the blocks that diverged, together with the provider's version of them
+/// in `--replace` mode.
+async fn check_chunk(
+    block_hashes: &[H256],
+    chain_store: &ChainStore,
+    ethereum_adapter: &dyn EthereumAdapterTrait,
+    logger: &Logger,
+    options: &CheckOptions,
+    progress: &Progress,
+    report: &mut CheckBlocksReport,
+) -> anyhow::Result<Vec<(H256, Option<Value>)>> {
+    let lookup_started = Instant::now();
+    let cached_blocks = steps::fetch_cached_blocks(block_hashes, chain_store, options)?;
+    let chain_head = match options.skip_tip_gap {
+        Some(_) => Some(steps::find_chain_head(chain_store, options)?),
+        None => None,
+    };
+    report.timings.db += lookup_started.elapsed();
+    compare_chunk(
+        block_hashes,
+        cached_blocks,
+        ethereum_adapter,
+        NetworkProfile::for_network(&chain_store.chain),
+        chain_head,
+        logger,
+        options,
+        progress,
+        report,
+    )
+    .await
+}
+
+/// Compares `cached_blocks` with the provider's version of `block_hashes`, which they have to
+/// line up with. Works like [`check_chunk`], but doesn't need the store, so the `chain_head` has
+/// to be passed in for `options.skip_tip_gap`.
+///
+/// Results are reported as soon as each comparison completes, and the check aborts as soon as
+/// more blocks diverged than `--max-deletions` allows.
+#[allow(clippy::too_many_arguments)]
+pub(super) async fn compare_chunk(
+    block_hashes: &[H256],
+    cached_blocks: Vec<Value>,
+    ethereum_adapter: &dyn EthereumAdapterTrait,
+    profile: &NetworkProfile,
+    chain_head: Option<i32>,
+    logger: &Logger,
+    options: &CheckOptions,
+    progress: &Progress,
+    report: &mut CheckBlocksReport,
+) -> anyhow::Result<Vec<(H256, Option<Value>)>> {
+    let fixed = report.deleted.len() + report.replaced.len();
+    let comparisons = compare_blocks(
+        block_hashes,
+        cached_blocks,
+        ethereum_adapter,
+        profile,
+        chain_head,
+        logger,
+        options,
+        progress,
+    );
+    futures::pin_mut!(comparisons);
+    let mut diverged_blocks = Vec::new();
+    while let Some(comparison) = comparisons.next().await {
+        let BlockComparison {
+            hash,
+            number,
+            outcome,
+        } = comparison?;
+        let mut result = BlockResult {
+            hash,
+            number,
+            ..Default::default()
+        };
+        match outcome {
+            Comparison::Corrupt(problem) => {
+                progress.suspend(|| eprintln!("Cached block {hash:?} is corrupt: {problem}"));
+                report.corrupt.push((hash, problem));
+                result.corrupt = true;
+            }
+            Comparison::ProviderMisbehavior(e) => {
+                progress.suspend(|| eprintln!("{e:#}"));
+                report.provider_misbehavior.push(hash);
+                result.provider_misbehavior = true;
+            }
+            Comparison::NotYetAvailable => {
+                progress.suspend(|| {
+                    eprintln!(
+                        "The provider doesn't have {} yet, skipping it since it is near the \
+                         chain head",
+                        steps::describe_block(&hash, number)
+                    )
+                });
+                report.tip_skipped.push(hash);
+                result.tip_skipped = true;
+            }
+            Comparison::FetchFailed(e) => {
+                let error = format!("{e:#}");
+                progress.suspend(|| {
+                    eprintln!(
+                        "Could not fetch {} from the provider, skipping it: {error}",
+                        steps::describe_block(&hash, number)
+                    )
+                });
+                let failed_in_a_row = 1 + report
+                    .results
+                    .iter()
+                    .rev()
+                    .take_while(|result| result.fetch_error.is_some())
+                    .count();
+                if failed_in_a_row >= MAX_CONSECUTIVE_FETCH_FAILURES {
+                    progress.finish();
+                    bail!(
+                        "Aborting: the provider failed on {failed_in_a_row} blocks in a row, so it \
+                         is probably down. The last one was {}: {error}",
+                        steps::describe_block(&hash, number)
+                    );
+                }
+                report.fetch_failed.push((hash, error.clone()));
+                result.fetch_error = Some(error);
+            }
+            Comparison::Compared {
+                latency,
+                mut diverged,
+                diff,
+                replacement,
+                evidence,
+                cached_digest,
+            } => {
+                report.timings.rpc_latencies.push(latency);
+                report.checked += 1;
+                if options.output == OutputFormat::Human && !options.list_only {
+                    match (&options.diff_file, &diff) {
+                        (Some(diff_file), Some(diff)) => diff_file.write_diff(
+                            &hash,
+                            number,
+                            &steps::plain_diff(diff, options),
+                        )?,
+                        _ => {
+                            let rendered = diff
+                                .as_ref()
+                                .map(|diff| steps::render_diff(diff, &options.diff_render));
+                            progress.suspend(|| {
+                                steps::report_difference(rendered.as_deref(), &hash, number)
+                            });
+                        }
+                    }
+                }
+                let fields = diff.as_ref().map(steps::count_diff_fields);
+                if let (true, Some(fields), Some(min_diff_fields)) =
+                    (diverged, fields, options.min_diff_fields)
+                {
+                    if fields < min_diff_fields {
+                        progress.suspend(|| {
+                            eprintln!(
+                                "{} differs from the provider in only {fields} fields, fewer \
+                                 than {min_diff_fields}, leaving it alone",
+                                steps::describe_block(&hash, number)
+                            )
+                        });
+                        report.minor.push((hash, fields));
+                        diverged = false;
+                    }
+                }
+                if let Some(provider_block) = evidence.filter(|_| diverged) {
+                    let agreeing = steps::count_agreeing_providers(
+                        &hash,
+                        &provider_block,
+                        logger,
+                        options,
+                        progress,
+                    )
+                    .await;
+                    result.agreeing_providers = Some(agreeing);
+                    if agreeing < options.quorum {
+                        progress.suspend(|| {
+                            eprintln!(
+                                "Only {agreeing} of {} providers agree that {} diverges from the \
+                                 cache, leaving it alone",
+                                options.witnesses.0.len() + 1,
+                                steps::describe_block(&hash, number)
+                            )
+                        });
+                        report.unconfirmed.push(hash);
+                        diverged = false;
+                    }
+                }
+                if diverged {
+                    let number = number
+                        .ok_or_else(|| anyhow!("Could not read the number of block {hash:?}"))?;
+                    report.diverged.push((hash, number));
+                    for path in diff.iter().flat_map(steps::diff_field_paths) {
+                        *report.diverging_fields.entry(path).or_default() += 1;
+                    }
+                    progress.block_diverged();
+                    diverged_blocks.push((hash, replacement));
+                    if let Err(e) =
+                        steps::enforce_max_deletions(fixed, diverged_blocks.len(), options)
+                    {
+                        progress.finish();
+                        return Err(e);
+                    }
+                }
+                result.diverged = diverged;
+                result.diff_text = diff.as_ref().map(|diff| steps::plain_diff(diff, options));
+                result.diff = diff;
+                result.cached_digest = cached_digest.filter(|_| diverged);
+            }
+        }
+        report.results.push(result);
+    }
+    Ok(diverged_blocks)
+}
+
+/// How a cached block compares with the provider's version of it.
+enum Comparison {
+    /// The cached block is malformed, so it wasn't compared.
+    Corrupt(String),
+    /// The provider returned a different block than the one that was asked for.
+    ProviderMisbehavior(anyhow::Error),
+    /// The provider doesn't have the block, which is within `options.skip_tip_gap` of the chain
+    /// head, so it probably just hasn't caught up yet.
+    NotYetAvailable,
+    /// The provider's block could not be fetched, even after retrying. The other blocks are
+    /// still compared, so that a single unavailable block doesn't fail a long check, up to
+    /// [`MAX_CONSECUTIVE_FETCH_FAILURES`] in a row.
+    FetchFailed(anyhow::Error),
+    Compared {
+        /// How long the provider took to return the block.
+        latency: Duration,
+        diverged: bool,
+        /// How the blocks differ. Not computed with `options.list_only`.
+        diff: Option<Value>,
+        /// The provider's block, kept with `options.replace` to overwrite the cached one.
+        replacement: Option<Value>,
+        /// The provider's block in the form it was compared in, kept for a diverged block when
+        /// the other providers have to agree with it.
+        evidence: Option<Value>,
+        /// The digest of the cached block, kept for a diverged block unless this is a dry run.
+        cached_digest: Option<H256>,
+    },
+}
+
+struct BlockComparison {
+    hash: H256,
+    number: Option<i32>,
+    outcome: Comparison,
+}
+
+/// Compares `cached_blocks` with the provider's version of `block_hashes`, which they have to
+/// line up with, yielding every comparison as soon as the provider block for it has arrived.
+fn compare_blocks<'a>(
+    block_hashes: &'a [H256],
+    cached_blocks: Vec<Value>,
+    ethereum_adapter: &'a dyn EthereumAdapterTrait,
+    profile: &'a NetworkProfile,
+    chain_head: Option<i32>,
+    logger: &'a Logger,
+    options: &'a CheckOptions,
+    progress: &'a Progress,
+) -> impl Stream<Item = anyhow::Result<BlockComparison>> + 'a {
+    let provider_blocks =
+        steps::fetch_provider_blocks(block_hashes, ethereum_adapter, logger, options, progress);
+    stream::iter(block_hashes.iter().zip(cached_blocks))
+        .zip(provider_blocks)
+        .map(move |((block_hash, cached_block), provider_block)| {
+            compare_block(
+                *block_hash,
+                cached_block,
+                provider_block,
+                profile,
+                chain_head,
+                options,
+            )
+        })
+}
+
+fn compare_block(
+    hash: H256,
+    cached_block: Value,
+    provider_block: anyhow::Result<(Value, Duration)>,
+    profile: &NetworkProfile,
+    chain_head: Option<i32>,
+    options: &CheckOptions,
+) -> anyhow::Result<BlockComparison> {
+    let cached_number = helpers::block_number(&cached_block);
+    let (provider_block, latency) =
+        match comparable_provider_block(&cached_block, provider_block, chain_head, options) {
+            Ok(provider_block) => provider_block,
+            Err(outcome) => {
+                return Ok(BlockComparison {
+                    hash,
+                    number: cached_number,
+                    outcome: *outcome,
+                })
+            }
+        };
+    let number = cached_number.or_else(|| helpers::block_number(&provider_block));
+    let replacement = options.replace.then(|| provider_block.clone());
+    // A diverged block is only deleted or replaced if it is still the block that was compared,
+    // which takes the digest of the block as it is cached. Comparing a copy of what the scope
+    // keeps holds on to the cached block, so that only diverged blocks have to be digested
+    let (uncompared, cached_block) = if options.dry_run {
+        (None, cached_block)
+    } else {
+        let narrowed = options.scope.narrowed(&cached_block);
+        (Some(cached_block), narrowed)
+    };
+    let originals = options.export_dir.as_ref().map(|dir| {
+        let cached_block = uncompared.as_ref().unwrap_or(&cached_block).clone();
+        (dir, cached_block, provider_block.clone())
+    });
+    let (cached_block, provider_block) =
+        comparable_pair(cached_block, provider_block, profile, options);
+    let (diverged, diff) = if options.list_only {
+        // A scan only reports whether the blocks differ, not how
+        let diverged =
+            steps::blocks_differ(&cached_block, &provider_block, &options.ignored_fields);
+        (diverged, None)
+    } else {
+        let diff = steps::diff_block_pair(&cached_block, &provider_block, &options.ignored_fields);
+        (diff.is_some(), diff)
+    };
+    if let (true, Some((dir, cached_block, provider_block))) = (diverged, originals) {
+        steps::export_block_pair(dir, &hash, &cached_block, &provider_block, options.compress)?;
+    }
+    let evidence = (diverged && options.quorum > 1).then_some(provider_block);
+    Ok(BlockComparison {
+        hash,
+        number,
+        outcome: Comparison::Compared {
+            latency,
+            diverged,
+            diff,
+            replacement,
+            evidence,
+            cached_digest: uncompared
+                .filter(|_| diverged)
+                .map(|block| helpers::block_digest(&block)),
+        },
+    })
+}
+
+/// The provider's block for `cached_block` if the two can be compared, or the outcome of the
+/// comparison if they can't: the cached block is corrupt, or the provider's block could not be
+/// fetched, which is only to be expected for a block within `options.skip_tip_gap` of a known
+/// `chain_head`.
+fn comparable_provider_block(
+    cached_block: &Value,
+    provider_block: anyhow::Result<(Value, Duration)>,
+    chain_head: Option<i32>,
+    options: &CheckOptions,
+) -> Result<(Value, Duration), Box<Comparison>> {
+    if let Err(problem) = steps::validate_cached_block(cached_block) {
+        return Err(Box::new(Comparison::Corrupt(problem)));
+    }
+    let number = helpers::block_number(cached_block);
+    provider_block.map_err(|e| {
+        Box::new(if is_provider_misbehavior(&e) {
+            Comparison::ProviderMisbehavior(e)
+        } else if is_near_tip(&e, number, chain_head, options) {
+            Comparison::NotYetAvailable
+        } else {
+            Comparison::FetchFailed(e)
+        })
+    })
+}
+
+/// Brings a cached block and the provider's version of it into the form in which they are
+/// compared: narrowed down to `options.scope` and the fields that are diffed, and normalized
+/// unless `options.exact` or `options.strict_fields` say otherwise.
+pub(super) fn comparable_pair(
+    mut cached_block: Value,
+    mut provider_block: Value,
+    profile: &NetworkProfile,
+    options: &CheckOptions,
+) -> (Value, Value) {
+    if options.ignore_tx_order {
+        steps::sort_transactions(&mut cached_block);
+        steps::sort_transactions(&mut provider_block);
+    }
+    let mut cached_block = steps::comparable_block(cached_block, options);
+    let mut provider_block = steps::comparable_block(provider_block, options);
+    if !options.exact {
+        profile.normalize_quantities(&mut cached_block);
+        profile.normalize_quantities(&mut provider_block);
+    }
+    if !options.strict_fields {
+        steps::drop_fork_fields_missing_from(&cached_block, &mut provider_block);
+        profile.drop_optional_fields(&mut cached_block, &mut provider_block);
+    }
+    if options.superset_ok {
+        steps::drop_fields_missing_from(&cached_block, &mut provider_block);
+    }
+    (cached_block, provider_block)
+}
+
+fn is_provider_misbehavior(e: &anyhow::Error) -> bool {
+    matches!(
+        CheckBlocksError::find(e),
+        Some(CheckBlocksError::ProviderMisbehavior { .. })
+    )
+}
+
+/// Whether `e` is the provider missing the block with `number`, and that block is within
+/// `options.skip_tip_gap` of the chain head. Blocks further down are expected to be there.
+fn is_near_tip(
+    e: &anyhow::Error,
+    number: Option<i32>,
+    chain_head: Option<i32>,
+    options: &CheckOptions,
+) -> bool {
+    let (Some(gap), Some(number), Some(chain_head)) = (options.skip_tip_gap, number, chain_head)
+    else {
+        return false;
+    };
+    matches!(
+        CheckBlocksError::find(e),
+        Some(CheckBlocksError::ProviderMissingBlock { .. })
+    ) && i64::from(chain_head) - i64::from(number) <= i64::from(gap)
+}
+
+/// Asks the provider for its blocks at `block_numbers`, for which there is nothing in the cache,
+/// and adds the ones it has to `report.gaps`. With `options.fill_gaps`, they are also added to the
+/// cache.
+pub(super) async fn check_gaps(
+    block_numbers: &[i32],
+    chain_store: &ChainStore,
+    ethereum_adapter: &dyn EthereumAdapterTrait,
+    logger: &Logger,
+    options: &CheckOptions,
+    report: &mut CheckBlocksReport,
+) -> anyhow::Result<()> {
+    options.info(format_args!(
+        "Asking the provider for the {} blocks that are missing from the cache",
+        block_numbers.len()
+    ));
+    let provider_blocks =
+        steps::fetch_provider_blocks_by_number(block_numbers, ethereum_adapter, logger, options);
+    futures::pin_mut!(provider_blocks);
+    while let Some((number, provider_block)) = provider_blocks.next().await {
+        // If the provider doesn't have the block either, the range goes beyond what it knows
+        let block = match provider_block? {
+            Some(block) => block,
+            None => continue,
+        };
+        let hash = block
+            .hash
+            .ok_or_else(|| anyhow!("The provider returned block {number} without a hash"))?;
+        report.gaps.push((number, hash));
+        if options.fill_gaps {
+            steps::insert_block(&hash, block, chain_store, ethereum_adapter, logger, options)
+                .await?;
+            report.filled_gaps.push(hash);
+        }
+        report.results.push(BlockResult {
+            hash,
+            number: Some(number),
+            diverged: false,
+            deleted: false,
+            diff: None,
+            replaced: false,
+            provider_misbehavior: false,
+            missing: true,
+            inserted: options.fill_gaps && !options.dry_run,
+            ..Default::default()
+        });
+    }
+    Ok(())
+}
+
+/// Handles a height in a range for which the store has several blocks, which happens when a fork
+/// was recorded there.
+///
+/// If the provider's canonical block is one of `block_hashes`, the other blocks are deleted and
+/// the canonical one is returned so it gets checked like any other block. Otherwise, it isn't
+/// clear which block to keep and this falls back to [`handle_multiple_block_hashes`].
+///
+/// What became of the deleted blocks is recorded in `report`.
+#[allow(clippy::too_many_arguments)]
+pub(super) async fn handle_forked_block_number(
+    block_number: i32,
+    block_hashes: &[H256],
+    chain_store: &ChainStore,
+    ethereum_adapter: &dyn EthereumAdapterTrait,
+    logger: &Logger,
+    delete_duplicates: bool,
+    options: &CheckOptions,
+    report: &mut CheckBlocksReport,
+) -> anyhow::Result<Option<H256>> {
+    let canonical =
+        steps::fetch_canonical_block_hash(block_number, ethereum_adapter, logger, options).await?;
+    match canonical.filter(|canonical| block_hashes.contains(canonical)) {
+        Some(canonical) => {
+            options.info(format_args!(
+                "Found {} blocks for block number {block_number} in the store, \
+                 keeping the provider's canonical block {canonical:?}",
+                block_hashes.len()
+            ));
+            for hash in block_hashes.iter().filter(|hash| **hash != canonical) {
+                let deletion = steps::Deletion {
+                    number: Some(block_number),
+                    reason: "not the provider's canonical block",
+                    diff: None,
+                    compared: None,
+                };
+                let outcome = steps::delete_block(hash, chain_store, options, deletion)?;
+                report.record_delete_outcome(*hash, outcome);
+            }
+            Ok(Some(canonical))
+        }
+        None => {
+            handle_multiple_block_hashes(
+                block_number,
+                block_hashes,
+                chain_store,
+                delete_duplicates,
+                options,
+                report,
+            )
+            .await?;
+            Ok(None)
+        }
+    }
+}
+
+/// Reports the ambiguous `block_hashes` found for `block_number`, deleting all of them if
+/// `delete_duplicates` is set.
+///
+/// What became of the deleted blocks is recorded in `report`.
+pub(super) async fn handle_multiple_block_hashes(
+    block_number: i32,
+    block_hashes: &[H256],
+    chain_store: &ChainStore,
+    delete_duplicates: bool,
+    options: &CheckOptions,
+    report: &mut CheckBlocksReport,
+) -> anyhow::Result<()> {
+    options.info(format_args!(
+        "graphman found {} different block hashes for block number {} in the store \
+         and is unable to tell which one to check:",
+        block_hashes.len(),
+        block_number
+    ));
+    for (num, hash) in block_hashes.iter().enumerate() {
+        options.info(format_args!("{:>4}:  {hash:?}", num + 1));
+    }
+    if delete_duplicates {
+        delete_duplicate_blocks(block_number, block_hashes, chain_store, options, report)?;
+    } else {
+        eprintln!(
+            "Operation aborted for block number {block_number}.\n\
+             To delete the duplicated blocks and continue this operation, rerun this command with \
+             the `--delete-duplicates` option."
+        );
+    }
+    Ok(())
+}
+
+/// Deletes the `block_hashes` that are cached for `block_number` besides another block, and
+/// records what became of them in `report`.
+pub(super) fn delete_duplicate_blocks(
+    block_number: i32,
+    block_hashes: &[H256],
+    chain_store: &ChainStore,
+    options: &CheckOptions,
+    report: &mut CheckBlocksReport,
+) -> anyhow::Result<()> {
+    options.info("Deleting duplicated blocks...");
+    for hash in block_hashes {
+        let deletion = steps::Deletion {
+            number: Some(block_number),
+            reason: "one of several blocks with the same number",
+            diff: None,
+            compared: None,
+        };
+        let outcome = steps::delete_block(hash, chain_store, options, deletion)?;
+        report.record_delete_outcome(*hash, outcome);
+    }
+    Ok(())
+}
+
+/// Compares the cached block `block_hash` with `number` with the provider's canonical block at
+/// that number. The cached block is deleted as stale if the provider has a different block
+/// there, and checked by its hash otherwise.
+pub(super) async fn check_canonical_block(
+    number: i32,
+    block_hash: H256,
+    chain_store: &ChainStore,
+    ethereum_adapter: &dyn EthereumAdapterTrait,
+    logger: &Logger,
+    options: &CheckOptions,
+) -> anyhow::Result<CheckBlocksReport> {
+    let canonical_hash =
+        steps::fetch_canonical_block_hash(number, ethereum_adapter, logger, options).await?;
+    match canonical_hash {
+        Some(canonical_hash) if canonical_hash != block_hash => {
+            return fix_stale_block(
+                number,
+                block_hash,
+                canonical_hash,
+                chain_store,
+                ethereum_adapter,
+                logger,
+                options,
+            )
+            .await;
+        }
+        Some(_) => {}
+        None => options.info(format_args!(
+            "The provider has no canonical block with number {number}, \
+             only checking the cached block by its hash"
+        )),
+    }
+    run(
+        &[block_hash],
+        chain_store,
+        ethereum_adapter,
+        logger,
+        options,
+        None,
+        None,
+    )
+    .await
+}
+
+/// Fixes the cached block `block_hash` with `number` like a diverged block, since the
+/// provider's canonical block at that number is `canonical_hash`, and reports it as diverged.
+/// The stale block is deleted, or with `options.replace` replaced with the provider's canonical
+/// block. Like a diverged block, it is left alone if it changes in the cache in the meantime.
+async fn fix_stale_block(
+    number: i32,
+    block_hash: H256,
+    canonical_hash: H256,
+    chain_store: &ChainStore,
+    ethereum_adapter: &dyn EthereumAdapterTrait,
+    logger: &Logger,
+    options: &CheckOptions,
+) -> anyhow::Result<CheckBlocksReport> {
+    options.info(format_args!(
+        "The provider's canonical block with number {number} is {canonical_hash:?}, \
+         but the cache has {block_hash:?}"
+    ));
+    let cached_digest = chain_store
+        .blocks_by_hashes(&[block_hash.into()])?
+        .first()
+        .map(|(_, block)| helpers::block_digest(block));
+    let provider_block = if options.replace {
+        let blocks =
+            steps::fetch_provider_blocks_by_number(&[number], ethereum_adapter, logger, options);
+        futures::pin_mut!(blocks);
+        let block = match blocks.next().await {
+            Some((_, block)) => block?,
+            None => None,
+        };
+        let block = block.ok_or_else(|| {
+            anyhow!("The provider has no block with number {number} to replace {block_hash:?} with")
+        })?;
+        Some(serde_json::to_value(block)?)
+    } else {
+        None
+    };
+    let mut report = CheckBlocksReport {
+        checked: 1,
+        diverged: vec![(block_hash, number)],
+        results: vec![BlockResult {
+            hash: block_hash,
+            number: Some(number),
+            diverged: true,
+            canonical_hash: Some(canonical_hash),
+            cached_digest,
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    fix_diverged_blocks(
+        vec![(block_hash, provider_block)],
+        0,
+        chain_store,
+        ethereum_adapter,
+        logger,
+        options,
+        &Progress::hidden(),
+        &mut report,
+        &mut None,
+    )
+    .await?;
+    Ok(report)
+}
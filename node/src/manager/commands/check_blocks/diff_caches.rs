@@ -0,0 +1,128 @@
+//! `diff-caches`, which compares the block cache with another one, e.g. in another database.
+
+use super::*;
+
+/// Compares the cached blocks in `range` with the blocks that `other_store` holds for the same
+/// numbers, e.g. the cache of a redundant node in another database, without contacting a
+/// provider. Neither cache is changed.
+///
+/// Blocks that are cached under the same hash in both are compared like a cached block is with
+/// the provider's, with `options.scope`, `options.ignored_fields` and the other comparison
+/// options applied to both sides. Blocks that only one of the caches holds are listed on their
+/// own; at a number that both caches hold different blocks for, one of them usually missed a
+/// reorg.
+pub async fn diff_caches(
+    chain_store: Arc<ChainStore>,
+    other_store: Arc<ChainStore>,
+    range: &RangeSet,
+    options: &CheckOptions,
+) -> anyhow::Result<CacheDiffReport> {
+    ensure!(options.chunk_size > 0, "Chunk size must be at least 1");
+    let range = steps::resolve_range_hashes(range, &chain_store).await?;
+    let block_numbers = range.block_numbers(
+        || steps::find_chain_head(&chain_store, options),
+        || steps::find_earliest_cached_block(&chain_store),
+    )?;
+    let ours = steps::resolve_block_hashes_from_block_numbers(&block_numbers, &chain_store)?;
+    let theirs = steps::resolve_block_hashes_from_block_numbers(&block_numbers, &other_store)?;
+    let (common, only_here, only_there) = helpers::match_cached_blocks(ours, theirs);
+
+    let profile = NetworkProfile::for_network(&chain_store.chain);
+    let mut report = CacheDiffReport {
+        only_here,
+        only_there,
+        manifest: Some(steps::local_manifest(
+            &chain_store,
+            &helpers::block_runs(&block_numbers),
+        )),
+        ..Default::default()
+    };
+    for chunk in common.chunks(options.chunk_size) {
+        if options.interrupt.is_set() {
+            report.interrupted = Some(common.len() - report.checked);
+            break;
+        }
+        let hashes: Vec<H256> = chunk.iter().map(|block| block.hash).collect();
+        let here = steps::fetch_cached_blocks(&hashes, &chain_store, options)?;
+        let there = steps::fetch_cached_blocks(&hashes, &other_store, options)?;
+        for ((block, here), there) in chunk.iter().zip(here).zip(there) {
+            report.checked += 1;
+            let (here, there) = comparable_pair(here, there, &profile, options);
+            if let Some(diff) = steps::diff_block_pair(&here, &there, &options.ignored_fields) {
+                report.diverged.push(CacheDivergence {
+                    number: block.number,
+                    hash: block.hash,
+                    diff_text: steps::plain_diff(&diff, options),
+                    diff,
+                });
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// A block that two caches hold under the same hash, but with different contents.
+#[derive(Debug, Serialize)]
+pub struct CacheDivergence {
+    pub number: i32,
+    pub hash: H256,
+    /// The structural difference from this cache's block to the other cache's.
+    pub diff: Value,
+    /// The `diff` rendered like it is printed, but never colorized or cut off.
+    pub diff_text: String,
+}
+
+/// Where two block caches disagree, as found by [`diff_caches`].
+#[derive(Debug, Default, Serialize)]
+pub struct CacheDiffReport {
+    /// How many blocks both caches hold and were compared.
+    #[serde(skip)]
+    pub checked: usize,
+    pub diverged: Vec<CacheDivergence>,
+    /// The blocks that only this cache holds.
+    pub only_here: Vec<CachedBlockRef>,
+    /// The blocks that only the other cache holds.
+    pub only_there: Vec<CachedBlockRef>,
+    /// If the comparison was interrupted, how many of the blocks both caches hold were left
+    /// uncompared.
+    pub interrupted: Option<usize>,
+    /// The manifest entry of the check, for the JSON output.
+    #[serde(skip)]
+    pub manifest: Option<Value>,
+}
+
+impl CacheDiffReport {
+    /// Prints the report in the format selected by `options.output`.
+    pub fn render(&self, options: &CheckOptions) -> anyhow::Result<()> {
+        if let Some(left) = self.interrupted {
+            eprintln!("The comparison was interrupted and {left} blocks were not compared");
+        }
+        match options.output {
+            OutputFormat::Human => {
+                for CacheDivergence {
+                    number, hash, diff, ..
+                } in &self.diverged
+                {
+                    println!("block {number} {hash:?} differs between the caches:");
+                    println!("{}", steps::render_diff(diff, &options.diff_render));
+                }
+                for CachedBlockRef { number, hash } in &self.only_here {
+                    println!("block {number} {hash:?} is only in this cache");
+                }
+                for CachedBlockRef { number, hash } in &self.only_there {
+                    println!("block {number} {hash:?} is only in the other cache");
+                }
+                println!(
+                    "Compared {} blocks: {} differ, {} are only in this cache, {} only in the \
+                     other one.",
+                    self.checked,
+                    self.diverged.len(),
+                    self.only_here.len(),
+                    self.only_there.len()
+                );
+            }
+            OutputFormat::Json => helpers::print_json_envelope(self.manifest.as_ref(), self)?,
+        }
+        Ok(())
+    }
+}
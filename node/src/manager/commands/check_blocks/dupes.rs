@@ -0,0 +1,111 @@
+//! `find-dupes`, which lists the cached blocks whose contents are those of another one.
+
+use super::*;
+
+/// Lists the cached blocks in `range` whose contents are identical to those of another cached
+/// block, which usually means that a block was ingested under the wrong hash or stored twice.
+/// The blocks are compared by a digest of their JSON representation, so only a digest per block
+/// is kept in memory. This only looks at the block cache and changes nothing.
+pub async fn find_dupes(
+    chain_store: Arc<ChainStore>,
+    range: &RangeSet,
+    options: &CheckOptions,
+) -> anyhow::Result<DupesReport> {
+    ensure!(options.chunk_size > 0, "Chunk size must be at least 1");
+    let range = steps::resolve_range_hashes(range, &chain_store).await?;
+    let block_numbers = range.block_numbers(
+        || steps::find_chain_head(&chain_store, options),
+        || steps::find_earliest_cached_block(&chain_store),
+    )?;
+    let block_hashes =
+        steps::resolve_block_hashes_from_block_numbers(&block_numbers, &chain_store)?;
+    let numbers: HashMap<H256, i32> = block_hashes
+        .iter()
+        .flat_map(|(number, hashes)| hashes.iter().map(move |hash| (*hash, *number)))
+        .collect();
+    let mut hashes: Vec<H256> = numbers.keys().copied().collect();
+    hashes.sort_unstable();
+
+    // Every row the cache has for a hash is looked at, so that a block that is stored twice
+    // shows up, too
+    let mut digests = Vec::with_capacity(hashes.len());
+    let mut interrupted = None;
+    for (index, chunk) in hashes.chunks(options.chunk_size).enumerate() {
+        if options.interrupt.is_set() {
+            interrupted = Some(hashes.len() - index * options.chunk_size);
+            break;
+        }
+        let chunk: Vec<_> = chunk.iter().map(|hash| (*hash).into()).collect();
+        for (hash, block) in chain_store.blocks_by_hashes(&chunk)? {
+            let hash = helpers::cached_block_hash(&hash)?;
+            let number = *numbers.get(&hash).ok_or_else(|| {
+                anyhow!("the cache returned block {hash:?}, which wasn't asked for")
+            })?;
+            digests.push((
+                helpers::block_digest(&block),
+                CachedBlockRef { number, hash },
+            ));
+        }
+    }
+    Ok(DupesReport {
+        checked: digests.len(),
+        duplicates: helpers::duplicate_blocks(digests),
+        interrupted,
+        manifest: Some(steps::local_manifest(
+            &chain_store,
+            &helpers::block_runs(&block_numbers),
+        )),
+    })
+}
+
+/// Cached blocks whose contents are identical.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct DuplicateBlocks {
+    /// The digest of the contents that the blocks share.
+    pub digest: H256,
+    pub blocks: Vec<CachedBlockRef>,
+}
+
+/// The cached blocks with the same contents as another cached block, as found by
+/// [`find_dupes`].
+#[derive(Debug, Default)]
+pub struct DupesReport {
+    /// How many cached blocks were looked at.
+    pub checked: usize,
+    pub duplicates: Vec<DuplicateBlocks>,
+    /// If the search was interrupted, how many cached blocks were left.
+    pub interrupted: Option<usize>,
+    /// The manifest entry of the check, for the JSON output.
+    pub manifest: Option<Value>,
+}
+
+impl DupesReport {
+    /// Prints the report in the format selected by `options.output`.
+    pub fn render(&self, options: &CheckOptions) -> anyhow::Result<()> {
+        if let Some(left) = self.interrupted {
+            eprintln!("The search was interrupted and {left} cached blocks were not looked at");
+        }
+        match options.output {
+            OutputFormat::Human => {
+                for DuplicateBlocks { digest, blocks } in &self.duplicates {
+                    println!(
+                        "{} cached blocks have the same contents, with digest {digest:?}:",
+                        blocks.len()
+                    );
+                    for CachedBlockRef { number, hash } in blocks {
+                        println!("  #{number} {hash:?}");
+                    }
+                }
+                println!(
+                    "Looked at {} cached blocks: {} sets of them have identical contents.",
+                    self.checked,
+                    self.duplicates.len()
+                );
+            }
+            OutputFormat::Json => {
+                helpers::print_json_envelope(self.manifest.as_ref(), &self.duplicates)?
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,82 @@
+//! `list-forks`, which lists the block numbers that the cache holds more than one block for.
+
+use super::*;
+
+/// Lists the block numbers in `range` for which the cache holds more than one block, with all of
+/// their hashes. This only looks at the block cache and changes nothing.
+pub async fn list_forks(
+    chain_store: Arc<ChainStore>,
+    range: &RangeSet,
+    options: &CheckOptions,
+) -> anyhow::Result<ForksReport> {
+    ensure!(options.chunk_size > 0, "Chunk size must be at least 1");
+    let range = steps::resolve_range_hashes(range, &chain_store).await?;
+    let block_numbers = range.block_numbers(
+        || steps::find_chain_head(&chain_store, options),
+        || steps::find_earliest_cached_block(&chain_store),
+    )?;
+    let mut report = ForksReport {
+        manifest: Some(steps::local_manifest(
+            &chain_store,
+            &helpers::block_runs(&block_numbers),
+        )),
+        ..Default::default()
+    };
+    for chunk in block_numbers.chunks(options.chunk_size) {
+        if options.interrupt.is_set() {
+            report.interrupted = Some(block_numbers.len() - report.checked);
+            break;
+        }
+        let block_hashes = steps::resolve_block_hashes_from_block_numbers(chunk, &chain_store)?;
+        report.checked += chunk.len();
+        report.forks.extend(helpers::forks(block_hashes));
+    }
+    Ok(report)
+}
+
+/// A block number for which the cache holds more than one block.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct Fork {
+    pub number: i32,
+    pub hashes: Vec<H256>,
+}
+
+/// The block numbers with more than one cached block, as found by [`list_forks`].
+#[derive(Debug, Default)]
+pub struct ForksReport {
+    /// How many block numbers were looked at.
+    pub checked: usize,
+    pub forks: Vec<Fork>,
+    /// If the search was interrupted, how many block numbers were left.
+    pub interrupted: Option<usize>,
+    /// The manifest entry of the check, for the JSON output.
+    pub manifest: Option<Value>,
+}
+
+impl ForksReport {
+    /// Prints the report in the format selected by `options.output`.
+    pub fn render(&self, options: &CheckOptions) -> anyhow::Result<()> {
+        if let Some(left) = self.interrupted {
+            eprintln!("The search was interrupted and {left} block numbers were not looked at");
+        }
+        match options.output {
+            OutputFormat::Human => {
+                for Fork { number, hashes } in &self.forks {
+                    println!("block {number} has {} cached blocks:", hashes.len());
+                    for hash in hashes {
+                        println!("  {hash:?}");
+                    }
+                }
+                println!(
+                    "Looked at {} block numbers: {} have more than one cached block.",
+                    self.checked,
+                    self.forks.len()
+                );
+            }
+            OutputFormat::Json => {
+                helpers::print_json_envelope(self.manifest.as_ref(), &self.forks)?
+            }
+        }
+        Ok(())
+    }
+}
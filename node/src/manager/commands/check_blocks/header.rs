@@ -0,0 +1,141 @@
+//! Recomputes block hashes from the header fields of cached blocks.
+
+use super::*;
+use graph::prelude::{hex, tiny_keccak};
+
+/// How a header field is encoded in its RLP form.
+#[derive(Clone, Copy)]
+enum Field {
+    /// Data of a fixed or variable length, like a hash or the extra data.
+    Bytes,
+    /// An integer, which RLP encodes without leading zeros.
+    Quantity,
+}
+
+/// The fields that every header has, in the order of their RLP encoding.
+const FIELDS: &[(&str, Field)] = &[
+    ("parentHash", Field::Bytes),
+    ("sha3Uncles", Field::Bytes),
+    ("miner", Field::Bytes),
+    ("stateRoot", Field::Bytes),
+    ("transactionsRoot", Field::Bytes),
+    ("receiptsRoot", Field::Bytes),
+    ("logsBloom", Field::Bytes),
+    ("difficulty", Field::Quantity),
+    ("number", Field::Quantity),
+    ("gasLimit", Field::Quantity),
+    ("gasUsed", Field::Quantity),
+    ("timestamp", Field::Quantity),
+    ("extraData", Field::Bytes),
+    ("mixHash", Field::Bytes),
+    ("nonce", Field::Bytes),
+];
+
+/// The fields that later forks appended to the header, in the order they were added. A
+/// header has some prefix of them, depending on the fork its block belongs to.
+const FORK_FIELDS: &[(&str, Field)] = &[
+    ("baseFeePerGas", Field::Quantity),
+    ("withdrawalsRoot", Field::Bytes),
+    ("blobGasUsed", Field::Quantity),
+    ("excessBlobGas", Field::Quantity),
+    ("parentBeaconBlockRoot", Field::Bytes),
+    ("requestsHash", Field::Bytes),
+];
+
+/// The keccak256 hash of the RLP-encoded header of `block`, which is what its hash has to
+/// be. Fails with a description of the problem if `block` is missing header fields or has
+/// malformed ones.
+///
+/// This follows the Ethereum header format; chains that changed it will not match.
+pub(super) fn header_hash(block: &Value) -> Result<H256, String> {
+    let field = |name: &str| match block.get(name) {
+        None | Some(Value::Null) => None,
+        Some(value) => Some(value),
+    };
+    let mut items = Vec::with_capacity(FIELDS.len() + FORK_FIELDS.len());
+    for (name, kind) in FIELDS {
+        let value = field(name).ok_or_else(|| format!("missing `{name}`"))?;
+        items.push(encode_field(name, value, *kind)?);
+    }
+    let mut fork_fields = FORK_FIELDS.iter();
+    for (name, kind) in fork_fields.by_ref() {
+        match field(name) {
+            Some(value) => items.push(encode_field(name, value, *kind)?),
+            None => break,
+        }
+    }
+    if let Some((name, _)) = fork_fields.find(|(name, _)| field(name).is_some()) {
+        return Err(format!(
+            "`{name}` is set although an earlier fork field is missing"
+        ));
+    }
+    Ok(H256::from(tiny_keccak::keccak256(&encode_list(&items))))
+}
+
+/// The first of the fields that later forks added to the header that `block` lacks, if it
+/// lacks any. Blocks cached before graph-node kept a fork field lack it although their
+/// header has it, so their [`header_hash`] can't match whatever else is right with them.
+pub(super) fn missing_fork_field(block: &Value) -> Option<&'static str> {
+    FORK_FIELDS
+        .iter()
+        .map(|(name, _)| *name)
+        .find(|name| matches!(block.get(name), None | Some(Value::Null)))
+}
+
+fn encode_field(name: &str, value: &Value, kind: Field) -> Result<Vec<u8>, String> {
+    let bytes = value
+        .as_str()
+        .and_then(|value| value.strip_prefix("0x"))
+        .and_then(|digits| match kind {
+            Field::Bytes => hex::decode(digits).ok(),
+            Field::Quantity => decode_quantity(digits),
+        })
+        .ok_or_else(|| format!("malformed `{name}`"))?;
+    Ok(encode_bytes(&bytes))
+}
+
+/// The big-endian bytes of a hex quantity, without leading zeros.
+fn decode_quantity(digits: &str) -> Option<Vec<u8>> {
+    let digits = digits.trim_start_matches('0');
+    let padded = if digits.len() % 2 == 1 {
+        format!("0{digits}")
+    } else {
+        digits.to_string()
+    };
+    hex::decode(padded).ok()
+}
+
+pub(super) fn encode_bytes(bytes: &[u8]) -> Vec<u8> {
+    match bytes {
+        [byte] if *byte < 0x80 => vec![*byte],
+        _ => {
+            let mut encoded = encode_length(bytes.len(), 0x80);
+            encoded.extend_from_slice(bytes);
+            encoded
+        }
+    }
+}
+
+pub(super) fn encode_list(items: &[Vec<u8>]) -> Vec<u8> {
+    let payload_len = items.iter().map(Vec::len).sum();
+    let mut encoded = encode_length(payload_len, 0xc0);
+    for item in items {
+        encoded.extend_from_slice(item);
+    }
+    encoded
+}
+
+/// The prefix of an RLP string (`offset` 0x80) or list (`offset` 0xc0) of `len` bytes.
+fn encode_length(len: usize, offset: u8) -> Vec<u8> {
+    if len <= 55 {
+        return vec![offset + len as u8];
+    }
+    let len_bytes: Vec<u8> = len
+        .to_be_bytes()
+        .into_iter()
+        .skip_while(|byte| *byte == 0)
+        .collect();
+    let mut encoded = vec![offset + 55 + len_bytes.len() as u8];
+    encoded.extend(len_bytes);
+    encoded
+}
@@ -0,0 +1,623 @@
+use super::*;
+use graph::prelude::transaction_receipt::LightTransactionReceipt;
+use graph::prelude::{hex, tiny_keccak, LightEthereumBlock};
+
+/// Parses a block hash from its hex digits, with or without a `0x` prefix. Every command
+/// that accepts block hashes, in arguments, files or range bounds, goes through this, so
+/// that they all accept the same forms and explain malformed hashes the same way.
+pub(super) fn parse_block_hash(input: &str) -> anyhow::Result<H256> {
+    let trimmed = input.trim();
+    let (prefix, digits) = match trimmed
+        .strip_prefix("0x")
+        .or_else(|| trimmed.strip_prefix("0X"))
+    {
+        Some(digits) => (2, digits),
+        None => (0, trimmed),
+    };
+    // All characters before the first bad one are hex digits, so byte and character
+    // positions agree
+    let bad_char = digits.char_indices().find(|(_, c)| !c.is_ascii_hexdigit());
+    let problem = if let Some((index, c)) = bad_char {
+        format!(
+            "`{c}` at position {} is not a hex digit",
+            prefix + index + 1
+        )
+    } else if digits.is_empty() {
+        "it has no hex digits".to_string()
+    } else if digits.len() % 2 == 1 {
+        format!("it has an odd number of hex digits, {}", digits.len())
+    } else if digits.len() != 2 * H256::len_bytes() {
+        format!(
+            "it has {} bytes, expected {} bytes",
+            digits.len() / 2,
+            H256::len_bytes()
+        )
+    } else {
+        let bytes = hex::decode(digits).expect("only hex digits are left");
+        return Ok(H256::from_slice(&bytes));
+    };
+    bail!("Invalid block hash `{input}`: {problem}")
+}
+
+/// The [`H256`] that a hash from the block cache stands for. The cache doesn't check the
+/// length of the hashes it stores, so a malformed one is an error rather than a panic.
+pub(super) fn cached_block_hash(hash: &BlockHash) -> anyhow::Result<H256> {
+    let bytes = hash.as_slice();
+    if bytes.len() != H256::len_bytes() {
+        return Err(CheckBlocksError::CorruptCacheEntry {
+            hash: hash.clone(),
+            problem: format!(
+                "its hash has {} bytes, expected {} bytes",
+                bytes.len(),
+                H256::len_bytes()
+            ),
+        }
+        .into());
+    }
+    Ok(H256::from_slice(bytes))
+}
+
+/// Parses each of `hashes` on its own, and returns the block hashes along with the
+/// arguments that are not block hashes and why. A single hash that can't be parsed is an
+/// error, and so is a list without a single valid hash.
+pub(super) fn parse_block_hashes(
+    hashes: &[String],
+) -> anyhow::Result<(Vec<H256>, Vec<(String, String)>)> {
+    let mut block_hashes = Vec::new();
+    let mut invalid_hashes = Vec::new();
+    for hash in hashes {
+        match parse_block_hash(hash) {
+            Ok(block_hash) => block_hashes.push(block_hash),
+            Err(e) if hashes.len() == 1 => return Err(e),
+            Err(e) => invalid_hashes.push((hash.clone(), format!("{e:#}"))),
+        }
+    }
+    if block_hashes.is_empty() {
+        bail!("None of the {} arguments is a block hash", hashes.len());
+    }
+    Ok((block_hashes, invalid_hashes))
+}
+
+/// The block numbers in `block_hashes` that have more than one hash, sorted by number. The
+/// hashes of each fork are sorted, too, so that the output is stable.
+pub(super) fn forks(block_hashes: HashMap<i32, Vec<H256>>) -> Vec<Fork> {
+    let mut forks: Vec<Fork> = block_hashes
+        .into_iter()
+        .filter(|(_, hashes)| hashes.len() > 1)
+        .map(|(number, mut hashes)| {
+            hashes.sort_unstable();
+            Fork { number, hashes }
+        })
+        .collect();
+    forks.sort_unstable_by_key(|fork| fork.number);
+    forks
+}
+
+/// Groups cached blocks by the digest of their contents, and returns the groups with more
+/// than one block. Blocks are sorted by number and hash, and groups by their first block.
+pub(super) fn duplicate_blocks(digests: Vec<(H256, CachedBlockRef)>) -> Vec<DuplicateBlocks> {
+    let mut blocks_by_digest: HashMap<H256, Vec<CachedBlockRef>> = HashMap::new();
+    for (digest, block) in digests {
+        blocks_by_digest.entry(digest).or_default().push(block);
+    }
+    let mut duplicates: Vec<DuplicateBlocks> = blocks_by_digest
+        .into_iter()
+        .filter(|(_, blocks)| blocks.len() > 1)
+        .map(|(digest, mut blocks)| {
+            blocks.sort_unstable_by_key(|block| (block.number, block.hash));
+            DuplicateBlocks { digest, blocks }
+        })
+        .collect();
+    duplicates.sort_unstable_by_key(|duplicate| {
+        let first = &duplicate.blocks[0];
+        (first.number, first.hash)
+    });
+    duplicates
+}
+
+/// Splits the blocks that two caches hold, by number, into those that both hold, those that
+/// only `ours` holds and those that only `theirs` holds. Each list is sorted by number and
+/// hash, so that the output is stable.
+pub(super) fn match_cached_blocks(
+    ours: HashMap<i32, Vec<H256>>,
+    mut theirs: HashMap<i32, Vec<H256>>,
+) -> (
+    Vec<CachedBlockRef>,
+    Vec<CachedBlockRef>,
+    Vec<CachedBlockRef>,
+) {
+    let (mut common, mut only_ours, mut only_theirs) = (Vec::new(), Vec::new(), Vec::new());
+    for (number, hashes) in ours {
+        let their_hashes = theirs.remove(&number).unwrap_or_default();
+        for hash in &hashes {
+            let block = CachedBlockRef {
+                number,
+                hash: *hash,
+            };
+            if their_hashes.contains(hash) {
+                common.push(block);
+            } else {
+                only_ours.push(block);
+            }
+        }
+        only_theirs.extend(
+            their_hashes
+                .into_iter()
+                .filter(|hash| !hashes.contains(hash))
+                .map(|hash| CachedBlockRef { number, hash }),
+        );
+    }
+    only_theirs.extend(theirs.into_iter().flat_map(|(number, hashes)| {
+        hashes
+            .into_iter()
+            .map(move |hash| CachedBlockRef { number, hash })
+    }));
+    for blocks in [&mut common, &mut only_ours, &mut only_theirs] {
+        blocks.sort_unstable_by_key(|block| (block.number, block.hash));
+    }
+    (common, only_ours, only_theirs)
+}
+
+/// Matches the blocks that the provider returned for a batch request of `batch` with the
+/// requested hashes by their hash, whatever order they came in, and returns them together
+/// with the hashes of the blocks that nobody asked for. A requested hash that no block was
+/// returned for is missing from the provider, unless there are such strays: there's no
+/// telling then what the provider's answer for it was, so it fails.
+pub(super) fn match_batched_blocks(
+    batch: &[H256],
+    blocks: Vec<Option<LightEthereumBlock>>,
+) -> (
+    Vec<anyhow::Result<Option<LightEthereumBlock>>>,
+    Vec<Option<H256>>,
+) {
+    let requested: HashSet<&H256> = batch.iter().collect();
+    let mut by_hash = HashMap::new();
+    let mut strays = Vec::new();
+    for block in blocks {
+        match block {
+            Some(block) if block.hash.map_or(false, |hash| requested.contains(&hash)) => {
+                // Unwrap: we just checked that the block has a hash
+                by_hash.insert(block.hash.unwrap(), block);
+            }
+            Some(block) => strays.push(block.hash),
+            None => {}
+        }
+    }
+    let matched = batch
+        .iter()
+        .map(|block_hash| match by_hash.remove(block_hash) {
+            Some(block) => Ok(Some(block)),
+            None if strays.is_empty() => Ok(None),
+            None => Err(anyhow!(
+                "the provider answered the batch request for block {block_hash:?} with \
+                 blocks that were not asked for: {}",
+                first_few(&strays)
+            )),
+        })
+        .collect();
+    (matched, strays)
+}
+
+/// Runs `work` on each of `items`, on up to `parallel` threads of their own, and returns the
+/// results in the order of `items`. Every thread takes on the next item once it is done
+/// with one, so a slow item doesn't hold up the ones after it.
+pub(super) fn run_in_parallel<T: Send, R: Send>(
+    items: Vec<T>,
+    parallel: usize,
+    work: impl Fn(T) -> R + Sync,
+) -> Vec<R> {
+    let queue = Mutex::new(items.into_iter().enumerate());
+    let results = Mutex::new(Vec::new());
+    std::thread::scope(|scope| {
+        for _ in 0..parallel.max(1) {
+            scope.spawn(|| loop {
+                let next = queue.lock().unwrap().next();
+                let Some((index, item)) = next else {
+                    break;
+                };
+                let result = work(item);
+                results.lock().unwrap().push((index, result));
+            });
+        }
+    });
+    let mut results = results.into_inner().unwrap();
+    results.sort_unstable_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Turns the payload of a caught panic into an error with the panic message.
+pub(super) fn panic_error(panic: Box<dyn std::any::Any + Send>) -> anyhow::Error {
+    let message = panic
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown cause".to_string());
+    anyhow!("panicked: {message}")
+}
+
+/// Classifies every number in `block_numbers` by the hashes that `block_hashes_by_number`
+/// has for it, in the order of `block_numbers`.
+pub(super) fn classify_heights(
+    block_numbers: &[i32],
+    mut block_hashes_by_number: HashMap<i32, Vec<H256>>,
+) -> Vec<(i32, Height)> {
+    block_numbers
+        .iter()
+        .map(|number| {
+            let height = match block_hashes_by_number.remove(number) {
+                None => Height::Missing,
+                Some(hashes) => match hashes.as_slice() {
+                    [] => Height::Missing,
+                    [hash] => Height::Present(*hash),
+                    _ => Height::Ambiguous(hashes),
+                },
+            };
+            (*number, height)
+        })
+        .collect()
+}
+
+/// What went wrong with deleting a block, for `verify_deletes`, given how many rows the
+/// delete `removed` and how many blocks with the same hash are `remaining` in the cache.
+pub(super) fn deletion_problem(removed: usize, remaining: usize) -> Option<String> {
+    if remaining > 0 {
+        Some(format!(
+            "left {remaining} blocks with its hash in the cache"
+        ))
+    } else if removed == 0 {
+        Some("removed no rows, the block may already have been gone".to_string())
+    } else {
+        None
+    }
+}
+
+/// The `block_numbers` for which `block_hashes_by_number` has no block at all, in the order
+/// of `block_numbers`.
+pub(super) fn missing_block_numbers(
+    block_numbers: &[i32],
+    block_hashes_by_number: HashMap<i32, Vec<H256>>,
+) -> Vec<i32> {
+    classify_heights(block_numbers, block_hashes_by_number)
+        .into_iter()
+        .filter_map(|(number, height)| (height == Height::Missing).then_some(number))
+        .collect()
+}
+
+/// Splits `block_numbers` into those more than `gap` blocks below `chain_head`, and those
+/// closer to it, keeping their order.
+pub(super) fn split_off_tip(
+    block_numbers: Vec<i32>,
+    chain_head: i32,
+    gap: u32,
+) -> (Vec<i32>, Vec<i32>) {
+    block_numbers
+        .into_iter()
+        .partition(|number| i64::from(chain_head) - i64::from(*number) > i64::from(gap))
+}
+
+/// How the chain identifier of a provider differs from the one of the chain store, if it
+/// does.
+pub(super) fn chain_mismatch(
+    store: &ChainIdentifier,
+    provider: &ChainIdentifier,
+) -> Option<String> {
+    if store.genesis_block_hash != provider.genesis_block_hash {
+        Some(format!(
+            "its genesis block is {} instead of {}",
+            provider.genesis_block_hash, store.genesis_block_hash
+        ))
+    } else if store.net_version != provider.net_version {
+        Some(format!(
+            "its net version is {} instead of {}",
+            provider.net_version, store.net_version
+        ))
+    } else {
+        None
+    }
+}
+
+/// Whether the file at `path` is to be gzipped.
+pub(super) fn compress(path: &Path, options: &CheckOptions) -> bool {
+    options.compress
+        || path
+            .extension()
+            .map_or(false, |extension| extension == "gz")
+}
+
+/// The first bytes of every gzip stream.
+pub(super) const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Reads the file at `path`, decompressing it if it is gzipped, which is told by its
+/// contents rather than its name. A gzipped file that ends early is read up to there.
+pub(super) fn read_maybe_gzipped(path: &Path) -> anyhow::Result<Vec<u8>> {
+    let data =
+        std::fs::read(path).with_context(|| format!("failed to read `{}`", path.display()))?;
+    if !data.starts_with(&GZIP_MAGIC) {
+        return Ok(data);
+    }
+    let mut decompressed = Vec::new();
+    match flate2::read::MultiGzDecoder::new(data.as_slice()).read_to_end(&mut decompressed) {
+        Ok(_) => {}
+        // A run that was killed leaves the last gzip member without its end, but everything
+        // it wrote before that was flushed and has been read
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+        Err(e) => {
+            return Err(e).with_context(|| format!("failed to decompress `{}`", path.display()))
+        }
+    }
+    Ok(decompressed)
+}
+
+/// The hashes of the blocks in the lines of an audit log. Lines without a block hash, like
+/// manifests and purged ranges, are skipped.
+pub(super) fn audited_hashes(audit_log: &str) -> anyhow::Result<HashSet<H256>> {
+    let mut hashes = HashSet::new();
+    for (index, line) in audit_log.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: Value = serde_json::from_str(line)
+            .with_context(|| format!("line {} is not a JSON object", index + 1))?;
+        if let Some(hash) = entry.get("hash").and_then(Value::as_str) {
+            let hash = parse_block_hash(hash)
+                .with_context(|| format!("line {} has a malformed hash", index + 1))?;
+            hashes.insert(hash);
+        }
+    }
+    Ok(hashes)
+}
+
+/// `data` as a complete gzip stream.
+pub(super) fn gzip(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// How many items [`first_few`] lists before it only counts the rest.
+const FIRST_FEW: usize = 3;
+
+/// `Debug`-formats the first few `items` as a list for an error message, and says how many
+/// more there are, e.g. `[1, 2, 3] and 2 more`.
+pub(super) fn first_few<T: fmt::Debug>(items: &[T]) -> String {
+    let shown = &items[..items.len().min(FIRST_FEW)];
+    match items.len() - shown.len() {
+        0 => format!("{shown:?}"),
+        rest => format!("{shown:?} and {rest} more"),
+    }
+}
+
+/// How many of the lowest and of the highest numbers [`sample_block_numbers`] lists.
+const SAMPLE_ENDS: usize = 3;
+
+/// Up to `size` of `items`, spread evenly from the first to the last one, in their order.
+pub(super) fn spread_sample<T>(items: Vec<T>, size: usize) -> Vec<T> {
+    if items.len() <= size {
+        return items;
+    }
+    let last = items.len() - 1;
+    let picks: HashSet<usize> = match size {
+        0 => HashSet::new(),
+        1 => HashSet::from([last / 2]),
+        _ => (0..size).map(|i| i * last / (size - 1)).collect(),
+    };
+    items
+        .into_iter()
+        .enumerate()
+        .filter(|(index, _)| picks.contains(index))
+        .map(|(_, item)| item)
+        .collect()
+}
+
+/// Lists the lowest and the highest few of the block `numbers`, like `#1, #2, #3, ..., #98,
+/// #99, #100`, or all of them if there are only a few.
+pub(super) fn sample_block_numbers(numbers: &[i32]) -> String {
+    let mut numbers = numbers.to_vec();
+    numbers.sort_unstable();
+    numbers.dedup();
+    let describe = |numbers: &[i32]| {
+        numbers
+            .iter()
+            .map(|number| format!("#{number}"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    if numbers.len() <= 2 * SAMPLE_ENDS {
+        describe(&numbers)
+    } else {
+        format!(
+            "{}, ..., {}",
+            describe(&numbers[..SAMPLE_ENDS]),
+            describe(&numbers[numbers.len() - SAMPLE_ENDS..])
+        )
+    }
+}
+
+/// Drops repeated hashes from `block_hashes`, keeping the first occurrence of each so that
+/// the order doesn't change.
+pub(super) fn unique_block_hashes(block_hashes: &[H256]) -> Vec<H256> {
+    let mut seen = HashSet::new();
+    block_hashes
+        .iter()
+        .filter(|hash| seen.insert(**hash))
+        .copied()
+        .collect()
+}
+
+/// The manifest of a check of `target` on `chain` with `provider`: the effective parameters
+/// that decide what the check compares and whether it deletes anything.
+pub(super) fn manifest(
+    chain: &str,
+    provider: &str,
+    target: &str,
+    range: Option<&[(i32, i32)]>,
+    version: &str,
+    options: &CheckOptions,
+) -> Value {
+    serde_json::json!({
+        "chain": chain,
+        "target": target,
+        "range": range.map(range_value),
+        "provider": provider,
+        "profile": NetworkProfile::for_network(chain).name,
+        "concurrency": options.concurrency,
+        "dry_run": options.dry_run,
+        "ignored_fields": options.ignored_fields,
+        "version": version,
+    })
+}
+
+/// Reads the block with `hash` from the provider fixture `dir`. The file is `<hash>.json`,
+/// or `<hash>.provider.json` as written to an export directory, and may be gzipped.
+pub(super) fn read_fixture_block(dir: &Path, hash: &H256) -> anyhow::Result<LightEthereumBlock> {
+    let names = [
+        format!("{hash:?}.json"),
+        format!("{hash:?}.provider.json"),
+        format!("{hash:?}.provider.json.gz"),
+    ];
+    let path = names
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.exists())
+        .ok_or_else(|| {
+            anyhow!(
+                "the provider fixture `{}` has no block {hash:?}, expected a file named \
+                 `{hash:?}.json` or `{hash:?}.provider.json`",
+                dir.display()
+            )
+        })?;
+    let data = read_maybe_gzipped(&path)?;
+    serde_json::from_slice(&data)
+        .with_context(|| format!("`{}` doesn't hold a block", path.display()))
+}
+
+/// The manifest of a method of `target` on `chain` that only looks at the block cache, with
+/// the same `chain`, `target` and `range` as a [`manifest`] has.
+pub(super) fn local_manifest(
+    chain: &str,
+    target: &str,
+    range: Option<&[(i32, i32)]>,
+    version: &str,
+) -> Value {
+    serde_json::json!({
+        "chain": chain,
+        "target": target,
+        "range": range.map(range_value),
+        "version": version,
+    })
+}
+
+/// A range of block numbers given by the inclusive bounds of its segments, as it appears in
+/// JSON output: `[{ "from": 1, "to": 10 }, ...]`.
+pub(super) fn range_value(bounds: &[(i32, i32)]) -> Value {
+    bounds
+        .iter()
+        .map(|(from, to)| serde_json::json!({ "from": from, "to": to }))
+        .collect()
+}
+
+/// The object that `--output json` prints for a check with the manifest `entry` from
+/// `print_manifest`: the `results` along with what a consumer needs to tell what they are
+/// about without parsing the manifest.
+pub(super) fn json_envelope(entry: Option<&Value>, results: Value) -> Value {
+    let entry = entry.unwrap_or(&Value::Null);
+    serde_json::json!({
+        "schema_version": JSON_SCHEMA_VERSION,
+        "chain": entry["manifest"]["chain"],
+        "target": entry["manifest"]["target"],
+        "range": entry["manifest"]["range"],
+        "timestamp": entry["timestamp"],
+        "manifest": entry["manifest"],
+        "results": results,
+    })
+}
+
+/// `invalid_hashes` as a JSON array of objects with the `argument` and the `error`.
+pub(super) fn invalid_hashes_value(invalid_hashes: &[(String, String)]) -> Value {
+    invalid_hashes
+        .iter()
+        .map(|(argument, error)| serde_json::json!({ "argument": argument, "error": error }))
+        .collect()
+}
+
+/// Prints `results` to stdout in the [`json_envelope`] of the manifest `entry`, which is
+/// what every method prints with `--output json`.
+pub(super) fn print_json_envelope(
+    entry: Option<&Value>,
+    results: impl Serialize,
+) -> anyhow::Result<()> {
+    let results =
+        serde_json::to_value(results).context("failed to serialize the results as JSON")?;
+    let json = serde_json::to_string_pretty(&json_envelope(entry, results))
+        .context("failed to serialize the results as JSON")?;
+    println!("{json}");
+    Ok(())
+}
+
+/// A digest of a cached block's JSON representation, which tells whether a block is still the
+/// same without keeping a copy of it.
+pub(super) fn block_digest(block: &Value) -> H256 {
+    // Object keys are sorted, so equal blocks always serialize the same way
+    let bytes = serde_json::to_vec(block).expect("JSON values can always be serialized");
+    H256::from(tiny_keccak::keccak256(&bytes))
+}
+
+/// Reads the block number from a block's JSON representation, where it is stored as a hex
+/// quantity.
+pub(super) fn block_number(block: &Value) -> Option<i32> {
+    let number = block.get("number")?.as_str()?;
+    i32::from_str_radix(number.trim_start_matches("0x"), 16).ok()
+}
+
+/// The JSON form in which the receipts of a block are compared: an object with each receipt
+/// under the hash of its transaction, so that a receipt that only one side has shows up in
+/// the diff as well.
+pub(super) fn receipts_value(receipts: &[LightTransactionReceipt]) -> Value {
+    let receipts = receipts
+        .iter()
+        .map(|receipt| {
+            let fields = serde_json::json!({
+                "transactionIndex": receipt.transaction_index,
+                "blockHash": receipt.block_hash,
+                "blockNumber": receipt.block_number,
+                "gasUsed": receipt.gas_used,
+                "status": receipt.status,
+            });
+            (format!("{:?}", receipt.transaction_hash), fields)
+        })
+        .collect();
+    Value::Object(receipts)
+}
+
+/// Formats a sorted list of block numbers compactly, collapsing consecutive numbers into
+/// ranges, e.g. `1-3, 7, 9-10`.
+pub(super) fn format_block_numbers(numbers: &[i32]) -> String {
+    format_runs(block_runs(numbers))
+}
+
+/// The runs of consecutive numbers in the sorted `numbers`, by their inclusive bounds.
+pub(super) fn block_runs(numbers: &[i32]) -> Vec<(i32, i32)> {
+    let mut runs: Vec<(i32, i32)> = Vec::new();
+    for &number in numbers {
+        match runs.last_mut() {
+            Some((_, end)) if *end + 1 == number => *end = number,
+            _ => runs.push((number, number)),
+        }
+    }
+    runs
+}
+
+/// Formats runs of block numbers given by their inclusive bounds like
+/// [`format_block_numbers`] does.
+pub(super) fn format_runs(runs: impl IntoIterator<Item = (i32, i32)>) -> String {
+    runs.into_iter()
+        .map(|(start, end)| {
+            if start == end {
+                start.to_string()
+            } else {
+                format!("{start}-{end}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
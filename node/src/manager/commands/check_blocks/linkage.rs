@@ -0,0 +1,182 @@
+//! `check-linkage`, which verifies that cached blocks link up with their predecessors.
+
+use super::*;
+
+/// Walks the cached blocks in `range` and verifies that the parent hash of each of them is the hash
+/// of a cached block one number lower. Blocks whose predecessor is not cached at all are skipped.
+///
+/// With `delete`, the blocks that don't link up with their predecessor are removed from the cache,
+/// together with their descendants in `range`.
+pub async fn check_linkage(
+    chain_store: Arc<ChainStore>,
+    range: &RangeSet,
+    delete: bool,
+    options: &CheckOptions,
+) -> anyhow::Result<LinkageReport> {
+    let range = steps::resolve_range_hashes(range, &chain_store).await?;
+    let block_numbers = range.block_numbers(
+        || steps::find_chain_head(&chain_store, options),
+        || steps::find_earliest_cached_block(&chain_store),
+    )?;
+    // We also need the predecessors of the first block of every range segment
+    let mut queried_numbers: Vec<i32> = block_numbers
+        .iter()
+        .flat_map(|number| [number - 1, *number])
+        .collect();
+    queried_numbers.sort_unstable();
+    queried_numbers.dedup();
+
+    let mut blocks_by_number: HashMap<i32, Vec<(H256, Option<H256>)>> = HashMap::new();
+    for (number, hash, parent_hash) in
+        chain_store.block_parents_by_block_numbers(&queried_numbers)?
+    {
+        let hash = helpers::cached_block_hash(&hash)?;
+        let parent_hash = parent_hash
+            .map(|x| helpers::cached_block_hash(&x))
+            .transpose()?;
+        blocks_by_number
+            .entry(number)
+            .or_default()
+            .push((hash, parent_hash));
+    }
+
+    let mut report = LinkageReport {
+        manifest: Some(steps::local_manifest(
+            &chain_store,
+            &helpers::block_runs(&block_numbers),
+        )),
+        ..Default::default()
+    };
+    let mut orphans = Vec::new();
+    let mut orphan_set = HashSet::new();
+    for number in &block_numbers {
+        let cached_parents: Vec<H256> = blocks_by_number
+            .get(&(number - 1))
+            .map(|blocks| blocks.iter().map(|(hash, _)| *hash).collect())
+            .unwrap_or_default();
+        for (hash, parent_hash) in blocks_by_number.get(number).into_iter().flatten() {
+            report.checked += 1;
+            let parent_hash = match parent_hash {
+                Some(parent_hash) => parent_hash,
+                None => continue,
+            };
+            if orphan_set.contains(parent_hash) {
+                // The parent is fine as far as linkage goes, but it's going away
+                orphans.push((
+                    *number,
+                    *hash,
+                    "descendant of a block with a broken parent link",
+                ));
+                orphan_set.insert(*hash);
+            } else if !cached_parents.is_empty() && !cached_parents.contains(parent_hash) {
+                report.breaks.push(LinkageBreak {
+                    number: *number,
+                    hash: *hash,
+                    parent_hash: *parent_hash,
+                    cached_parents: cached_parents.clone(),
+                });
+                orphans.push((*number, *hash, "broken parent link"));
+                orphan_set.insert(*hash);
+            }
+        }
+    }
+
+    if delete {
+        let total = orphans.len();
+        for (index, (number, hash, reason)) in orphans.into_iter().enumerate() {
+            if options.interrupt.is_set() {
+                report.interrupted = Some(total - index);
+                break;
+            }
+            let deletion = steps::Deletion {
+                number: Some(number),
+                reason,
+                diff: None,
+                compared: None,
+            };
+            match steps::delete_block(&hash, &chain_store, options, deletion)? {
+                steps::DeleteOutcome::Deleted => report.deleted.push(hash),
+                // Only blocks that are deleted with the digest they were compared with can race
+                steps::DeleteOutcome::Raced => {}
+                steps::DeleteOutcome::Unverified(problem) => {
+                    report.unverified_deletes.push((hash, problem))
+                }
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// A cached block whose parent hash doesn't match any of the cached blocks one number lower.
+#[derive(Debug, Serialize)]
+pub struct LinkageBreak {
+    pub number: i32,
+    pub hash: H256,
+    pub parent_hash: H256,
+    /// The hashes of the cached blocks at `number - 1`.
+    pub cached_parents: Vec<H256>,
+}
+
+/// What a linkage check found and did.
+#[derive(Debug, Default)]
+pub struct LinkageReport {
+    /// How many cached blocks were looked at.
+    pub checked: usize,
+    pub breaks: Vec<LinkageBreak>,
+    /// The blocks that were removed from the cache, or that would have been in a dry run.
+    pub deleted: Vec<H256>,
+    /// The blocks that `verify_deletes` found were not actually removed by deleting them, with
+    /// what went wrong.
+    pub unverified_deletes: Vec<(H256, String)>,
+    /// If the deletions were interrupted, how many blocks were left in the cache.
+    pub interrupted: Option<usize>,
+    /// The manifest entry of the check, for the JSON output.
+    pub manifest: Option<Value>,
+}
+
+impl LinkageReport {
+    /// Prints the report in the format selected by `options.output`.
+    pub fn render(&self, options: &CheckOptions) -> anyhow::Result<()> {
+        if let Some(left) = self.interrupted {
+            eprintln!("The deletions were interrupted and {left} blocks were left in the cache");
+        }
+        match options.output {
+            OutputFormat::Human => {
+                for LinkageBreak {
+                    number,
+                    hash,
+                    parent_hash,
+                    cached_parents,
+                } in &self.breaks
+                {
+                    let cached_parents = cached_parents
+                        .iter()
+                        .map(|hash| format!("{hash:?}"))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    println!(
+                        "block {number} {hash:?} has parent hash {parent_hash:?}, \
+                         but the cache has {cached_parents} at block {}",
+                        number - 1
+                    );
+                }
+                let deleted = if options.dry_run {
+                    "would have been deleted"
+                } else {
+                    "deleted"
+                };
+                println!(
+                    "Checked {} blocks: {} don't link up with their parent, {} {deleted}.",
+                    self.checked,
+                    self.breaks.len(),
+                    self.deleted.len()
+                );
+                print_unverified_deletes(&self.unverified_deletes);
+            }
+            OutputFormat::Json => {
+                helpers::print_json_envelope(self.manifest.as_ref(), &self.breaks)?
+            }
+        }
+        Ok(())
+    }
+}
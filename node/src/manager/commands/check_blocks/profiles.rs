@@ -0,0 +1,105 @@
+//! How the blocks of networks that add their own fields to them, like L2s, are compared.
+
+use super::*;
+
+/// What sets the blocks of a group of networks apart from those of Ethereum, in the fields
+/// that providers return differently. Supporting another network only takes adding a
+/// profile for it to [`PROFILES`].
+#[derive(Debug, PartialEq, Eq)]
+pub struct NetworkProfile {
+    pub name: &'static str,
+    /// The network names, as configured for chains, that the profile applies to.
+    networks: &'static [&'static str],
+    /// Block fields that some providers leave out. They are only compared if both blocks
+    /// have them.
+    optional_fields: &'static [&'static str],
+    /// Block fields that are quantities besides those in [`QUANTITY_FIELDS`].
+    quantity_fields: &'static [&'static str],
+}
+
+/// The profile of networks without one of their own.
+const ETHEREUM: NetworkProfile = NetworkProfile {
+    name: "ethereum",
+    networks: &[],
+    optional_fields: &[],
+    quantity_fields: &[],
+};
+
+/// Arbitrum blocks have the number of the L1 block they were posted in, and the count and
+/// root of the L2-to-L1 messages, which not all providers return.
+const ARBITRUM: NetworkProfile = NetworkProfile {
+    name: "arbitrum",
+    networks: &[
+        "arbitrum-one",
+        "arbitrum-nova",
+        "arbitrum-goerli",
+        "arbitrum-sepolia",
+    ],
+    optional_fields: &["l1BlockNumber", "sendCount", "sendRoot"],
+    quantity_fields: &["l1BlockNumber", "sendCount"],
+};
+
+/// Providers of OP Stack chains disagree on whether blocks from before the Bedrock upgrade
+/// have a `baseFeePerGas`.
+const OPTIMISM: NetworkProfile = NetworkProfile {
+    name: "optimism",
+    networks: &[
+        "optimism",
+        "optimism-goerli",
+        "optimism-sepolia",
+        "base",
+        "base-sepolia",
+    ],
+    optional_fields: &["baseFeePerGas"],
+    quantity_fields: &[],
+};
+
+const PROFILES: &[&NetworkProfile] = &[&ARBITRUM, &OPTIMISM];
+
+impl NetworkProfile {
+    /// The profile for the chain with the network name `network`, or [`ETHEREUM`] if there is
+    /// none for it.
+    pub fn for_network(network: &str) -> &'static NetworkProfile {
+        PROFILES
+            .iter()
+            .copied()
+            .find(|profile| profile.networks.contains(&network))
+            .unwrap_or(&ETHEREUM)
+    }
+
+    /// Brings the profile's quantity fields of `block` into canonical form, like
+    /// [`steps::normalize_hex`] does for the others.
+    pub(super) fn normalize_quantities(&self, block: &mut Value) {
+        let Value::Object(fields) = block else {
+            return;
+        };
+        for field in self.quantity_fields {
+            if let Some(Value::String(s)) = fields.get_mut(*field) {
+                steps::lowercase_hex(s);
+                steps::strip_leading_zeros(s);
+            }
+        }
+    }
+
+    /// Removes the profile's optional fields from both blocks unless both of them have them.
+    pub(super) fn drop_optional_fields(
+        &self,
+        cached_block: &mut Value,
+        provider_block: &mut Value,
+    ) {
+        let (Value::Object(cached_fields), Value::Object(provider_fields)) =
+            (cached_block, provider_block)
+        else {
+            return;
+        };
+        for field in self.optional_fields {
+            let present = |fields: &serde_json::Map<String, Value>| {
+                fields.get(*field).map_or(false, |value| !value.is_null())
+            };
+            if !present(cached_fields) || !present(provider_fields) {
+                cached_fields.remove(*field);
+                provider_fields.remove(*field);
+            }
+        }
+    }
+}
@@ -0,0 +1,222 @@
+//! A progress line on stderr for long-running checks.
+
+use super::*;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// The progress line is redrawn at most this often, so that fast providers don't flood the
+/// terminal.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The throughput shown on the progress line, and the remaining time estimated from it, are
+/// averaged over this long, which smooths out the pauses between chunks.
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/// The width of the bar on the progress line, in characters.
+const BAR_WIDTH: usize = 20;
+
+/// Counts fetched and diverging blocks, and keeps a line on stderr up to date with them.
+///
+/// Anything else that prints while the line is shown has to go through [`Progress::suspend`],
+/// otherwise the two end up interleaved on the terminal.
+pub(super) struct Progress {
+    enabled: bool,
+    total: usize,
+    state: Mutex<State>,
+    status: Option<Arc<CheckStatus>>,
+    /// The metrics to count in, and the chain to count for.
+    metrics: Option<(Arc<CheckBlocksMetrics>, String)>,
+}
+
+#[derive(Default)]
+struct State {
+    fetched: usize,
+    diverged: usize,
+    /// Whether the progress line is currently on screen.
+    shown: bool,
+    last_draw: Option<Instant>,
+    /// When the line was drawn, and how many blocks had been fetched by then, over the last
+    /// [`RATE_WINDOW`].
+    samples: VecDeque<(Instant, usize)>,
+}
+
+impl Progress {
+    pub(super) fn new(total: usize, chain: &str, options: &CheckOptions) -> Self {
+        if let Some(status) = &options.status {
+            status.total.fetch_add(total, Ordering::Relaxed);
+        }
+        Progress {
+            enabled: options.progress && options.output == OutputFormat::Human,
+            total,
+            state: Mutex::default(),
+            status: options.status.clone(),
+            metrics: options
+                .metrics
+                .clone()
+                .map(|metrics| (metrics, chain.to_string())),
+        }
+    }
+
+    /// A progress line that is never shown.
+    pub(super) fn hidden() -> Self {
+        Progress {
+            enabled: false,
+            total: 0,
+            state: Mutex::default(),
+            status: None,
+            metrics: None,
+        }
+    }
+
+    pub(super) fn block_fetched(&self) {
+        if let Some(status) = &self.status {
+            status.fetched.fetch_add(1, Ordering::Relaxed);
+        }
+        self.update(|state| state.fetched += 1);
+    }
+
+    /// Counts `count` blocks of the total as fetched without fetching them, for the blocks
+    /// that are dealt with before the check gets to them.
+    pub(super) fn blocks_done(&self, count: usize) {
+        if count == 0 {
+            return;
+        }
+        if let Some(status) = &self.status {
+            status.fetched.fetch_add(count, Ordering::Relaxed);
+        }
+        self.update(|state| state.fetched += count);
+    }
+
+    pub(super) fn block_diverged(&self) {
+        if let Some(status) = &self.status {
+            status.diverged.fetch_add(1, Ordering::Relaxed);
+        }
+        if let Some((metrics, chain)) = &self.metrics {
+            metrics.block_diverged(chain);
+        }
+        self.update(|state| state.diverged += 1);
+    }
+
+    /// Counts a failed provider request, whether or not it is retried.
+    pub(super) fn rpc_error(&self) {
+        if let Some((metrics, chain)) = &self.metrics {
+            metrics.rpc_error(chain);
+        }
+    }
+
+    /// Runs `f` with the progress line cleared, and draws it again afterwards.
+    pub(super) fn suspend<R>(&self, f: impl FnOnce() -> R) -> R {
+        if !self.enabled {
+            return f();
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.shown {
+            eprint!("\r\x1b[2K");
+            state.shown = false;
+        }
+        let result = f();
+        self.draw(&mut state);
+        result
+    }
+
+    /// Leaves the final state of the progress line on screen.
+    pub(super) fn finish(&self) {
+        if !self.enabled {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        self.draw(&mut state);
+        eprintln!();
+        state.shown = false;
+    }
+
+    fn update(&self, f: impl FnOnce(&mut State)) {
+        if !self.enabled {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        f(&mut state);
+        let due = state
+            .last_draw
+            .map_or(true, |last_draw| last_draw.elapsed() >= REDRAW_INTERVAL);
+        if due || state.fetched == self.total {
+            self.draw(&mut state);
+        }
+    }
+
+    fn draw(&self, state: &mut State) {
+        let now = Instant::now();
+        state.samples.push_back((now, state.fetched));
+        // Keep one sample from before the window so that the rate covers all of it
+        while state.samples.len() > 2 && now.duration_since(state.samples[1].0) >= RATE_WINDOW {
+            state.samples.pop_front();
+        }
+
+        let mut line = format!(
+            "{} {}/{} blocks fetched, {} diverged",
+            bar(state.fetched, self.total),
+            state.fetched,
+            self.total,
+            state.diverged
+        );
+        if let Some(rate) = rate(&state.samples) {
+            line.push_str(&format!(", {rate:.0}/s"));
+            if let Some(eta) = eta(self.total.saturating_sub(state.fetched), rate) {
+                line.push_str(&format!(", ETA {}", format_eta(eta)));
+            }
+        }
+        eprint!("\r\x1b[2K{line}");
+        state.shown = true;
+        state.last_draw = Some(now);
+    }
+}
+
+/// A bar like `[=========>          ]  45%` for `fetched` out of `total` blocks. Blocks can
+/// still be fetched once the total has been reached, when a range had an open upper bound and
+/// the chain head moved on, so the bar never goes beyond full.
+pub(super) fn bar(fetched: usize, total: usize) -> String {
+    let permille = if total == 0 {
+        1000
+    } else {
+        (fetched.min(total) as u128 * 1000 / total as u128) as usize
+    };
+    let filled = permille * BAR_WIDTH / 1000;
+    let bar = if filled == BAR_WIDTH {
+        "=".repeat(BAR_WIDTH)
+    } else {
+        format!(
+            "{}>{}",
+            "=".repeat(filled),
+            " ".repeat(BAR_WIDTH - filled - 1)
+        )
+    };
+    format!("[{bar}] {:>3}%", permille / 10)
+}
+
+/// The blocks fetched per second between the first and the last of `samples`, or `None`
+/// until they span enough time to tell.
+pub(super) fn rate(samples: &VecDeque<(Instant, usize)>) -> Option<f64> {
+    let ((first, first_fetched), (last, last_fetched)) = (samples.front()?, samples.back()?);
+    let elapsed = last.duration_since(*first);
+    if elapsed < REDRAW_INTERVAL {
+        return None;
+    }
+    Some(last_fetched.saturating_sub(*first_fetched) as f64 / elapsed.as_secs_f64())
+}
+
+/// How long fetching `remaining` more blocks takes at `rate` blocks per second, or `None`
+/// if nothing is being fetched.
+pub(super) fn eta(remaining: usize, rate: f64) -> Option<Duration> {
+    if rate <= 0.0 || !rate.is_finite() {
+        return None;
+    }
+    Some(Duration::from_secs_f64(remaining as f64 / rate))
+}
+
+/// Formats `eta` as `HH:MM:SS`, with as many digits for the hours as it takes.
+pub(super) fn format_eta(eta: Duration) -> String {
+    // Round up, so that the estimate only reaches zero when there is nothing left
+    let secs = eta.as_secs() + u64::from(eta.subsec_nanos() > 0);
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
@@ -0,0 +1,174 @@
+//! `quickcheck`, which tells whether a full check of a range is likely to find anything.
+
+use super::*;
+
+/// Quickly tells whether a full check of `range` is likely to find anything: the cache should
+/// hold exactly one block for every number in the range, the provider should have blocks up to
+/// the end of the range, and `sample_size` blocks spread evenly over the range should not
+/// diverge from the provider's. This changes nothing, and a quick check that passes is a reason
+/// to skip the full scan rather than proof that every block is fine.
+pub async fn quickcheck(
+    chain_store: Arc<ChainStore>,
+    ethereum_adapter: &dyn EthereumAdapterTrait,
+    range: &RangeSet,
+    logger: &Logger,
+    sample_size: usize,
+    options: &CheckOptions,
+) -> anyhow::Result<QuickcheckReport> {
+    steps::ensure_same_chain(&chain_store, ethereum_adapter, options).await?;
+    let range = steps::resolve_range_hashes(range, &chain_store).await?;
+    let block_numbers = range.block_numbers(
+        || steps::find_chain_head(&chain_store, options),
+        || steps::find_earliest_cached_block(&chain_store),
+    )?;
+    let target = format!(
+        "a sample of {sample_size} blocks of {}",
+        helpers::format_block_numbers(&block_numbers)
+    );
+    let manifest = steps::print_manifest(
+        &chain_store,
+        ethereum_adapter,
+        &target,
+        Some(&helpers::block_runs(&block_numbers)),
+        options,
+    )?;
+    let block_hashes =
+        steps::resolve_block_hashes_from_block_numbers(&block_numbers, &chain_store)?;
+
+    let mut report = QuickcheckReport {
+        expected: block_numbers.len(),
+        manifest: Some(manifest),
+        ..Default::default()
+    };
+    let mut present = Vec::new();
+    for (number, height) in helpers::classify_heights(&block_numbers, block_hashes) {
+        match height {
+            Height::Missing => report.missing.push(number),
+            Height::Present(hash) => present.push(CachedBlockRef { number, hash }),
+            Height::Ambiguous(_) => report.forked.push(number),
+        }
+    }
+    report.cached = present.len() + report.forked.len();
+    // The provider has a block for every number up to its head, which saves asking for each
+    report.provider_blocks = steps::fetch_provider_head(ethereum_adapter, logger, options)
+        .await
+        .map(|head| {
+            block_numbers
+                .iter()
+                .filter(|number| **number <= head)
+                .count()
+        });
+
+    report.sampled = helpers::spread_sample(present, sample_size);
+    let hashes: Vec<H256> = report.sampled.iter().map(|block| block.hash).collect();
+    let cached_blocks = steps::fetch_cached_blocks(&hashes, &chain_store, options)?;
+    let comparisons = compare_loaded_blocks(
+        hashes,
+        cached_blocks,
+        ethereum_adapter,
+        NetworkProfile::for_network(&chain_store.chain),
+        logger,
+        options,
+    )
+    .await;
+    for (block, comparison) in report.sampled.iter().zip(comparisons) {
+        match comparison {
+            Ok(compared) if compared.diverged() => report.diverged.push(*block),
+            Ok(_) => {}
+            Err(e) => report.failed.push((block.hash, format!("{e:#}"))),
+        }
+    }
+    Ok(report)
+}
+
+/// The outcome of [`quickcheck`].
+#[derive(Debug, Default, Serialize)]
+pub struct QuickcheckReport {
+    /// How many block numbers the range has.
+    pub expected: usize,
+    /// How many of them have at least one cached block.
+    pub cached: usize,
+    /// The block numbers without a cached block.
+    pub missing: Vec<i32>,
+    /// The block numbers with more than one cached block.
+    pub forked: Vec<i32>,
+    /// How many of the block numbers the provider has a block for, going by its latest block,
+    /// or `None` if the provider couldn't tell.
+    pub provider_blocks: Option<usize>,
+    /// The blocks that were compared with the provider.
+    pub sampled: Vec<CachedBlockRef>,
+    /// The sampled blocks that diverge from the provider's.
+    pub diverged: Vec<CachedBlockRef>,
+    /// The sampled blocks that could not be compared, with the error.
+    pub failed: Vec<(H256, String)>,
+    /// The manifest entry of the check, for the JSON output.
+    #[serde(skip)]
+    pub manifest: Option<Value>,
+}
+
+impl QuickcheckReport {
+    /// Whether nothing suggests that a full check of the range would find a problem.
+    pub fn passed(&self) -> bool {
+        self.missing.is_empty()
+            && self.forked.is_empty()
+            && self
+                .provider_blocks
+                .map_or(true, |blocks| blocks == self.expected)
+            && self.diverged.is_empty()
+            && self.failed.is_empty()
+    }
+
+    /// Prints the report in the format selected by `options.output`.
+    pub fn render(&self, options: &CheckOptions) -> anyhow::Result<()> {
+        match options.output {
+            OutputFormat::Human => {
+                println!(
+                    "{} of {} block numbers have a cached block: {} are missing, {} have more \
+                     than one.",
+                    self.cached,
+                    self.expected,
+                    self.missing.len(),
+                    self.forked.len()
+                );
+                if !self.missing.is_empty() {
+                    println!(
+                        "  missing: {}",
+                        helpers::sample_block_numbers(&self.missing)
+                    );
+                }
+                if !self.forked.is_empty() {
+                    println!("  forked: {}", helpers::sample_block_numbers(&self.forked));
+                }
+                match self.provider_blocks {
+                    Some(blocks) => {
+                        println!("The provider has {blocks} of the {} blocks.", self.expected)
+                    }
+                    None => println!("The provider couldn't tell how many of the blocks it has."),
+                }
+                for CachedBlockRef { number, hash } in &self.diverged {
+                    println!("block #{number} {hash:?} diverges from the provider");
+                }
+                for (hash, error) in &self.failed {
+                    println!("block {hash:?} could not be compared: {error}");
+                }
+                println!(
+                    "Compared {} sampled blocks: {} diverged, {} could not be compared.",
+                    self.sampled.len(),
+                    self.diverged.len(),
+                    self.failed.len()
+                );
+                if self.passed() {
+                    println!("The quick check passed, a full check is unlikely to find anything.");
+                } else {
+                    println!("The quick check failed, run a full check with `by-range`.");
+                }
+            }
+            OutputFormat::Json => {
+                let mut results = serde_json::to_value(self)?;
+                results["passed"] = self.passed().into();
+                helpers::print_json_envelope(self.manifest.as_ref(), results)?
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,350 @@
+//! Custom range type
+
+use super::helpers;
+use graph::prelude::anyhow::{self, bail, Context};
+use graph::prelude::web3::types::H256;
+use std::collections::HashMap;
+use std::env;
+use std::str::FromStr;
+
+/// The environment variable with the range expression that commands use when neither a
+/// range nor `--from` and `--to` are given, so that a templated command line can be
+/// parameterized from outside.
+pub const RANGE_VAR: &str = "GRAPHMAN_FIX_RANGE";
+
+/// One end of a range: a fixed block number, a block number relative to the chain head,
+/// which is only known when the block numbers are listed, or the number of a cached block,
+/// which has to be looked up with [`RangeSet::resolve_hashes`] first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Bound {
+    Number(i32),
+    /// The chain head plus this offset, which is never positive.
+    Head(i32),
+    /// The number of the cached block with this hash plus an offset, which is `-1` for an
+    /// exclusive upper bound and `0` otherwise.
+    Hash(H256, i32),
+}
+
+impl Bound {
+    fn resolve(self, chain_head: Option<i32>) -> Option<i32> {
+        match self {
+            Bound::Number(number) => Some(number),
+            Bound::Head(offset) => chain_head.map(|head| head.saturating_add(offset)),
+            // `RangeSet::bounds` refuses to work with unresolved hashes
+            Bound::Hash(..) => None,
+        }
+    }
+}
+
+/// A range of block numbers, inclusive on both ends. Open and head-relative bounds are
+/// resolved when the block numbers are listed: an open lower bound starts at the earliest
+/// cached block and an open upper bound ends at the chain head.
+#[derive(Clone, Debug)]
+pub(super) struct Range {
+    pub(super) lower_bound: Option<Bound>,
+    pub(super) upper_bound: Option<Bound>,
+}
+
+impl Range {
+    pub fn new(lower_bound: Option<i32>, upper_bound: Option<i32>) -> anyhow::Result<Self> {
+        Self::from_bounds(
+            lower_bound.map(Bound::Number),
+            upper_bound.map(Bound::Number),
+        )
+    }
+
+    fn from_bounds(lower_bound: Option<Bound>, upper_bound: Option<Bound>) -> anyhow::Result<Self> {
+        use Bound::*;
+
+        let (lower_bound, upper_bound) = match (lower_bound, upper_bound) {
+            // Invalid cases:
+            (None, None) => {
+                bail!(
+                    "This would wipe the whole cache. \
+                     Use `graphman chain truncate` instead"
+                )
+            }
+            (Some(Number(0)), _) => bail!("Genesis block can't be removed"),
+            (Some(Number(x)), _) | (_, Some(Number(x))) if x < 0 => {
+                bail!("Negative block number used as range bound: {}", x)
+            }
+            (Some(Number(lower)), Some(Number(upper))) if upper < lower => bail!(
+                "Upper bound ({}) can't be smaller than lower bound ({})",
+                upper,
+                lower
+            ),
+            (Some(Head(lower)), Some(Head(upper))) if upper < lower => bail!(
+                "Upper bound (head{:+}) can't be smaller than lower bound (head{:+})",
+                upper,
+                lower
+            ),
+
+            // Valid cases:
+            (lower, upper) => (lower, upper),
+        };
+
+        Ok(Self {
+            lower_bound,
+            upper_bound,
+        })
+    }
+
+    /// Parses a single segment of a range expression: a block number `n`, an exclusive range
+    /// `a..b` or an inclusive range `a..=b`, where either bound, but not both, can be left
+    /// open. Like a closed upper bound, the chain head is excluded by `a..` and included by
+    /// `a..=`. Instead of a number, a bound can be `head` or `head-N` for the block `N` blocks
+    /// below the chain head, or the hash of a cached block.
+    fn parse_segment(segment: &str) -> anyhow::Result<Self> {
+        let segment = segment.trim();
+        let (lower, upper, inclusive) = match segment.split_once("..") {
+            None => {
+                if segment.is_empty() {
+                    bail!("Empty range segment");
+                }
+                let bound = parse_bound(segment)?;
+                return Range::from_bounds(bound, bound);
+            }
+            Some((lower, upper)) => match upper.strip_prefix('=') {
+                Some(upper) => (lower, upper, true),
+                None => (lower, upper, false),
+            },
+        };
+        let (lower, upper) = match (parse_bound(lower)?, parse_bound(upper)?) {
+            (None, None) => bail!(
+                "`{segment}` has neither a lower nor an upper bound. \
+                 Use `graphman chain truncate` to drop the whole cache"
+            ),
+            // Any lower bound other than a number resolves to block 1 or above, since the
+            // genesis block is never part of a range
+            (None, Some(Bound::Number(0))) if inclusive => {
+                bail!("`{segment}` contains no blocks, the genesis block can't be removed")
+            }
+            (lower, Some(Bound::Number(upper))) if !inclusive => {
+                let lowest = match lower {
+                    Some(Bound::Number(lower)) => lower,
+                    _ => 1,
+                };
+                if upper <= lowest {
+                    bail!("`{segment}` contains no blocks, its upper bound is exclusive");
+                }
+                (lower, Some(Bound::Number(upper - 1)))
+            }
+            (lower, Some(Bound::Head(offset))) if !inclusive => {
+                (lower, Some(Bound::Head(offset.saturating_sub(1))))
+            }
+            (lower, Some(Bound::Hash(hash, _))) if !inclusive => {
+                (lower, Some(Bound::Hash(hash, -1)))
+            }
+            (lower, None) if !inclusive => (lower, Some(Bound::Head(-1))),
+            (lower, upper) => (lower, upper),
+        };
+        Range::from_bounds(lower, upper)
+    }
+}
+
+fn parse_bound(bound: &str) -> anyhow::Result<Option<Bound>> {
+    let bound = bound.trim();
+    if bound.is_empty() {
+        return Ok(None);
+    }
+    if let Some(offset) = bound.strip_prefix("head") {
+        let offset = offset.trim();
+        if offset.is_empty() {
+            return Ok(Some(Bound::Head(0)));
+        }
+        let distance = offset
+            .strip_prefix('-')
+            .and_then(|distance| distance.trim().parse::<u32>().ok())
+            .and_then(|distance| i32::try_from(distance).ok())
+            .with_context(|| format!("Invalid head-relative bound `{bound}`, expected `head-N`"))?;
+        return Ok(Some(Bound::Head(-distance)));
+    }
+    // Block numbers never have as many digits as a hash, so a hash needs no `0x` either
+    if bound.starts_with("0x") || bound.starts_with("0X") || bound.len() == 2 * H256::len_bytes() {
+        return helpers::parse_block_hash(bound).map(|hash| Some(Bound::Hash(hash, 0)));
+    }
+    bound
+        .parse()
+        .map(|number| Some(Bound::Number(number)))
+        .with_context(|| format!("Invalid block number `{bound}`"))
+}
+
+/// A union of block ranges, written as comma-separated segments like
+/// `100..200,5000..=5005,999999`.
+#[derive(Clone, Debug)]
+pub struct RangeSet {
+    ranges: Vec<Range>,
+}
+
+impl RangeSet {
+    /// A single range given by its (inclusive) bounds.
+    pub fn from_bounds(lower_bound: Option<i32>, upper_bound: Option<i32>) -> anyhow::Result<Self> {
+        Ok(Self {
+            ranges: vec![Range::new(lower_bound, upper_bound)?],
+        })
+    }
+
+    /// The range given on the command line, either as a range expression or by `--from` and
+    /// `--to`, or else the one in [`RANGE_VAR`]. Without either, this fails like
+    /// [`RangeSet::from_bounds`] does for a range without bounds.
+    pub fn from_args(
+        range: Option<RangeSet>,
+        lower_bound: Option<i32>,
+        upper_bound: Option<i32>,
+    ) -> anyhow::Result<Self> {
+        Self::from_args_or_env(
+            range,
+            lower_bound,
+            upper_bound,
+            env::var(RANGE_VAR).ok().as_deref(),
+        )
+    }
+
+    pub(super) fn from_args_or_env(
+        range: Option<RangeSet>,
+        lower_bound: Option<i32>,
+        upper_bound: Option<i32>,
+        env_range: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        match (range, lower_bound, upper_bound, env_range) {
+            (Some(range), _, _, _) => Ok(range),
+            (None, None, None, Some(expr)) => expr
+                .parse()
+                .with_context(|| format!("invalid range `{expr}` in {RANGE_VAR}")),
+            (None, lower_bound, upper_bound, _) => Self::from_bounds(lower_bound, upper_bound),
+        }
+    }
+
+    /// The block hashes used as bounds, which [`RangeSet::resolve_hashes`] needs the numbers
+    /// of.
+    pub(super) fn hashes(&self) -> Vec<H256> {
+        let mut hashes: Vec<H256> = self
+            .ranges
+            .iter()
+            .flat_map(|range| [range.lower_bound, range.upper_bound])
+            .filter_map(|bound| match bound {
+                Some(Bound::Hash(hash, _)) => Some(hash),
+                _ => None,
+            })
+            .collect();
+        hashes.sort_unstable();
+        hashes.dedup();
+        hashes
+    }
+
+    /// Replaces the block hashes used as bounds with the block numbers in `numbers`, which
+    /// has to have all of [`RangeSet::hashes`]. Ranges are checked again once their bounds
+    /// are numbers, so that ranges whose upper bound lies below their lower bound are
+    /// rejected.
+    pub(super) fn resolve_hashes(&self, numbers: &HashMap<H256, i32>) -> anyhow::Result<Self> {
+        let resolve = |bound: Option<Bound>| match bound {
+            Some(Bound::Hash(hash, offset)) => numbers
+                .get(&hash)
+                .map(|number| Some(Bound::Number(number + offset)))
+                .with_context(|| format!("No block number for block hash {hash:?}")),
+            bound => Ok(bound),
+        };
+        let ranges = self
+            .ranges
+            .iter()
+            .map(|range| {
+                Range::from_bounds(resolve(range.lower_bound)?, resolve(range.upper_bound)?)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self { ranges })
+    }
+
+    /// Returns all block numbers in the set, sorted and without duplicates. Open bounds are
+    /// resolved like [`RangeSet::bounds`] does.
+    pub(super) fn block_numbers(
+        &self,
+        chain_head: impl FnOnce() -> anyhow::Result<i32>,
+        earliest_block: impl FnOnce() -> anyhow::Result<Option<i32>>,
+    ) -> anyhow::Result<Vec<i32>> {
+        Ok(self
+            .bounds(chain_head, earliest_block)?
+            .into_iter()
+            .flat_map(|(lower, upper)| lower..=upper)
+            .collect())
+    }
+
+    /// Returns the inclusive bounds of the ranges in the set, sorted, with overlapping and
+    /// adjacent ranges merged, and without empty ranges.
+    ///
+    /// `chain_head` is only called if some range has an open or head-relative bound; open
+    /// upper bounds extend up to the chain head. Likewise, `earliest_block` is only called for
+    /// open and head-relative lower bounds. Open lower bounds start at the earliest cached
+    /// block, but never at the genesis block, and head-relative ones never start below that.
+    /// If the cache is empty, ranges with an open lower bound are empty, too.
+    pub(super) fn bounds(
+        &self,
+        chain_head: impl FnOnce() -> anyhow::Result<i32>,
+        earliest_block: impl FnOnce() -> anyhow::Result<Option<i32>>,
+    ) -> anyhow::Result<Vec<(i32, i32)>> {
+        if !self.hashes().is_empty() {
+            bail!("The block hashes in the range have to be resolved to block numbers first");
+        }
+        let needs_head = |bound: &Option<Bound>| !matches!(bound, Some(Bound::Number(_)));
+        let chain_head = if self.ranges.iter().any(|range| {
+            needs_head(&range.upper_bound) || matches!(range.lower_bound, Some(Bound::Head(_)))
+        }) {
+            Some(chain_head()?)
+        } else {
+            None
+        };
+        let earliest_block = if self
+            .ranges
+            .iter()
+            .any(|range| needs_head(&range.lower_bound))
+        {
+            earliest_block()?.map(|number| number.max(1))
+        } else {
+            None
+        };
+        let mut bounds: Vec<(i32, i32)> = self
+            .ranges
+            .iter()
+            .filter_map(|range| {
+                let lower = match range.lower_bound {
+                    Some(Bound::Number(number)) => number,
+                    Some(bound) => bound.resolve(chain_head)?.max(earliest_block.unwrap_or(1)),
+                    None => earliest_block?,
+                };
+                let upper = match range.upper_bound {
+                    Some(bound) => bound.resolve(chain_head)?,
+                    None => chain_head?,
+                };
+                Some((lower, upper))
+            })
+            .filter(|(lower, upper)| lower <= upper)
+            .collect();
+
+        // Merge overlapping and adjacent ranges so no block number appears twice
+        bounds.sort_unstable();
+        let mut merged: Vec<(i32, i32)> = Vec::with_capacity(bounds.len());
+        for (lower, upper) in bounds {
+            match merged.last_mut() {
+                Some((_, last_upper)) if lower <= last_upper.saturating_add(1) => {
+                    *last_upper = (*last_upper).max(upper)
+                }
+                _ => merged.push((lower, upper)),
+            }
+        }
+        Ok(merged)
+    }
+}
+
+impl FromStr for RangeSet {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ranges = s
+            .split(',')
+            .map(|segment| {
+                Range::parse_segment(segment)
+                    .with_context(|| format!("Invalid range segment `{}`", segment.trim()))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self { ranges })
+    }
+}
@@ -0,0 +1,283 @@
+//! The `receipts` check, of the receipts cached with the blocks in a range.
+
+use super::*;
+
+/// Compares the transaction receipts cached with the blocks in `range` with the provider's
+/// receipts for the same transactions. Only the fields that the cache keeps of a receipt are
+/// compared: the transaction index, the block it belongs to, the gas used and the status.
+///
+/// Receipts are cached as part of their block, so a block whose receipts diverge is deleted, or
+/// with `options.replace` replaced with the provider's block and receipts, after the same
+/// confirmation and within the same `--max-deletions` as diverged blocks. Blocks that are
+/// cached without receipts are skipped, and blocks that the provider doesn't have are only
+/// reported, since checking them `by-range` takes care of them.
+pub async fn check_receipts(
+    chain_store: Arc<ChainStore>,
+    ethereum_adapter: &dyn EthereumAdapterTrait,
+    range: &RangeSet,
+    logger: &Logger,
+    options: &CheckOptions,
+) -> anyhow::Result<ReceiptsReport> {
+    ensure!(options.concurrency > 0, "Concurrency must be at least 1");
+    steps::ensure_same_chain(&chain_store, ethereum_adapter, options).await?;
+    let range = steps::resolve_range_hashes(range, &chain_store).await?;
+    let block_numbers = range.block_numbers(
+        || steps::find_chain_head(&chain_store, options),
+        || steps::find_earliest_cached_block(&chain_store),
+    )?;
+    let block_hashes =
+        steps::resolve_block_hashes_from_block_numbers(&block_numbers, &chain_store)?;
+    let blocks: Vec<CachedBlockRef> = block_numbers
+        .iter()
+        .flat_map(|number| {
+            let hashes = block_hashes.get(number).into_iter().flatten();
+            hashes.map(move |hash| CachedBlockRef {
+                number: *number,
+                hash: *hash,
+            })
+        })
+        .collect();
+    let target = format!(
+        "the receipts of blocks {}",
+        helpers::format_block_numbers(&block_numbers)
+    );
+    let manifest = steps::print_manifest(
+        &chain_store,
+        ethereum_adapter,
+        &target,
+        Some(&helpers::block_runs(&block_numbers)),
+        options,
+    )?;
+
+    let mut report = ReceiptsReport {
+        manifest: Some(manifest),
+        ..Default::default()
+    };
+    let chain_store = &chain_store;
+    let mut provider_blocks = compare_block_receipts(
+        &blocks,
+        move |hash| async move {
+            chain_store
+                .transaction_receipts_in_block(&hash)
+                .await
+                .with_context(|| format!("failed to load the cached receipts for block {hash:?}"))
+        },
+        ethereum_adapter,
+        logger,
+        options,
+        &mut report,
+    )
+    .await?;
+
+    let mut confirmed = None;
+    let numbers: Vec<i32> = report
+        .diverged
+        .iter()
+        .map(|divergence| divergence.number)
+        .collect();
+    if report.diverged.is_empty()
+        || !steps::confirm_deletion(
+            report.diverged.len(),
+            &numbers,
+            report.checked,
+            None,
+            chain_store,
+            options,
+            &mut confirmed,
+        )?
+    {
+        return Ok(report);
+    }
+    steps::enforce_max_deletions(0, report.diverged.len(), options)?;
+    for divergence in &report.diverged {
+        let deletion = steps::Deletion {
+            number: Some(divergence.number),
+            reason: "receipts diverged from the provider",
+            diff: Some(&divergence.diff),
+            compared: None,
+        };
+        match provider_blocks.remove(&divergence.hash) {
+            Some(provider_block) => {
+                steps::replace_full_block(provider_block, chain_store, options, &deletion).await?;
+                report.fixed.push(divergence.hash);
+            }
+            None => match steps::delete_block(&divergence.hash, chain_store, options, deletion)? {
+                steps::DeleteOutcome::Deleted => report.fixed.push(divergence.hash),
+                // Only blocks that are deleted with the digest they were compared with can race
+                steps::DeleteOutcome::Raced => {}
+                steps::DeleteOutcome::Unverified(problem) => {
+                    report.unverified_deletes.push((divergence.hash, problem))
+                }
+            },
+        }
+    }
+    Ok(report)
+}
+
+/// Compares the receipts of each of `blocks`, as `load_cached` loads them from the cache, with
+/// the provider's, and records what it finds in `report`. Returns the provider's blocks with
+/// their receipts for the diverged blocks in `--replace` mode.
+///
+/// A block that the provider fails on is recorded in `report.fetch_failed` and doesn't stop the
+/// comparison of the others, while failing to load cached receipts does. Once
+/// `options.interrupt` is set, the remaining blocks are left alone.
+pub(super) async fn compare_block_receipts<Load, Cached>(
+    blocks: &[CachedBlockRef],
+    load_cached: Load,
+    ethereum_adapter: &dyn EthereumAdapterTrait,
+    logger: &Logger,
+    options: &CheckOptions,
+    report: &mut ReceiptsReport,
+) -> anyhow::Result<HashMap<H256, EthereumBlock>>
+where
+    Load: Fn(H256) -> Cached,
+    Cached: Future<Output = anyhow::Result<Vec<LightTransactionReceipt>>>,
+{
+    let mut provider_blocks = HashMap::new();
+    let progress = Progress::hidden();
+    let (load_cached, progress) = (&load_cached, &progress);
+    let mut comparisons = stream::iter(blocks)
+        .map(|block| async move {
+            let cached = load_cached(block.hash).await?;
+            let comparison = steps::compare_receipts(
+                &block.hash,
+                cached,
+                ethereum_adapter,
+                logger,
+                options,
+                progress,
+            )
+            .await;
+            anyhow::Ok((block, comparison))
+        })
+        .buffered(options.concurrency);
+    let mut done = 0;
+    while let Some(compared) = comparisons.next().await {
+        if options.interrupt.is_set() {
+            report.interrupted = Some(blocks.len() - done);
+            break;
+        }
+        done += 1;
+        let (block, comparison) = compared?;
+        let CachedBlockRef { number, hash } = *block;
+        let comparison = match comparison {
+            Ok(comparison) => comparison,
+            Err(e) => {
+                report.fetch_failed.push((hash, format!("{e:#}")));
+                continue;
+            }
+        };
+        match comparison {
+            steps::ReceiptComparison::Uncached => report.uncached += 1,
+            steps::ReceiptComparison::Missing => {
+                report.missing.push(CachedBlockRef { number, hash })
+            }
+            steps::ReceiptComparison::Same => report.checked += 1,
+            steps::ReceiptComparison::Diverged {
+                diff,
+                provider_block,
+            } => {
+                report.checked += 1;
+                report.diverged.push(ReceiptDivergence {
+                    number,
+                    hash,
+                    diff_text: steps::plain_diff(&diff, options),
+                    diff,
+                });
+                if let Some(provider_block) = provider_block {
+                    provider_blocks.insert(hash, provider_block);
+                }
+            }
+        }
+    }
+    Ok(provider_blocks)
+}
+
+/// A block whose cached receipts differ from the provider's.
+#[derive(Debug, Serialize)]
+pub struct ReceiptDivergence {
+    pub number: i32,
+    pub hash: H256,
+    /// The structural difference from the cached receipts to the provider's, keyed by the hash
+    /// of their transaction.
+    pub diff: Value,
+    /// The `diff` rendered like it is printed, but never colorized or cut off.
+    pub diff_text: String,
+}
+
+/// What comparing cached receipts with the provider's found and did, see [`check_receipts`].
+#[derive(Debug, Default, Serialize)]
+pub struct ReceiptsReport {
+    /// How many blocks had their receipts compared.
+    #[serde(skip)]
+    pub checked: usize,
+    pub diverged: Vec<ReceiptDivergence>,
+    /// The blocks that the provider doesn't have, so that their receipts couldn't be compared.
+    pub missing: Vec<CachedBlockRef>,
+    /// How many blocks are cached without receipts, and weren't compared.
+    #[serde(skip)]
+    pub uncached: usize,
+    /// The blocks whose receipts couldn't be fetched from the provider, with the error.
+    pub fetch_failed: Vec<(H256, String)>,
+    /// If the check was interrupted, how many blocks were left uncompared.
+    pub interrupted: Option<usize>,
+    /// The blocks that were deleted or replaced, or that would have been in a dry run.
+    #[serde(skip)]
+    pub fixed: Vec<H256>,
+    /// The blocks that `verify_deletes` found were not actually removed by deleting them, with
+    /// what went wrong.
+    pub unverified_deletes: Vec<(H256, String)>,
+    /// The manifest entry of the check, for the JSON output.
+    #[serde(skip)]
+    pub manifest: Option<Value>,
+}
+
+impl ReceiptsReport {
+    /// Prints the report in the format selected by `options.output`.
+    pub fn render(&self, options: &CheckOptions) -> anyhow::Result<()> {
+        if let Some(left) = self.interrupted {
+            eprintln!("The check was interrupted and the receipts of {left} blocks were left");
+        }
+        match options.output {
+            OutputFormat::Human => {
+                for ReceiptDivergence {
+                    number, hash, diff, ..
+                } in &self.diverged
+                {
+                    println!("block {number} {hash:?} has receipts that diverge:");
+                    println!("{}", steps::render_diff(diff, &options.diff_render));
+                }
+                for CachedBlockRef { number, hash } in &self.missing {
+                    println!("block {number} {hash:?} is not known to the provider");
+                }
+                let fixed = match (options.dry_run, options.replace) {
+                    (true, _) => "would have been fixed",
+                    (false, true) => "replaced",
+                    (false, false) => "deleted",
+                };
+                println!(
+                    "Compared the receipts of {} blocks: {} diverge, {} {fixed}. {} blocks are \
+                     not known to the provider, {} have no cached receipts.",
+                    self.checked,
+                    self.diverged.len(),
+                    self.fixed.len(),
+                    self.missing.len(),
+                    self.uncached
+                );
+                if !self.fetch_failed.is_empty() {
+                    println!(
+                        "The receipts of {} blocks could not be fetched from the provider and \
+                         were not compared:",
+                        self.fetch_failed.len()
+                    );
+                    for (hash, error) in &self.fetch_failed {
+                        println!("  {hash:?}: {error}");
+                    }
+                }
+                print_unverified_deletes(&self.unverified_deletes);
+            }
+            OutputFormat::Json => helpers::print_json_envelope(self.manifest.as_ref(), self)?,
+        }
+        Ok(())
+    }
+}
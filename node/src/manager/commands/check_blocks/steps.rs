@@ -0,0 +1,1844 @@
+use super::*;
+
+use futures::compat::Future01CompatExt;
+use futures::{Future, Stream};
+use graph::prelude::chrono::{SecondsFormat, Utc};
+use graph::prelude::transaction_receipt::LightTransactionReceipt;
+use graph::prelude::{tokio, web3, EthereumBlock, EthereumBlockWithCalls, LightEthereumBlock};
+use graph_chain_ethereum::chain::BlockFinality;
+use json_structural_diff::{colorize as diff_to_string, JsonDiff};
+
+/// Queries the [`ChainStore`] about the block hash for the given block number.
+///
+/// Multiple block hashes can be returned as the store does not enforce uniqueness based on
+/// block numbers.
+/// Returns an empty vector if no block hash is found.
+pub(super) fn resolve_block_hash_from_block_number(
+    number: i32,
+    chain_store: &ChainStore,
+) -> anyhow::Result<Vec<H256>> {
+    let block_hashes = chain_store.block_hashes_by_block_number(number)?;
+    block_hashes
+        .iter()
+        .map(helpers::cached_block_hash)
+        .collect()
+}
+
+/// Looks up the numbers of the cached blocks whose hashes `range` uses as bounds, and
+/// returns the range with these numbers in their place.
+pub(super) async fn resolve_range_hashes(
+    range: &RangeSet,
+    chain_store: &ChainStore,
+) -> anyhow::Result<RangeSet> {
+    let mut numbers = HashMap::new();
+    for hash in range.hashes() {
+        let number = chain_store
+            .block_number(&hash.into())
+            .await?
+            .map(|(_, number, _)| number)
+            .ok_or(CheckBlocksError::BlockNotCached { hash })
+            .with_context(|| {
+                format!(
+                    "Can't resolve block hash {hash:?} in the range for {}",
+                    chain_store.chain
+                )
+            })?;
+        numbers.insert(hash, number);
+    }
+    range.resolve_hashes(&numbers)
+}
+
+/// Queries the [`ChainStore`] about the block hashes for all the given block numbers at once.
+///
+/// Every block number is present in the returned map, with an empty vector if no block hash
+/// was found for it.
+pub(super) fn resolve_block_hashes_from_block_numbers(
+    numbers: &[i32],
+    chain_store: &ChainStore,
+) -> anyhow::Result<HashMap<i32, Vec<H256>>> {
+    let block_hashes = chain_store.block_hashes_by_block_numbers(numbers)?;
+    block_hashes
+        .into_iter()
+        .map(|(number, hashes)| {
+            let hashes = hashes
+                .iter()
+                .map(helpers::cached_block_hash)
+                .collect::<anyhow::Result<_>>()?;
+            Ok((number, hashes))
+        })
+        .collect()
+}
+
+/// What tells apart cached blocks that have the same hash.
+struct CachedBlockSummary {
+    number: Option<i32>,
+    parent_hash: Option<String>,
+}
+
+impl CachedBlockSummary {
+    fn new(block: &Value) -> Self {
+        CachedBlockSummary {
+            number: helpers::block_number(block),
+            parent_hash: block["parentHash"].as_str().map(str::to_string),
+        }
+    }
+}
+
+impl fmt::Debug for CachedBlockSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.number {
+            Some(number) => write!(f, "block {number}")?,
+            None => write!(f, "block without a number")?,
+        }
+        match &self.parent_hash {
+            Some(parent_hash) => write!(f, " with parent {parent_hash}"),
+            None => write!(f, " without a parent hash"),
+        }
+    }
+}
+
+/// The number of the provider's latest block. Failing to get it is reported, but otherwise
+/// treated as not knowing, since it is only used to tell how many blocks the provider has.
+pub(super) async fn fetch_provider_head(
+    ethereum_adapter: &dyn EthereumAdapterTrait,
+    logger: &Logger,
+    options: &CheckOptions,
+) -> Option<i32> {
+    let latest = request_retried_by_adapter(
+        "the provider's latest block".to_string(),
+        options,
+        &Progress::hidden(),
+        || async {
+            let latest = ethereum_adapter.latest_block_header(logger).compat().await;
+            latest.map_err(anyhow::Error::from)
+        },
+    )
+    .await;
+    match latest {
+        Ok(latest) => latest
+            .number
+            .map(|number| number.as_u64().min(i32::MAX as u64) as i32),
+        Err(e) => {
+            eprintln!("Could not get the provider's latest block: {e:#}");
+            None
+        }
+    }
+}
+
+/// How many blocks `chain_head` is behind the provider's latest block, if that's more than
+/// [`HEAD_LAG_WARNING_THRESHOLD`]. This is only a warning, so failing to get the latest block
+/// is reported but otherwise treated as no lag.
+pub(super) async fn chain_head_lag(
+    chain_head: i32,
+    ethereum_adapter: &dyn EthereumAdapterTrait,
+    logger: &Logger,
+    options: &CheckOptions,
+) -> Option<i32> {
+    fetch_provider_head(ethereum_adapter, logger, options)
+        .await
+        .map(|latest| latest.saturating_sub(chain_head))
+        .filter(|lag| *lag > HEAD_LAG_WARNING_THRESHOLD)
+}
+
+/// The one block in `blocks`, which are all the blocks cached under `block_hash`.
+///
+/// Errors on a non-unary result.
+fn single_cached_block(block_hash: H256, blocks: Vec<Value>) -> anyhow::Result<Value> {
+    match blocks.len() {
+        0 => return Err(CheckBlocksError::BlockNotCached { hash: block_hash }.into()),
+        1 => {}
+        count => {
+            let found: Vec<_> = blocks.iter().map(CachedBlockSummary::new).collect();
+            return Err(CheckBlocksError::AmbiguousBlock {
+                hash: block_hash,
+                count,
+                found: helpers::first_few(&found),
+            }
+            .into());
+        }
+    };
+    // Unwrap: We just checked that the vector has a single element
+    Ok(blocks.into_iter().next().unwrap())
+}
+
+/// Loads the cached blocks for `block_hashes` in the same order, with one query for all of them.
+/// With `options.db_concurrency`, the hashes are split between that many threads that each use
+/// their own connection and run one query, but one connection of the pool is always left for
+/// others.
+pub(super) fn fetch_cached_blocks(
+    block_hashes: &[H256],
+    chain_store: &ChainStore,
+    options: &CheckOptions,
+) -> anyhow::Result<Vec<Value>> {
+    let workers = match options.db_concurrency {
+        0 | 1 => 1,
+        db_concurrency => {
+            let pool_size = chain_store.connection_pool_size()?;
+            db_concurrency.min(pool_size.saturating_sub(1)).max(1)
+        }
+    };
+    fetch_cached_blocks_with(block_hashes, workers, |block_hashes| {
+        let block_hashes: Vec<_> = block_hashes.iter().map(|hash| (*hash).into()).collect();
+        chain_store
+            .blocks_by_hashes(&block_hashes)?
+            .into_iter()
+            .map(|(hash, block)| Ok((helpers::cached_block_hash(&hash)?, block)))
+            .collect()
+    })
+}
+
+/// Splits `block_hashes` between `workers` threads that each load the cached blocks for
+/// their share with a single call to `fetch`, which returns the blocks it found together
+/// with the hash they are cached under. The blocks are put back in the order of
+/// `block_hashes`, with an error for any hash that has no block or more than one.
+pub(super) fn fetch_cached_blocks_with<F>(
+    block_hashes: &[H256],
+    workers: usize,
+    fetch: F,
+) -> anyhow::Result<Vec<Value>>
+where
+    F: Fn(&[H256]) -> anyhow::Result<Vec<(H256, Value)>> + Sync,
+{
+    let fetch = |block_hashes: &[H256]| {
+        let mut blocks_by_hash: HashMap<H256, Vec<Value>> = HashMap::new();
+        for (hash, block) in fetch(block_hashes)? {
+            blocks_by_hash.entry(hash).or_default().push(block);
+        }
+        block_hashes
+            .iter()
+            .map(|hash| {
+                let blocks = blocks_by_hash.remove(hash).unwrap_or_default();
+                single_cached_block(*hash, blocks)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()
+    };
+    if workers <= 1 || block_hashes.len() < 2 {
+        return fetch(block_hashes);
+    }
+    let per_worker = (block_hashes.len() + workers - 1) / workers;
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = block_hashes
+            .chunks(per_worker)
+            .map(|block_hashes| scope.spawn(move || fetch(block_hashes)))
+            .collect();
+        let mut cached_blocks = Vec::with_capacity(block_hashes.len());
+        for handle in handles {
+            let blocks = handle
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic))?;
+            cached_blocks.extend(blocks);
+        }
+        Ok(cached_blocks)
+    })
+}
+
+/// Fetches blocks from a JRPC endpoint, keeping up to `options.concurrency` requests in
+/// flight. With `options.rpc_batch_size`, the blocks are requested in batches through
+/// [`fetch_provider_blocks_batched`], unless the adapter can't send batch requests.
+///
+/// The stream yields the blocks in the same order as `block_hashes`, together with how long
+/// each of them took to arrive, and `progress` is advanced as soon as each of them arrives.
+pub(super) fn fetch_provider_blocks<'a>(
+    block_hashes: &'a [H256],
+    ethereum_adapter: &'a dyn EthereumAdapterTrait,
+    logger: &'a Logger,
+    options: &'a CheckOptions,
+    progress: &'a Progress,
+) -> impl Stream<Item = anyhow::Result<(Value, Duration)>> + 'a {
+    if let Some(dir) = &options.provider_fixture {
+        return fetch_fixture_blocks(block_hashes, dir, progress).left_stream();
+    }
+    let blocks = match options.rpc_batch_size {
+        Some(batch_size) if batch_size > 1 && ethereum_adapter.supports_batch_requests() => {
+            fetch_provider_blocks_batched(
+                block_hashes,
+                batch_size,
+                ethereum_adapter,
+                logger,
+                options,
+                progress,
+            )
+            .left_stream()
+        }
+        _ => fetch_provider_blocks_one_by_one(
+            block_hashes,
+            ethereum_adapter,
+            logger,
+            options,
+            progress,
+        )
+        .right_stream(),
+    };
+    blocks.right_stream()
+}
+
+/// Reads blocks like [`fetch_provider_blocks`] from the provider fixture `dir` rather than
+/// from a JRPC endpoint. The blocks are checked like the provider's would be, so a fixture
+/// for the wrong block is reported as provider misbehavior.
+fn fetch_fixture_blocks<'a>(
+    block_hashes: &'a [H256],
+    dir: &'a Path,
+    progress: &'a Progress,
+) -> impl Stream<Item = anyhow::Result<(Value, Duration)>> + 'a {
+    stream::iter(block_hashes).map(move |block_hash| {
+        let started = Instant::now();
+        let block = helpers::read_fixture_block(dir, block_hash)
+            .and_then(|block| provider_block_value(block_hash, Some(block)));
+        progress.block_fetched();
+        block.map(|block| (block, started.elapsed()))
+    })
+}
+
+/// Fetches blocks like [`fetch_provider_blocks`], with one request per block.
+fn fetch_provider_blocks_one_by_one<'a>(
+    block_hashes: &'a [H256],
+    ethereum_adapter: &'a dyn EthereumAdapterTrait,
+    logger: &'a Logger,
+    options: &'a CheckOptions,
+    progress: &'a Progress,
+) -> impl Stream<Item = anyhow::Result<(Value, Duration)>> + 'a {
+    stream::iter(block_hashes)
+        .map(move |block_hash| async move {
+            let started = Instant::now();
+            let block = fetch_single_provider_block(
+                block_hash,
+                ethereum_adapter,
+                logger,
+                options,
+                progress,
+            )
+            .await;
+            progress.block_fetched();
+            block.map(|block| (block, started.elapsed()))
+        })
+        .buffered(options.concurrency)
+}
+
+/// Fetches blocks like [`fetch_provider_blocks`], with one JSON-RPC batch request for every
+/// `batch_size` blocks. Up to `options.concurrency` batches are in flight, and a batch that
+/// fails is retried as a whole.
+///
+/// The answers are matched with the requested blocks by their hash, so it doesn't matter in
+/// which order the provider returns them. Every block of a batch took as long to arrive as
+/// the batch.
+fn fetch_provider_blocks_batched<'a>(
+    block_hashes: &'a [H256],
+    batch_size: usize,
+    ethereum_adapter: &'a dyn EthereumAdapterTrait,
+    logger: &'a Logger,
+    options: &'a CheckOptions,
+    progress: &'a Progress,
+) -> impl Stream<Item = anyhow::Result<(Value, Duration)>> + 'a {
+    stream::iter(block_hashes.chunks(batch_size))
+        .map(move |batch| async move {
+            let started = Instant::now();
+            let blocks = retry_provider_requests(
+                format!("a batch of {} blocks from {:?}", batch.len(), batch[0]),
+                batch.len(),
+                options.max_attempts,
+                options,
+                progress,
+                || ethereum_adapter.blocks_by_hashes_batched(logger, batch),
+            )
+            .await;
+            let latency = started.elapsed();
+            for _ in batch {
+                progress.block_fetched();
+            }
+            let results: Vec<_> = match blocks {
+                Ok(blocks) => {
+                    let (blocks, strays) = helpers::match_batched_blocks(batch, blocks);
+                    if !strays.is_empty() {
+                        progress.suspend(|| {
+                            eprintln!(
+                                "The provider returned {} blocks that the batch request from \
+                                 block {:?} did not ask for: {}",
+                                strays.len(),
+                                batch[0],
+                                helpers::first_few(&strays)
+                            )
+                        });
+                    }
+                    blocks
+                        .into_iter()
+                        .zip(batch)
+                        .map(|(block, block_hash)| {
+                            provider_block_value(block_hash, block?).map(|block| (block, latency))
+                        })
+                        .collect()
+                }
+                // Every block of the batch is reported as failed, each with the batch's cause
+                Err(e) => {
+                    let cause = format!("{e:#}");
+                    batch
+                        .iter()
+                        .map(|block_hash| {
+                            Err(anyhow!(
+                                "the batch request for block {block_hash:?} failed: {cause}"
+                            ))
+                        })
+                        .collect()
+                }
+            };
+            stream::iter(results)
+        })
+        .buffered(options.concurrency)
+        .flatten()
+}
+
+/// Fetches a block from a JRPC endpoint.
+///
+/// Errors on provider failure, and with [`CheckBlocksError::ProviderMisbehavior`] if the
+/// returned block has a different hash than the one requested.
+async fn fetch_single_provider_block(
+    block_hash: &H256,
+    ethereum_adapter: &dyn EthereumAdapterTrait,
+    logger: &Logger,
+    options: &CheckOptions,
+    progress: &Progress,
+) -> anyhow::Result<Value> {
+    let provider_block =
+        request_provider_block(block_hash, ethereum_adapter, logger, options, progress).await?;
+    provider_block_value(block_hash, provider_block)
+}
+
+/// Turns the provider's answer to the request for the block with `block_hash` into the JSON
+/// value that is compared with the cached block.
+///
+/// Errors if the provider doesn't have the block, and with
+/// [`CheckBlocksError::ProviderMisbehavior`] if it returned a block with another hash.
+fn provider_block_value(
+    block_hash: &H256,
+    provider_block: Option<LightEthereumBlock>,
+) -> anyhow::Result<Value> {
+    let provider_block =
+        provider_block.ok_or(CheckBlocksError::ProviderMissingBlock { hash: *block_hash })?;
+    if provider_block.hash != Some(*block_hash) {
+        return Err(CheckBlocksError::ProviderMisbehavior {
+            block_hash: *block_hash,
+            returned_hash: provider_block.hash,
+        }
+        .into());
+    }
+    serde_json::to_value(provider_block).context("failed to parse provider block as a JSON value")
+}
+
+/// Requests a block from a JRPC endpoint, retrying transient failures with an exponential
+/// backoff. The adapter makes a single attempt per call, so that only this retries.
+///
+/// A provider that doesn't know the block is not a failure and is not retried.
+async fn request_provider_block(
+    block_hash: &H256,
+    ethereum_adapter: &dyn EthereumAdapterTrait,
+    logger: &Logger,
+    options: &CheckOptions,
+    progress: &Progress,
+) -> anyhow::Result<Option<LightEthereumBlock>> {
+    retry_provider_request(format!("block {block_hash}"), options, progress, || {
+        ethereum_adapter.block_by_hash_once(logger, *block_hash)
+    })
+    .await
+}
+
+/// Fetches the blocks at `block_numbers` from a JRPC endpoint, keeping up to
+/// `options.concurrency` requests in flight. The stream yields the blocks in the same order as
+/// `block_numbers`, or `None` where the provider has no block.
+pub(super) fn fetch_provider_blocks_by_number<'a>(
+    block_numbers: &'a [i32],
+    ethereum_adapter: &'a dyn EthereumAdapterTrait,
+    logger: &'a Logger,
+    options: &'a CheckOptions,
+) -> impl Stream<Item = (i32, anyhow::Result<Option<LightEthereumBlock>>)> + 'a {
+    stream::iter(block_numbers)
+        .map(move |number| async move {
+            let block = retry_provider_request(
+                format!("block number {number}"),
+                options,
+                &Progress::hidden(),
+                || ethereum_adapter.block_by_number_once(logger, *number),
+            )
+            .await;
+            (*number, block)
+        })
+        .buffered(options.concurrency)
+}
+
+/// Fails unless the provider serves the same chain as `chain_store`, going by the net version
+/// and the genesis block hash. Comparing the cache with the provider of another chain would
+/// make every block diverge, and deleting them all is hard to undo.
+///
+/// A provider fixture has no chain identifier to go by, so checks with one have to be dry
+/// runs.
+pub(super) async fn ensure_same_chain(
+    chain_store: &ChainStore,
+    ethereum_adapter: &dyn EthereumAdapterTrait,
+    options: &CheckOptions,
+) -> anyhow::Result<()> {
+    if options.provider_fixture.is_some() {
+        ensure!(
+            options.dry_run,
+            "A provider fixture can't be checked against the chain, so checks with one have \
+             to be dry runs"
+        );
+        return Ok(());
+    }
+    let provider = ethereum_adapter.provider();
+    let identifier = request_retried_by_adapter(
+        format!("the chain identifier of provider `{provider}`"),
+        options,
+        &Progress::hidden(),
+        || ethereum_adapter.net_identifiers(),
+    )
+    .await?;
+    if let Some(mismatch) = helpers::chain_mismatch(&chain_store.chain_identifier, &identifier) {
+        bail!(
+            "Provider `{provider}` is not for chain {}: {mismatch}. Refusing to compare \
+             blocks with it",
+            chain_store.chain
+        );
+    }
+    Ok(())
+}
+
+/// Asks a JRPC endpoint for the hash of its canonical block at `block_number`. The adapter
+/// retries the request on its own.
+pub(super) async fn fetch_canonical_block_hash(
+    block_number: i32,
+    ethereum_adapter: &dyn EthereumAdapterTrait,
+    logger: &Logger,
+    options: &CheckOptions,
+) -> anyhow::Result<Option<H256>> {
+    request_retried_by_adapter(
+        format!("the hash of block number {block_number}"),
+        options,
+        &Progress::hidden(),
+        || {
+            ethereum_adapter
+                .block_hash_by_block_number(logger, block_number)
+                .compat()
+        },
+    )
+    .await
+}
+
+/// Runs `request` until it succeeds, it fails with an error that is not transient, or
+/// `options.max_attempts` is reached. The delay between attempts starts at
+/// `options.retry_delay` and doubles every time.
+///
+/// An attempt that takes longer than `options.rpc_timeout` counts as a transient failure.
+/// The timed out request is dropped, which cancels the underlying futures 0.1 request since
+/// `.compat()` doesn't spawn it.
+async fn retry_provider_request<T, F, Fut>(
+    what: String,
+    options: &CheckOptions,
+    progress: &Progress,
+    request: F,
+) -> anyhow::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    retry_provider_requests(what, 1, options.max_attempts, options, progress, request).await
+}
+
+/// Runs `request` once, with the rate limit and timeout of [`retry_provider_request`], for
+/// the adapter calls that retry on their own: retrying them here as well would multiply the
+/// attempts.
+async fn request_retried_by_adapter<T, F, Fut>(
+    what: String,
+    options: &CheckOptions,
+    progress: &Progress,
+    request: F,
+) -> anyhow::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    retry_provider_requests(what, 1, 1, options, progress, request).await
+}
+
+/// Like [`retry_provider_request`], for a request that asks for `requests` things at once,
+/// like a JSON-RPC batch, and is given up after `max_attempts`. Every attempt is charged as
+/// that many requests against `--rps`.
+async fn retry_provider_requests<T, F, Fut>(
+    what: String,
+    requests: usize,
+    max_attempts: u32,
+    options: &CheckOptions,
+    progress: &Progress,
+    mut request: F,
+) -> anyhow::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    let mut attempt = 1;
+    loop {
+        if let Some(rate_limiter) = &options.rate_limiter {
+            rate_limiter.acquire(requests).await;
+        }
+        let e = match tokio::time::timeout(options.rpc_timeout, request()).await {
+            Ok(Ok(value)) => {
+                if let Some(rate_limiter) = &options.rate_limiter {
+                    rate_limiter.succeeded();
+                }
+                return Ok(value);
+            }
+            Ok(Err(e)) => {
+                if let (Some(rate_limiter), true) = (&options.rate_limiter, is_rate_limited(&e)) {
+                    rate_limiter.throttled();
+                }
+                e
+            }
+            Err(_) => anyhow!(
+                "request timed out after {}s",
+                options.rpc_timeout.as_secs_f64()
+            ),
+        };
+        if !is_transient(&e) {
+            progress.rpc_error();
+            return Err(e.context(format!(
+                "failed to fetch {what} after {}",
+                attempts_text(&attempt)
+            )));
+        }
+        if attempt >= max_attempts {
+            progress.rpc_error();
+            return Err(CheckBlocksError::ProviderUnreachable {
+                what,
+                attempts: attempt,
+                source: e.into(),
+            }
+            .into());
+        }
+        let delay = options
+            .retry_delay
+            .saturating_mul(2u32.saturating_pow(attempt - 1));
+        progress.rpc_error();
+        progress.suspend(|| {
+            eprintln!(
+                "Failed to fetch {what} (attempt {attempt} of {}), \
+                 retrying in {}ms: {e:#}",
+                max_attempts,
+                delay.as_millis()
+            )
+        });
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// Whether the provider rejected a request for exceeding its quota, with an HTTP 429 or the
+/// JSON-RPC error `-32005` that several providers use for it.
+pub(super) fn is_rate_limited(e: &anyhow::Error) -> bool {
+    e.chain()
+        .any(|cause| match cause.downcast_ref::<web3::Error>() {
+            Some(web3::Error::Transport(web3::error::TransportError::Code(429))) => true,
+            Some(web3::Error::Rpc(error)) => error.code.code() == -32005,
+            _ => {
+                let message = cause.to_string().to_lowercase();
+                message.contains("too many requests") || message.contains("rate limit")
+            }
+        })
+}
+
+/// Tells whether a failed provider request might succeed when retried. Responses that
+/// graph-node couldn't make sense of will look the same the next time around, and so will a
+/// provider that doesn't know a block or receipt; connection problems and timeouts usually
+/// go away.
+fn is_transient(e: &anyhow::Error) -> bool {
+    !matches!(
+        e.downcast_ref::<web3::Error>(),
+        Some(web3::Error::Decoder(_) | web3::Error::InvalidResponse(_))
+    ) && !matches!(
+        e.downcast_ref::<IngestorError>(),
+        Some(IngestorError::BlockUnavailable(_) | IngestorError::ReceiptUnavailable(..))
+    )
+}
+
+/// Brings the hex strings in `value` into a canonical form: lowercase, and for the members
+/// named in [`QUANTITY_FIELDS`], without leading zeros.
+pub(super) fn normalize_hex(value: &mut Value) {
+    match value {
+        Value::String(s) => lowercase_hex(s),
+        Value::Array(values) => values.iter_mut().for_each(normalize_hex),
+        Value::Object(fields) => {
+            for (key, value) in fields.iter_mut() {
+                match value {
+                    Value::String(s) if QUANTITY_FIELDS.contains(&key.as_str()) => {
+                        lowercase_hex(s);
+                        strip_leading_zeros(s);
+                    }
+                    value => normalize_hex(value),
+                }
+            }
+        }
+        Value::Null | Value::Bool(_) | Value::Number(_) => {}
+    }
+}
+
+fn is_hex(s: &str) -> bool {
+    s.strip_prefix("0x").map_or(false, |digits| {
+        digits.chars().all(|c| c.is_ascii_hexdigit())
+    })
+}
+
+pub(super) fn lowercase_hex(s: &mut String) {
+    if is_hex(s) {
+        s.make_ascii_lowercase();
+    }
+}
+
+pub(super) fn strip_leading_zeros(s: &mut String) {
+    if is_hex(s) {
+        let digits = s[2..].trim_start_matches('0');
+        *s = if digits.is_empty() {
+            "0x0".to_string()
+        } else {
+            format!("0x{digits}")
+        };
+    }
+}
+
+/// Tells whether two [`serde_json::Value`] values differ, disregarding the fields in
+/// `ignored_fields`, without keeping the difference. The values are compared in place, up to
+/// the first difference, so equal blocks, by far the most common case, are neither copied
+/// nor diffed. Only blocks that differ are diffed, to go by the [`divergence`] of their diff
+/// like [`diff_block_pair`] does.
+pub(super) fn blocks_differ(a: &Value, b: &Value, ignored_fields: &[String]) -> bool {
+    blocks_differ_with(a, b, ignored_fields, |a, b| {
+        JsonDiff::diff(a, b, false).diff
+    })
+}
+
+/// [`blocks_differ`] with the structural diff computed by `differ`.
+pub(super) fn blocks_differ_with(
+    a: &Value,
+    b: &Value,
+    ignored_fields: &[String],
+    differ: impl Fn(&Value, &Value) -> Option<Value>,
+) -> bool {
+    !equal_ignoring(a, b, &mut String::new(), ignored_fields)
+        && diff_block_pair_with(a, b, ignored_fields, differ).is_some()
+}
+
+/// Tells whether `a` and `b`, found at the JSON `pointer` of two blocks, are equal once the
+/// object members that `ignored_fields` point to are removed from both, like
+/// [`remove_field`] does.
+fn equal_ignoring(a: &Value, b: &Value, pointer: &mut String, ignored_fields: &[String]) -> bool {
+    if ignored_fields.is_empty() {
+        return a == b;
+    }
+    let mut equal_at = |segment: &str, a: Option<&Value>, b: Option<&Value>, member: bool| {
+        let len = pointer.len();
+        pointer.push('/');
+        pointer.push_str(segment);
+        let equal = (member && ignored_fields.contains(pointer))
+            || match (a, b) {
+                (Some(a), Some(b)) => equal_ignoring(a, b, pointer, ignored_fields),
+                _ => false,
+            };
+        pointer.truncate(len);
+        equal
+    };
+    match (a, b) {
+        (Value::Object(a), Value::Object(b)) => {
+            let keys: BTreeSet<&String> = a.keys().chain(b.keys()).collect();
+            keys.into_iter().all(|key| {
+                let segment = key.replace('~', "~0").replace('/', "~1");
+                equal_at(&segment, a.get(key), b.get(key), true)
+            })
+        }
+        (Value::Array(a), Value::Array(b)) => {
+            a.len() == b.len()
+                && (0..a.len()).all(|i| equal_at(&i.to_string(), a.get(i), b.get(i), false))
+        }
+        (a, b) => a == b,
+    }
+}
+
+/// The fields that every block has, no matter which provider it came from.
+const REQUIRED_BLOCK_FIELDS: &[&str] = &["hash", "number", "parentHash"];
+
+/// Checks that a cached block is a JSON object with all of [`REQUIRED_BLOCK_FIELDS`], or
+/// describes what is wrong with it.
+///
+/// Comparing a partial or malformed block with the provider's would produce a large diff
+/// that says nothing about whether the block was reorged.
+pub(super) fn validate_cached_block(block: &Value) -> Result<(), String> {
+    let fields = match block {
+        Value::Object(fields) => fields,
+        Value::Null => return Err("it is null".to_string()),
+        _ => return Err("it is not a JSON object".to_string()),
+    };
+    let missing: Vec<_> = REQUIRED_BLOCK_FIELDS
+        .iter()
+        .filter(|field| !fields.get(**field).map_or(false, Value::is_string))
+        .map(|field| format!("`{field}`"))
+        .collect();
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("missing or malformed {}", missing.join(", ")))
+    }
+}
+
+/// Narrows `block` to `options.scope` and, unless `options.exact`, normalizes its hex strings.
+/// That's the form in which blocks are compared.
+pub(super) fn comparable_block(block: Value, options: &CheckOptions) -> Value {
+    let mut block = options.scope.narrow(block);
+    if !options.diff_include.is_empty() || !options.diff_exclude.is_empty() {
+        filter_fields(&mut block, &options.diff_include, &options.diff_exclude);
+    }
+    if !options.exact {
+        normalize_hex(&mut block);
+    }
+    block
+}
+
+/// Sorts the `transactions` of `block` by their `transactionIndex`, and by their hash if
+/// they have no index, like when the block only lists the transaction hashes. This happens
+/// before the fields are narrowed down and normalized, so that it works however the
+/// transactions are compared.
+pub(super) fn sort_transactions(block: &mut Value) {
+    let Some(Value::Array(transactions)) = block.get_mut("transactions") else {
+        return;
+    };
+    transactions.sort_by_cached_key(|transaction| {
+        let index = transaction
+            .get("transactionIndex")
+            .and_then(Value::as_str)
+            .and_then(|index| u64::from_str_radix(index.trim_start_matches("0x"), 16).ok());
+        let hash = transaction
+            .get("hash")
+            .or(Some(transaction))
+            .and_then(Value::as_str)
+            .map(str::to_ascii_lowercase);
+        (index, hash)
+    });
+}
+
+/// Removes the fields of [`FORK_BLOCK_FIELDS`] that `cached_block` lacks, or has as `null`,
+/// from `provider_block`.
+pub(super) fn drop_fork_fields_missing_from(cached_block: &Value, provider_block: &mut Value) {
+    let Value::Object(provider_fields) = provider_block else {
+        return;
+    };
+    for field in FORK_BLOCK_FIELDS {
+        if cached_block.get(field).map_or(true, Value::is_null) {
+            provider_fields.remove(*field);
+        }
+    }
+}
+
+/// Removes every field from `provider_block` that `cached_block` doesn't have, in nested
+/// objects and in the elements of arrays, too. Fields that are `null` in the cached block
+/// are kept, and so are array elements, so only fields the provider added are dropped.
+pub(super) fn drop_fields_missing_from(cached_block: &Value, provider_block: &mut Value) {
+    match (cached_block, provider_block) {
+        (Value::Object(cached_fields), Value::Object(provider_fields)) => {
+            let added: Vec<String> = provider_fields
+                .keys()
+                .filter(|field| !cached_fields.contains_key(*field))
+                .cloned()
+                .collect();
+            for field in added {
+                provider_fields.remove(&field);
+            }
+            for (field, value) in provider_fields.iter_mut() {
+                drop_fields_missing_from(&cached_fields[field], value);
+            }
+        }
+        (Value::Array(cached_elements), Value::Array(provider_elements)) => {
+            for (cached, provider) in cached_elements.iter().zip(provider_elements) {
+                drop_fields_missing_from(cached, provider);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Leaves only the fields of `value` whose path matches one of the `include` globs, or all
+/// of them if there are none, and removes the ones that match one of the `exclude` globs.
+///
+/// Paths are the object keys and array indices leading to a field, joined with dots, like
+/// `transactions.0.from`. In a glob, `*` matches any part of a path segment and `**` any
+/// number of whole segments; a glob that matches a field matches everything inside it, too.
+/// Array elements are never removed, just replaced with `null`, so that the remaining ones
+/// still line up with those of the other block.
+pub(super) fn filter_fields(value: &mut Value, include: &[String], exclude: &[String]) {
+    let split = |globs: &[String]| -> Vec<Vec<String>> {
+        globs
+            .iter()
+            .map(|glob| glob.split('.').map(str::to_string).collect())
+            .collect()
+    };
+    let (include, exclude) = (split(include), split(exclude));
+    filter_value(
+        value,
+        &mut Vec::new(),
+        &include,
+        &exclude,
+        include.is_empty(),
+    );
+}
+
+fn filter_value(
+    value: &mut Value,
+    path: &mut Vec<String>,
+    include: &[Vec<String>],
+    exclude: &[Vec<String>],
+    included: bool,
+) {
+    let keep = |path: &mut Vec<String>, child: &mut Value| {
+        if exclude.iter().any(|glob| glob_matches(glob, path, false)) {
+            return false;
+        }
+        let child_included = included || include.iter().any(|glob| glob_matches(glob, path, false));
+        if !child_included && !include.iter().any(|glob| glob_matches(glob, path, true)) {
+            // Nothing below this field can be included
+            return false;
+        }
+        let is_container = matches!(child, Value::Object(_) | Value::Array(_));
+        if !child_included && !is_container {
+            return false;
+        }
+        filter_value(child, path, include, exclude, child_included);
+        true
+    };
+    match value {
+        Value::Object(fields) => {
+            let names: Vec<String> = fields.keys().cloned().collect();
+            for name in names {
+                path.push(name.clone());
+                // Unwrap: `name` was just taken from `fields`
+                if !keep(path, fields.get_mut(&name).unwrap()) {
+                    fields.remove(&name);
+                }
+                path.pop();
+            }
+        }
+        Value::Array(elements) => {
+            for (index, element) in elements.iter_mut().enumerate() {
+                path.push(index.to_string());
+                if !keep(path, element) {
+                    *element = Value::Null;
+                }
+                path.pop();
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Whether the segments of `glob` match `path`. With `partial`, it is enough that they
+/// could match some path below `path`.
+fn glob_matches(glob: &[String], path: &[String], partial: bool) -> bool {
+    match (glob.split_first(), path.split_first()) {
+        (None, None) => true,
+        (None, Some(_)) => false,
+        (Some((segment, rest)), _) if segment == "**" => {
+            glob_matches(rest, path, partial)
+                || (!path.is_empty() && glob_matches(glob, &path[1..], partial))
+        }
+        (Some(_), None) => partial,
+        (Some((segment, rest)), Some((name, names))) => {
+            segment_matches(segment, name) && glob_matches(rest, names, partial)
+        }
+    }
+}
+
+/// Whether `name` matches `pattern`, in which `*` stands for any number of characters.
+fn segment_matches(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => {
+            let Some(name) = name.strip_prefix(prefix) else {
+                return false;
+            };
+            if rest.is_empty() {
+                return true;
+            }
+            (0..=name.len())
+                .filter(|start| name.is_char_boundary(*start))
+                .any(|start| segment_matches(rest, &name[start..]))
+        }
+    }
+}
+
+/// Asks each of `options.witnesses` for the block with `hash`, and counts how many of them
+/// return the same block as the main provider, which returned `provider_block` in
+/// [`comparable_block`] form. The main provider is counted, too.
+///
+/// A witness that fails to return the block doesn't agree.
+pub(super) async fn count_agreeing_providers(
+    hash: &H256,
+    provider_block: &Value,
+    logger: &Logger,
+    options: &CheckOptions,
+    progress: &Progress,
+) -> usize {
+    let votes = options.witnesses.0.iter().map(|witness| async move {
+        match fetch_single_provider_block(hash, &**witness, logger, options, progress).await {
+            Ok(block) => !blocks_differ(
+                &comparable_block(block, options),
+                provider_block,
+                &options.ignored_fields,
+            ),
+            Err(e) => {
+                progress.suspend(|| {
+                    eprintln!(
+                        "Provider {} could not confirm block {hash:?}: {e:#}",
+                        witness.provider()
+                    )
+                });
+                false
+            }
+        }
+    });
+    1 + futures::future::join_all(votes)
+        .await
+        .into_iter()
+        .filter(|agrees| *agrees)
+        .count()
+}
+
+/// Compares two [`serde_json::Value`] values, disregarding the fields in `ignored_fields`.
+///
+/// If they are different, returns the structural difference between them.
+pub(super) fn diff_block_pair(a: &Value, b: &Value, ignored_fields: &[String]) -> Option<Value> {
+    diff_block_pair_with(a, b, ignored_fields, |a, b| {
+        JsonDiff::diff(a, b, false).diff
+    })
+}
+
+/// [`diff_block_pair`] with the structural diff computed by `differ`.
+pub(super) fn diff_block_pair_with(
+    a: &Value,
+    b: &Value,
+    ignored_fields: &[String],
+    differ: impl Fn(&Value, &Value) -> Option<Value>,
+) -> Option<Value> {
+    if !ignored_fields.is_empty() {
+        let (mut a, mut b) = (a.clone(), b.clone());
+        for field in ignored_fields {
+            remove_field(&mut a, field);
+            remove_field(&mut b, field);
+        }
+        return diff_block_pair_with(&a, &b, &[], differ);
+    }
+    if a == b {
+        None
+    } else {
+        divergence(differ(a, b))
+    }
+}
+
+/// The one definition of what makes two blocks diverge: their structural diff, unless there
+/// is none or it is a `Value::Null`, which is equivalent to not being different at all. All
+/// ways of checking blocks decide through [`diff_block_pair`] or [`blocks_differ`], and thus
+/// through this.
+pub(super) fn divergence(diff: Option<Value>) -> Option<Value> {
+    diff.filter(|diff| !diff.is_null())
+}
+
+/// Removes the object member that the JSON `pointer` refers to from `value`, if there is one.
+fn remove_field(value: &mut Value, pointer: &str) {
+    if let Some((parent, key)) = pointer.rsplit_once('/') {
+        let key = key.replace("~1", "/").replace("~0", "~");
+        if let Some(Value::Object(object)) = value.pointer_mut(parent) {
+            object.remove(&key);
+        }
+    }
+}
+
+/// Converts a JSON diff to a text that will be displayed to the user on stderr, in the style
+/// that `render` asks for. `render.color` should only be set if the `--color` setting allows
+/// it for stderr, so that logs of captured output don't fill up with escape codes.
+pub(super) fn render_diff(diff: &Value, render: &DiffRenderOptions) -> String {
+    let text = match render.style {
+        DiffStyle::Full => diff_to_string(diff, render.color),
+        DiffStyle::Compact => {
+            let mut lines = Vec::new();
+            compact_diff_lines(diff, "", &mut lines);
+            lines.join("\n")
+        }
+    };
+    match render.max_lines {
+        Some(max_lines) if text.lines().count() > max_lines => {
+            let rest = text.lines().count() - max_lines;
+            let mut shown: Vec<_> = text.lines().take(max_lines).collect();
+            let more = format!("... {rest} more lines");
+            shown.push(&more);
+            shown.join("\n")
+        }
+        _ => text,
+    }
+}
+
+/// Renders `diff` in the style of `options.diff_render`, but without color and in full, for
+/// the JSON output and the audit log, which are read outside of a terminal.
+pub(super) fn plain_diff(diff: &Value, options: &CheckOptions) -> String {
+    let render = DiffRenderOptions {
+        color: false,
+        max_lines: None,
+        ..options.diff_render
+    };
+    render_diff(diff, &render)
+}
+
+/// How many values differ in the structural `diff`, counting every changed, removed or added
+/// leaf once, like the lines of a compact diff.
+pub(super) fn count_diff_fields(diff: &Value) -> usize {
+    let mut lines = Vec::new();
+    compact_diff_lines(diff, "", &mut lines);
+    lines.len()
+}
+
+/// The JSON pointers of the fields that differ in the structural `diff`, sorted and without
+/// duplicates. Array indexes are replaced by `*`, so that the same field of different
+/// transactions counts as one field.
+pub(super) fn diff_field_paths(diff: &Value) -> Vec<String> {
+    let mut paths = BTreeSet::new();
+    for_each_change(diff, "", &mut |path, _| {
+        let path: Vec<&str> = path
+            .split('/')
+            .map(|segment| {
+                let is_index = !segment.is_empty() && segment.bytes().all(|b| b.is_ascii_digit());
+                if is_index {
+                    "*"
+                } else {
+                    segment
+                }
+            })
+            .collect();
+        paths.insert(path.join("/"));
+    });
+    paths.into_iter().collect()
+}
+
+/// Adds a line for every change in the structural `diff` at JSON pointer `path` to `lines`:
+/// `~ path: old -> new` for a changed value, and `- path: old` or `+ path: new` for a removed
+/// or added one.
+fn compact_diff_lines(diff: &Value, path: &str, lines: &mut Vec<String>) {
+    for_each_change(diff, path, &mut |path, change| {
+        lines.push(match change {
+            Change::Changed(old, new) => format!("~ {path}: {old} -> {new}"),
+            Change::Removed(old) => format!("- {path}: {old}"),
+            Change::Added(new) => format!("+ {path}: {new}"),
+            Change::Other(value) => format!("~ {path}: {value}"),
+        })
+    });
+}
+
+/// A single change in a structural diff.
+enum Change<'a> {
+    Changed(&'a Value, &'a Value),
+    Removed(&'a Value),
+    Added(&'a Value),
+    /// A leaf of the diff that isn't in any of the forms above.
+    Other(&'a Value),
+}
+
+/// Calls `f` with the JSON pointer and the change for every change in the structural `diff`
+/// at JSON pointer `path`.
+fn for_each_change<F: FnMut(&str, Change)>(diff: &Value, path: &str, f: &mut F) {
+    match diff {
+        Value::Object(object) => {
+            if let (Some(old), Some(new)) = (object.get("__old"), object.get("__new")) {
+                f(path, Change::Changed(old, new));
+                return;
+            }
+            for (key, value) in object {
+                if let Some(key) = key.strip_suffix("__deleted") {
+                    f(&format!("{path}/{key}"), Change::Removed(value));
+                } else if let Some(key) = key.strip_suffix("__added") {
+                    f(&format!("{path}/{key}"), Change::Added(value));
+                } else {
+                    for_each_change(value, &format!("{path}/{key}"), f);
+                }
+            }
+        }
+        // Arrays are diffed element by element, as `[op, value]` pairs where `op` is one of
+        // ` `, `-`, `+` or `~`. Indexes refer to the cached array
+        Value::Array(elements) => {
+            let mut index = 0;
+            for element in elements {
+                let op = element.get(0).and_then(Value::as_str).unwrap_or(" ");
+                let value = element.get(1).unwrap_or(&Value::Null);
+                match op {
+                    "-" => f(&format!("{path}/{index}"), Change::Removed(value)),
+                    "+" => {
+                        f(&format!("{path}/{index}"), Change::Added(value));
+                        continue;
+                    }
+                    "~" => for_each_change(value, &format!("{path}/{index}"), f),
+                    _ => {}
+                }
+                index += 1;
+            }
+        }
+        value => f(path, Change::Other(value)),
+    }
+}
+
+/// Refers to a block by its number and hash, or only its hash if the number isn't known.
+pub(super) fn describe_block(hash: &H256, number: Option<i32>) -> String {
+    match number {
+        Some(number) => format!("block #{number} ({hash:?})"),
+        None => format!("block {hash:?}"),
+    }
+}
+
+/// Prints the difference between two [`serde_json::Value`] values to the user.
+pub(super) fn report_difference(difference: Option<&str>, hash: &H256, number: Option<i32>) {
+    if let Some(diff) = difference {
+        eprintln!("{} diverges from cache:", describe_block(hash, number));
+        eprintln!("{diff}");
+    } else {
+        println!("Cached block is equal to the same block from provider.")
+    }
+}
+
+/// Counts a block of `chain_store` that is about to be deleted or replaced, failing instead if
+/// that is more than `options.max_deletions` allows. Commands that know how many blocks they
+/// are going to fix already stop with [`enforce_max_deletions`] before they start; this
+/// keeps every other way of deleting blocks under the limit, too. Dry runs are never counted.
+pub(super) fn count_deletion(
+    action: &str,
+    hash: &H256,
+    chain_store: &ChainStore,
+    options: &CheckOptions,
+) -> anyhow::Result<()> {
+    if options.dry_run {
+        return Ok(());
+    }
+    if let Err(deleted) = options
+        .deletions
+        .add(&chain_store.chain, options.max_deletions)
+    {
+        bail!(
+            "Aborting before {action} block {hash:?}: {deleted} blocks of {} had already \
+             been deleted or replaced, which is all that `--max-deletions` allows. This \
+             often means the provider is returning bad data; rerun with `--dry-run` to \
+             review the blocks first.",
+            chain_store.chain
+        );
+    }
+    Ok(())
+}
+
+/// Fails once `fixed` blocks have been fixed and `pending` more diverged, if that's more than
+/// `options.max_deletions` allows. Dry runs never fail.
+pub(super) fn enforce_max_deletions(
+    fixed: usize,
+    pending: usize,
+    options: &CheckOptions,
+) -> anyhow::Result<()> {
+    let total = fixed + pending;
+    match options.max_deletions {
+        Some(max_deletions) if !options.dry_run && total > max_deletions => bail!(
+            "Aborting: {total} blocks diverged from the provider, more than the \
+             {max_deletions} that `--max-deletions` allows to be {}. {fixed} blocks had \
+             already been {}. This often means the provider is returning bad data; \
+             rerun with `--dry-run` to review the diverging blocks first.",
+            options.fix_action(),
+            options.fix_action()
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Tells whether deleting blocks can go ahead, now that `count` blocks in total are to be
+/// deleted. Once that's more than [`DELETE_CONFIRMATION_THRESHOLD`], the user is asked; the
+/// answer is kept in `confirmed` and applies to all later deletions. Dry runs and
+/// `options.skip_confirmation` never ask.
+///
+/// The question shows a sample of the `numbers` of the diverged blocks and how many of the
+/// `checked` blocks diverged, so that a provider that differs on every block stands out
+/// before anything is deleted.
+pub(super) fn confirm_deletion(
+    count: usize,
+    numbers: &[i32],
+    checked: usize,
+    cached_blocks: Option<usize>,
+    chain_store: &ChainStore,
+    options: &CheckOptions,
+    confirmed: &mut Option<bool>,
+) -> anyhow::Result<bool> {
+    if options.dry_run || options.skip_confirmation {
+        return Ok(true);
+    }
+    if let Some(confirmed) = confirmed {
+        return Ok(*confirmed);
+    }
+    if count <= DELETE_CONFIRMATION_THRESHOLD {
+        return Ok(true);
+    }
+    let warning = if numbers.len() >= checked {
+        "\nEvery checked block diverged, which usually means that the provider is broken \
+         rather than the cache."
+    } else {
+        ""
+    };
+    let remaining = match cached_blocks {
+        Some(cached_blocks) if cached_blocks > checked => format!(
+            " {} of the {cached_blocks} cached blocks in the range are still to be checked.",
+            cached_blocks - checked
+        ),
+        _ => String::new(),
+    };
+    let prompt = format!(
+        "{count} cached blocks for {} diverge from the provider and will be {}.\n\
+         {} of {checked} checked blocks diverged, among them {}.{remaining}{warning}\n\
+         Proceed?",
+        chain_store.chain,
+        options.fix_action(),
+        numbers.len(),
+        helpers::sample_block_numbers(numbers),
+    );
+    let answer = prompt_for_confirmation(&prompt)?;
+    if !answer {
+        println!("Not changing any more blocks.");
+    }
+    *confirmed = Some(answer);
+    Ok(answer)
+}
+
+/// Tells whether [`backfill`] can go ahead with adding blocks for the `missing` numbers.
+/// Like [`confirm_deletion`], the user is only asked once that's more than
+/// [`DELETE_CONFIRMATION_THRESHOLD`] blocks, and dry runs and `options.skip_confirmation`
+/// never ask.
+pub(super) fn confirm_backfill(
+    missing: &[i32],
+    chain_store: &ChainStore,
+    options: &CheckOptions,
+) -> anyhow::Result<bool> {
+    if options.dry_run
+        || options.skip_confirmation
+        || missing.len() <= DELETE_CONFIRMATION_THRESHOLD
+    {
+        return Ok(true);
+    }
+    let prompt = format!(
+        "{} blocks for {} are missing from the cache, among them {}. They will be fetched \
+         from the provider and added.\nProceed?",
+        missing.len(),
+        chain_store.chain,
+        helpers::sample_block_numbers(missing),
+    );
+    let answer = prompt_for_confirmation(&prompt)?;
+    if !answer {
+        println!("Not adding any blocks.");
+    }
+    Ok(answer)
+}
+
+/// Appends a line about a purged range segment to `options.audit_log`, if there is one.
+pub(super) fn record_purge(
+    segment: &PurgedSegment,
+    chain_store: &ChainStore,
+    options: &CheckOptions,
+) -> anyhow::Result<()> {
+    let entry = serde_json::json!({
+        "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        "chain": chain_store.chain,
+        "range": [segment.lower, segment.upper],
+        "count": segment.blocks,
+        "reason": "purged",
+    });
+    append_to_audit_log(&entry, options)
+}
+
+/// What is known about a block that is removed from the cache, for the audit log.
+pub(super) struct Deletion<'a> {
+    pub(super) number: Option<i32>,
+    pub(super) reason: &'static str,
+    pub(super) diff: Option<&'a Value>,
+    /// The [`helpers::block_digest`] of the block as it was compared. If this is set, the
+    /// block is only deleted if it still is the same.
+    pub(super) compared: Option<H256>,
+}
+
+/// What became of a block that [`delete_block`] was asked to delete.
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum DeleteOutcome {
+    /// The block was deleted, or would have been in a dry run.
+    Deleted,
+    /// The block changed in the cache after it was compared, so it was left alone.
+    Raced,
+    /// With `options.verify_deletes`, the block turned out not to be removed from the cache,
+    /// for the reason given.
+    Unverified(String),
+}
+
+/// Attempts to delete a block from the block cache, and records the deletion in the audit log.
+/// The block isn't deleted if it changed since it was compared. With
+/// `options.verify_deletes`, the cache is checked for the block afterwards, so that a delete
+/// that silently matched the wrong rows is noticed.
+///
+/// In a dry run, only reports which block would have been deleted.
+pub(super) fn delete_block(
+    hash: &H256,
+    chain_store: &ChainStore,
+    options: &CheckOptions,
+    deletion: Deletion,
+) -> anyhow::Result<DeleteOutcome> {
+    let block = describe_block(hash, deletion.number);
+    if options.dry_run {
+        options.info(format_args!("Dry run: would delete {block} from cache."));
+        if options.explain {
+            options.info(chain_store.explain_delete_blocks(&[hash]));
+        }
+        return Ok(DeleteOutcome::Deleted);
+    }
+    count_deletion("deleting", hash, chain_store, options)?;
+    options.info(format_args!("Deleting {block} from cache."));
+    let removed = match deletion.compared {
+        Some(digest) => {
+            let unchanged = |cached: &Value| helpers::block_digest(cached) == digest;
+            if !chain_store.delete_block_if_unchanged(hash, unchanged)? {
+                options.info(format_args!(
+                    "Raced: {block} changed in the cache after it was compared, skipped."
+                ));
+                return Ok(DeleteOutcome::Raced);
+            }
+            1
+        }
+        None => chain_store.delete_blocks(&[hash])?,
+    };
+    // Whatever the check below finds, rows that were removed have to be accounted for
+    if removed > 0 || !options.verify_deletes {
+        if let Some(metrics) = &options.metrics {
+            metrics.block_deleted(&chain_store.chain);
+        }
+        record_deletion(hash, chain_store, options, &deletion)?;
+    }
+    if options.verify_deletes {
+        let remaining = chain_store.blocks_by_hashes(&[(*hash).into()])?.len();
+        if let Some(problem) = helpers::deletion_problem(removed, remaining) {
+            eprintln!("Warning: deleting {block} {problem}");
+            return Ok(DeleteOutcome::Unverified(problem));
+        }
+    }
+    options.info("Done.");
+    Ok(DeleteOutcome::Deleted)
+}
+
+/// Appends a line about a deleted or replaced block to `options.audit_log`, if there is one.
+pub(super) fn record_deletion(
+    hash: &H256,
+    chain_store: &ChainStore,
+    options: &CheckOptions,
+    deletion: &Deletion,
+) -> anyhow::Result<()> {
+    let entry = serde_json::json!({
+        "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        "chain": chain_store.chain,
+        "number": deletion.number,
+        "hash": hash,
+        "reason": deletion.reason,
+        "diff": deletion.diff,
+        "diff_text": deletion.diff.map(|diff| plain_diff(diff, options)),
+    });
+    append_to_audit_log(&entry, options)
+}
+
+/// Prints the parameters of a check of `target` once all defaults and flags are resolved,
+/// so that pasted output documents how it was produced, and records them in the audit log.
+/// Returns the audit log entry, which has the manifest and the time the check started.
+pub(super) fn print_manifest(
+    chain_store: &ChainStore,
+    ethereum_adapter: &dyn EthereumAdapterTrait,
+    target: &str,
+    range: Option<&[(i32, i32)]>,
+    options: &CheckOptions,
+) -> anyhow::Result<Value> {
+    let version = render_testament!(TESTAMENT);
+    let provider = match &options.provider_fixture {
+        Some(dir) => format!("fixture `{}`", dir.display()),
+        None => ethereum_adapter.provider().to_string(),
+    };
+    let manifest = helpers::manifest(
+        &chain_store.chain,
+        &provider,
+        target,
+        range,
+        &version,
+        options,
+    );
+    options.info(format_args!("Manifest: {manifest}"));
+    let entry = serde_json::json!({
+        "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        "manifest": manifest,
+    });
+    append_to_audit_log(&entry, options)?;
+    Ok(entry)
+}
+
+/// The manifest entry of a method that only looks at the blocks `range` of the block cache,
+/// like the one [`print_manifest`] returns, for the JSON output. Nothing is printed or
+/// recorded, since there is no provider or comparison to document.
+pub(super) fn local_manifest(chain_store: &ChainStore, range: &[(i32, i32)]) -> Value {
+    let version = render_testament!(TESTAMENT);
+    let target = format!("blocks {}", helpers::format_runs(range.iter().copied()));
+    serde_json::json!({
+        "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        "manifest": helpers::local_manifest(&chain_store.chain, &target, Some(range), &version),
+    })
+}
+
+/// Appends `entry` as a line to `options.audit_log`, if there is one.
+///
+/// A plain log is opened for every line and written in one go, so that it stays usable when
+/// `graphman` is interrupted; a gzipped one goes through `options.audit_stream`. Nothing is
+/// recorded in a dry run.
+fn append_to_audit_log(entry: &Value, options: &CheckOptions) -> anyhow::Result<()> {
+    let path = match &options.audit_log {
+        Some(path) if !options.dry_run => path,
+        _ => return Ok(()),
+    };
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+    if helpers::compress(path, options) {
+        return options.audit_stream.append(path, line.as_bytes());
+    }
+    let mut file = open_audit_log(path)?;
+    file.write_all(line.as_bytes())
+        .and_then(|()| file.flush())
+        .with_context(|| format!("failed to write to audit log `{}`", path.display()))
+}
+
+/// What a checkpoint file holds. The chain is recorded so that a checkpoint isn't resumed on
+/// the wrong chain by accident.
+#[derive(Serialize, serde::Deserialize)]
+struct Checkpoint {
+    chain: String,
+    /// The highest block number up to which all blocks have been checked.
+    block_number: i32,
+}
+
+/// Writes the cached and the provider's version of the block with `hash` to
+/// `<dir>/<hash>.cached.json` and `<dir>/<hash>.provider.json`, creating `dir` if needed. With
+/// `compress`, the files are gzipped and named `*.json.gz`. Like checkpoints, each file is
+/// written under a temporary name first and then renamed, so that an interruption never leaves
+/// a partial file behind.
+pub(super) fn export_block_pair(
+    dir: &Path,
+    hash: &H256,
+    cached_block: &Value,
+    provider_block: &Value,
+    compress: bool,
+) -> anyhow::Result<()> {
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("failed to create export directory `{}`", dir.display()))?;
+    let extension = if compress { "json.gz" } else { "json" };
+    for (source, block) in [("cached", cached_block), ("provider", provider_block)] {
+        let path = dir.join(format!("{hash:?}.{source}.{extension}"));
+        let tmp_path = dir.join(format!("{hash:?}.{source}.{extension}.tmp"));
+        let json = serde_json::to_vec_pretty(block)?;
+        let contents = if compress {
+            helpers::gzip(&json)
+        } else {
+            Ok(json)
+        };
+        contents
+            .and_then(|contents| std::fs::write(&tmp_path, contents))
+            .and_then(|()| std::fs::rename(&tmp_path, &path))
+            .with_context(|| format!("failed to export block to `{}`", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Records in the checkpoint file at `path` that all blocks of `chain` up to `block_number`
+/// have been checked. The file is replaced in one go, so that an interrupted write doesn't
+/// leave a truncated checkpoint behind.
+pub(super) fn save_checkpoint(path: &Path, chain: &str, block_number: i32) -> anyhow::Result<()> {
+    let checkpoint = Checkpoint {
+        chain: chain.to_string(),
+        block_number,
+    };
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    std::fs::write(&tmp_path, serde_json::to_vec(&checkpoint)?)
+        .and_then(|()| std::fs::rename(&tmp_path, path))
+        .with_context(|| format!("failed to write checkpoint `{}`", path.display()))
+}
+
+/// Reads the checkpoint file at `path` and returns the block number up to which `chain` has
+/// been checked, or `None` if there is no such file. A checkpoint for another chain is an
+/// error.
+pub(super) fn load_checkpoint(path: &Path, chain: &str) -> anyhow::Result<Option<i32>> {
+    let contents = match std::fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("failed to read checkpoint `{}`", path.display()))
+        }
+    };
+    let checkpoint: Checkpoint = serde_json::from_slice(&contents)
+        .with_context(|| format!("checkpoint `{}` is malformed", path.display()))?;
+    ensure!(
+        checkpoint.chain == chain,
+        "Checkpoint `{}` is for chain {}, not {chain}",
+        path.display(),
+        checkpoint.chain
+    );
+    Ok(Some(checkpoint.block_number))
+}
+
+/// Overwrites a cached block with the provider's version of it, loading its receipts like the
+/// block ingestor does. Like [`delete_block`], the block isn't replaced if it changed since
+/// it was compared, which `compared` is the digest of.
+///
+/// In a dry run, only reports which block would have been replaced.
+#[allow(clippy::too_many_arguments)]
+pub(super) async fn replace_block(
+    hash: &H256,
+    number: Option<i32>,
+    compared: Option<H256>,
+    provider_block: Value,
+    chain_store: &ChainStore,
+    ethereum_adapter: &dyn EthereumAdapterTrait,
+    logger: &Logger,
+    options: &CheckOptions,
+    progress: &Progress,
+) -> anyhow::Result<DeleteOutcome> {
+    let described = describe_block(hash, number);
+    if options.dry_run {
+        progress.suspend(|| {
+            options.info(format_args!(
+                "Dry run: would replace {described} in cache with the provider's version."
+            ))
+        });
+        return Ok(DeleteOutcome::Deleted);
+    }
+    count_deletion("replacing", hash, chain_store, options)?;
+    progress.suspend(|| options.info(format_args!("Replacing {described} in cache.")));
+    let block: LightEthereumBlock =
+        serde_json::from_value(provider_block).context("failed to parse provider block")?;
+    let ethereum_block =
+        load_full_block(hash, block, ethereum_adapter, logger, options, progress).await?;
+    if !store_full_block(ethereum_block, chain_store, compared).await? {
+        progress.suspend(|| {
+            options.info(format_args!(
+                "Raced: {described} changed in the cache after it was compared, skipped."
+            ))
+        });
+        return Ok(DeleteOutcome::Raced);
+    }
+    progress.suspend(|| options.info("Done."));
+    Ok(DeleteOutcome::Deleted)
+}
+
+/// Loads the receipts of the provider's `block` with `hash`, like the block ingestor does.
+/// The receipts are requested one per transaction, so the request is charged as that many
+/// requests against `--rps`. The adapter retries the receipts on its own, so this makes a
+/// single attempt.
+async fn load_full_block(
+    hash: &H256,
+    block: LightEthereumBlock,
+    ethereum_adapter: &dyn EthereumAdapterTrait,
+    logger: &Logger,
+    options: &CheckOptions,
+    progress: &Progress,
+) -> anyhow::Result<EthereumBlock> {
+    retry_provider_requests(
+        format!("the receipts of block {hash:?}"),
+        block.transactions.len(),
+        1,
+        options,
+        progress,
+        || {
+            let full_block = ethereum_adapter.load_full_block(logger, block.clone());
+            async move { Ok(full_block.await?) }
+        },
+    )
+    .await
+}
+
+/// Adds a block that is missing from the cache, loading its receipts like the block ingestor
+/// does.
+///
+/// In a dry run, only reports which block would have been added.
+pub(super) async fn insert_block(
+    hash: &H256,
+    block: LightEthereumBlock,
+    chain_store: &ChainStore,
+    ethereum_adapter: &dyn EthereumAdapterTrait,
+    logger: &Logger,
+    options: &CheckOptions,
+) -> anyhow::Result<()> {
+    let described = describe_block(hash, block.number.map(|number| number.as_u64() as i32));
+    if options.dry_run {
+        options.info(format_args!("Dry run: would add {described} to cache."));
+        return Ok(());
+    }
+    options.info(format_args!("Adding {described} to cache."));
+    store_provider_block(hash, block, chain_store, ethereum_adapter, logger, options).await?;
+    options.info("Done.");
+    Ok(())
+}
+
+async fn store_provider_block(
+    hash: &H256,
+    block: LightEthereumBlock,
+    chain_store: &ChainStore,
+    ethereum_adapter: &dyn EthereumAdapterTrait,
+    logger: &Logger,
+    options: &CheckOptions,
+) -> anyhow::Result<()> {
+    let ethereum_block = load_full_block(
+        hash,
+        block,
+        ethereum_adapter,
+        logger,
+        options,
+        &Progress::hidden(),
+    )
+    .await?;
+    store_full_block(ethereum_block, chain_store, None).await?;
+    Ok(())
+}
+
+/// Stores `ethereum_block`, overwriting the cached block with its hash. With `compared`, the
+/// [`helpers::block_digest`] of the cached block, that block is only overwritten if it is
+/// still the same. Returns whether the block was stored.
+async fn store_full_block(
+    ethereum_block: EthereumBlock,
+    chain_store: &ChainStore,
+    compared: Option<H256>,
+) -> anyhow::Result<bool> {
+    // Unwrap: the block was requested by its hash
+    let hash = ethereum_block.block.hash.unwrap();
+    let block = BlockFinality::NonFinal(EthereumBlockWithCalls {
+        ethereum_block,
+        calls: None,
+    });
+    match compared {
+        Some(digest) => {
+            let unchanged = |cached: &Value| helpers::block_digest(cached) == digest;
+            Ok(chain_store.replace_block_if_unchanged(&hash, &block, unchanged)?)
+        }
+        None => {
+            chain_store.upsert_block(Arc::new(block)).await?;
+            Ok(true)
+        }
+    }
+}
+
+/// Overwrites a cached block and its receipts with the provider's `ethereum_block`, whose
+/// receipts are already loaded, and records that in the audit log.
+///
+/// In a dry run, only reports which block would have been replaced.
+pub(super) async fn replace_full_block(
+    ethereum_block: EthereumBlock,
+    chain_store: &ChainStore,
+    options: &CheckOptions,
+    deletion: &Deletion<'_>,
+) -> anyhow::Result<()> {
+    // Unwrap: the block was requested by its hash
+    let hash = ethereum_block.block.hash.unwrap();
+    let described = describe_block(&hash, deletion.number);
+    if options.dry_run {
+        options.info(format_args!(
+            "Dry run: would replace {described} and its receipts in cache with the \
+             provider's version."
+        ));
+        return Ok(());
+    }
+    count_deletion("replacing", &hash, chain_store, options)?;
+    options.info(format_args!(
+        "Replacing {described} and its receipts in cache."
+    ));
+    store_full_block(ethereum_block, chain_store, deletion.compared).await?;
+    record_deletion(&hash, chain_store, options, deletion)?;
+    options.info("Done.");
+    Ok(())
+}
+
+/// What comparing the receipts of a cached block with the provider's found.
+pub(super) enum ReceiptComparison {
+    /// The block is cached without receipts.
+    Uncached,
+    /// The provider doesn't have the block.
+    Missing,
+    Same,
+    /// The structural difference from the cached receipts to the provider's, and in
+    /// `--replace` mode, the provider's block with its receipts.
+    Diverged {
+        diff: Value,
+        provider_block: Option<EthereumBlock>,
+    },
+}
+
+/// Compares the receipts `cached` with the block `hash` with the receipts the provider
+/// returns for the block's transactions.
+pub(super) async fn compare_receipts(
+    hash: &H256,
+    cached: Vec<LightTransactionReceipt>,
+    ethereum_adapter: &dyn EthereumAdapterTrait,
+    logger: &Logger,
+    options: &CheckOptions,
+    progress: &Progress,
+) -> anyhow::Result<ReceiptComparison> {
+    if cached.is_empty() {
+        return Ok(ReceiptComparison::Uncached);
+    }
+    let provider_block =
+        request_provider_block(hash, ethereum_adapter, logger, options, progress).await?;
+    let Some(provider_block) = provider_block else {
+        return Ok(ReceiptComparison::Missing);
+    };
+    if provider_block.hash != Some(*hash) {
+        return Err(CheckBlocksError::ProviderMisbehavior {
+            block_hash: *hash,
+            returned_hash: provider_block.hash,
+        }
+        .into());
+    }
+    let provider_block = load_full_block(
+        hash,
+        provider_block,
+        ethereum_adapter,
+        logger,
+        options,
+        progress,
+    )
+    .await?;
+    let provider: Vec<LightTransactionReceipt> = provider_block
+        .transaction_receipts
+        .iter()
+        .map(|receipt| LightTransactionReceipt::from((**receipt).clone()))
+        .collect();
+    let diff = diff_block_pair(
+        &helpers::receipts_value(&cached),
+        &helpers::receipts_value(&provider),
+        &[],
+    );
+    Ok(match diff {
+        None => ReceiptComparison::Same,
+        Some(diff) => ReceiptComparison::Diverged {
+            diff,
+            provider_block: options.replace.then_some(provider_block),
+        },
+    })
+}
+
+/// Queries the [`ChainStore`] about the lowest block number in the block cache.
+pub(super) fn find_earliest_cached_block(chain_store: &ChainStore) -> anyhow::Result<Option<i32>> {
+    Ok(chain_store.earliest_cached_block_number()?)
+}
+
+/// Queries the [`ChainStore`] about the chain head, unless `options.chain_heads` already
+/// knows it.
+pub(super) fn find_chain_head(
+    chain_store: &ChainStore,
+    options: &CheckOptions,
+) -> anyhow::Result<i32> {
+    options.chain_heads.get(&chain_store.chain, || {
+        let chain_head: Option<i32> = chain_store.chain_head_block(&chain_store.chain)?;
+        chain_head.ok_or_else(|| anyhow!("Could not find the chain head for {}", chain_store.chain))
+    })
+}
@@ -0,0 +1,2608 @@
+use super::compare::{compare_chunk, compare_loaded_blocks};
+use super::helpers::{
+    block_digest, block_runs, cached_block_hash, chain_mismatch, classify_heights,
+    deletion_problem, duplicate_blocks, first_few, forks, json_envelope, local_manifest, manifest,
+    match_batched_blocks, match_cached_blocks, missing_block_numbers, parse_block_hash,
+    parse_block_hashes, receipts_value, run_in_parallel, sample_block_numbers, split_off_tip,
+    spread_sample, unique_block_hashes,
+};
+use super::progress::{self, Progress};
+use super::receipts::compare_block_receipts;
+use super::steps::{self, diff_block_pair, normalize_hex, render_diff};
+use super::{
+    AuditStream, CachedBlockRef, ChainHeads, CheckBlocksError, CheckBlocksReport, CheckOptions,
+    CheckStatus, CompareScope, DeletionCounts, DiffFile, DiffRenderOptions, DiffStyle,
+    DuplicateBlocks, Fork, Height, Interrupt, NetworkProfile, OutputFormat, QuickcheckReport,
+    RangeSet, RateLimiter, ReceiptsReport, Witnesses, JSON_SCHEMA_VERSION,
+    MAX_CONSECUTIVE_FETCH_FAILURES,
+};
+use graph::{
+    blockchain::{BlockHash, BlockPtr, ChainIdentifier, IngestorError},
+    components::store::{ChainStore, EthereumCallCache},
+    log::discard,
+    prelude::{
+        anyhow::{self, Error},
+        async_trait,
+        ethabi::Token,
+        future as future01,
+        serde_json::{self, json, Value},
+        stream as stream01, tokio,
+        transaction_receipt::LightTransactionReceipt,
+        web3::types::{Block, Transaction, TransactionReceipt, H256, U256, U64},
+        BlockNumber, EthereumBlock, Future as Future01, LightEthereumBlock, Stream as Stream01,
+    },
+    slog::Logger,
+};
+use graph_chain_ethereum::{EthereumAdapterTrait, EthereumContractCall, EthereumContractCallError};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// An adapter that answers `block_by_hash` with canned blocks, and `load_full_block` with
+/// canned receipts.
+struct MockEthereumAdapter {
+    blocks: HashMap<H256, LightEthereumBlock>,
+    /// The receipts of the blocks that `load_full_block` can load, by block hash.
+    receipts: HashMap<H256, Vec<TransactionReceipt>>,
+    /// How often `block_by_hash` or `blocks_by_hashes_batched` was called.
+    calls: AtomicUsize,
+    /// Whether the mock answers batch requests, which it does in reverse order.
+    batching: bool,
+}
+
+impl MockEthereumAdapter {
+    fn new(blocks: impl IntoIterator<Item = (H256, LightEthereumBlock)>) -> Self {
+        MockEthereumAdapter {
+            blocks: blocks.into_iter().collect(),
+            receipts: HashMap::new(),
+            calls: AtomicUsize::new(0),
+            batching: false,
+        }
+    }
+
+    fn with_receipts(
+        self,
+        receipts: impl IntoIterator<Item = (H256, Vec<TransactionReceipt>)>,
+    ) -> Self {
+        MockEthereumAdapter {
+            receipts: receipts.into_iter().collect(),
+            ..self
+        }
+    }
+
+    fn batching(self) -> Self {
+        MockEthereumAdapter {
+            batching: true,
+            ..self
+        }
+    }
+
+    fn calls(&self) -> usize {
+        self.calls.load(Ordering::SeqCst)
+    }
+
+    /// The canned block with the highest number, which the mock takes as the chain head.
+    fn latest(&self) -> &LightEthereumBlock {
+        self.blocks
+            .values()
+            .max_by_key(|block| block.number)
+            .expect("the mock has blocks")
+    }
+
+    fn block_with_number(&self, number: BlockNumber) -> Option<&LightEthereumBlock> {
+        self.blocks
+            .values()
+            .find(|block| block.number == Some(U64::from(number as u64)))
+    }
+}
+
+#[async_trait]
+impl EthereumAdapterTrait for MockEthereumAdapter {
+    fn provider(&self) -> &str {
+        "mock"
+    }
+
+    async fn net_identifiers(&self) -> Result<ChainIdentifier, Error> {
+        let genesis = self.block_with_number(0).and_then(|block| block.hash);
+        Ok(ChainIdentifier {
+            net_version: "1".to_string(),
+            genesis_block_hash: genesis.unwrap_or_default().into(),
+        })
+    }
+
+    fn latest_block(
+        &self,
+        _logger: &Logger,
+    ) -> Box<dyn Future01<Item = LightEthereumBlock, Error = IngestorError> + Send + Unpin> {
+        Box::new(future01::ok(self.latest().clone()))
+    }
+
+    fn latest_block_header(
+        &self,
+        _logger: &Logger,
+    ) -> Box<dyn Future01<Item = Block<H256>, Error = IngestorError> + Send> {
+        let latest = self.latest();
+        Box::new(future01::ok(Block {
+            hash: latest.hash,
+            number: latest.number,
+            ..Default::default()
+        }))
+    }
+
+    fn load_block(
+        &self,
+        _logger: &Logger,
+        block_hash: H256,
+    ) -> Box<dyn Future01<Item = LightEthereumBlock, Error = Error> + Send> {
+        Box::new(future01::result(
+            self.blocks
+                .get(&block_hash)
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("the mock has no block {block_hash:?}")),
+        ))
+    }
+
+    fn load_blocks(
+        &self,
+        _logger: Logger,
+        _chain_store: Arc<dyn ChainStore>,
+        block_hashes: HashSet<H256>,
+    ) -> Box<dyn Stream01<Item = Arc<LightEthereumBlock>, Error = Error> + Send> {
+        let blocks: Vec<_> = block_hashes
+            .iter()
+            .filter_map(|block_hash| self.blocks.get(block_hash).cloned().map(Arc::new))
+            .collect();
+        Box::new(stream01::iter_ok(blocks))
+    }
+
+    fn block_by_hash(
+        &self,
+        _logger: &Logger,
+        block_hash: H256,
+    ) -> Box<dyn Future01<Item = Option<LightEthereumBlock>, Error = Error> + Send> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        Box::new(future01::ok(self.blocks.get(&block_hash).cloned()))
+    }
+
+    fn supports_batch_requests(&self) -> bool {
+        self.batching
+    }
+
+    async fn blocks_by_hashes_batched(
+        &self,
+        _logger: &Logger,
+        block_hashes: &[H256],
+    ) -> Result<Vec<Option<LightEthereumBlock>>, Error> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        Ok(block_hashes
+            .iter()
+            .rev()
+            .map(|block_hash| self.blocks.get(block_hash).cloned())
+            .collect())
+    }
+
+    fn block_by_number(
+        &self,
+        _logger: &Logger,
+        block_number: BlockNumber,
+    ) -> Box<dyn Future01<Item = Option<LightEthereumBlock>, Error = Error> + Send> {
+        Box::new(future01::ok(self.block_with_number(block_number).cloned()))
+    }
+
+    fn load_full_block(
+        &self,
+        _logger: &Logger,
+        block: LightEthereumBlock,
+    ) -> Pin<Box<dyn std::future::Future<Output = Result<EthereumBlock, IngestorError>> + Send>>
+    {
+        let hash = block.hash.unwrap_or_default();
+        let full_block = match self.receipts.get(&hash) {
+            Some(receipts) => Ok(EthereumBlock {
+                block: Arc::new(block),
+                transaction_receipts: receipts.iter().cloned().map(Arc::new).collect(),
+            }),
+            None => Err(IngestorError::BlockUnavailable(hash)),
+        };
+        Box::pin(std::future::ready(full_block))
+    }
+
+    fn block_pointer_from_number(
+        &self,
+        _logger: &Logger,
+        block_number: BlockNumber,
+    ) -> Box<dyn Future01<Item = BlockPtr, Error = IngestorError> + Send> {
+        let block_ptr = self
+            .block_with_number(block_number)
+            .and_then(|block| block.hash)
+            .map(|block_hash| BlockPtr::from((block_hash, block_number)))
+            .ok_or_else(|| anyhow::anyhow!("the mock has no block number {block_number}"));
+        Box::new(future01::result(block_ptr.map_err(IngestorError::Unknown)))
+    }
+
+    fn block_hash_by_block_number(
+        &self,
+        _logger: &Logger,
+        block_number: BlockNumber,
+    ) -> Box<dyn Future01<Item = Option<H256>, Error = Error> + Send> {
+        let block_hash = self
+            .block_with_number(block_number)
+            .and_then(|block| block.hash);
+        Box::new(future01::ok(block_hash))
+    }
+
+    fn contract_call(
+        &self,
+        _logger: &Logger,
+        _call: EthereumContractCall,
+        _cache: Arc<dyn EthereumCallCache>,
+    ) -> Box<dyn Future01<Item = Vec<Token>, Error = EthereumContractCallError> + Send> {
+        // The canned blocks hold no contracts, so every call reverts
+        Box::new(future01::err(EthereumContractCallError::Revert(
+            "the mock has no contracts".to_string(),
+        )))
+    }
+}
+
+fn options() -> CheckOptions {
+    CheckOptions {
+        concurrency: 4,
+        skip_confirmation: true,
+        output: OutputFormat::Json,
+        ignored_fields: vec![],
+        max_attempts: 1,
+        retry_delay: Duration::ZERO,
+        rpc_timeout: Duration::from_secs(5),
+        ..CheckOptions::default()
+    }
+}
+
+/// An empty directory for the files of one test, which is removed again when it goes out of
+/// scope, also when the test panics.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("check-blocks-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    fn path(&self) -> &Path {
+        &self.0
+    }
+
+    fn join(&self, name: impl AsRef<Path>) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+fn hash(n: u64) -> H256 {
+    H256::from_low_u64_be(n)
+}
+
+/// A block shaped like the ones the provider returns.
+fn block(number: u64) -> LightEthereumBlock {
+    LightEthereumBlock {
+        hash: Some(hash(number)),
+        parent_hash: hash(number - 1),
+        number: Some(U64::from(number)),
+        gas_used: U256::from(21_000),
+        gas_limit: U256::from(30_000_000),
+        timestamp: U256::from(1_700_000_000),
+        total_difficulty: Some(U256::from(42)),
+        size: Some(U256::from(512)),
+        ..Default::default()
+    }
+}
+
+/// The block serialized the same way `fetch_single_provider_block` does.
+fn representative_block() -> Value {
+    serde_json::to_value(block(2)).unwrap()
+}
+
+async fn compare(
+    adapter: &MockEthereumAdapter,
+    cached_blocks: Vec<(H256, Value)>,
+    report: &mut CheckBlocksReport,
+) -> anyhow::Result<Vec<(H256, Option<Value>)>> {
+    compare_with(adapter, cached_blocks, &options(), report).await
+}
+
+async fn compare_with(
+    adapter: &MockEthereumAdapter,
+    cached_blocks: Vec<(H256, Value)>,
+    options: &CheckOptions,
+    report: &mut CheckBlocksReport,
+) -> anyhow::Result<Vec<(H256, Option<Value>)>> {
+    let (block_hashes, cached_blocks): (Vec<_>, Vec<_>) = cached_blocks.into_iter().unzip();
+    compare_chunk(
+        &block_hashes,
+        cached_blocks,
+        adapter,
+        NetworkProfile::for_network("mainnet"),
+        None,
+        &discard(),
+        options,
+        &Progress::hidden(),
+        report,
+    )
+    .await
+}
+
+#[tokio::test]
+async fn identical_block_is_not_reported() {
+    let adapter = MockEthereumAdapter::new([(hash(2), block(2))]);
+    let mut report = CheckBlocksReport::default();
+
+    let diverged = compare(
+        &adapter,
+        vec![(hash(2), representative_block())],
+        &mut report,
+    )
+    .await
+    .unwrap();
+
+    assert!(diverged.is_empty());
+    assert_eq!(report.checked, 1);
+    assert!(report.diverged.is_empty());
+    assert_eq!(report.results[0].diff, None);
+}
+
+#[tokio::test]
+async fn batched_blocks_are_matched_by_hash() {
+    let adapter =
+        MockEthereumAdapter::new((2..=4).map(|number| (hash(number), block(number)))).batching();
+    let mut diverging = serde_json::to_value(block(3)).unwrap();
+    diverging["gasUsed"] = json!("0x1");
+    let cached_blocks = vec![
+        (hash(2), serde_json::to_value(block(2)).unwrap()),
+        (hash(3), diverging),
+        (hash(4), serde_json::to_value(block(4)).unwrap()),
+    ];
+    let options = CheckOptions {
+        rpc_batch_size: Some(2),
+        ..options()
+    };
+    let mut report = CheckBlocksReport::default();
+
+    let diverged = compare_with(&adapter, cached_blocks, &options, &mut report)
+        .await
+        .unwrap();
+
+    assert_eq!(adapter.calls(), 2);
+    assert_eq!(report.checked, 3);
+    assert_eq!(diverged.len(), 1);
+    assert_eq!(diverged[0].0, hash(3));
+}
+
+#[test]
+fn batched_blocks_are_matched_strictly_by_hash() {
+    let batch = [hash(2), hash(3), hash(4)];
+
+    let (blocks, strays) =
+        match_batched_blocks(&batch, vec![Some(block(3)), Some(block(9)), Some(block(2))]);
+
+    assert_eq!(
+        blocks[0].as_ref().unwrap().as_ref().unwrap().hash,
+        Some(hash(2))
+    );
+    assert_eq!(
+        blocks[1].as_ref().unwrap().as_ref().unwrap().hash,
+        Some(hash(3))
+    );
+    // With a stray block around, the missing answer for block 4 can't be trusted
+    let e = blocks[2].as_ref().unwrap_err();
+    assert!(e.to_string().contains(&format!("{:?}", hash(9))));
+    assert!(CheckBlocksError::find(e).is_none());
+    assert_eq!(strays, vec![Some(hash(9))]);
+
+    // Without one, the provider just doesn't have the block
+    let (blocks, strays) = match_batched_blocks(&batch, vec![Some(block(3)), None]);
+    assert!(blocks[0].as_ref().unwrap().is_none());
+    assert!(blocks[2].as_ref().unwrap().is_none());
+    assert!(strays.is_empty());
+}
+
+#[tokio::test]
+async fn block_with_a_different_field_is_reported_for_deletion() {
+    let adapter = MockEthereumAdapter::new([(hash(2), block(2))]);
+    let mut cached = representative_block();
+    cached["gasUsed"] = json!("0x5209");
+    let mut report = CheckBlocksReport::default();
+
+    let diverged = compare(&adapter, vec![(hash(2), cached)], &mut report)
+        .await
+        .unwrap();
+
+    assert_eq!(diverged, vec![(hash(2), None)]);
+    assert_eq!(report.checked, 1);
+    assert_eq!(report.diverged, vec![(hash(2), 2)]);
+    assert!(report.results[0].diverged);
+    assert!(report.results[0].diff.is_some());
+}
+
+#[tokio::test]
+async fn check_aborts_as_soon_as_too_many_blocks_diverged() {
+    let adapter = MockEthereumAdapter::new((2..=4).map(|number| (hash(number), block(number))));
+    let cached = (2..=4)
+        .map(|number| {
+            let mut cached = serde_json::to_value(block(number)).unwrap();
+            cached["gasUsed"] = json!("0x5209");
+            (hash(number), cached)
+        })
+        .collect();
+    let options = CheckOptions {
+        max_deletions: Some(1),
+        ..options()
+    };
+    let mut report = CheckBlocksReport::default();
+
+    let err = compare_with(&adapter, cached, &options, &mut report)
+        .await
+        .unwrap_err();
+
+    assert!(format!("{err:#}").contains("`--max-deletions`"));
+    // The third block was never compared
+    assert_eq!(report.checked, 2);
+}
+
+#[tokio::test]
+async fn divergence_needs_a_quorum_of_providers() {
+    let mut stale = block(2);
+    stale.gas_used = U256::from(21_001);
+    let mut cached = representative_block();
+    cached["gasUsed"] = json!("0x5209");
+    let check = |witnesses: Vec<LightEthereumBlock>| {
+        let cached = cached.clone();
+        async move {
+            let adapter = MockEthereumAdapter::new([(hash(2), block(2))]);
+            let witnesses = witnesses
+                .into_iter()
+                .map(|block| -> Arc<dyn EthereumAdapterTrait> {
+                    Arc::new(MockEthereumAdapter::new([(hash(2), block)]))
+                })
+                .collect();
+            let options = CheckOptions {
+                quorum: 2,
+                witnesses: Witnesses(witnesses),
+                ..options()
+            };
+            let mut report = CheckBlocksReport::default();
+            let diverged = compare_with(&adapter, vec![(hash(2), cached)], &options, &mut report)
+                .await
+                .unwrap();
+            (diverged, report)
+        }
+    };
+
+    // The other provider agrees with the main one
+    let (diverged, report) = check(vec![block(2)]).await;
+    assert_eq!(diverged, vec![(hash(2), None)]);
+    assert_eq!(report.results[0].agreeing_providers, Some(2));
+
+    // Neither of the other providers agrees with the main one
+    let (diverged, report) = check(vec![stale, block(3)]).await;
+    assert!(diverged.is_empty());
+    assert!(report.diverged.is_empty());
+    assert_eq!(report.unconfirmed, vec![hash(2)]);
+    assert_eq!(report.results[0].agreeing_providers, Some(1));
+}
+
+#[tokio::test]
+async fn diverged_blocks_remember_what_they_were_compared_as() {
+    let adapter = MockEthereumAdapter::new([(hash(2), block(2)), (hash(3), block(3))]);
+    let mut cached = representative_block();
+    cached["gasUsed"] = json!("0x5209");
+    let same = serde_json::to_value(block(3)).unwrap();
+    let mut report = CheckBlocksReport::default();
+
+    compare(
+        &adapter,
+        vec![(hash(2), cached.clone()), (hash(3), same)],
+        &mut report,
+    )
+    .await
+    .unwrap();
+    assert_eq!(report.results[0].cached_digest, Some(block_digest(&cached)));
+    assert_eq!(report.results[1].cached_digest, None);
+
+    // The digest only matches the block it was computed from
+    let mut changed = cached.clone();
+    assert_eq!(block_digest(&changed), block_digest(&cached));
+    changed["gasUsed"] = json!("0x5208");
+    assert_ne!(block_digest(&changed), block_digest(&cached));
+
+    // Dry runs never delete, so they don't need the digest
+    let options = CheckOptions {
+        dry_run: true,
+        ..options()
+    };
+    let mut report = CheckBlocksReport::default();
+    compare_with(&adapter, vec![(hash(2), cached)], &options, &mut report)
+        .await
+        .unwrap();
+    assert_eq!(report.results[0].cached_digest, None);
+}
+
+#[test]
+fn l2_fields_are_normalized_by_network_profile() {
+    assert_eq!(NetworkProfile::for_network("mainnet").name, "ethereum");
+    assert_eq!(NetworkProfile::for_network("arbitrum-one").name, "arbitrum");
+    assert_eq!(NetworkProfile::for_network("base").name, "optimism");
+
+    let arbitrum = NetworkProfile::for_network("arbitrum-one");
+    let mut cached = json!({ "number": "0x2", "l1BlockNumber": "0x0010", "sendCount": "0x1" });
+    let mut provider = json!({ "number": "0x2", "l1BlockNumber": "0x10", "sendRoot": "0xab" });
+    arbitrum.normalize_quantities(&mut cached);
+    arbitrum.normalize_quantities(&mut provider);
+    arbitrum.drop_optional_fields(&mut cached, &mut provider);
+    assert_eq!(cached, json!({ "number": "0x2", "l1BlockNumber": "0x10" }));
+    assert_eq!(cached, provider);
+
+    // Other networks keep these fields
+    let ethereum = NetworkProfile::for_network("mainnet");
+    let mut cached = json!({ "l1BlockNumber": "0x0010" });
+    let mut provider = json!({});
+    ethereum.normalize_quantities(&mut cached);
+    ethereum.drop_optional_fields(&mut cached, &mut provider);
+    assert_eq!(cached, json!({ "l1BlockNumber": "0x0010" }));
+}
+
+#[tokio::test]
+async fn blocks_with_few_differences_are_left_alone() {
+    let adapter = MockEthereumAdapter::new([(hash(2), block(2))]);
+    let mut cached = representative_block();
+    cached["gasUsed"] = json!("0x5209");
+    let options = CheckOptions {
+        min_diff_fields: Some(2),
+        ..options()
+    };
+    let mut report = CheckBlocksReport::default();
+
+    let diverged = compare_with(
+        &adapter,
+        vec![(hash(2), cached.clone())],
+        &options,
+        &mut report,
+    )
+    .await
+    .unwrap();
+    assert!(diverged.is_empty());
+    assert!(report.diverged.is_empty());
+    assert_eq!(report.minor, vec![(hash(2), 1)]);
+    assert!(report.results[0].diff.is_some());
+
+    cached["gasLimit"] = json!("0x1");
+    let mut report = CheckBlocksReport::default();
+    let diverged = compare_with(&adapter, vec![(hash(2), cached)], &options, &mut report)
+        .await
+        .unwrap();
+    assert_eq!(diverged, vec![(hash(2), None)]);
+    assert!(report.minor.is_empty());
+}
+
+#[tokio::test]
+async fn missing_provider_block_is_reported_without_failing_the_check() {
+    let adapter = MockEthereumAdapter::new([(hash(3), block(3))]);
+    let mut report = CheckBlocksReport::default();
+
+    let diverged = compare(
+        &adapter,
+        vec![
+            (hash(2), representative_block()),
+            (hash(3), serde_json::to_value(block(3)).unwrap()),
+        ],
+        &mut report,
+    )
+    .await
+    .unwrap();
+
+    assert!(diverged.is_empty());
+    assert_eq!(report.checked, 1);
+    let [(failed, error)] = report.fetch_failed.as_slice() else {
+        panic!("expected one failed block, got {:?}", report.fetch_failed);
+    };
+    assert_eq!(*failed, hash(2));
+    assert!(error.contains("found no block"), "{error}");
+    assert_eq!(report.results[0].fetch_error.as_ref(), Some(error));
+    assert!(report.results[1].fetch_error.is_none());
+}
+
+#[tokio::test]
+async fn check_gives_up_when_the_provider_fails_on_many_blocks_in_a_row() {
+    let numbers = 2..2 + MAX_CONSECUTIVE_FETCH_FAILURES as u64;
+    let cached: Vec<_> = numbers
+        .clone()
+        .map(|number| (hash(number), serde_json::to_value(block(number)).unwrap()))
+        .collect();
+
+    // One block in between that the provider has resets the count
+    let adapter = MockEthereumAdapter::new([(hash(10), block(10))]);
+    let mut report = CheckBlocksReport::default();
+    compare(&adapter, cached.clone(), &mut report)
+        .await
+        .unwrap();
+    assert_eq!(
+        report.fetch_failed.len(),
+        MAX_CONSECUTIVE_FETCH_FAILURES - 1
+    );
+
+    let adapter = MockEthereumAdapter::new([]);
+    let mut report = CheckBlocksReport::default();
+    let err = compare(&adapter, cached, &mut report).await.unwrap_err();
+    assert!(format!("{err:#}").contains("blocks in a row"), "{err:#}");
+}
+
+#[tokio::test]
+async fn missing_provider_blocks_near_the_tip_are_skipped() {
+    let adapter = MockEthereumAdapter::new([]);
+    let options = CheckOptions {
+        skip_tip_gap: Some(3),
+        ..options()
+    };
+    let check = |number: u64, chain_head: Option<i32>| {
+        let (adapter, options) = (&adapter, &options);
+        async move {
+            let mut report = CheckBlocksReport::default();
+            let cached = serde_json::to_value(block(number)).unwrap();
+            compare_chunk(
+                &[hash(number)],
+                vec![cached],
+                adapter,
+                NetworkProfile::for_network("mainnet"),
+                chain_head,
+                &discard(),
+                options,
+                &Progress::hidden(),
+                &mut report,
+            )
+            .await
+            .map(|_| report)
+        }
+    };
+
+    let report = check(7, Some(10)).await.unwrap();
+    assert_eq!(report.tip_skipped, vec![hash(7)]);
+    assert_eq!(report.checked, 0);
+    assert!(report.results[0].tip_skipped);
+
+    // Deeper blocks are expected to be there
+    let report = check(6, Some(10)).await.unwrap();
+    assert!(report.tip_skipped.is_empty());
+    assert_eq!(report.fetch_failed[0].0, hash(6));
+    let report = check(7, None).await.unwrap();
+    assert_eq!(report.fetch_failed[0].0, hash(7));
+}
+
+#[test]
+fn errors_can_be_told_apart_under_context() {
+    let err = anyhow::Error::from(CheckBlocksError::BlockNotCached { hash: hash(7) })
+        .context("failed to check the range");
+    assert!(matches!(
+        CheckBlocksError::find(&err),
+        Some(CheckBlocksError::BlockNotCached { hash }) if *hash == self::hash(7)
+    ));
+    assert!(CheckBlocksError::find(&anyhow::anyhow!("something else")).is_none());
+
+    let err = CheckBlocksError::ProviderUnreachable {
+        what: "block 7".to_string(),
+        attempts: 3,
+        source: "connection refused".into(),
+    };
+    assert_eq!(err.to_string(), "failed to fetch block 7 after 3 attempts");
+}
+
+#[tokio::test]
+async fn wrong_provider_block_is_provider_misbehavior() {
+    let adapter = MockEthereumAdapter::new([(hash(2), block(3))]);
+    let mut report = CheckBlocksReport::default();
+
+    let diverged = compare(
+        &adapter,
+        vec![(hash(2), representative_block())],
+        &mut report,
+    )
+    .await
+    .unwrap();
+
+    assert!(diverged.is_empty());
+    assert_eq!(report.checked, 0);
+    assert_eq!(report.provider_misbehavior, vec![hash(2)]);
+    assert!(report.results[0].provider_misbehavior);
+}
+
+#[tokio::test]
+async fn corrupt_cached_block_is_not_compared() {
+    let adapter = MockEthereumAdapter::new([(hash(2), block(2))]);
+    let mut report = CheckBlocksReport::default();
+    let mut partial = representative_block();
+    partial.as_object_mut().unwrap().remove("parentHash");
+    let options = CheckOptions {
+        delete_corrupt: true,
+        ..options()
+    };
+
+    let diverged = compare_with(&adapter, vec![(hash(2), partial)], &options, &mut report)
+        .await
+        .unwrap();
+
+    assert!(diverged.is_empty());
+    assert_eq!(report.checked, 0);
+    assert!(report.diverged.is_empty());
+    assert_eq!(
+        report.corrupt,
+        vec![(hash(2), "missing or malformed `parentHash`".to_string())]
+    );
+    assert!(report.results[0].corrupt);
+    // Only deleting the block marks it as deleted, which comparing never does
+    assert!(!report.results[0].deleted);
+}
+
+#[test]
+fn cached_blocks_need_a_hash_number_and_parent_hash() {
+    assert!(steps::validate_cached_block(&representative_block()).is_ok());
+    assert_eq!(
+        steps::validate_cached_block(&serde_json::json!({ "hash": "0x01", "number": 1 })),
+        Err("missing or malformed `number`, `parentHash`".to_string())
+    );
+    assert_eq!(
+        steps::validate_cached_block(&Value::Null),
+        Err("it is null".to_string())
+    );
+    assert_eq!(
+        steps::validate_cached_block(&serde_json::json!([])),
+        Err("it is not a JSON object".to_string())
+    );
+}
+
+#[test]
+fn repair_command_rechecks_the_diverged_blocks() {
+    let mut report = CheckBlocksReport::default();
+    assert_eq!(report.repair_command("mainnet"), None);
+
+    report.diverged.push((hash(2), 2));
+    assert_eq!(
+        report.repair_command("mainnet").unwrap(),
+        format!("graphman chain check-blocks mainnet by-hash {:?}", hash(2))
+    );
+
+    report.diverged.push((hash(5), 5));
+    assert_eq!(
+        report.repair_command("mainnet").unwrap(),
+        format!(
+            "printf '%s\\n' {:?} {:?} | graphman chain check-blocks mainnet by-hash-list -",
+            hash(2),
+            hash(5)
+        )
+    );
+}
+
+#[test]
+fn checkpoints_are_only_resumed_on_their_chain() {
+    let dir = TempDir::new("checkpoint");
+    let path = dir.join("checkpoint.json");
+    assert_eq!(steps::load_checkpoint(&path, "mainnet").unwrap(), None);
+
+    steps::save_checkpoint(&path, "mainnet", 1000).unwrap();
+    steps::save_checkpoint(&path, "mainnet", 2000).unwrap();
+    assert_eq!(
+        steps::load_checkpoint(&path, "mainnet").unwrap(),
+        Some(2000)
+    );
+    let err = steps::load_checkpoint(&path, "goerli").unwrap_err();
+    assert!(err.to_string().contains("is for chain mainnet, not goerli"));
+}
+
+#[tokio::test]
+async fn diffs_go_to_the_diff_file() {
+    let dir = TempDir::new("diffs");
+    let path = dir.join("diffs.txt");
+    let adapter = MockEthereumAdapter::new([(hash(2), block(2)), (hash(3), block(3))]);
+    let mut diverging = serde_json::to_value(block(3)).unwrap();
+    diverging["gasUsed"] = json!("0x1");
+    let cached_blocks = vec![
+        (hash(2), serde_json::to_value(block(2)).unwrap()),
+        (hash(3), diverging),
+    ];
+    let options = CheckOptions {
+        dry_run: true,
+        output: OutputFormat::Human,
+        diff_render: DiffRenderOptions {
+            color: true,
+            ..DiffRenderOptions::default()
+        },
+        diff_file: Some(Arc::new(DiffFile::create(path.clone()).unwrap())),
+        ..options()
+    };
+    let mut report = CheckBlocksReport::default();
+
+    compare_with(&adapter, cached_blocks, &options, &mut report)
+        .await
+        .unwrap();
+
+    let text = std::fs::read_to_string(&path).unwrap();
+    assert!(text.starts_with(&format!("block #3 ({:?}) diverges from cache:\n", hash(3))));
+    assert!(text.contains("gasUsed"));
+    assert!(!text.contains('\u{1b}'));
+    assert!(!text.contains(&format!("{:?}", hash(2))));
+}
+
+#[tokio::test]
+async fn diverged_blocks_are_exported() {
+    let dir = TempDir::new("export");
+    let adapter = MockEthereumAdapter::new([(hash(2), block(2)), (hash(3), block(3))]);
+    let mut diverging = serde_json::to_value(block(3)).unwrap();
+    diverging["gasUsed"] = json!("0x1");
+    let cached_blocks = vec![
+        (hash(2), serde_json::to_value(block(2)).unwrap()),
+        (hash(3), diverging.clone()),
+    ];
+    let options = CheckOptions {
+        dry_run: true,
+        export_dir: Some(dir.path().to_path_buf()),
+        ..options()
+    };
+    let mut report = CheckBlocksReport::default();
+
+    compare_with(&adapter, cached_blocks, &options, &mut report)
+        .await
+        .unwrap();
+
+    let read = |name: String| -> Value {
+        serde_json::from_slice(&std::fs::read(dir.join(name)).unwrap()).unwrap()
+    };
+    assert_eq!(read(format!("{:?}.cached.json", hash(3))), diverging);
+    assert_eq!(
+        read(format!("{:?}.provider.json", hash(3))),
+        serde_json::to_value(block(3)).unwrap()
+    );
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+}
+
+#[tokio::test]
+async fn provider_fixtures_take_the_place_of_the_provider() {
+    let dir = TempDir::new("fixture");
+    let mut diverging = serde_json::to_value(block(3)).unwrap();
+    diverging["gasUsed"] = json!("0x1");
+    for (name, block) in [
+        (format!("{:?}.json", hash(2)), block(2)),
+        (format!("{:?}.provider.json", hash(3)), block(3)),
+    ] {
+        std::fs::write(dir.join(name), serde_json::to_vec(&block).unwrap()).unwrap();
+    }
+    // The adapter knows none of the blocks, all of them come from the fixture
+    let adapter = MockEthereumAdapter::new([]);
+    let options = CheckOptions {
+        dry_run: true,
+        provider_fixture: Some(dir.path().to_path_buf()),
+        ..options()
+    };
+    let cached_blocks = vec![
+        (hash(2), serde_json::to_value(block(2)).unwrap()),
+        (hash(3), diverging),
+    ];
+    let mut report = CheckBlocksReport::default();
+
+    compare_with(&adapter, cached_blocks, &options, &mut report)
+        .await
+        .unwrap();
+    assert_eq!(report.checked, 2);
+    assert_eq!(report.diverged, vec![(hash(3), 3)]);
+    assert_eq!(adapter.calls(), 0);
+
+    let cached_blocks = vec![(hash(4), serde_json::to_value(block(4)).unwrap())];
+    compare_with(&adapter, cached_blocks, &options, &mut report)
+        .await
+        .unwrap();
+    assert_eq!(report.fetch_failed[0].0, hash(4));
+    assert!(report.fetch_failed[0].1.contains("has no block"));
+}
+
+#[test]
+fn providers_of_other_chains_are_recognized() {
+    let identifier = |net_version: &str, genesis: u64| ChainIdentifier {
+        net_version: net_version.to_string(),
+        genesis_block_hash: hash(genesis).into(),
+    };
+    let mainnet = identifier("1", 1);
+
+    assert_eq!(chain_mismatch(&mainnet, &identifier("1", 1)), None);
+    let goerli = chain_mismatch(&mainnet, &identifier("5", 5)).unwrap();
+    assert!(goerli.starts_with("its genesis block is"), "{goerli}");
+    assert_eq!(
+        chain_mismatch(&mainnet, &identifier("5", 1)).as_deref(),
+        Some("its net version is 5 instead of 1")
+    );
+}
+
+#[test]
+fn heights_are_classified_by_their_cached_blocks() {
+    let block_hashes = HashMap::from([
+        (3, vec![hash(3)]),
+        (4, vec![]),
+        (6, vec![hash(60), hash(61)]),
+        (9, vec![hash(9)]),
+    ]);
+
+    assert_eq!(
+        classify_heights(&[3, 4, 5, 6, 9], block_hashes),
+        vec![
+            (3, Height::Present(hash(3))),
+            (4, Height::Missing),
+            (5, Height::Missing),
+            (6, Height::Ambiguous(vec![hash(60), hash(61)])),
+            (9, Height::Present(hash(9))),
+        ]
+    );
+}
+
+#[test]
+fn gzipped_lines_can_be_read_as_one_stream() {
+    use std::io::Read;
+
+    let mut log = super::helpers::gzip(b"first\n").unwrap();
+    log.extend(super::helpers::gzip(b"second\n").unwrap());
+
+    let mut text = String::new();
+    flate2::read::MultiGzDecoder::new(log.as_slice())
+        .read_to_string(&mut text)
+        .unwrap();
+    assert_eq!(text, "first\nsecond\n");
+}
+
+#[test]
+fn audited_hashes_are_read_from_gzipped_logs() {
+    let dir = TempDir::new("audit");
+    let path = dir.join("audit.log");
+    let lines = [
+        json!({ "manifest": { "chain": "mainnet" } }),
+        json!({ "chain": "mainnet", "number": 3, "hash": hash(3), "reason": "diverged" }),
+        json!({ "chain": "mainnet", "range": [5, 9], "count": 4, "reason": "purged" }),
+        json!({ "chain": "mainnet", "number": 7, "hash": hash(7), "reason": "diverged" }),
+    ];
+    let mut log = Vec::new();
+    for line in lines {
+        log.extend(super::helpers::gzip(format!("{line}\n").as_bytes()).unwrap());
+    }
+    std::fs::write(&path, log).unwrap();
+
+    let audited = super::read_audited_hashes(&path).unwrap();
+    assert_eq!(audited, HashSet::from([hash(3), hash(7)]));
+
+    let err = super::helpers::audited_hashes("{}\nnot json\n").unwrap_err();
+    assert!(err.to_string().contains("line 2"));
+}
+
+#[test]
+fn a_run_appends_one_gzip_member_to_the_audit_log() {
+    let dir = TempDir::new("audit-stream");
+    let path = dir.join("audit.log.gz");
+    let line = |number: u8| format!("{}\n", json!({ "number": number, "hash": hash(number) }));
+
+    let stream = AuditStream::default();
+    stream.append(&path, line(3).as_bytes()).unwrap();
+    stream.append(&path, line(7).as_bytes()).unwrap();
+    // Before the stream is finished, the lines are already readable
+    let audited = super::read_audited_hashes(&path).unwrap();
+    assert_eq!(audited, HashSet::from([hash(3), hash(7)]));
+    drop(stream);
+
+    let stream = AuditStream::default();
+    stream.append(&path, line(9).as_bytes()).unwrap();
+    drop(stream);
+
+    // One gzip member per run rather than per line
+    let log = std::fs::read(&path).unwrap();
+    let mut rest = log.as_slice();
+    let mut members = 0;
+    while !rest.is_empty() {
+        let mut member = flate2::bufread::GzDecoder::new(&mut rest);
+        io::copy(&mut member, &mut io::sink()).unwrap();
+        members += 1;
+    }
+    assert_eq!(members, 2);
+    let audited = super::read_audited_hashes(&path).unwrap();
+    assert_eq!(audited, HashSet::from([hash(3), hash(7), hash(9)]));
+}
+
+#[test]
+fn audit_logs_are_either_gzipped_or_plain() {
+    let dir = TempDir::new("audit-check");
+    let plain = dir.join("audit.log");
+    let gzipped = dir.join("audit.log.gz");
+
+    // New files only need the right name
+    super::check_audit_log(&plain, false).unwrap();
+    super::check_audit_log(&gzipped, true).unwrap();
+    let err = super::check_audit_log(&plain, true).unwrap_err();
+    assert!(err.to_string().contains("must end in `.gz`"), "{err}");
+
+    std::fs::write(&plain, super::helpers::gzip(b"{}\n").unwrap()).unwrap();
+    std::fs::write(&gzipped, "{}\n").unwrap();
+    let err = super::check_audit_log(&plain, false).unwrap_err();
+    assert!(err.to_string().contains("is gzipped"), "{err}");
+    let err = super::check_audit_log(&gzipped, false).unwrap_err();
+    assert!(err.to_string().contains("holds plain text"), "{err}");
+
+    let mut unfinished = super::helpers::gzip(b"{}\n{}\n").unwrap();
+    unfinished.truncate(unfinished.len() - 4);
+    std::fs::write(&gzipped, unfinished).unwrap();
+    let err = super::check_audit_log(&gzipped, false).unwrap_err();
+    assert!(err.to_string().contains("middle of a gzip member"), "{err}");
+}
+
+#[test]
+fn only_numbers_with_several_blocks_are_forks() {
+    let block_hashes = HashMap::from([
+        (7, vec![hash(71), hash(70)]),
+        (5, vec![hash(5)]),
+        (3, vec![hash(30), hash(31), hash(32)]),
+    ]);
+    assert_eq!(
+        forks(block_hashes),
+        vec![
+            Fork {
+                number: 3,
+                hashes: vec![hash(30), hash(31), hash(32)]
+            },
+            Fork {
+                number: 7,
+                hashes: vec![hash(70), hash(71)]
+            },
+        ]
+    );
+}
+
+#[test]
+fn cached_blocks_are_matched_by_number_and_hash() {
+    let ours = HashMap::from([
+        (3, vec![hash(3)]),
+        (4, vec![hash(40), hash(41)]),
+        (5, vec![hash(5)]),
+    ]);
+    let theirs = HashMap::from([
+        (4, vec![hash(42), hash(40)]),
+        (5, vec![hash(5)]),
+        (6, vec![hash(6)]),
+    ]);
+    let block = |number, hash| CachedBlockRef { number, hash };
+
+    let (common, only_ours, only_theirs) = match_cached_blocks(ours, theirs);
+    assert_eq!(common, vec![block(4, hash(40)), block(5, hash(5))]);
+    assert_eq!(only_ours, vec![block(3, hash(3)), block(4, hash(41))]);
+    assert_eq!(only_theirs, vec![block(4, hash(42)), block(6, hash(6))]);
+}
+
+#[test]
+fn deletions_are_only_verified_if_they_removed_the_block() {
+    assert_eq!(None, deletion_problem(1, 0));
+    assert_eq!(None, deletion_problem(2, 0));
+    assert!(deletion_problem(0, 0).unwrap().contains("removed no rows"));
+    assert!(deletion_problem(1, 1).unwrap().contains("left 1 blocks"));
+    assert!(deletion_problem(0, 2).unwrap().contains("left 2 blocks"));
+}
+
+#[test]
+fn only_heights_without_any_block_are_missing() {
+    let block_hashes = HashMap::from([
+        (1, vec![hash(1)]),
+        (2, vec![]),
+        (4, vec![hash(4), hash(44)]),
+    ]);
+    assert_eq!(
+        vec![2, 3, 5],
+        missing_block_numbers(&[1, 2, 3, 4, 5], block_hashes)
+    );
+}
+
+#[test]
+fn parallel_work_runs_at_once_and_keeps_its_order() {
+    let running = AtomicUsize::new(0);
+    let most_running = AtomicUsize::new(0);
+    let results = run_in_parallel((0..6).collect(), 3, |item: u64| {
+        let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+        most_running.fetch_max(now, Ordering::SeqCst);
+        // Blocks the thread like a synchronous database query does
+        std::thread::sleep(Duration::from_millis(20 * (6 - item)));
+        running.fetch_sub(1, Ordering::SeqCst);
+        item * 10
+    });
+    assert_eq!(results, vec![0, 10, 20, 30, 40, 50]);
+    assert_eq!(most_running.load(Ordering::SeqCst), 3);
+
+    let most_running = AtomicUsize::new(0);
+    let running = AtomicUsize::new(0);
+    run_in_parallel(vec![1, 2, 3], 1, |_| {
+        let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+        most_running.fetch_max(now, Ordering::SeqCst);
+        running.fetch_sub(1, Ordering::SeqCst);
+    });
+    assert_eq!(most_running.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn block_numbers_near_the_tip_are_split_off() {
+    assert_eq!(
+        split_off_tip(vec![1, 97, 5, 98, 100, 102], 100, 2),
+        (vec![1, 97, 5], vec![98, 100, 102])
+    );
+    assert_eq!(split_off_tip(vec![1, 2], 100, 0), (vec![1, 2], vec![]));
+}
+
+#[test]
+fn samples_are_spread_over_all_items() {
+    let items: Vec<i32> = (0..=100).collect();
+    assert_eq!(spread_sample(items.clone(), 5), vec![0, 25, 50, 75, 100]);
+    assert_eq!(spread_sample(items.clone(), 2), vec![0, 100]);
+    assert_eq!(spread_sample(items.clone(), 1), vec![50]);
+    assert!(spread_sample(items.clone(), 0).is_empty());
+    assert_eq!(spread_sample(items.clone(), 101), items);
+    assert_eq!(spread_sample(vec![1, 2], 5), vec![1, 2]);
+    let sample = spread_sample(items, 100);
+    assert_eq!(sample.len(), 100);
+    assert_eq!((sample[0], sample[99]), (0, 100));
+}
+
+#[test]
+fn quickcheck_only_passes_without_any_sign_of_trouble() {
+    let passing = || QuickcheckReport {
+        expected: 10,
+        cached: 10,
+        provider_blocks: Some(10),
+        ..Default::default()
+    };
+    assert!(passing().passed());
+    // Not knowing what the provider has is no reason to fail
+    assert!(QuickcheckReport {
+        provider_blocks: None,
+        ..passing()
+    }
+    .passed());
+    assert!(!QuickcheckReport {
+        provider_blocks: Some(9),
+        ..passing()
+    }
+    .passed());
+    assert!(!QuickcheckReport {
+        missing: vec![3],
+        ..passing()
+    }
+    .passed());
+    assert!(!QuickcheckReport {
+        forked: vec![3],
+        ..passing()
+    }
+    .passed());
+    assert!(!QuickcheckReport {
+        diverged: vec![CachedBlockRef {
+            number: 3,
+            hash: hash(3)
+        }],
+        ..passing()
+    }
+    .passed());
+    assert!(!QuickcheckReport {
+        failed: vec![(hash(3), "unreachable".to_string())],
+        ..passing()
+    }
+    .passed());
+}
+
+#[test]
+fn sampled_block_numbers_show_both_ends() {
+    assert_eq!("", sample_block_numbers(&[]));
+    assert_eq!("#2, #5, #7", sample_block_numbers(&[7, 2, 5, 2]));
+    assert_eq!(
+        "#1, #2, #3, #4, #5, #6",
+        sample_block_numbers(&[6, 5, 4, 3, 2, 1])
+    );
+    let numbers: Vec<i32> = (1..=100).rev().collect();
+    assert_eq!(
+        "#1, #2, #3, ..., #98, #99, #100",
+        sample_block_numbers(&numbers)
+    );
+}
+
+#[test]
+fn interrupt_is_set_once_the_runtime_is_used_up() {
+    assert!(!Interrupt::default().is_set());
+
+    let interrupt = Interrupt::default().with_max_runtime(Duration::from_secs(3600));
+    assert!(!interrupt.is_set());
+    assert!(!interrupt.out_of_time());
+
+    let interrupt = Interrupt::default().with_max_runtime(Duration::ZERO);
+    assert!(interrupt.is_set());
+    assert!(interrupt.out_of_time());
+}
+
+#[tokio::test(start_paused = true)]
+async fn rate_limiter_spaces_out_requests() {
+    let limiter = RateLimiter::new(10.0);
+    let started = tokio::time::Instant::now();
+
+    // The first request goes out right away, the others at ten per second
+    for _ in 0..21 {
+        limiter.acquire(1).await;
+    }
+
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(1990), "{elapsed:?}");
+    assert!(elapsed < Duration::from_millis(2100), "{elapsed:?}");
+}
+
+#[tokio::test(start_paused = true)]
+async fn rate_limiter_charges_a_batch_for_each_of_its_requests() {
+    let limiter = RateLimiter::new(10.0);
+    let started = tokio::time::Instant::now();
+
+    // The batch goes out right away, and the next request waits until the batch is paid for
+    limiter.acquire(20).await;
+    assert!(started.elapsed() < Duration::from_millis(10));
+    limiter.acquire(1).await;
+
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(1990), "{elapsed:?}");
+    assert!(elapsed < Duration::from_millis(2100), "{elapsed:?}");
+}
+
+#[test]
+fn rate_limiter_backs_off_and_recovers() {
+    let limiter = RateLimiter::new(20.0);
+
+    limiter.throttled();
+    assert_eq!(limiter.rate(), 10.0);
+    for _ in 0..10 {
+        limiter.throttled();
+    }
+    assert_eq!(limiter.rate(), 1.25);
+    limiter.succeeded();
+    assert_eq!(limiter.rate(), 2.25);
+    for _ in 0..100 {
+        limiter.succeeded();
+    }
+    assert_eq!(limiter.rate(), 20.0);
+}
+
+#[test]
+fn rate_limit_errors_are_recognized() {
+    use graph::prelude::web3::{self, error::TransportError};
+
+    let too_many = anyhow::Error::from(web3::Error::Transport(TransportError::Code(429)));
+    assert!(steps::is_rate_limited(
+        &too_many.context("failed to fetch block")
+    ));
+    let unavailable = anyhow::Error::from(web3::Error::Transport(TransportError::Code(503)));
+    assert!(!steps::is_rate_limited(&unavailable));
+    assert!(steps::is_rate_limited(&anyhow::anyhow!(
+        "Request failed: Rate limit exceeded"
+    )));
+    assert!(!steps::is_rate_limited(&anyhow::anyhow!(
+        "connection reset"
+    )));
+}
+
+#[tokio::test]
+async fn lagging_chain_head_is_detected() {
+    let adapter = MockEthereumAdapter::new([(hash(2), block(2)), (hash(200), block(200))]);
+    let lag = |chain_head| steps::chain_head_lag(chain_head, &adapter, &discard(), &options());
+
+    assert_eq!(lag(100).await, Some(100));
+    assert_eq!(lag(150).await, None);
+    assert_eq!(lag(200).await, None);
+    assert_eq!(lag(210).await, None);
+}
+
+#[test]
+fn repeated_hashes_are_dropped() {
+    assert_eq!(
+        unique_block_hashes(&[hash(3), hash(2), hash(3), hash(1), hash(2)]),
+        vec![hash(3), hash(2), hash(1)]
+    );
+}
+
+#[test]
+fn chain_heads_are_looked_up_once_unless_refreshed() {
+    let lookups = AtomicUsize::new(0);
+    let fetch =
+        || -> anyhow::Result<i32> { Ok(100 + lookups.fetch_add(1, Ordering::SeqCst) as i32) };
+
+    let chain_heads = ChainHeads::default();
+    assert_eq!(chain_heads.get("mainnet", fetch).unwrap(), 100);
+    assert_eq!(chain_heads.get("mainnet", fetch).unwrap(), 100);
+    assert_eq!(chain_heads.get("goerli", fetch).unwrap(), 101);
+    assert_eq!(lookups.load(Ordering::SeqCst), 2);
+
+    let chain_heads = ChainHeads::new(Some(Duration::ZERO));
+    assert_eq!(chain_heads.get("mainnet", fetch).unwrap(), 102);
+    assert_eq!(chain_heads.get("mainnet", fetch).unwrap(), 103);
+
+    // A failed lookup isn't remembered
+    let chain_heads = ChainHeads::default();
+    assert!(chain_heads
+        .get("mainnet", || anyhow::bail!("no head"))
+        .is_err());
+    assert_eq!(chain_heads.get("mainnet", fetch).unwrap(), 104);
+}
+
+#[test]
+fn deletions_are_counted_up_to_the_maximum_per_chain() {
+    let deletions = DeletionCounts::default();
+    assert_eq!(deletions.add("mainnet", Some(2)), Ok(()));
+    assert_eq!(deletions.add("mainnet", Some(2)), Ok(()));
+    assert_eq!(deletions.add("mainnet", Some(2)), Err(2));
+    // A refused deletion isn't counted
+    assert_eq!(deletions.add("mainnet", Some(3)), Ok(()));
+    assert_eq!(deletions.add("goerli", Some(2)), Ok(()));
+    for _ in 0..10 {
+        assert_eq!(deletions.add("goerli", None), Ok(()));
+    }
+}
+
+#[test]
+fn blocks_with_identical_contents_are_duplicates() {
+    let body = |number: u64| serde_json::to_value(block(number)).unwrap();
+    let block_ref = |number: i32, hash: H256| CachedBlockRef { number, hash };
+    let digests = vec![
+        (block_digest(&body(2)), block_ref(2, hash(2))),
+        (block_digest(&body(3)), block_ref(3, hash(3))),
+        (block_digest(&body(2)), block_ref(5, hash(5))),
+        (block_digest(&body(4)), block_ref(4, hash(4))),
+        (block_digest(&body(4)), block_ref(4, hash(4))),
+        (block_digest(&body(2)), block_ref(1, hash(1))),
+    ];
+    assert_eq!(
+        duplicate_blocks(digests),
+        vec![
+            DuplicateBlocks {
+                digest: block_digest(&body(2)),
+                blocks: vec![
+                    block_ref(1, hash(1)),
+                    block_ref(2, hash(2)),
+                    block_ref(5, hash(5))
+                ],
+            },
+            DuplicateBlocks {
+                digest: block_digest(&body(4)),
+                blocks: vec![block_ref(4, hash(4)), block_ref(4, hash(4))],
+            },
+        ]
+    );
+}
+
+#[test]
+fn manifest_has_the_effective_parameters() {
+    let options = CheckOptions {
+        concurrency: 4,
+        dry_run: true,
+        ignored_fields: vec!["/size".to_string()],
+        ..options()
+    };
+    assert_eq!(
+        manifest(
+            "mainnet",
+            "mainnet-0",
+            "blocks 1-10",
+            Some(&[(1, 10)]),
+            "0.30.0",
+            &options
+        ),
+        json!({
+            "chain": "mainnet",
+            "target": "blocks 1-10",
+            "range": [{ "from": 1, "to": 10 }],
+            "provider": "mainnet-0",
+            "profile": "ethereum",
+            "concurrency": 4,
+            "dry_run": true,
+            "ignored_fields": ["/size"],
+            "version": "0.30.0",
+        })
+    );
+}
+
+#[test]
+fn json_output_is_wrapped_in_a_versioned_envelope() {
+    let entry = json!({
+        "timestamp": "2024-01-01T00:00:00.000Z",
+        "manifest": manifest(
+            "mainnet",
+            "mainnet-0",
+            "blocks 1-5,8-10",
+            Some(&[(1, 5), (8, 10)]),
+            "0.30.0",
+            &options()
+        ),
+    });
+    let envelope = json_envelope(Some(&entry), json!([{ "number": 1 }]));
+    assert_eq!(envelope["schema_version"], json!(JSON_SCHEMA_VERSION));
+    assert_eq!(envelope["chain"], json!("mainnet"));
+    assert_eq!(envelope["target"], json!("blocks 1-5,8-10"));
+    assert_eq!(
+        envelope["range"],
+        json!([{ "from": 1, "to": 5 }, { "from": 8, "to": 10 }])
+    );
+    assert_eq!(envelope["timestamp"], json!("2024-01-01T00:00:00.000Z"));
+    assert_eq!(envelope["manifest"], entry["manifest"]);
+    assert_eq!(envelope["results"], json!([{ "number": 1 }]));
+
+    let envelope = json_envelope(None, json!([]));
+    assert_eq!(envelope["chain"], Value::Null);
+    assert_eq!(envelope["results"], json!([]));
+
+    // A check of blocks by hash has no range
+    let entry = json!({
+        "manifest": manifest("mainnet", "mainnet-0", "1 block", None, "0.30.0", &options()),
+    });
+    assert_eq!(json_envelope(Some(&entry), json!([]))["range"], Value::Null);
+
+    let entry = json!({
+        "manifest": local_manifest("mainnet", "blocks 3", Some(&[(3, 3)]), "0.30.0"),
+    });
+    let envelope = json_envelope(Some(&entry), json!([]));
+    assert_eq!(envelope["target"], json!("blocks 3"));
+    assert_eq!(envelope["range"], json!([{ "from": 3, "to": 3 }]));
+}
+
+#[test]
+fn block_numbers_are_split_into_runs() {
+    assert_eq!(block_runs(&[]), Vec::<(i32, i32)>::new());
+    assert_eq!(block_runs(&[7]), vec![(7, 7)]);
+    assert_eq!(
+        block_runs(&[1, 2, 3, 5, 8, 9]),
+        vec![(1, 3), (5, 5), (8, 9)]
+    );
+}
+
+#[test]
+fn error_lists_the_first_few_items() {
+    assert_eq!(first_few(&[1, 2]), "[1, 2]");
+    assert_eq!(first_few(&[1, 2, 3]), "[1, 2, 3]");
+    assert_eq!(first_few(&[1, 2, 3, 4, 5]), "[1, 2, 3] and 2 more");
+    assert_eq!(first_few::<i32>(&[]), "[]");
+}
+
+#[test]
+fn cached_blocks_are_fetched_with_one_query_per_chunk() {
+    let queries = AtomicUsize::new(0);
+    // Block 4 isn't cached, and block 5 is cached twice
+    let fetch = |block_hashes: &[H256]| -> anyhow::Result<Vec<(H256, Value)>> {
+        queries.fetch_add(1, Ordering::SeqCst);
+        Ok(block_hashes
+            .iter()
+            .filter(|block_hash| **block_hash != hash(4))
+            .flat_map(|block_hash| {
+                let number = block_hash.to_low_u64_be();
+                let block = json!({ "number": format!("{number:#x}") });
+                let copies = if *block_hash == hash(5) { 2 } else { 1 };
+                vec![(*block_hash, block); copies]
+            })
+            // The store returns blocks in no particular order
+            .rev()
+            .collect())
+    };
+    let block_hashes: Vec<_> = (1..=3).map(hash).collect();
+
+    let blocks = steps::fetch_cached_blocks_with(&block_hashes, 1, fetch).unwrap();
+    assert_eq!(queries.swap(0, Ordering::SeqCst), 1);
+    assert_eq!(
+        blocks,
+        vec![
+            json!({ "number": "0x1" }),
+            json!({ "number": "0x2" }),
+            json!({ "number": "0x3" })
+        ]
+    );
+
+    let blocks = steps::fetch_cached_blocks_with(&block_hashes, 2, fetch).unwrap();
+    assert_eq!(queries.swap(0, Ordering::SeqCst), 2);
+    assert_eq!(blocks.len(), 3);
+    assert_eq!(blocks[2], json!({ "number": "0x3" }));
+
+    let err = steps::fetch_cached_blocks_with(&[hash(3), hash(4)], 1, fetch).unwrap_err();
+    assert!(matches!(
+        CheckBlocksError::find(&err),
+        Some(CheckBlocksError::BlockNotCached { hash: missing }) if *missing == hash(4)
+    ));
+    let err = steps::fetch_cached_blocks_with(&[hash(5)], 1, fetch).unwrap_err();
+    assert!(matches!(
+        CheckBlocksError::find(&err),
+        Some(CheckBlocksError::AmbiguousBlock { count: 2, .. })
+    ));
+}
+
+#[tokio::test]
+async fn duplicated_hash_is_fetched_once() {
+    let adapter = MockEthereumAdapter::new([(hash(2), block(2)), (hash(3), block(3))]);
+    let requested = [hash(2), hash(3), hash(2)];
+    let block_hashes = unique_block_hashes(&requested);
+    let cached_blocks = block_hashes
+        .iter()
+        .map(|hash| {
+            let number = hash.to_low_u64_be();
+            (*hash, serde_json::to_value(block(number)).unwrap())
+        })
+        .collect();
+    let mut report = CheckBlocksReport::default();
+
+    compare(&adapter, cached_blocks, &mut report).await.unwrap();
+
+    assert_eq!(adapter.calls(), 2);
+    assert_eq!(report.checked, 2);
+}
+
+/// The block numbers in `expr` for a chain whose head is at 10 and whose earliest cached
+/// block is 3.
+fn block_numbers(expr: &str) -> anyhow::Result<Vec<i32>> {
+    expr.parse::<RangeSet>()?
+        .block_numbers(|| Ok(10), || Ok(Some(3)))
+}
+
+fn range_error(expr: &str) -> String {
+    format!("{:#}", block_numbers(expr).unwrap_err())
+}
+
+/// Resolves the hashes in `expr` with `hash(n)` being the hash of block `n`, for blocks 3 to
+/// 10.
+fn block_numbers_by_hash(expr: &str) -> anyhow::Result<Vec<i32>> {
+    let numbers: HashMap<H256, i32> = (3..=10).map(|n| (hash(n as u64), n)).collect();
+    expr.parse::<RangeSet>()?
+        .resolve_hashes(&numbers)?
+        .block_numbers(|| Ok(10), || Ok(Some(3)))
+}
+
+#[test]
+fn range_segments() {
+    assert_eq!(block_numbers("5").unwrap(), vec![5]);
+    assert_eq!(block_numbers("5..8").unwrap(), vec![5, 6, 7]);
+    assert_eq!(block_numbers("5..=8").unwrap(), vec![5, 6, 7, 8]);
+    assert_eq!(block_numbers("..5").unwrap(), vec![3, 4]);
+    assert_eq!(block_numbers("..=5").unwrap(), vec![3, 4, 5]);
+    assert_eq!(block_numbers(" 5 ..= 6 ").unwrap(), vec![5, 6]);
+}
+
+#[test]
+fn open_upper_bound_includes_the_chain_head_only_if_inclusive() {
+    assert_eq!(block_numbers("8..").unwrap(), vec![8, 9]);
+    assert_eq!(block_numbers("8..=").unwrap(), vec![8, 9, 10]);
+    assert_eq!(block_numbers("10..").unwrap(), Vec::<i32>::new());
+}
+
+#[test]
+fn head_relative_bounds() {
+    assert_eq!(block_numbers("head").unwrap(), vec![10]);
+    assert_eq!(block_numbers("head-3..=head").unwrap(), vec![7, 8, 9, 10]);
+    assert_eq!(block_numbers("head-3..head").unwrap(), vec![7, 8, 9]);
+    assert_eq!(block_numbers("head-3..").unwrap(), vec![7, 8, 9]);
+    assert_eq!(block_numbers("..=head-6").unwrap(), vec![3, 4]);
+    assert_eq!(block_numbers("5..=head-4").unwrap(), vec![5, 6]);
+    assert_eq!(block_numbers("head - 1..=head").unwrap(), vec![9, 10]);
+}
+
+#[test]
+fn head_relative_lower_bound_starts_at_the_earliest_cached_block() {
+    assert_eq!(block_numbers("head-20..=head-5").unwrap(), vec![3, 4, 5]);
+    assert_eq!(
+        block_numbers("head-20..=head-15").unwrap(),
+        Vec::<i32>::new()
+    );
+}
+
+#[test]
+fn malformed_head_relative_bounds_are_rejected() {
+    assert!(range_error("head+1").contains("expected `head-N`"));
+    assert!(range_error("head-x..head").contains("expected `head-N`"));
+    assert!(range_error("head-1-2").contains("expected `head-N`"));
+    assert!(range_error("head..=head-2").contains("can't be smaller than lower bound"));
+}
+
+#[test]
+fn block_hash_bounds() {
+    let (five, eight) = (format!("{:?}", hash(5)), format!("{:?}", hash(8)));
+    let expr = format!("{five}..={eight}");
+    assert_eq!(
+        expr.parse::<RangeSet>().unwrap().hashes(),
+        vec![hash(5), hash(8)]
+    );
+    assert_eq!(block_numbers_by_hash(&expr).unwrap(), vec![5, 6, 7, 8]);
+    assert_eq!(
+        block_numbers_by_hash(&format!("{five}..{eight}")).unwrap(),
+        vec![5, 6, 7]
+    );
+    assert_eq!(
+        block_numbers_by_hash(&format!("{eight}..=head")).unwrap(),
+        vec![8, 9, 10]
+    );
+    assert_eq!(block_numbers_by_hash(&five).unwrap(), vec![5]);
+
+    let err = format!(
+        "{:#}",
+        block_numbers_by_hash(&format!("{eight}..={five}")).unwrap_err()
+    );
+    assert!(err.contains("can't be smaller"), "{err}");
+    let err = format!(
+        "{:#}",
+        block_numbers_by_hash(&format!("{:?}..=9", hash(42))).unwrap_err()
+    );
+    assert!(err.contains("No block number for block hash"), "{err}");
+    assert!(range_error(&expr).contains("have to be resolved"));
+    assert!(range_error("0x1234..=5").contains("Invalid block hash `0x1234`"));
+    assert_eq!(block_numbers_by_hash(&five[2..]).unwrap(), vec![5]);
+    assert_eq!(
+        block_numbers_by_hash(&format!("{}..={eight}", &five[2..])).unwrap(),
+        vec![5, 6, 7, 8]
+    );
+}
+
+#[test]
+fn block_hashes_are_parsed_with_and_without_prefix() {
+    let seven = format!("{:?}", hash(7));
+    assert_eq!(parse_block_hash(&seven).unwrap(), hash(7));
+    assert_eq!(parse_block_hash(&seven[2..]).unwrap(), hash(7));
+    assert_eq!(parse_block_hash(&seven.to_uppercase()).unwrap(), hash(7));
+    assert_eq!(parse_block_hash(&format!(" {seven}\n")).unwrap(), hash(7));
+}
+
+#[test]
+fn malformed_block_hashes_are_explained() {
+    let error = |input: &str| format!("{:#}", parse_block_hash(input).unwrap_err());
+    assert_eq!(error(""), "Invalid block hash ``: it has no hex digits");
+    assert_eq!(error("0x"), "Invalid block hash `0x`: it has no hex digits");
+    assert_eq!(
+        error("0x123"),
+        "Invalid block hash `0x123`: it has an odd number of hex digits, 3"
+    );
+    assert_eq!(
+        error("0x12zz"),
+        "Invalid block hash `0x12zz`: `z` at position 5 is not a hex digit"
+    );
+    assert_eq!(
+        error("0x0x12"),
+        "Invalid block hash `0x0x12`: `x` at position 4 is not a hex digit"
+    );
+    assert_eq!(
+        error("1234"),
+        "Invalid block hash `1234`: it has 2 bytes, expected 32 bytes"
+    );
+    let too_long = format!("{:?}00", hash(7));
+    assert!(error(&too_long).ends_with("it has 33 bytes, expected 32 bytes"));
+}
+
+#[test]
+fn malformed_cached_block_hashes_are_errors() {
+    assert_eq!(
+        cached_block_hash(&BlockHash::from(hash(7))).unwrap(),
+        hash(7)
+    );
+    let short = BlockHash::from(vec![0x12, 0x34]);
+    let e = cached_block_hash(&short).unwrap_err();
+    assert_eq!(
+        format!("{e:#}"),
+        "The cached block 0x1234 is corrupt: its hash has 2 bytes, expected 32 bytes"
+    );
+    assert!(matches!(
+        CheckBlocksError::find(&e),
+        Some(CheckBlocksError::CorruptCacheEntry { hash, .. }) if *hash == short
+    ));
+}
+
+#[test]
+fn range_unions_are_sorted_and_merged() {
+    assert_eq!(block_numbers("8,5..=6").unwrap(), vec![5, 6, 8]);
+    assert_eq!(block_numbers("5..=7,6..=8").unwrap(), vec![5, 6, 7, 8]);
+    assert_eq!(block_numbers("5..7,7").unwrap(), vec![5, 6, 7]);
+    assert_eq!(block_numbers("5,5,head,10").unwrap(), vec![5, 10]);
+
+    let ranges: RangeSet = "4..=5,8..=9,5..8".parse().unwrap();
+    let bounds = ranges
+        .bounds(
+            || panic!("no chain head needed"),
+            || panic!("no earliest block needed"),
+        )
+        .unwrap();
+    assert_eq!(bounds, vec![(4, 9)]);
+}
+
+#[test]
+fn open_lower_bound_never_starts_at_the_genesis_block() {
+    let ranges: RangeSet = "..=4".parse().unwrap();
+    assert_eq!(
+        ranges.block_numbers(|| Ok(10), || Ok(Some(0))).unwrap(),
+        vec![1, 2, 3, 4]
+    );
+    // With an empty cache, there is nothing below the upper bound to check
+    assert_eq!(
+        ranges.block_numbers(|| Ok(10), || Ok(None)).unwrap(),
+        Vec::<i32>::new()
+    );
+    let ranges: RangeSet = "head-2..=head".parse().unwrap();
+    assert_eq!(
+        ranges.block_numbers(|| Ok(1), || Ok(None)).unwrap(),
+        vec![1]
+    );
+}
+
+#[test]
+fn exclusive_upper_bounds_are_one_below_the_bound() {
+    assert_eq!(block_numbers("5..6").unwrap(), vec![5]);
+    assert_eq!(block_numbers("..4").unwrap(), vec![3]);
+    // The open lower bound starts at block 1 at the earliest, so these contain no blocks
+    assert!(range_error("..1").contains("contains no blocks"));
+    assert!(range_error("..=0").contains("contains no blocks"));
+    assert!(range_error("..-1").contains("contains no blocks"));
+    assert!(range_error("..=-1").contains("Negative block number"));
+}
+
+#[test]
+fn ranges_from_bounds() {
+    let numbers = |lower: Option<i32>, upper: Option<i32>| -> anyhow::Result<Vec<i32>> {
+        RangeSet::from_bounds(lower, upper)?.block_numbers(|| Ok(10), || Ok(Some(3)))
+    };
+    assert_eq!(numbers(Some(5), Some(7)).unwrap(), vec![5, 6, 7]);
+    assert_eq!(numbers(Some(5), Some(5)).unwrap(), vec![5]);
+    assert_eq!(numbers(Some(8), None).unwrap(), vec![8, 9, 10]);
+    assert_eq!(numbers(None, Some(4)).unwrap(), vec![3, 4]);
+
+    let error = |lower, upper| format!("{:#}", numbers(lower, upper).unwrap_err());
+    assert!(error(None, None).contains("Use `graphman chain truncate` instead"));
+    assert!(error(Some(0), Some(5)).contains("Genesis block can't be removed"));
+    assert!(error(Some(-2), None).contains("Negative block number used as range bound: -2"));
+    assert!(error(None, Some(-2)).contains("Negative block number used as range bound: -2"));
+    assert!(error(Some(5), Some(4)).contains("Upper bound (4) can't be smaller"));
+}
+
+#[test]
+fn command_line_ranges_take_precedence_over_the_environment() {
+    let numbers = |range: Option<&str>, lower, upper, env_range| -> anyhow::Result<Vec<i32>> {
+        let range = range.map(str::parse::<RangeSet>).transpose()?;
+        RangeSet::from_args_or_env(range, lower, upper, env_range)?
+            .block_numbers(|| Ok(10), || Ok(Some(3)))
+    };
+    assert_eq!(
+        vec![4, 5],
+        numbers(Some("4..=5"), None, None, Some("7..=8")).unwrap()
+    );
+    assert_eq!(
+        vec![6],
+        numbers(None, Some(6), Some(6), Some("7..=8")).unwrap()
+    );
+    assert_eq!(
+        vec![3, 4, 5],
+        numbers(None, None, Some(5), Some("7..=8")).unwrap()
+    );
+    assert_eq!(
+        vec![7, 8],
+        numbers(None, None, None, Some("7..=8")).unwrap()
+    );
+
+    let error = |env_range| format!("{:#}", numbers(None, None, None, env_range).unwrap_err());
+    assert!(error(None).contains("Use `graphman chain truncate` instead"));
+    assert!(error(Some("7..x")).contains("invalid range `7..x` in GRAPHMAN_FIX_RANGE"));
+}
+
+#[test]
+fn ranges_without_any_bound_are_rejected() {
+    assert!(range_error("..").contains("neither a lower nor an upper bound"));
+    assert!(range_error("..=").contains("neither a lower nor an upper bound"));
+}
+
+#[test]
+fn malformed_range_segments_are_rejected() {
+    assert!(range_error("").contains("Empty range segment"));
+    assert!(range_error("1,,2").contains("Empty range segment"));
+    assert!(range_error("5..5").contains("contains no blocks"));
+    assert!(range_error("..0").contains("contains no blocks"));
+    assert!(range_error("8..5").contains("contains no blocks"));
+    assert!(range_error("8..=5").contains("can't be smaller than lower bound"));
+    assert!(range_error("0..5").contains("Genesis block can't be removed"));
+    assert!(range_error("-1..=5").contains("Negative block number"));
+    assert!(range_error("a..5").contains("Invalid block number `a`"));
+    assert!(range_error("5..=x").contains("Invalid block number `x`"));
+}
+
+#[test]
+fn progress_bar_and_eta() {
+    assert_eq!(progress::bar(0, 200), format!("[>{}]   0%", " ".repeat(19)));
+    assert_eq!(
+        progress::bar(90, 200),
+        format!("[{}>{}]  45%", "=".repeat(9), " ".repeat(10))
+    );
+    assert_eq!(
+        progress::bar(200, 200),
+        format!("[{}] 100%", "=".repeat(20))
+    );
+    // The chain head moved on after the total was counted
+    assert_eq!(
+        progress::bar(250, 200),
+        format!("[{}] 100%", "=".repeat(20))
+    );
+    assert_eq!(progress::bar(0, 0), format!("[{}] 100%", "=".repeat(20)));
+
+    let start = Instant::now();
+    let mut samples = VecDeque::from(vec![(start, 100)]);
+    assert_eq!(progress::rate(&samples), None);
+    samples.push_back((start + Duration::from_secs(4), 4900));
+    assert_eq!(progress::rate(&samples), Some(1200.0));
+
+    assert_eq!(
+        progress::eta(234_000, 1200.0),
+        Some(Duration::from_secs(195))
+    );
+    assert_eq!(progress::eta(10, 0.0), None);
+    assert_eq!(progress::format_eta(Duration::from_secs(195)), "00:03:15");
+    assert_eq!(progress::format_eta(Duration::from_millis(100)), "00:00:01");
+    assert_eq!(progress::format_eta(Duration::ZERO), "00:00:00");
+    assert_eq!(
+        progress::format_eta(Duration::from_secs(100 * 3600 + 61)),
+        "100:01:01"
+    );
+}
+
+#[test]
+fn blocks_dealt_with_beforehand_count_as_fetched() {
+    let status = Arc::new(CheckStatus::default());
+    let options = CheckOptions {
+        status: Some(status.clone()),
+        ..options()
+    };
+    let progress = Progress::new(10, "mainnet", &options);
+    progress.blocks_done(3);
+    progress.block_fetched();
+    assert_eq!(
+        status.to_json(),
+        json!({ "total": 10, "fetched": 4, "diverged": 0 })
+    );
+}
+
+#[test]
+fn invalid_block_hash_arguments_are_skipped() {
+    let args = |hashes: &[&str]| {
+        hashes
+            .iter()
+            .map(|hash| hash.to_string())
+            .collect::<Vec<_>>()
+    };
+    let (five, eight) = (format!("{:?}", hash(5)), format!("{:?}", hash(8)));
+
+    let (block_hashes, invalid) =
+        parse_block_hashes(&args(&[five.as_str(), "0x1234", eight.as_str(), "nope"])).unwrap();
+    assert_eq!(block_hashes, vec![hash(5), hash(8)]);
+    assert!(
+        invalid[0].1.contains("expected 32 bytes"),
+        "{}",
+        invalid[0].1
+    );
+    let invalid: Vec<_> = invalid.into_iter().map(|(argument, _)| argument).collect();
+    assert_eq!(invalid, args(&["0x1234", "nope"]));
+
+    let err = parse_block_hashes(&args(&["0x1234"])).unwrap_err();
+    assert!(format!("{err:#}").contains("expected 32 bytes"), "{err:#}");
+    let err = parse_block_hashes(&args(&["0x1234", "nope"])).unwrap_err();
+    assert_eq!(err.to_string(), "None of the 2 arguments is a block hash");
+}
+
+#[test]
+fn invalid_hashes_are_part_of_the_json_output() {
+    let report = CheckBlocksReport {
+        invalid_hashes: vec![("nope".to_string(), "not hex".to_string())],
+        ..CheckBlocksReport::default()
+    };
+    let expected = json!([{ "argument": "nope", "error": "not hex" }]);
+    assert_eq!(report.json_envelope()["invalid_hashes"], expected);
+    assert_eq!(report.to_json()["invalid_hashes"], expected);
+}
+
+#[test]
+fn receipts_are_compared_by_transaction() {
+    let receipt = |tx: u64, status: u64| LightTransactionReceipt {
+        transaction_hash: hash(tx),
+        transaction_index: U64::from(tx),
+        block_hash: Some(hash(7)),
+        block_number: Some(U64::from(7)),
+        gas_used: Some(U256::from(21_000)),
+        status: Some(U64::from(status)),
+    };
+    let cached = receipts_value(&[receipt(1, 1), receipt(2, 1)]);
+    assert_eq!(
+        cached[format!("{:?}", hash(2))],
+        json!({
+            "transactionIndex": "0x2",
+            "blockHash": format!("{:?}", hash(7)),
+            "blockNumber": "0x7",
+            "gasUsed": "0x5208",
+            "status": "0x1",
+        })
+    );
+    // The order in which the provider returns the receipts doesn't matter
+    let provider = receipts_value(&[receipt(2, 1), receipt(1, 1)]);
+    assert_eq!(diff_block_pair(&cached, &provider, &[]), None);
+
+    let provider = receipts_value(&[receipt(1, 0), receipt(3, 1)]);
+    let diff = diff_block_pair(&cached, &provider, &[]).unwrap();
+    let diff = diff.as_object().unwrap();
+    assert_eq!(
+        diff[&format!("{:?}", hash(1))],
+        json!({ "status": { "__old": "0x1", "__new": "0x0" } })
+    );
+    assert!(diff.contains_key(&format!("{:?}__deleted", hash(2))));
+    assert!(diff.contains_key(&format!("{:?}__added", hash(3))));
+}
+
+/// The receipt of the only transaction in block `number`, as the provider returns it.
+fn provider_receipt(number: u64, gas_used: u64) -> TransactionReceipt {
+    TransactionReceipt {
+        transaction_hash: hash(1000 + number),
+        transaction_index: U64::from(0),
+        block_hash: Some(hash(number)),
+        block_number: Some(U64::from(number)),
+        gas_used: Some(U256::from(gas_used)),
+        status: Some(U64::from(1)),
+        ..Default::default()
+    }
+}
+
+async fn compare_receipts_of(
+    numbers: &[u64],
+    cached: HashMap<H256, Vec<TransactionReceipt>>,
+    adapter: &MockEthereumAdapter,
+    options: &CheckOptions,
+) -> (ReceiptsReport, HashMap<H256, EthereumBlock>) {
+    let blocks: Vec<_> = numbers
+        .iter()
+        .map(|number| CachedBlockRef {
+            number: *number as i32,
+            hash: hash(*number),
+        })
+        .collect();
+    let cached = &cached;
+    let mut report = ReceiptsReport::default();
+    let provider_blocks = compare_block_receipts(
+        &blocks,
+        |hash| async move {
+            let receipts = cached.get(&hash).into_iter().flatten().cloned();
+            let receipts: Vec<_> = receipts.map(LightTransactionReceipt::from).collect();
+            anyhow::Ok(receipts)
+        },
+        adapter,
+        &discard(),
+        options,
+        &mut report,
+    )
+    .await
+    .unwrap();
+    (report, provider_blocks)
+}
+
+#[tokio::test]
+async fn receipts_are_compared_block_by_block() {
+    // The provider has no receipts for block 6 and doesn't know block 5
+    let adapter =
+        MockEthereumAdapter::new([2, 3, 4, 6].map(|number| (hash(number), block(number))))
+            .with_receipts(
+                [2, 3, 4].map(|number| (hash(number), vec![provider_receipt(number, 21_000)])),
+            );
+    // Block 3 has diverged and block 4 is cached without receipts
+    let cached = HashMap::from([
+        (hash(2), vec![provider_receipt(2, 21_000)]),
+        (hash(3), vec![provider_receipt(3, 42_000)]),
+        (hash(5), vec![provider_receipt(5, 21_000)]),
+        (hash(6), vec![provider_receipt(6, 21_000)]),
+    ]);
+    let options = CheckOptions {
+        replace: true,
+        ..options()
+    };
+
+    let (report, provider_blocks) =
+        compare_receipts_of(&[2, 6, 3, 4, 5], cached, &adapter, &options).await;
+
+    assert_eq!(report.checked, 2);
+    assert_eq!(report.uncached, 1);
+    let [divergence] = report.diverged.as_slice() else {
+        panic!("expected one diverged block, got {:?}", report.diverged);
+    };
+    assert_eq!((divergence.number, divergence.hash), (3, hash(3)));
+    assert_eq!(
+        report.missing,
+        vec![CachedBlockRef {
+            number: 5,
+            hash: hash(5)
+        }]
+    );
+    // A provider error doesn't stop the comparison of the blocks after it
+    let [(failed, error)] = report.fetch_failed.as_slice() else {
+        panic!("expected one failed block, got {:?}", report.fetch_failed);
+    };
+    assert_eq!(*failed, hash(6));
+    assert!(error.contains("Block data unavailable"), "{error}");
+    assert_eq!(report.interrupted, None);
+    let replacements: Vec<_> = provider_blocks.keys().collect();
+    assert_eq!(replacements, vec![&hash(3)]);
+}
+
+#[tokio::test]
+async fn receipts_comparison_stops_when_interrupted() {
+    let adapter = MockEthereumAdapter::new([(hash(2), block(2))])
+        .with_receipts([(hash(2), vec![provider_receipt(2, 21_000)])]);
+    let cached = HashMap::from([(hash(2), vec![provider_receipt(2, 42_000)])]);
+    let options = CheckOptions {
+        interrupt: Interrupt::default().with_max_runtime(Duration::ZERO),
+        ..options()
+    };
+
+    let (report, provider_blocks) = compare_receipts_of(&[2], cached, &adapter, &options).await;
+
+    assert_eq!(report.interrupted, Some(1));
+    assert_eq!(report.checked, 0);
+    assert!(report.diverged.is_empty());
+    assert!(provider_blocks.is_empty());
+}
+
+#[test]
+fn diverging_fields_are_tallied_by_path() {
+    let diff = json!({
+        "totalDifficulty__added": "0x5",
+        "transactions": [
+            ["~", { "gas": { "__old": "0x1", "__new": "0x3" } }],
+            ["~", { "gas": { "__old": "0x2", "__new": "0x4" } }],
+            ["+", { "hash": "0x3" }],
+        ],
+    });
+    assert_eq!(
+        steps::diff_field_paths(&diff),
+        vec!["/totalDifficulty", "/transactions/*", "/transactions/*/gas"]
+    );
+
+    let mut report = CheckBlocksReport::default();
+    for (path, count) in [
+        ("/nonce", 1),
+        ("/totalDifficulty", 7),
+        ("/size", 7),
+        ("/a", 2),
+    ] {
+        report.diverging_fields.insert(path.to_string(), count);
+    }
+    assert_eq!(
+        report.top_diverging_fields(3),
+        vec![("/size", 7), ("/totalDifficulty", 7), ("/a", 2)]
+    );
+    assert_eq!(
+        report.json_envelope()["top_diverging_fields"][0],
+        json!({ "field": "/size", "blocks": 7 })
+    );
+}
+
+#[test]
+fn blocks_are_described_by_number_and_hash() {
+    let block_hash = hash(7);
+    assert_eq!(
+        steps::describe_block(&block_hash, Some(7)),
+        format!("block #7 ({block_hash:?})")
+    );
+    assert_eq!(
+        steps::describe_block(&block_hash, None),
+        format!("block {block_hash:?}")
+    );
+}
+
+#[test]
+fn hex_is_normalized() {
+    let mut block = json!({
+        "hash": "0x00AB",
+        "number": "0x00",
+        "gasUsed": "0x0A",
+        "transactions": [{ "value": "0x001", "input": "0x00Ff" }],
+        "extraData": "not hex",
+    });
+    normalize_hex(&mut block);
+    assert_eq!(
+        block,
+        json!({
+            "hash": "0x00ab",
+            "number": "0x0",
+            "gasUsed": "0xa",
+            "transactions": [{ "value": "0x1", "input": "0x00ff" }],
+            "extraData": "not hex",
+        })
+    );
+}
+
+#[test]
+fn plain_diffs_are_neither_colorized_nor_cut_off() {
+    let diff = json!({
+        "gasUsed": { "__old": "0x1", "__new": "0x2" },
+        "nonce": { "__old": "0x0", "__new": "0x1" },
+    });
+    let options = CheckOptions {
+        diff_render: DiffRenderOptions {
+            style: DiffStyle::Full,
+            color: true,
+            max_lines: Some(1),
+        },
+        ..options()
+    };
+
+    let plain = steps::plain_diff(&diff, &options);
+    assert!(!plain.contains('\u{1b}'));
+    assert!(plain.contains("gasUsed") && plain.contains("nonce"));
+    assert_eq!(plain, render_diff(&diff, &DiffRenderOptions::default()));
+}
+
+#[test]
+fn compact_diffs_have_a_line_per_change() {
+    let diff = json!({
+        "gasUsed": { "__old": "0x1", "__new": "0x2" },
+        "nonce__deleted": "0x0",
+        "transactions": [
+            [" "],
+            ["~", { "value": { "__old": "0x1", "__new": "0x3" } }],
+            ["-", "0xaa"],
+            ["+", "0xbb"],
+        ],
+    });
+    let compact = DiffRenderOptions {
+        style: DiffStyle::Compact,
+        ..DiffRenderOptions::default()
+    };
+
+    assert_eq!(
+        render_diff(&diff, &compact),
+        "~ /gasUsed: \"0x1\" -> \"0x2\"\n\
+         - /nonce: \"0x0\"\n\
+         ~ /transactions/1/value: \"0x1\" -> \"0x3\"\n\
+         - /transactions/2: \"0xaa\"\n\
+         + /transactions/3: \"0xbb\""
+    );
+    let short = DiffRenderOptions {
+        max_lines: Some(2),
+        ..compact
+    };
+    assert_eq!(
+        render_diff(&diff, &short),
+        "~ /gasUsed: \"0x1\" -> \"0x2\"\n- /nonce: \"0x0\"\n... 3 more lines"
+    );
+}
+
+#[tokio::test]
+async fn differently_encoded_quantities_do_not_diverge() {
+    let adapter = MockEthereumAdapter::new([(hash(2), block(2))]);
+    let mut cached = representative_block();
+    cached["gasUsed"] = json!("0x05208");
+    let mut report = CheckBlocksReport::default();
+
+    let diverged = compare(&adapter, vec![(hash(2), cached)], &mut report)
+        .await
+        .unwrap();
+
+    assert!(diverged.is_empty());
+}
+
+#[test]
+fn diff_of_a_single_serialization_matches_a_double_one() {
+    let cached = representative_block();
+    let mut provider = representative_block();
+    provider["gasUsed"] = json!("0x5209");
+
+    let once = diff_block_pair(&cached, &provider, &[]);
+    let twice = diff_block_pair(&cached, &serde_json::to_value(&provider).unwrap(), &[]);
+
+    assert!(once.is_some());
+    assert_eq!(once, twice);
+}
+
+#[test]
+fn identical_blocks_do_not_diff() {
+    let block = representative_block();
+    assert_eq!(diff_block_pair(&block, &block.clone(), &[]), None);
+}
+
+#[test]
+fn rlp_encoding() {
+    use super::header::{encode_bytes, encode_list};
+
+    assert_eq!(encode_bytes(b""), vec![0x80]);
+    assert_eq!(encode_bytes(&[0x0f]), vec![0x0f]);
+    assert_eq!(encode_bytes(&[0x04, 0x00]), vec![0x82, 0x04, 0x00]);
+    assert_eq!(encode_bytes(b"dog"), b"\x83dog".to_vec());
+    let lorem = b"Lorem ipsum dolor sit amet, consectetur adipisicing elit";
+    assert_eq!(encode_bytes(lorem)[..2], [0xb8, 0x38]);
+    assert_eq!(encode_list(&[]), vec![0xc0]);
+    assert_eq!(
+        encode_list(&[encode_bytes(b"cat"), encode_bytes(b"dog")]),
+        b"\xc8\x83cat\x83dog".to_vec()
+    );
+}
+
+#[test]
+fn header_hash_of_the_mainnet_genesis_block() {
+    use super::header::header_hash;
+
+    let zeros = |len: usize| format!("0x{}", "00".repeat(len));
+    let empty_trie = "0x56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421";
+    let mut genesis = json!({
+        "parentHash": zeros(32),
+        "sha3Uncles": "0x1dcc4de8dec75d7aab85b567b6ccd41ad312451b948a7413f0a142fd40d49347",
+        "miner": zeros(20),
+        "stateRoot": "0xd7f8974fb5ac78d9ac099b9ad5018bedc2ce0a72dad1827a1709da30580f0544",
+        "transactionsRoot": empty_trie,
+        "receiptsRoot": empty_trie,
+        "logsBloom": zeros(256),
+        "difficulty": "0x400000000",
+        "number": "0x0",
+        "gasLimit": "0x1388",
+        "gasUsed": "0x0",
+        "timestamp": "0x0",
+        "extraData": "0x11bbe8db4e347b4e8c937c1c8370e4b5ed33adb3db69cbdb7a38e1e50b1b82fa",
+        "mixHash": zeros(32),
+        "nonce": "0x0000000000000042",
+        "totalDifficulty": "0x400000000",
+        "baseFeePerGas": null,
+    });
+    let hash: H256 = "0xd4e56740f876aef8c010b86a40d5f56745a118d0906a34e69aec8c0db1cb8fa3"
+        .parse()
+        .unwrap();
+    assert_eq!(header_hash(&genesis), Ok(hash));
+
+    genesis["gasLimit"] = json!("0x1389");
+    assert_ne!(header_hash(&genesis), Ok(hash));
+    genesis["withdrawalsRoot"] = json!(empty_trie);
+    assert!(header_hash(&genesis)
+        .unwrap_err()
+        .contains("withdrawalsRoot"));
+    genesis["nonce"] = json!("0x42z");
+    assert_eq!(header_hash(&genesis), Err("malformed `nonce`".to_string()));
+}
+
+#[test]
+fn blocks_without_every_fork_field_are_recognized() {
+    use super::header::missing_fork_field;
+
+    let mut block = json!({ "number": "0x1", "baseFeePerGas": null });
+    assert_eq!(missing_fork_field(&block), Some("baseFeePerGas"));
+    block["baseFeePerGas"] = json!("0x7");
+    block["withdrawalsRoot"] = json!("0x56e8");
+    assert_eq!(missing_fork_field(&block), Some("blobGasUsed"));
+    for field in [
+        "blobGasUsed",
+        "excessBlobGas",
+        "parentBeaconBlockRoot",
+        "requestsHash",
+    ] {
+        block[field] = json!("0x0");
+    }
+    assert_eq!(missing_fork_field(&block), None);
+}
+
+#[test]
+fn fork_fields_are_only_compared_if_cached() {
+    let cached = json!({ "hash": "0x1", "withdrawalsRoot": null, "blobGasUsed": "0x0" });
+    let mut provider = json!({
+        "hash": "0x1",
+        "withdrawalsRoot": "0x56e8",
+        "withdrawals": [],
+        "blobGasUsed": "0x20000",
+    });
+    steps::drop_fork_fields_missing_from(&cached, &mut provider);
+    assert_eq!(provider, json!({ "hash": "0x1", "blobGasUsed": "0x20000" }));
+}
+
+#[test]
+fn null_diff_is_no_divergence() {
+    assert_eq!(steps::divergence(None), None);
+    assert_eq!(steps::divergence(Some(Value::Null)), None);
+    let diff = json!({ "gasUsed__deleted": "0x5208" });
+    assert_eq!(steps::divergence(Some(diff.clone())), Some(diff));
+}
+
+#[test]
+fn null_diff_of_different_blocks_is_no_divergence() {
+    let cached = representative_block();
+    let mut provider = representative_block();
+    provider["gasUsed"] = json!("0x5209");
+    let null_diff = |_: &Value, _: &Value| Some(Value::Null);
+
+    // Every check decides whether a block diverged, and thus whether it is deleted, through
+    // one of these
+    assert_eq!(
+        steps::diff_block_pair_with(&cached, &provider, &[], null_diff),
+        None
+    );
+    assert!(!steps::blocks_differ_with(
+        &cached,
+        &provider,
+        &[],
+        null_diff
+    ));
+    let ignored = vec!["/size".to_string()];
+    assert_eq!(
+        steps::diff_block_pair_with(&cached, &provider, &ignored, null_diff),
+        None
+    );
+    assert!(!steps::blocks_differ_with(
+        &cached, &provider, &ignored, null_diff
+    ));
+}
+
+#[test]
+fn blocks_differ_like_their_diff_says() {
+    let cached = json!({
+        "hash": "0x1",
+        "size": "0x10",
+        "transactions": [{ "hash": "0xa", "gas": "0x1" }],
+    });
+    let ignored = vec!["/size".to_string(), "/transactions/0/gas".to_string()];
+    let changed = |change: fn(&mut Value)| {
+        let mut provider = cached.clone();
+        change(&mut provider);
+        provider
+    };
+    let cases = [
+        changed(|block| {
+            block["size"] = json!("0x20");
+            block["transactions"][0]["gas"] = json!("0x2");
+        }),
+        changed(|block| {
+            block.as_object_mut().unwrap().remove("size");
+            block["transactions"][0]
+                .as_object_mut()
+                .unwrap()
+                .remove("gas");
+        }),
+        changed(|block| block["hash"] = json!("0x2")),
+        changed(|block| block["transactions"] = json!([])),
+        changed(|block| block["transactions"][0]["hash"] = json!("0xb")),
+        changed(|block| block["extra"] = Value::Null),
+    ];
+    for provider in &cases {
+        for ignored in [&ignored[..], &[]] {
+            assert_eq!(
+                steps::blocks_differ(&cached, provider, ignored),
+                steps::diff_block_pair(&cached, provider, ignored).is_some(),
+                "{provider} with {ignored:?}"
+            );
+        }
+    }
+    assert!(!steps::blocks_differ(&cached, &cases[0], &ignored));
+    assert!(!steps::blocks_differ(&cached, &cases[1], &ignored));
+    assert!(steps::blocks_differ(&cached, &cases[2], &ignored));
+}
+
+#[test]
+fn ignored_fields_do_not_diff() {
+    let cached = representative_block();
+    let mut provider = representative_block();
+    provider["totalDifficulty"] = json!("0x2b");
+    provider["size"] = Value::Null;
+
+    assert!(diff_block_pair(&cached, &provider, &[]).is_some());
+    let ignored = ["/totalDifficulty".to_string(), "/size".to_string()];
+    assert_eq!(diff_block_pair(&cached, &provider, &ignored), None);
+}
+
+#[test]
+fn field_globs_select_what_is_compared() {
+    let globs = |globs: &[&str]| {
+        globs
+            .iter()
+            .map(|glob| glob.to_string())
+            .collect::<Vec<_>>()
+    };
+    let filtered = |include: &[&str], exclude: &[&str]| {
+        let mut block = json!({
+            "hash": "0x1",
+            "extraData": "0xab",
+            "transactions": [
+                { "from": "0xa", "to": "0xb", "input": { "data": "0x" } },
+                { "from": "0xc", "to": "0xd" },
+            ],
+        });
+        steps::filter_fields(&mut block, &globs(include), &globs(exclude));
+        block
+    };
+
+    assert_eq!(
+        filtered(&["transactions.*.from"], &[]),
+        json!({ "transactions": [{ "from": "0xa" }, { "from": "0xc" }] })
+    );
+    assert_eq!(
+        filtered(&[], &["extraData", "transactions.1"]),
+        json!({
+            "hash": "0x1",
+            "transactions": [
+                { "from": "0xa", "to": "0xb", "input": { "data": "0x" } },
+                null,
+            ],
+        })
+    );
+    assert_eq!(
+        filtered(&["**.data", "ha*"], &["transactions.0.to"]),
+        json!({
+            "hash": "0x1",
+            "transactions": [{ "input": { "data": "0x" } }, {}],
+        })
+    );
+    assert_eq!(
+        filtered(&["transactions"], &["**.t*"]),
+        json!({
+            "transactions": [
+                { "from": "0xa", "input": { "data": "0x" } },
+                { "from": "0xc" },
+            ],
+        })
+    );
+}
+
+#[tokio::test]
+async fn loaded_blocks_are_compared_without_acting_on_them() {
+    let adapter = MockEthereumAdapter::new([(hash(2), block(2)), (hash(3), block(3))]);
+    let mut diverging = serde_json::to_value(block(3)).unwrap();
+    diverging["gasUsed"] = json!("0x1");
+    let cached_blocks = vec![representative_block(), diverging, json!(null)];
+
+    let comparisons = compare_loaded_blocks(
+        vec![hash(2), hash(3), hash(4)],
+        cached_blocks,
+        &adapter,
+        NetworkProfile::for_network("mainnet"),
+        &discard(),
+        &options(),
+    )
+    .await;
+    assert_eq!(3, comparisons.len());
+    let same = comparisons[0].as_ref().unwrap();
+    assert_eq!((hash(2), Some(2)), (same.hash, same.number));
+    assert!(!same.diverged());
+    let diverged = comparisons[1].as_ref().unwrap();
+    assert!(diverged.diverged());
+    assert_eq!(json!("0x1"), diverged.cached["gasUsed"]);
+    assert_eq!(json!("0x5208"), diverged.provider["gasUsed"]);
+    // The corrupt block fails on its own
+    assert!(matches!(
+        CheckBlocksError::find(comparisons[2].as_ref().unwrap_err()),
+        Some(CheckBlocksError::CorruptCacheEntry { hash: corrupt, .. })
+            if *corrupt == BlockHash::from(hash(4))
+    ));
+}
+
+#[tokio::test]
+async fn blocks_that_only_lack_fields_only_diverge_without_superset_ok() {
+    let adapter = MockEthereumAdapter::new([(hash(2), block(2))]);
+    let mut cached = serde_json::to_value(block(2)).unwrap();
+    let fields = cached.as_object_mut().unwrap();
+    fields.remove("gasLimit");
+    fields.remove("extraData");
+    let compare = |cached: Value, options: CheckOptions| {
+        let adapter = &adapter;
+        async move {
+            let mut report = CheckBlocksReport::default();
+            compare_with(adapter, vec![(hash(2), cached)], &options, &mut report)
+                .await
+                .unwrap()
+                .len()
+        }
+    };
+    let superset_ok = || CheckOptions {
+        superset_ok: true,
+        ..options()
+    };
+
+    assert_eq!(1, compare(cached.clone(), options()).await);
+    assert_eq!(0, compare(cached.clone(), superset_ok()).await);
+
+    // Values the blocks share still have to match
+    cached["gasUsed"] = json!("0x1");
+    assert_eq!(1, compare(cached, superset_ok()).await);
+}
+
+#[test]
+fn only_fields_that_the_cached_block_lacks_are_dropped() {
+    let cached = json!({ "a": 1, "b": null, "c": { "d": 2 }, "e": [{ "f": 3 }] });
+    let mut provider = json!({
+        "a": 1,
+        "b": 2,
+        "c": { "d": 4, "g": 5 },
+        "e": [{ "f": 3, "h": 6 }, { "i": 7 }],
+        "j": 8,
+    });
+    steps::drop_fields_missing_from(&cached, &mut provider);
+    assert_eq!(
+        provider,
+        json!({ "a": 1, "b": 2, "c": { "d": 4 }, "e": [{ "f": 3 }, { "i": 7 }] })
+    );
+}
+
+#[tokio::test]
+async fn reordered_transactions_only_diverge_without_ignore_tx_order() {
+    let transaction = |index: u64| Transaction {
+        hash: hash(100 + index),
+        block_hash: Some(hash(2)),
+        block_number: Some(U64::from(2)),
+        transaction_index: Some(U64::from(index)),
+        ..Default::default()
+    };
+    let provider_block = LightEthereumBlock {
+        transactions: (0..4).map(transaction).collect(),
+        ..block(2)
+    };
+    let mut cached = serde_json::to_value(&provider_block).unwrap();
+    let transactions = cached["transactions"].as_array_mut().unwrap();
+    transactions.swap(0, 3);
+    transactions.swap(1, 2);
+    let adapter = MockEthereumAdapter::new([(hash(2), provider_block)]);
+    let options = CheckOptions {
+        scope: CompareScope::Transactions,
+        ..options()
+    };
+
+    let mut report = CheckBlocksReport::default();
+    let diverged = compare_with(
+        &adapter,
+        vec![(hash(2), cached.clone())],
+        &options,
+        &mut report,
+    )
+    .await
+    .unwrap();
+    assert_eq!(1, diverged.len());
+
+    let options = CheckOptions {
+        ignore_tx_order: true,
+        ..options
+    };
+    let mut report = CheckBlocksReport::default();
+    let diverged = compare_with(&adapter, vec![(hash(2), cached)], &options, &mut report)
+        .await
+        .unwrap();
+    assert!(diverged.is_empty());
+}
+
+#[test]
+fn transactions_are_sorted_by_index_or_hash() {
+    let mut block = json!({
+        "transactions": [
+            { "hash": "0xb", "transactionIndex": "0x2" },
+            { "hash": "0xc", "transactionIndex": "0xA" },
+            { "hash": "0xa", "transactionIndex": "0x1" },
+        ],
+    });
+    steps::sort_transactions(&mut block);
+    let hashes: Vec<_> = block["transactions"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|transaction| transaction["hash"].as_str().unwrap())
+        .collect();
+    assert_eq!(vec!["0xa", "0xb", "0xc"], hashes);
+
+    let mut block = json!({ "transactions": ["0x0c", "0x0A", "0x0b"] });
+    steps::sort_transactions(&mut block);
+    assert_eq!(json!({ "transactions": ["0x0A", "0x0b", "0x0c"] }), block);
+}
+
+#[tokio::test]
+async fn blocks_differing_in_excluded_fields_do_not_diverge() {
+    let adapter = MockEthereumAdapter::new([(hash(2), block(2))]);
+    let mut cached = representative_block();
+    cached["extraData"] = json!("0xff");
+    let options = CheckOptions {
+        diff_exclude: vec!["extraData".to_string()],
+        ..options()
+    };
+    let mut report = CheckBlocksReport::default();
+
+    let diverged = compare_with(&adapter, vec![(hash(2), cached)], &options, &mut report)
+        .await
+        .unwrap();
+    assert!(diverged.is_empty());
+}
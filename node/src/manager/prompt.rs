@@ -1,13 +1,33 @@
-use graph::anyhow;
+use graph::anyhow::{self, bail};
+use std::env;
 use std::io::{self, Write};
 
+/// Set this environment variable to `true` or `1` to answer all confirmation prompts with yes,
+/// e.g. in scripts.
+const ASSUME_YES_VAR: &str = "GRAPHMAN_ASSUME_YES";
+
 /// Asks users if they are certain about a certain action.
+///
+/// If `GRAPHMAN_ASSUME_YES` is set, the answer is yes without asking. The answer can be piped in,
+/// like with `echo y | graphman ...`; if stdin is closed before there is an answer, nobody could
+/// answer, and rather than taking that as a no, this fails with an error that explains how to skip
+/// the question.
 pub fn prompt_for_confirmation(prompt: &str) -> anyhow::Result<bool> {
+    if assume_yes(env::var(ASSUME_YES_VAR).ok().as_deref()) {
+        println!("{prompt} [y/N] yes ({ASSUME_YES_VAR} is set)");
+        return Ok(true);
+    }
     print!("{prompt} [y/N] ");
     io::stdout().flush()?;
 
     let mut answer = String::new();
-    io::stdin().read_line(&mut answer)?;
+    if io::stdin().read_line(&mut answer)? == 0 {
+        println!();
+        bail!(
+            "Can't ask for confirmation because stdin is closed. Pass the command's option to \
+             skip confirmation (like `--yes` or `--force`), or set {ASSUME_YES_VAR}=true"
+        );
+    }
     answer.make_ascii_lowercase();
 
     match answer.trim() {
@@ -15,3 +35,28 @@ pub fn prompt_for_confirmation(prompt: &str) -> anyhow::Result<bool> {
         _ => Ok(false),
     }
 }
+
+fn assume_yes(value: Option<&str>) -> bool {
+    matches!(
+        value
+            .map(|value| value.trim().to_ascii_lowercase())
+            .as_deref(),
+        Some("true" | "1" | "yes")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::assume_yes;
+
+    #[test]
+    fn assume_yes_needs_a_true_value() {
+        assert!(assume_yes(Some("true")));
+        assert!(assume_yes(Some("1")));
+        assert!(assume_yes(Some(" YES ")));
+        assert!(!assume_yes(Some("false")));
+        assert!(!assume_yes(Some("0")));
+        assert!(!assume_yes(Some("")));
+        assert!(!assume_yes(None));
+    }
+}
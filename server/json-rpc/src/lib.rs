@@ -11,6 +11,19 @@ use std::net::{Ipv4Addr, SocketAddr};
 
 type JsonRpcResult<T> = Result<T, jsonrpsee::core::Error>;
 
+/// Checks cached blocks against the chain's providers for the `chain_checkBlocks` and
+/// `chain_checkBlocksStatus` endpoints. The checks themselves are those of
+/// `graphman chain check-blocks`, which this crate can't depend on, so graph-node passes them in.
+pub trait BlockCacheChecker: Send + Sync + 'static {
+    /// Starts a check in the background and returns the id to poll its status with. Fails if
+    /// the check can't even be started, e.g. because the chain is unknown.
+    fn start_check(&self, params: CheckBlocksParams) -> Result<u64, Error>;
+
+    /// How far the check with the given id has come, together with its report once it's done,
+    /// or `None` if there is no such check.
+    fn check_status(&self, id: u64) -> Option<JsonValue>;
+}
+
 pub struct JsonRpcServer {
     // TODO: in the future we might want to have some sort of async drop to stop
     // the server. For now, we're just letting it run it forever.
@@ -23,6 +36,7 @@ impl JsonRpcServer {
         http_port: u16,
         ws_port: u16,
         registrar: Arc<R>,
+        block_checker: Arc<dyn BlockCacheChecker>,
         node_id: NodeId,
         logger: Logger,
     ) -> JsonRpcResult<Self>
@@ -38,6 +52,7 @@ impl JsonRpcServer {
 
         let state = ServerState {
             registrar,
+            block_checker,
             http_port,
             ws_port,
             node_id,
@@ -68,6 +83,16 @@ impl JsonRpcServer {
                 state.reassign_handler(params.parse()?).await
             })
             .unwrap();
+        rpc_module
+            .register_method("chain_checkBlocks", |params, state| {
+                state.check_blocks_handler(params.parse()?)
+            })
+            .unwrap();
+        rpc_module
+            .register_method("chain_checkBlocksStatus", |params, state| {
+                state.check_blocks_status_handler(params.parse()?)
+            })
+            .unwrap();
 
         let _handle = http_server.start(rpc_module)?;
         Ok(Self { _handle })
@@ -76,6 +101,7 @@ impl JsonRpcServer {
 
 struct ServerState<R> {
     registrar: Arc<R>,
+    block_checker: Arc<dyn BlockCacheChecker>,
     http_port: u16,
     ws_port: u16,
     node_id: NodeId,
@@ -87,6 +113,7 @@ impl<R: SubgraphRegistrar> ServerState<R> {
     const REMOVE_ERROR: i64 = 1;
     const CREATE_ERROR: i64 = 2;
     const REASSIGN_ERROR: i64 = 3;
+    const CHECK_BLOCKS_ERROR: i64 = 4;
 
     /// Handler for the `subgraph_create` endpoint.
     async fn create_handler(&self, params: SubgraphCreateParams) -> JsonRpcResult<JsonValue> {
@@ -173,6 +200,41 @@ impl<R: SubgraphRegistrar> ServerState<R> {
             )),
         }
     }
+
+    /// Handler for the `chain_checkBlocks` endpoint.
+    fn check_blocks_handler(&self, params: CheckBlocksParams) -> JsonRpcResult<JsonValue> {
+        info!(&self.logger, "Received chain_checkBlocks request"; "params" => format!("{:?}", params));
+
+        let description = format!("{:?}", params);
+        match self.block_checker.start_check(params) {
+            Ok(id) => Ok(serde_json::json!({ "id": id })),
+            Err(e) => {
+                error!(&self.logger, "chain_checkBlocks failed";
+                    "error" => format!("{:#}", e),
+                    "params" => description);
+                Err(JsonRpcError::Call(CallError::Custom(ErrorObject::owned(
+                    Self::CHECK_BLOCKS_ERROR as _,
+                    format!("{:#}", e),
+                    None::<String>,
+                ))))
+            }
+        }
+    }
+
+    /// Handler for the `chain_checkBlocksStatus` endpoint.
+    fn check_blocks_status_handler(
+        &self,
+        params: CheckBlocksStatusParams,
+    ) -> JsonRpcResult<JsonValue> {
+        match self.block_checker.check_status(params.id) {
+            Some(status) => Ok(status),
+            None => Err(JsonRpcError::Call(CallError::Custom(ErrorObject::owned(
+                Self::CHECK_BLOCKS_ERROR as _,
+                format!("there is no block check with id {}", params.id),
+                None::<String>,
+            )))),
+        }
+    }
 }
 
 fn json_rpc_error(
@@ -250,3 +312,37 @@ struct SubgraphReassignParams {
     ipfs_hash: DeploymentHash,
     node_id: NodeId,
 }
+
+/// The parameters of `chain_checkBlocks`: the chain, which of its cached blocks to check, e.g.
+/// `"range": "100..200"`, and what to do with the ones that diverged.
+#[derive(Debug, Deserialize)]
+pub struct CheckBlocksParams {
+    pub chain: String,
+    #[serde(flatten)]
+    pub target: CheckBlocksTarget,
+    /// Unless this is set, the check is a dry run: there is nobody to ask for confirmation over
+    /// the API, so deleting blocks has to be asked for up front.
+    #[serde(default)]
+    pub confirm: bool,
+    /// The most blocks the check may delete, beyond which it fails instead. Required with
+    /// `confirm`.
+    #[serde(default)]
+    pub max_deletions: Option<usize>,
+    /// Replace diverged blocks with the provider's version instead of deleting them.
+    #[serde(default)]
+    pub replace: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckBlocksTarget {
+    Hash(String),
+    Number(i32),
+    /// A range expression, like `100..200,5000..=5005`.
+    Range(String),
+}
+
+#[derive(Debug, Deserialize)]
+struct CheckBlocksStatusParams {
+    id: u64,
+}
@@ -482,7 +482,7 @@ impl BlockStore {
 
     fn truncate_block_caches(&self) -> Result<(), StoreError> {
         for store in self.stores.read().unwrap().values() {
-            store.truncate_block_cache()?
+            store.truncate_block_cache()?;
         }
         Ok(())
     }
@@ -45,6 +45,7 @@ mod public {
     }
 }
 
+use data::h256_from_bytes;
 pub use data::Storage;
 
 /// Encapuslate access to the blocks table for a chain.
@@ -55,6 +56,7 @@ mod data {
     use diesel::{dsl::sql, pg::PgConnection};
     use diesel::{
         pg::Pg,
+        query_builder::{QueryFragment, QueryId},
         serialize::Output,
         sql_types::Text,
         types::{FromSql, ToSql},
@@ -132,9 +134,15 @@ mod data {
         hash: Vec<u8>,
     }
 
+    #[derive(QueryableByName)]
+    struct BlockData {
+        #[sql_type = "Jsonb"]
+        block: json::Value,
+    }
+
     // Like H256::from_slice, but returns an error instead of panicking
     // when `bytes` does not have the right length
-    fn h256_from_bytes(bytes: &[u8]) -> Result<H256, StoreError> {
+    pub(super) fn h256_from_bytes(bytes: &[u8]) -> Result<H256, StoreError> {
         if bytes.len() == H256::len_bytes() {
             Ok(H256::from_slice(bytes))
         } else {
@@ -183,6 +191,10 @@ mod data {
             self.table.column::<BigInt, _>("number")
         }
 
+        fn parent_hash(&self) -> DynColumn<Bytea> {
+            self.table.column::<Bytea, _>("parent_hash")
+        }
+
         fn data(&self) -> DynColumn<Jsonb> {
             self.table.column::<Jsonb, _>("data")
         }
@@ -400,13 +412,88 @@ mod data {
             }
         }
 
-        pub(super) fn truncate_block_cache(&self, conn: &PgConnection) -> Result<(), StoreError> {
-            let table_name = match &self {
-                Storage::Shared => ETHEREUM_BLOCKS_TABLE_NAME,
-                Storage::Private(Schema { blocks, .. }) => &blocks.qname,
+        /// Remove all blocks of `chain` and return how many there were
+        pub(super) fn truncate_block_cache(
+            &self,
+            conn: &PgConnection,
+            chain: &str,
+        ) -> Result<usize, StoreError> {
+            match self {
+                Storage::Shared => {
+                    use public::ethereum_blocks as b;
+
+                    // The table is shared with other chains, so we can't
+                    // truncate it
+                    Ok(diesel::delete(b::table.filter(b::network_name.eq(chain))).execute(conn)?)
+                }
+                Storage::Private(Schema { blocks, .. }) => conn.transaction(|| {
+                    // Keep anybody from adding blocks between counting and
+                    // truncating
+                    conn.batch_execute(&format!(
+                        "lock table {} in access exclusive mode",
+                        blocks.qname
+                    ))?;
+                    let count = self.count_blocks(conn, chain)?;
+                    conn.batch_execute(&format!(
+                        "truncate table {} restart identity",
+                        blocks.qname
+                    ))?;
+                    Ok(count)
+                }),
+            }
+        }
+
+        /// Count the blocks of `chain`
+        pub(super) fn count_blocks(
+            &self,
+            conn: &PgConnection,
+            chain: &str,
+        ) -> Result<usize, StoreError> {
+            let count = match self {
+                Storage::Shared => {
+                    use public::ethereum_blocks as b;
+
+                    b::table
+                        .filter(b::network_name.eq(chain))
+                        .count()
+                        .get_result::<i64>(conn)?
+                }
+                Storage::Private(Schema { blocks, .. }) => {
+                    blocks.table().count().get_result::<i64>(conn)?
+                }
             };
-            conn.batch_execute(&format!("truncate table {} restart identity", table_name))?;
-            Ok(())
+            Ok(count as usize)
+        }
+
+        /// Count the blocks of `chain` whose number is between `min` and
+        /// `max`, inclusive
+        pub(super) fn count_blocks_in_range(
+            &self,
+            conn: &PgConnection,
+            chain: &str,
+            min: BlockNumber,
+            max: BlockNumber,
+        ) -> Result<usize, StoreError> {
+            let (min, max) = (min as i64, max as i64);
+            let count = match self {
+                Storage::Shared => {
+                    use public::ethereum_blocks as b;
+
+                    b::table
+                        .filter(b::network_name.eq(chain))
+                        .filter(b::number.ge(min))
+                        .filter(b::number.le(max))
+                        .count()
+                        .get_result::<i64>(conn)?
+                }
+                Storage::Private(Schema { blocks, .. }) => blocks
+                    .table()
+                    .filter(blocks.number().ge(min))
+                    .filter(blocks.number().le(max))
+                    .count()
+                    .get_result::<i64>(conn)?,
+            };
+            Ok(count as usize)
         }
 
         fn truncate_call_cache(&self, conn: &PgConnection) -> Result<(), StoreError> {
@@ -549,6 +636,22 @@ mod data {
             chain: &str,
             hashes: &[BlockHash],
         ) -> Result<Vec<json::Value>, Error> {
+            Ok(self
+                .blocks_by_hashes(conn, chain, hashes)?
+                .into_iter()
+                .map(|(_, block)| block)
+                .collect())
+        }
+
+        /// Like `blocks`, but also returns the hash that each block is stored
+        /// under, so that callers asking for many blocks at once can tell
+        /// which block belongs to which hash
+        pub(super) fn blocks_by_hashes(
+            &self,
+            conn: &PgConnection,
+            chain: &str,
+            hashes: &[BlockHash],
+        ) -> Result<Vec<(BlockHash, json::Value)>, Error> {
             use diesel::dsl::any;
 
             // We need to deal with chain stores where some entries have a
@@ -563,24 +666,32 @@ mod data {
                     use public::ethereum_blocks as b;
 
                     b::table
-                        .select(sql::<Jsonb>("coalesce(data -> 'block', data)"))
+                        .select((b::hash, sql::<Jsonb>("coalesce(data -> 'block', data)")))
                         .filter(b::network_name.eq(chain))
                         .filter(b::hash.eq(any(Vec::from_iter(
                             hashes.iter().map(|h| format!("{:x}", h)),
                         ))))
-                        .load::<json::Value>(conn)
+                        .load::<(String, json::Value)>(conn)?
+                        .into_iter()
+                        .map(|(hash, block)| Ok((hash.parse()?, block)))
+                        .collect::<Result<Vec<_>, Error>>()
                 }
-                Storage::Private(Schema { blocks, .. }) => blocks
+                Storage::Private(Schema { blocks, .. }) => Ok(blocks
                     .table()
-                    .select(sql::<Jsonb>("coalesce(data -> 'block', data)"))
+                    .select((
+                        blocks.hash(),
+                        sql::<Jsonb>("coalesce(data -> 'block', data)"),
+                    ))
                     .filter(
                         blocks
                             .hash()
                             .eq(any(Vec::from_iter(hashes.iter().map(|h| h.as_slice())))),
                     )
-                    .load::<json::Value>(conn),
+                    .load::<(Vec<u8>, json::Value)>(conn)?
+                    .into_iter()
+                    .map(|(hash, block)| (BlockHash::from(hash), block))
+                    .collect()),
             }
-            .map_err(Into::into)
         }
 
         pub(super) fn block_hashes_by_block_number(
@@ -614,6 +725,105 @@ mod data {
             }
         }
 
+        pub(super) fn block_hashes_by_block_numbers(
+            &self,
+            conn: &PgConnection,
+            chain: &str,
+            numbers: &[BlockNumber],
+        ) -> Result<Vec<(BlockNumber, BlockHash)>, Error> {
+            use diesel::dsl::any;
+
+            let numbers: Vec<i64> = numbers.iter().map(|number| *number as i64).collect();
+            match self {
+                Storage::Shared => {
+                    use public::ethereum_blocks as b;
+
+                    b::table
+                        .select((b::number, b::hash))
+                        .filter(b::network_name.eq(&chain))
+                        .filter(b::number.eq(any(numbers)))
+                        .get_results::<(i64, String)>(conn)?
+                        .into_iter()
+                        .map(|(number, hash)| Ok((number as BlockNumber, hash.parse()?)))
+                        .collect::<Result<Vec<_>, Error>>()
+                }
+                Storage::Private(Schema { blocks, .. }) => Ok(blocks
+                    .table()
+                    .select((blocks.number(), blocks.hash()))
+                    .filter(blocks.number().eq(any(numbers)))
+                    .get_results::<(i64, Vec<u8>)>(conn)?
+                    .into_iter()
+                    .map(|(number, hash)| (number as BlockNumber, BlockHash::from(hash)))
+                    .collect()),
+            }
+        }
+
+        pub(super) fn earliest_block_number(
+            &self,
+            conn: &PgConnection,
+            chain: &str,
+        ) -> Result<Option<BlockNumber>, Error> {
+            use diesel::dsl::min;
+
+            let number = match self {
+                Storage::Shared => {
+                    use public::ethereum_blocks as b;
+
+                    b::table
+                        .filter(b::network_name.eq(chain))
+                        .select(min(b::number))
+                        .first::<Option<i64>>(conn)?
+                }
+                Storage::Private(Schema { blocks, .. }) => blocks
+                    .table()
+                    .select(min(blocks.number()))
+                    .first::<Option<i64>>(conn)?,
+            };
+            Ok(number.map(|number| number as BlockNumber))
+        }
+
+        pub(super) fn block_parents_by_block_numbers(
+            &self,
+            conn: &PgConnection,
+            chain: &str,
+            numbers: &[BlockNumber],
+        ) -> Result<Vec<(BlockNumber, BlockHash, Option<BlockHash>)>, Error> {
+            use diesel::dsl::any;
+
+            let numbers: Vec<i64> = numbers.iter().map(|number| *number as i64).collect();
+            match self {
+                Storage::Shared => {
+                    use public::ethereum_blocks as b;
+
+                    b::table
+                        .select((b::number, b::hash, b::parent_hash))
+                        .filter(b::network_name.eq(&chain))
+                        .filter(b::number.eq(any(numbers)))
+                        .get_results::<(i64, String, Option<String>)>(conn)?
+                        .into_iter()
+                        .map(|(number, hash, parent_hash)| {
+                            let parent_hash = parent_hash.map(|hash| hash.parse()).transpose()?;
+                            Ok((number as BlockNumber, hash.parse()?, parent_hash))
+                        })
+                        .collect::<Result<Vec<_>, Error>>()
+                }
+                Storage::Private(Schema { blocks, .. }) => Ok(blocks
+                    .table()
+                    .select((blocks.number(), blocks.hash(), blocks.parent_hash()))
+                    .filter(blocks.number().eq(any(numbers)))
+                    .get_results::<(i64, Vec<u8>, Vec<u8>)>(conn)?
+                    .into_iter()
+                    .map(|(number, hash, parent_hash)| {
+                        (
+                            number as BlockNumber,
+                            BlockHash::from(hash),
+                            Some(BlockHash::from(parent_hash)),
+                        )
+                    })
+                    .collect()),
+            }
+        }
+
         pub(super) fn confirm_block_hash(
             &self,
             conn: &PgConnection,
@@ -839,13 +1049,17 @@ mod data {
             }
         }
 
-        pub(super) fn ancestor_block(
+        /// The hash of the block `offset` blocks below `block_ptr`, going by
+        /// the parent hashes of the cached blocks. That block itself doesn't
+        /// have to be cached, but all the blocks above it up to `block_ptr`
+        /// have to be
+        pub(super) fn ancestor_hash(
             &self,
             conn: &PgConnection,
-            block_ptr: BlockPtr,
+            block_ptr: &BlockPtr,
             offset: BlockNumber,
-        ) -> Result<Option<(json::Value, BlockPtr)>, Error> {
-            let data_and_hash = match self {
+        ) -> Result<Option<BlockHash>, Error> {
+            match self {
                 Storage::Shared => {
                     const ANCESTOR_SQL: &str = "
         with recursive ancestors(block_hash, block_offset) as (
@@ -865,19 +1079,9 @@ mod data {
                         .bind::<BigInt, _>(offset as i64)
                         .get_result::<BlockHashText>(conn)
                         .optional()?;
-
-                    use public::ethereum_blocks as b;
-
-                    match hash {
-                        None => None,
-                        Some(hash) => Some((
-                            b::table
-                                .filter(b::hash.eq(&hash.hash))
-                                .select(b::data)
-                                .first::<json::Value>(conn)?,
-                            BlockHash::from_str(&hash.hash)?,
-                        )),
-                    }
+                    Ok(hash
+                        .map(|hash| BlockHash::from_str(&hash.hash))
+                        .transpose()?)
                 }
                 Storage::Private(Schema { blocks, .. }) => {
                     // Same as ANCESTOR_SQL except for the table name
@@ -902,17 +1106,36 @@ mod data {
                         .bind::<BigInt, _>(offset as i64)
                         .get_result::<BlockHashBytea>(conn)
                         .optional()?;
-                    match hash {
-                        None => None,
-                        Some(hash) => Some((
-                            blocks
-                                .table()
-                                .filter(blocks.hash().eq(&hash.hash))
-                                .select(blocks.data())
-                                .first::<json::Value>(conn)?,
-                            BlockHash::from(hash.hash),
-                        )),
-                    }
+                    Ok(hash.map(|hash| BlockHash::from(hash.hash)))
+                }
+            }
+        }
+
+        pub(super) fn ancestor_block(
+            &self,
+            conn: &PgConnection,
+            block_ptr: BlockPtr,
+            offset: BlockNumber,
+        ) -> Result<Option<(json::Value, BlockPtr)>, Error> {
+            let data_and_hash = match self.ancestor_hash(conn, &block_ptr, offset)? {
+                None => None,
+                Some(hash) => {
+                    let data = match self {
+                        Storage::Shared => {
+                            use public::ethereum_blocks as b;
+
+                            b::table
+                                .filter(b::hash.eq(hash.hash_hex()))
+                                .select(b::data)
+                                .first::<json::Value>(conn)?
+                        }
+                        Storage::Private(Schema { blocks, .. }) => blocks
+                            .table()
+                            .filter(blocks.hash().eq(hash.as_slice()))
+                            .select(blocks.data())
+                            .first::<json::Value>(conn)?,
+                    };
+                    Some((data, hash))
                 }
             };
 
@@ -968,34 +1191,54 @@ mod data {
             }
         }
 
-        pub(super) fn delete_blocks_by_hash(
+        /// Delete the blocks of `chain` whose number is between `min` and
+        /// `max`, inclusive, with one statement. The genesis block is kept
+        pub(super) fn delete_blocks_in_range(
             &self,
             conn: &PgConnection,
             chain: &str,
-            block_hashes: &[&H256],
+            min: BlockNumber,
+            max: BlockNumber,
         ) -> Result<usize, Error> {
-            use diesel::dsl::any;
+            let (min, max) = (min as i64, max as i64);
             match self {
                 Storage::Shared => {
                     use public::ethereum_blocks as b;
 
-                    let hashes: Vec<String> = block_hashes
-                        .iter()
-                        .map(|hash| format!("{hash:x}"))
-                        .collect();
-
                     diesel::delete(b::table)
                         .filter(b::network_name.eq(chain))
-                        .filter(b::hash.eq(any(hashes)))
-                        .filter(b::number.gt(0)) // keep genesis
+                        .filter(b::number.ge(min))
+                        .filter(b::number.le(max))
+                        .filter(b::number.gt(0))
                         .execute(conn)
                         .map_err(Error::from)
                 }
                 Storage::Private(Schema { blocks, .. }) => {
                     let query = format!(
-                        "delete from {} where hash = any($1) and number > 0",
+                        "delete from {} where number >= $1 and number <= $2 and number > 0",
                         blocks.qname
                     );
+                    sql_query(query)
+                        .bind::<BigInt, _>(min)
+                        .bind::<BigInt, _>(max)
+                        .execute(conn)
+                        .map_err(Error::from)
+                }
+            }
+        }
+
+        pub(super) fn delete_blocks_by_hash(
+            &self,
+            conn: &PgConnection,
+            chain: &str,
+            block_hashes: &[&H256],
+        ) -> Result<usize, Error> {
+            match self {
+                Storage::Shared => Self::shared_delete_blocks_by_hash(chain, block_hashes)
+                    .execute(conn)
+                    .map_err(Error::from),
+                Storage::Private(Schema { blocks, .. }) => {
+                    let query = Self::delete_blocks_by_hash_query(&blocks.qname);
 
                     let hashes: Vec<&[u8]> =
                         block_hashes.iter().map(|hash| hash.as_bytes()).collect();
@@ -1008,6 +1251,93 @@ mod data {
             }
         }
 
+        /// The cached block with `block_hash`, like `blocks` returns it,
+        /// locked until the end of the transaction that `conn` is in, or
+        /// `None` if there is no such block
+        pub(super) fn lock_block(
+            &self,
+            conn: &PgConnection,
+            chain: &str,
+            block_hash: &H256,
+        ) -> Result<Option<json::Value>, Error> {
+            // See `blocks_by_hashes` for why we coalesce
+            let block = match self {
+                Storage::Shared => sql_query(
+                    "select coalesce(data -> 'block', data) as block \
+                       from ethereum_blocks \
+                      where network_name = $1 and hash = $2 \
+                        for update",
+                )
+                .bind::<Text, _>(chain)
+                .bind::<Text, _>(format!("{block_hash:x}"))
+                .get_result::<BlockData>(conn),
+                Storage::Private(Schema { blocks, .. }) => sql_query(format!(
+                    "select coalesce(data -> 'block', data) as block \
+                       from {} \
+                      where hash = $1 \
+                        for update",
+                    blocks.qname
+                ))
+                .bind::<Bytea, _>(block_hash.as_bytes())
+                .get_result::<BlockData>(conn),
+            };
+            Ok(block.optional()?.map(|block| block.block))
+        }
+
+        /// The statement that `delete_blocks_by_hash` runs for
+        /// `Storage::Shared`
+        fn shared_delete_blocks_by_hash<'a>(
+            chain: &'a str,
+            block_hashes: &[&H256],
+        ) -> impl QueryFragment<Pg> + QueryId + RunQueryDsl<PgConnection> + 'a {
+            use diesel::dsl::any;
+            use public::ethereum_blocks as b;
+
+            let hashes: Vec<String> = block_hashes
+                .iter()
+                .map(|hash| format!("{hash:x}"))
+                .collect();
+
+            diesel::delete(b::table)
+                .filter(b::network_name.eq(chain))
+                .filter(b::hash.eq(any(hashes)))
+                .filter(b::number.gt(0)) // keep genesis
+        }
+
+        /// The statement that `delete_blocks_by_hash` runs for
+        /// `Storage::Private`
+        fn delete_blocks_by_hash_query(qname: &str) -> String {
+            format!("delete from {} where hash = any($1) and number > 0", qname)
+        }
+
+        /// The statement that `delete_blocks_by_hash` runs, followed by its bind parameters in a
+        /// `-- binds: [..]` comment, without running it
+        pub(super) fn explain_delete_blocks_by_hash(
+            &self,
+            chain: &str,
+            block_hashes: &[&H256],
+        ) -> String {
+            use diesel::debug_query;
+            match self {
+                Storage::Shared => {
+                    let query = Self::shared_delete_blocks_by_hash(chain, block_hashes);
+                    debug_query::<Pg, _>(&query).to_string()
+                }
+                Storage::Private(Schema { blocks, .. }) => {
+                    // `debug_query` would show the hashes as lists of bytes
+                    let hashes: Vec<String> = block_hashes
+                        .iter()
+                        .map(|hash| format!("'\\x{hash:x}'"))
+                        .collect();
+                    format!(
+                        "{} -- binds: [[{}]]",
+                        Self::delete_blocks_by_hash_query(&blocks.qname),
+                        hashes.join(", ")
+                    )
+                }
+            }
+        }
+
         pub(super) fn get_call_and_access(
             &self,
             conn: &PgConnection,
@@ -1471,6 +1801,10 @@ pub struct ChainStore {
     recent_blocks_cache: RecentBlocksCache,
 }
 
+/// How far below the chain head `ChainStore::canonical_block_hash` follows
+/// parent hashes before it gives up
+const CANONICAL_BLOCK_MAX_DEPTH: BlockNumber = 10_000;
+
 impl ChainStore {
     pub(crate) fn new(
         chain: String,
@@ -1593,12 +1927,179 @@ impl ChainStore {
             .set_chain(&conn, &self.chain, genesis_hash, chain);
     }
 
+    /// Look up the hashes of the cached blocks for all of `numbers` with a
+    /// single query. Every number in `numbers` has an entry in the result;
+    /// numbers without any cached block map to an empty vector
+    pub fn block_hashes_by_block_numbers(
+        &self,
+        numbers: &[BlockNumber],
+    ) -> Result<HashMap<BlockNumber, Vec<BlockHash>>, Error> {
+        let conn = self.get_conn()?;
+        let mut hashes: HashMap<BlockNumber, Vec<BlockHash>> =
+            numbers.iter().map(|number| (*number, Vec::new())).collect();
+        for (number, hash) in
+            self.storage
+                .block_hashes_by_block_numbers(&conn, &self.chain, numbers)?
+        {
+            hashes.entry(number).or_default().push(hash);
+        }
+        Ok(hashes)
+    }
+
+    /// The lowest number of any block in the block cache, or `None` if the
+    /// cache is empty
+    pub fn earliest_cached_block_number(&self) -> Result<Option<BlockNumber>, Error> {
+        let conn = self.get_conn()?;
+        self.storage.earliest_block_number(&conn, &self.chain)
+    }
+
+    /// The hash of the block with `number` on the chain that ends in the
+    /// chain head, found by following parent hashes down from the head.
+    /// Unlike `block_hashes_by_block_number`, which also returns uncles and
+    /// blocks of stale forks, there is at most one such block. Returns
+    /// `None` if there is no chain head, `number` is above it or more than
+    /// `CANONICAL_BLOCK_MAX_DEPTH` blocks below it, or the chain between the
+    /// head and `number` has a gap in the cache
+    pub fn canonical_block_hash(&self, number: BlockNumber) -> Result<Option<H256>, Error> {
+        use public::ethereum_networks as n;
+
+        let conn = self.get_conn()?;
+        let head = n::table
+            .filter(n::name.eq(&self.chain))
+            .select((n::head_block_hash, n::head_block_number))
+            .first::<(Option<String>, Option<i64>)>(&conn)
+            .optional()?;
+        let head = match head {
+            Some((Some(hash), Some(head_number))) => {
+                BlockPtr::try_from((hash.as_str(), head_number))?
+            }
+            _ => return Ok(None),
+        };
+        // Every block between the head and `number` is one step of the
+        // recursive query, which would take forever for old blocks
+        if number < 0 || number > head.number || head.number - number > CANONICAL_BLOCK_MAX_DEPTH {
+            return Ok(None);
+        }
+
+        let hash = self
+            .storage
+            .ancestor_hash(&conn, &head, head.number - number)?;
+        Ok(hash
+            .map(|hash| h256_from_bytes(hash.as_slice()))
+            .transpose()?)
+    }
+
+    /// Look up the hash and parent hash of every cached block whose number
+    /// is one of `numbers`. The parent hash is `None` for blocks that were
+    /// stored without one
+    pub fn block_parents_by_block_numbers(
+        &self,
+        numbers: &[BlockNumber],
+    ) -> Result<Vec<(BlockNumber, BlockHash, Option<BlockHash>)>, Error> {
+        let conn = self.get_conn()?;
+        self.storage
+            .block_parents_by_block_numbers(&conn, &self.chain, numbers)
+    }
+
     pub fn delete_blocks(&self, block_hashes: &[&H256]) -> Result<usize, Error> {
         let conn = self.get_conn()?;
         self.storage
             .delete_blocks_by_hash(&conn, &self.chain, block_hashes)
     }
 
+    /// Deletes all cached blocks whose number is between `min` and `max`,
+    /// inclusive, in a single statement, so that the range is either gone
+    /// entirely or not at all. Blocks at forked heights are all deleted; the
+    /// genesis block is kept. Returns how many blocks were deleted
+    pub fn delete_blocks_in_range(
+        &self,
+        min: BlockNumber,
+        max: BlockNumber,
+    ) -> Result<usize, Error> {
+        let conn = self.get_conn()?;
+        let deleted = self
+            .storage
+            .delete_blocks_in_range(&conn, &self.chain, min, max)?;
+        // The recent blocks may have been among those deleted
+        self.recent_blocks_cache.clear();
+        Ok(deleted)
+    }
+
+    /// Deletes the block with `block_hash` like `delete_blocks`, but only if
+    /// `unchanged` holds for the cached block. The block is read and locked
+    /// in the same transaction that deletes it, so that nobody can change it
+    /// in between. Returns whether the block was deleted
+    pub fn delete_block_if_unchanged(
+        &self,
+        block_hash: &H256,
+        unchanged: impl FnOnce(&json::Value) -> bool,
+    ) -> Result<bool, Error> {
+        let conn = self.get_conn()?;
+        conn.transaction(|| {
+            let block = self.storage.lock_block(&conn, &self.chain, block_hash)?;
+            if !block.as_ref().map_or(false, unchanged) {
+                return Ok(false);
+            }
+            let deleted = self
+                .storage
+                .delete_blocks_by_hash(&conn, &self.chain, &[block_hash])?;
+            Ok(deleted > 0)
+        })
+    }
+
+    /// Overwrite the cached block with hash `block_hash` with `block`, but
+    /// only if `unchanged` says that the cached block is still what the
+    /// caller expects. Like `delete_block_if_unchanged`, the block is
+    /// locked while that is checked. Returns whether the block was
+    /// overwritten, which it isn't if it is not cached
+    pub fn replace_block_if_unchanged(
+        &self,
+        block_hash: &H256,
+        block: &dyn Block,
+        unchanged: impl FnOnce(&json::Value) -> bool,
+    ) -> Result<bool, Error> {
+        let conn = self.get_conn()?;
+        let replaced = conn.transaction(|| {
+            let cached = self.storage.lock_block(&conn, &self.chain, block_hash)?;
+            if !cached.as_ref().map_or(false, unchanged) {
+                return Ok(false);
+            }
+            self.storage.upsert_block(&conn, &self.chain, block, true)?;
+            Ok::<_, Error>(true)
+        })?;
+        if replaced {
+            if let Some(parent_hash) = block.parent_hash() {
+                self.recent_blocks_cache
+                    .insert_block(block.ptr(), block.data().ok(), parent_hash);
+            }
+        }
+        Ok(replaced)
+    }
+
+    /// The maximum number of database connections that this store can use
+    /// at the same time
+    pub fn connection_pool_size(&self) -> Result<usize, StoreError> {
+        Ok(self.pool.max_size()? as usize)
+    }
+
+    /// The cached blocks for `hashes` together with the hash each of them is
+    /// stored under, all loaded with a single query. A hash that isn't cached
+    /// has no entry, and one that is cached more than once has several
+    pub fn blocks_by_hashes(
+        &self,
+        hashes: &[BlockHash],
+    ) -> Result<Vec<(BlockHash, json::Value)>, Error> {
+        let conn = self.get_conn()?;
+        self.storage.blocks_by_hashes(&conn, &self.chain, hashes)
+    }
+
+    /// The SQL statement and bind parameters that `delete_blocks` would run for `block_hashes`.
+    /// Nothing is sent to the database
+    pub fn explain_delete_blocks(&self, block_hashes: &[&H256]) -> String {
+        self.storage
+            .explain_delete_blocks_by_hash(&self.chain, block_hashes)
+    }
+
     pub fn cleanup_shallow_blocks(&self, lowest_block: i32) -> Result<(), StoreError> {
         let conn = self.get_conn()?;
         self.storage.cleanup_shallow_blocks(&conn, lowest_block)?;
@@ -1611,10 +2112,28 @@ impl ChainStore {
         self.storage.remove_cursor(&conn, chain)
     }
 
-    pub fn truncate_block_cache(&self) -> Result<(), StoreError> {
+    /// Delete all cached blocks and return how many there were
+    pub fn truncate_block_cache(&self) -> Result<usize, StoreError> {
         let conn = self.get_conn()?;
-        self.storage.truncate_block_cache(&conn)?;
-        Ok(())
+        self.storage.truncate_block_cache(&conn, &self.chain)
+    }
+
+    /// The number of blocks that `truncate_block_cache` would delete
+    pub fn cached_block_count(&self) -> Result<usize, StoreError> {
+        let conn = self.get_conn()?;
+        self.storage.count_blocks(&conn, &self.chain)
+    }
+
+    /// The number of cached blocks whose number is between `min` and `max`,
+    /// inclusive. Blocks at forked heights are all counted
+    pub fn count_blocks_in_range(
+        &self,
+        min: BlockNumber,
+        max: BlockNumber,
+    ) -> Result<usize, StoreError> {
+        let conn = self.get_conn()?;
+        self.storage
+            .count_blocks_in_range(&conn, &self.chain, min, max)
     }
 }
 
@@ -452,6 +452,11 @@ impl ConnectionPool {
         self.get_ready()?.get()
     }
 
+    /// The maximum number of connections that the pool will open
+    pub fn max_size(&self) -> Result<u32, StoreError> {
+        Ok(self.get_ready()?.pool.max_size())
+    }
+
     /// Get a connection from the pool for foreign data wrapper access;
     /// since that pool can be very contended, periodically log that we are
     /// still waiting for a connection
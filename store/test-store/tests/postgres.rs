@@ -1,5 +1,6 @@
 pub mod postgres {
     pub mod chain_head;
+    pub mod check_blocks;
     pub mod graft;
     pub mod relational;
     pub mod relational_bytes;
@@ -250,6 +250,192 @@ fn block_hashes_by_number() {
     })
 }
 
+#[test]
+fn block_hashes_by_numbers() {
+    let chain = vec![
+        &*GENESIS_BLOCK,
+        &*BLOCK_ONE,
+        &*BLOCK_TWO,
+        &*BLOCK_TWO_NO_PARENT,
+    ];
+    run_test(chain, move |store, _| {
+        let hashes = store.block_hashes_by_block_numbers(&[1, 2, 127]).unwrap();
+        assert_eq!(3, hashes.len());
+        assert_eq!(vec![BLOCK_ONE.block_hash()], hashes[&1]);
+        assert_eq!(2, hashes[&2].len());
+        assert!(hashes[&2].contains(&BLOCK_TWO.block_hash()));
+        assert!(hashes[&2].contains(&BLOCK_TWO_NO_PARENT.block_hash()));
+        assert!(hashes[&127].is_empty());
+
+        let hashes = store.block_hashes_by_block_numbers(&[]).unwrap();
+        assert!(hashes.is_empty());
+        Ok(())
+    })
+}
+
+#[test]
+fn block_parents_by_numbers() {
+    let chain = vec![&*GENESIS_BLOCK, &*BLOCK_ONE, &*BLOCK_TWO];
+    run_test(chain, move |store, _| {
+        let mut parents = store.block_parents_by_block_numbers(&[1, 2, 127]).unwrap();
+        parents.sort_by_key(|(number, _, _)| *number);
+        assert_eq!(
+            vec![
+                (1, BLOCK_ONE.block_hash(), Some(GENESIS_BLOCK.block_hash())),
+                (2, BLOCK_TWO.block_hash(), Some(BLOCK_ONE.block_hash())),
+            ],
+            parents
+        );
+        Ok(())
+    })
+}
+
+#[test]
+fn earliest_cached_block_number() {
+    let chain = vec![&*BLOCK_ONE, &*BLOCK_TWO];
+    run_test(chain, move |store, _| {
+        assert_eq!(Some(1), store.earliest_cached_block_number().unwrap());
+        store.truncate_block_cache().unwrap();
+        assert_eq!(None, store.earliest_cached_block_number().unwrap());
+        Ok(())
+    })
+}
+
+#[test]
+fn count_blocks_in_range() {
+    let chain = vec![
+        &*GENESIS_BLOCK,
+        &*BLOCK_ONE,
+        &*BLOCK_ONE_SIBLING,
+        &*BLOCK_TWO,
+        &*BLOCK_THREE,
+    ];
+    run_test(chain, move |store, _| {
+        assert_eq!(5, store.count_blocks_in_range(0, 3).unwrap());
+        assert_eq!(3, store.count_blocks_in_range(1, 2).unwrap());
+        assert_eq!(1, store.count_blocks_in_range(3, 10).unwrap());
+        assert_eq!(0, store.count_blocks_in_range(4, 10).unwrap());
+        Ok(())
+    })
+}
+
+#[test]
+fn delete_blocks_in_range() {
+    let chain = vec![
+        &*GENESIS_BLOCK,
+        &*BLOCK_ONE,
+        &*BLOCK_ONE_SIBLING,
+        &*BLOCK_TWO,
+        &*BLOCK_THREE,
+    ];
+    run_test(chain, move |store, _| {
+        // Both blocks at the forked height go, the genesis block stays
+        assert_eq!(2, store.delete_blocks_in_range(0, 1).unwrap());
+        assert_eq!(3, store.cached_block_count().unwrap());
+        assert_eq!(1, store.count_blocks_in_range(0, 0).unwrap());
+        assert_eq!(0, store.delete_blocks_in_range(4, 10).unwrap());
+        assert_eq!(2, store.delete_blocks_in_range(2, 3).unwrap());
+        assert_eq!(1, store.cached_block_count().unwrap());
+        Ok(())
+    })
+}
+
+#[test]
+fn delete_block_if_unchanged() {
+    let chain = vec![&*GENESIS_BLOCK, &*BLOCK_ONE, &*BLOCK_TWO];
+    run_test(chain, move |store, _| {
+        let one = BLOCK_ONE.block_ptr().hash_as_h256();
+
+        // A block that changed is left alone
+        assert!(!store.delete_block_if_unchanged(&one, |_| false).unwrap());
+        assert_eq!(3, store.cached_block_count().unwrap());
+
+        // The check sees the block as cached, without the receipts
+        let deleted = store
+            .delete_block_if_unchanged(&one, |cached| {
+                cached["hash"] == json::json!(format!("0x{}", BLOCK_ONE.hash))
+            })
+            .unwrap();
+        assert!(deleted);
+        assert_eq!(2, store.cached_block_count().unwrap());
+
+        // A block that isn't cached is never asked about
+        assert!(!store
+            .delete_block_if_unchanged(&one, |_| panic!("block one is gone"))
+            .unwrap());
+        Ok(())
+    })
+}
+
+#[test]
+fn replace_block_if_unchanged() {
+    let chain = vec![&*GENESIS_BLOCK, &*BLOCK_ONE];
+    run_test(chain, move |store, _| {
+        let one = BLOCK_ONE.block_ptr().hash_as_h256();
+        let two = BLOCK_TWO.block_ptr().hash_as_h256();
+
+        assert!(!store
+            .replace_block_if_unchanged(&one, &*BLOCK_ONE, |_| false)
+            .unwrap());
+        assert!(store
+            .replace_block_if_unchanged(&one, &*BLOCK_ONE, |_| true)
+            .unwrap());
+        assert_eq!(2, store.cached_block_count().unwrap());
+
+        // A block that isn't cached isn't added either
+        assert!(!store
+            .replace_block_if_unchanged(&two, &*BLOCK_TWO, |_| panic!("block two isn't cached"))
+            .unwrap());
+        assert_eq!(2, store.cached_block_count().unwrap());
+        Ok(())
+    })
+}
+
+#[test]
+fn canonical_block_hash() {
+    let chain = vec![
+        &*GENESIS_BLOCK,
+        &*BLOCK_ONE,
+        &*BLOCK_ONE_SIBLING,
+        &*BLOCK_TWO,
+        &*BLOCK_THREE,
+    ];
+    run_test_async(chain, move |store, _| async move {
+        // Without a chain head, there is no canonical chain
+        assert_eq!(None, store.canonical_block_hash(1).unwrap());
+
+        store
+            .clone()
+            .attempt_chain_head_update(ANCESTOR_COUNT)
+            .await
+            .expect("attempt_chain_head_update failed");
+
+        let canonical = |number| {
+            store
+                .canonical_block_hash(number)
+                .unwrap()
+                .map(|hash| format!("{:x}", hash))
+        };
+        assert_eq!(Some(GENESIS_BLOCK.hash.clone()), canonical(0));
+        // The sibling has the same number but isn't an ancestor of the head
+        assert_eq!(Some(BLOCK_ONE.hash.clone()), canonical(1));
+        assert_eq!(Some(BLOCK_THREE.hash.clone()), canonical(3));
+        assert_eq!(None, canonical(4));
+    })
+}
+
+#[test]
+fn truncate_block_cache() {
+    let chain = vec![&*GENESIS_BLOCK, &*BLOCK_ONE, &*BLOCK_TWO];
+    run_test(chain, move |store, _| {
+        assert_eq!(3, store.cached_block_count().unwrap());
+        assert_eq!(3, store.truncate_block_cache().unwrap());
+        assert_eq!(0, store.cached_block_count().unwrap());
+        assert_eq!(0, store.truncate_block_cache().unwrap());
+        Ok(())
+    })
+}
+
 #[track_caller]
 fn check_ancestor(
     store: &Arc<DieselChainStore>,
@@ -332,6 +518,29 @@ fn ancestor_block_ommers() {
     });
 }
 
+#[test]
+fn ancestor_block_with_a_gap() {
+    let chain = vec![&*GENESIS_BLOCK, &*BLOCK_TWO, &*BLOCK_THREE];
+
+    run_test(chain, move |store, _| -> Result<(), Error> {
+        check_ancestor(&store, &BLOCK_THREE, 0, &BLOCK_THREE)?;
+        check_ancestor(&store, &BLOCK_THREE, 1, &BLOCK_TWO)?;
+
+        // The parent hash of block two leads to block one, which isn't cached
+        let res = executor::block_on(
+            store
+                .cheap_clone()
+                .ancestor_block(BLOCK_THREE.block_ptr(), 2),
+        );
+        assert!(res.is_err());
+
+        // Below block one, there are no parent hashes left to follow
+        let block = executor::block_on(store.ancestor_block(BLOCK_THREE.block_ptr(), 3))?;
+        assert!(block.is_none());
+        Ok(())
+    });
+}
+
 #[test]
 fn eth_call_cache() {
     let chain = vec![&*GENESIS_BLOCK, &*BLOCK_ONE, &*BLOCK_TWO];
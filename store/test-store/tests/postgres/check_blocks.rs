@@ -0,0 +1,321 @@
+//! Test that `graphman chain check-blocks` fixes the block cache of a
+//! ChainStore, with a provider that answers from a list of blocks
+use std::collections::{HashMap, HashSet};
+use std::pin::Pin;
+use std::sync::Arc;
+
+use graph::blockchain::{BlockPtr, ChainIdentifier, IngestorError};
+use graph::cheap_clone::CheapClone;
+use graph::components::store::{BlockStore as _, ChainStore, EthereumCallCache};
+use graph::log::discard;
+use graph::prelude::ethabi::Token;
+use graph::prelude::web3::types::{Block, H256, U256, U64};
+use graph::prelude::{
+    anyhow::{anyhow, Error},
+    async_trait, future, stream, BlockNumber, EthereumBlock, Future, LightEthereumBlock, Logger,
+    Stream,
+};
+use graph_chain_ethereum::{EthereumAdapterTrait, EthereumContractCall, EthereumContractCallError};
+use graph_node::manager::commands::check_blocks::{by_hash, by_number, CheckOptions};
+use graph_store_postgres::{layout_for_tests::FAKE_NETWORK_SHARED, ChainStore as DieselChainStore};
+
+use test_store::block_store::{FakeBlock, FakeBlockList, BLOCK_ONE, BLOCK_TWO, GENESIS_BLOCK};
+use test_store::*;
+
+/// Test harness for running database integration tests.
+fn run_test<R, F>(chain: FakeBlockList, test: F)
+where
+    F: Fn(Arc<DieselChainStore>) -> R + Send + Sync + 'static,
+    R: std::future::Future<Output = ()> + Send + 'static,
+{
+    run_test_sequentially(|store| async move {
+        for name in &[NETWORK_NAME, FAKE_NETWORK_SHARED] {
+            block_store::set_chain(chain.clone(), name);
+
+            let chain_store = store.block_store().chain_store(name).expect("chain store");
+
+            // Run test
+            test(chain_store.cheap_clone()).await;
+        }
+    });
+}
+
+/// A provider for the chain of a ChainStore that knows the given blocks.
+struct FakeProvider {
+    chain_identifier: ChainIdentifier,
+    blocks: HashMap<H256, LightEthereumBlock>,
+    /// Runs whenever a block is requested by its number, to change the cache in the middle of a
+    /// check.
+    on_block_by_number: Option<Box<dyn Fn() + Send + Sync>>,
+}
+
+impl FakeProvider {
+    fn new(chain_store: &DieselChainStore, blocks: Vec<LightEthereumBlock>) -> Self {
+        FakeProvider {
+            chain_identifier: chain_store.chain_identifier.clone(),
+            blocks: blocks
+                .into_iter()
+                .map(|block| (block.hash.expect("blocks have a hash"), block))
+                .collect(),
+            on_block_by_number: None,
+        }
+    }
+
+    fn on_block_by_number(self, hook: impl Fn() + Send + Sync + 'static) -> Self {
+        FakeProvider {
+            on_block_by_number: Some(Box::new(hook)),
+            ..self
+        }
+    }
+
+    fn latest(&self) -> &LightEthereumBlock {
+        self.blocks
+            .values()
+            .max_by_key(|block| block.number)
+            .expect("the provider has blocks")
+    }
+
+    fn block_with_number(&self, number: BlockNumber) -> Option<&LightEthereumBlock> {
+        self.blocks
+            .values()
+            .find(|block| block.number == Some(U64::from(number as u64)))
+    }
+}
+
+#[async_trait]
+impl EthereumAdapterTrait for FakeProvider {
+    fn provider(&self) -> &str {
+        "fake"
+    }
+
+    async fn net_identifiers(&self) -> Result<ChainIdentifier, Error> {
+        Ok(self.chain_identifier.clone())
+    }
+
+    fn latest_block(
+        &self,
+        _logger: &Logger,
+    ) -> Box<dyn Future<Item = LightEthereumBlock, Error = IngestorError> + Send + Unpin> {
+        Box::new(future::ok(self.latest().clone()))
+    }
+
+    fn latest_block_header(
+        &self,
+        _logger: &Logger,
+    ) -> Box<dyn Future<Item = Block<H256>, Error = IngestorError> + Send> {
+        let latest = self.latest();
+        Box::new(future::ok(Block {
+            hash: latest.hash,
+            number: latest.number,
+            ..Default::default()
+        }))
+    }
+
+    fn load_block(
+        &self,
+        _logger: &Logger,
+        block_hash: H256,
+    ) -> Box<dyn Future<Item = LightEthereumBlock, Error = Error> + Send> {
+        Box::new(future::result(
+            self.blocks
+                .get(&block_hash)
+                .cloned()
+                .ok_or_else(|| anyhow!("no block {block_hash:?}")),
+        ))
+    }
+
+    fn load_blocks(
+        &self,
+        _logger: Logger,
+        _chain_store: Arc<dyn ChainStore>,
+        block_hashes: HashSet<H256>,
+    ) -> Box<dyn Stream<Item = Arc<LightEthereumBlock>, Error = Error> + Send> {
+        let blocks: Vec<_> = block_hashes
+            .iter()
+            .filter_map(|block_hash| self.blocks.get(block_hash).cloned().map(Arc::new))
+            .collect();
+        Box::new(stream::iter_ok(blocks))
+    }
+
+    fn block_by_hash(
+        &self,
+        _logger: &Logger,
+        block_hash: H256,
+    ) -> Box<dyn Future<Item = Option<LightEthereumBlock>, Error = Error> + Send> {
+        Box::new(future::ok(self.blocks.get(&block_hash).cloned()))
+    }
+
+    fn block_by_number(
+        &self,
+        _logger: &Logger,
+        block_number: BlockNumber,
+    ) -> Box<dyn Future<Item = Option<LightEthereumBlock>, Error = Error> + Send> {
+        if let Some(hook) = &self.on_block_by_number {
+            hook();
+        }
+        Box::new(future::ok(self.block_with_number(block_number).cloned()))
+    }
+
+    fn load_full_block(
+        &self,
+        _logger: &Logger,
+        block: LightEthereumBlock,
+    ) -> Pin<Box<dyn std::future::Future<Output = Result<EthereumBlock, IngestorError>> + Send>>
+    {
+        // The fake blocks have no transactions, and therefore no receipts
+        Box::pin(std::future::ready(Ok(EthereumBlock {
+            block: Arc::new(block),
+            transaction_receipts: vec![],
+        })))
+    }
+
+    fn block_pointer_from_number(
+        &self,
+        _logger: &Logger,
+        block_number: BlockNumber,
+    ) -> Box<dyn Future<Item = BlockPtr, Error = IngestorError> + Send> {
+        let block_ptr = self
+            .block_with_number(block_number)
+            .and_then(|block| block.hash)
+            .map(|block_hash| BlockPtr::from((block_hash, block_number)))
+            .ok_or_else(|| IngestorError::Unknown(anyhow!("no block number {block_number}")));
+        Box::new(future::result(block_ptr))
+    }
+
+    fn block_hash_by_block_number(
+        &self,
+        _logger: &Logger,
+        block_number: BlockNumber,
+    ) -> Box<dyn Future<Item = Option<H256>, Error = Error> + Send> {
+        let block_hash = self
+            .block_with_number(block_number)
+            .and_then(|block| block.hash);
+        Box::new(future::ok(block_hash))
+    }
+
+    fn contract_call(
+        &self,
+        _logger: &Logger,
+        _call: EthereumContractCall,
+        _cache: Arc<dyn EthereumCallCache>,
+    ) -> Box<dyn Future<Item = Vec<Token>, Error = EthereumContractCallError> + Send> {
+        Box::new(future::err(EthereumContractCallError::Revert(
+            "the fake blocks hold no contracts".to_string(),
+        )))
+    }
+}
+
+fn light_block(block: &FakeBlock) -> LightEthereumBlock {
+    (*block.as_ethereum_block().block).clone()
+}
+
+#[test]
+fn diverged_block_is_deleted() {
+    let chain = vec![&*GENESIS_BLOCK, &*BLOCK_ONE, &*BLOCK_TWO];
+    run_test(chain, |store| async move {
+        let one = BLOCK_ONE.block_ptr().hash_as_h256();
+        let mut diverged = light_block(&BLOCK_ONE);
+        diverged.gas_used = U256::from(21_000);
+        let provider = FakeProvider::new(&store, vec![light_block(&GENESIS_BLOCK), diverged]);
+        let options = CheckOptions {
+            skip_confirmation: true,
+            ..CheckOptions::default()
+        };
+
+        let report = by_hash(
+            &[format!("{one:?}")],
+            store.cheap_clone(),
+            &provider,
+            &discard(),
+            &options,
+        )
+        .await
+        .expect("the check succeeds");
+
+        assert_eq!(1, report.checked);
+        assert_eq!(vec![one], report.deleted);
+        assert!(store.blocks(&[one.into()]).unwrap().is_empty());
+        assert_eq!(2, store.cached_block_count().unwrap());
+    })
+}
+
+#[test]
+fn dry_run_deletes_nothing() {
+    let chain = vec![&*GENESIS_BLOCK, &*BLOCK_ONE, &*BLOCK_TWO];
+    run_test(chain, |store| async move {
+        let one = BLOCK_ONE.block_ptr().hash_as_h256();
+        let mut diverged = light_block(&BLOCK_ONE);
+        diverged.gas_used = U256::from(21_000);
+        let provider = FakeProvider::new(&store, vec![light_block(&GENESIS_BLOCK), diverged]);
+        let options = CheckOptions {
+            dry_run: true,
+            ..CheckOptions::default()
+        };
+
+        let report = by_hash(
+            &[format!("{one:?}")],
+            store.cheap_clone(),
+            &provider,
+            &discard(),
+            &options,
+        )
+        .await
+        .expect("the check succeeds");
+
+        assert_eq!(vec![(one, 1)], report.diverged);
+        assert_eq!(1, store.blocks(&[one.into()]).unwrap().len());
+        assert_eq!(3, store.cached_block_count().unwrap());
+    })
+}
+
+#[test]
+fn stale_block_that_changed_is_not_replaced() {
+    let chain = vec![&*GENESIS_BLOCK, &*BLOCK_ONE, &*BLOCK_TWO];
+    run_test(chain, |store| async move {
+        let one = BLOCK_ONE.block_ptr().hash_as_h256();
+        let canonical = GENESIS_BLOCK.make_child(
+            "3cbc6a5a4a1ed0bc49a5fcd1e538a43cb4a1e8c4840ec3a7e211fdcf93ed5a1f",
+            None,
+        );
+        let canonical_hash = canonical.block_ptr().hash_as_h256();
+        // Another writer stores a new version of the stale block while the provider is asked for
+        // the canonical one
+        let writer = store.cheap_clone();
+        let provider = FakeProvider::new(
+            &store,
+            vec![light_block(&GENESIS_BLOCK), light_block(&canonical)],
+        )
+        .on_block_by_number(move || {
+            let rewritten = FakeBlock {
+                timestamp: Some(U256::from(42)),
+                ..BLOCK_ONE.clone()
+            };
+            assert!(writer
+                .replace_block_if_unchanged(&one, &rewritten, |_| true)
+                .unwrap());
+        });
+        let options = CheckOptions {
+            replace: true,
+            skip_confirmation: true,
+            ..CheckOptions::default()
+        };
+
+        let report = by_number(
+            1,
+            store.cheap_clone(),
+            &provider,
+            &discard(),
+            false,
+            true,
+            &options,
+        )
+        .await
+        .expect("the check succeeds");
+
+        assert_eq!(vec![one], report.raced);
+        assert!(report.replaced.is_empty());
+        assert_eq!(1, store.blocks(&[one.into()]).unwrap().len());
+        assert!(store.blocks(&[canonical_hash.into()]).unwrap().is_empty());
+        assert_eq!(3, store.cached_block_count().unwrap());
+    })
+}